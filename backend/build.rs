@@ -1,5 +1,6 @@
 use std::fs::File;
 
+use migration::{sea_orm::Database, MigratorTrait, RealtimeMigrator};
 use regex::Regex;
 use sea_orm_cli::{DateTimeCrate, GenerateSubcommands, MigrateSubcommands};
 use std::fs;
@@ -17,10 +18,19 @@ fn main() {
     File::create(&db_path).unwrap();
     let db_url = format!("sqlite://{}", db_path.display());
 
+    // Only the schema is wanted, so there's no realtime database attached
+    // nor old realtime data to copy into one (see migrate_realtime)
     let cmd = MigrateSubcommands::Up { num: None };
     sea_orm_cli::run_migrate_command(Some(cmd), MIGRATION_DIR, None, Some(db_url.clone()), false)
         .unwrap();
 
+    // The realtime tables are in a database of their own, but their entities are generated with the rest
+    tokio::runtime::Runtime::new().unwrap().block_on(async {
+        let db = Database::connect(&db_url).await.unwrap();
+        RealtimeMigrator::up(&db, None).await.unwrap();
+        db.close().await.unwrap();
+    });
+
     let cmd = GenerateSubcommands::Entity {
         output_dir: ENTITY_DIR.to_string(),
         database_url: db_url,
@@ -30,7 +40,15 @@ fn main() {
         expanded_format: false,
         include_hidden_tables: false,
         tables: vec![],
-        ignore_tables: vec![],
+        // The stop index is an R*Tree, which isn't an ordinary table (nor are its own tables)
+        ignore_tables: [
+            "stop_index",
+            "stop_index_node",
+            "stop_index_parent",
+            "stop_index_rowid",
+        ]
+        .map(String::from)
+        .to_vec(),
         max_connections: 1,
         database_schema: "public".to_string(),
         serde_skip_deserializing_primary_key: false,
@@ -0,0 +1,75 @@
+-- A small feed for tests, loaded over the migrated schema by test_utils::db.
+-- The index is built as it would be for a run that started 5 minutes ago,
+-- so there are always arrivals to find.
+
+-- As for imports, trips reference shapes by a column that isn't unique
+PRAGMA foreign_keys = OFF;
+
+INSERT INTO "import" (id, feed_id, record_count, completed_timestamp, active)
+VALUES (1, 'at', 17, CURRENT_TIMESTAMP, 1);
+
+INSERT INTO gtfs_agency (agency_id, agency_name, agency_url, agency_timezone, agency_lang, import_id, feed_id)
+VALUES ('AM', 'AT Metro', 'https://at.govt.nz', 'Pacific/Auckland', 'en', 1, 'at');
+
+INSERT INTO service (service_id) VALUES ('daily');
+
+INSERT INTO gtfs_calendar (service_id, monday, tuesday, wednesday, thursday, friday, saturday, sunday, start_date, end_date, import_id, feed_id)
+VALUES ('daily', 1, 1, 1, 1, 1, 1, 1, 20200101, 20991231, 1, 'at');
+
+-- Yesterday to tomorrow, which is all the index covers
+INSERT INTO service_dates (service_id, date)
+SELECT 'daily', CAST(strftime('%Y%m%d', 'now', d.offset || ' days') AS INTEGER)
+FROM (SELECT -1 AS offset UNION SELECT 0 UNION SELECT 1) d;
+
+INSERT INTO gtfs_routes (route_id, route_short_name, route_long_name, route_type, agency_id, route_color, route_text_color, import_id, feed_id)
+VALUES
+    ('NX1-203', 'NX1', 'Britomart To Hibiscus Coast Station', 3, 'AM', '00A8E6', 'FFFFFF', 1, 'at'),
+    ('WEST-201', 'WEST', 'Britomart To Swanson Station', 2, 'AM', '6BB43F', 'FFFFFF', 1, 'at');
+
+INSERT INTO gtfs_stops (stop_id, stop_code, stop_name, stop_desc, location_type, parent_station, stop_lat, stop_lon, import_id, feed_id)
+VALUES
+    ('4018-7ef4a7b7', '4018', 'Lower Albert Street', '', 0, NULL, -36.84479, 174.76592, 1, 'at'),
+    ('7000-0b6a8a4a', '7000', 'Wellesley Street', '', 0, NULL, -36.85087, 174.76431, 1, 'at'),
+    ('1010-0c2d2a6b', '1010', 'Quay Street', '', 0, NULL, -36.84318, 174.76754, 1, 'at'),
+    ('133-1b4e7d5f', '133', 'Britomart Train Station', '', 1, NULL, -36.84429, 174.76811, 1, 'at'),
+    ('9218-20fd5c4e', '9218', 'Britomart Train Station 1', '', 0, '133-1b4e7d5f', -36.84429, 174.76811, 1, 'at'),
+    ('9218-3d2f2a11', '9219', 'Britomart Train Station 2', '', 0, '133-1b4e7d5f', -36.84432, 174.76815, 1, 'at');
+
+INSERT INTO gtfs_trips (trip_id, service_id, route_id, trip_headsign, direction_id, import_id, feed_id)
+VALUES
+    ('1-NX1-1', 'daily', 'NX1-203', 'Hibiscus Coast', 0, 1, 'at'),
+    ('1-WEST-1', 'daily', 'WEST-201', 'Swanson', 0, 1, 'at');
+
+INSERT INTO gtfs_stop_times (trip_id, stop_sequence, arrival_time, departure_time, stop_id, stop_headsign, import_id, feed_id)
+VALUES
+    ('1-NX1-1', 1, '08:00:00', '08:00:00', '1010-0c2d2a6b', 'Hibiscus Coast', 1, 'at'),
+    ('1-NX1-1', 2, '08:05:00', '08:05:00', '4018-7ef4a7b7', 'Hibiscus Coast', 1, 'at'),
+    ('1-NX1-1', 3, '08:10:00', '08:10:00', '7000-0b6a8a4a', 'Hibiscus Coast', 1, 'at'),
+    ('1-WEST-1', 1, '08:00:00', '08:00:00', '9218-20fd5c4e', 'Swanson', 1, 'at');
+
+INSERT INTO gtfs_transfers (from_stop_id, to_stop_id, transfer_type, min_transfer_time, import_id, feed_id)
+VALUES ('4018-7ef4a7b7', '9218-20fd5c4e', 2, 300, 1, 'at');
+
+-- Boxes of the index's 1 km search distance
+INSERT INTO stop_index (stop_id, min_lat, max_lat, min_lon, max_lon)
+SELECT stop_id, stop_lat - 0.009, stop_lat + 0.009, stop_lon - 0.0112, stop_lon + 0.0112
+FROM gtfs_stops;
+
+INSERT INTO trip_run (id, trip_id, route_id, direction_id, start_date, start_timestamp, feed_id)
+VALUES
+    (1, '1-NX1-1', 'NX1-203', 0, strftime('%Y%m%d', 'now'), (strftime('%s', 'now') - 300) * 1000, 'at'),
+    (2, '1-WEST-1', 'WEST-201', 0, strftime('%Y%m%d', 'now'), (strftime('%s', 'now') + 600) * 1000, 'at');
+
+INSERT INTO stop_time_index (stop_id, stop_sequence, trip_id, trip_run_id, arrival_timestamp, departure_timestamp)
+SELECT st.stop_id, st.stop_sequence, st.trip_id, tr.id,
+    tr.start_timestamp + (st.stop_sequence - 1) * 300000,
+    tr.start_timestamp + (st.stop_sequence - 1) * 300000
+FROM gtfs_stop_times st
+JOIN trip_run tr ON tr.trip_id = st.trip_id;
+
+-- A box around the stops, rather than their hull
+INSERT INTO service_area (id, hull) VALUES (1, '~e~_F_ysi`@?o}@_|B??n}@~{B?');
+
+INSERT INTO maintenance_time (id, minute_of_day, timezone) VALUES (1, 240, 'Pacific/Auckland');
+
+PRAGMA foreign_keys = ON;
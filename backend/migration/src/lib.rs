@@ -6,10 +6,38 @@ use crate::raw::*;
 
 pub struct Migrator;
 
-sql_up!("000001_gtfs_tables");
-sql_up!("000002_realtime");
+sql_up_down!("000001_gtfs_tables");
+sql_up_down!("000002_realtime");
 sql_up_down!("000003_stop_time_index_table");
-sql_up!("000004_stop_time_index_indexes");
+sql_up_down!("000004_stop_time_index_indexes");
+sql_up_down!("000005_import_stats");
+sql_up_down!("000006_realtime_entity");
+sql_up_down!("000007_stop_time_index_skipped");
+sql_up_down!("000008_realtime_added_trips");
+sql_up_down!("000009_stop_time_index_updated_stop");
+sql_up_down!("000010_stop_time_index_updated_departure");
+sql_up_down!("000011_realtime_dead_letter");
+sql_up_down!("000012_vehicle_position_history");
+sql_up_down!("000013_stop_time_index_estimated");
+sql_up_down!("000014_trip_run_last_update");
+sql_up_down!("000015_gtfs_frequencies");
+sql_up_down!("000016_gtfs_transfers");
+sql_up_down!("000017_gtfs_pathways");
+sql_up_down!("000018_gtfs_fares");
+sql_up_down!("000019_feed_id");
+sql_up_down!("000020_import_file_sha256");
+sql_up_down!("000021_import_active");
+sql_up_down!("000022_gtfs_translations");
+sql_up_down!("000023_service_dates");
+sql_up_down!("000024_index_change");
+sql_up_down!("000025_stop_index_rtree");
+sql_up_down!("000026_maintenance_timezone");
+sql_up_down!("000027_realtime_database");
+sql_up_down!("000028_service_area");
+sql_up_down!("000029_push_subscriptions");
+sql_up_down!("000030_stop_time_index_cancelled");
+sql_up_down!("000031_trip_run_vehicle_timestamp");
+sql_up_down!("000032_push_subscription_failures");
 
 #[async_trait::async_trait]
 impl MigratorTrait for Migrator {
@@ -19,6 +47,62 @@ impl MigratorTrait for Migrator {
             Sql000002Realtime::boxed(),
             Sql000003StopTimeIndexTable::boxed(),
             Sql000004StopTimeIndexIndexes::boxed(),
+            Sql000005ImportStats::boxed(),
+            Sql000006RealtimeEntity::boxed(),
+            Sql000007StopTimeIndexSkipped::boxed(),
+            Sql000008RealtimeAddedTrips::boxed(),
+            Sql000009StopTimeIndexUpdatedStop::boxed(),
+            Sql000010StopTimeIndexUpdatedDeparture::boxed(),
+            Sql000011RealtimeDeadLetter::boxed(),
+            Sql000012VehiclePositionHistory::boxed(),
+            Sql000013StopTimeIndexEstimated::boxed(),
+            Sql000014TripRunLastUpdate::boxed(),
+            Sql000015GtfsFrequencies::boxed(),
+            Sql000016GtfsTransfers::boxed(),
+            Sql000017GtfsPathways::boxed(),
+            Sql000018GtfsFares::boxed(),
+            Sql000019FeedId::boxed(),
+            Sql000020ImportFileSha256::boxed(),
+            Sql000021ImportActive::boxed(),
+            Sql000022GtfsTranslations::boxed(),
+            Sql000023ServiceDates::boxed(),
+            Sql000024IndexChange::boxed(),
+            Sql000025StopIndexRtree::boxed(),
+            Sql000026MaintenanceTimezone::boxed(),
+            Sql000027RealtimeDatabase::boxed(),
+            Sql000028ServiceArea::boxed(),
+            Sql000029PushSubscriptions::boxed(),
+            Sql000030StopTimeIndexCancelled::boxed(),
+            Sql000031TripRunVehicleTimestamp::boxed(),
+            Sql000032PushSubscriptionFailures::boxed(),
         ]
     }
 }
+
+/// The realtime-only tables, which are in a database of their own attached to the main one,
+/// so realtime writes don't wait for static imports to commit.
+/// It must be run on a connection to the realtime database itself.
+pub struct RealtimeMigrator;
+
+realtime_sql_up_down!("000001_realtime_tables");
+realtime_sql_up_down!("000002_vehicle_congestion");
+realtime_sql_up_down!("000003_alert_details");
+realtime_sql_up_down!("000004_alert_changes");
+realtime_sql_up_down!("000005_dead_letter_failures");
+
+#[async_trait::async_trait]
+impl MigratorTrait for RealtimeMigrator {
+    fn migrations() -> Vec<Box<dyn MigrationTrait>> {
+        vec![
+            Sql000001RealtimeTables::boxed(),
+            Sql000002VehicleCongestion::boxed(),
+            Sql000003AlertDetails::boxed(),
+            Sql000004AlertChanges::boxed(),
+            Sql000005DeadLetterFailures::boxed(),
+        ]
+    }
+
+    fn migration_table_name() -> DynIden {
+        Alias::new("seaql_realtime_migrations").into_iden()
+    }
+}
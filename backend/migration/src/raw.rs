@@ -54,6 +54,18 @@ macro_rules! sql_up_down {
     };
 }
 
+/// A migration of the realtime database, see `RealtimeMigrator`
+#[macro_export]
+macro_rules! realtime_sql_up_down {
+    ($name:expr) => {
+        sql_migration!(
+            $name,
+            include_str!(concat!("realtime_sql/", $name, "/up.sql")).to_string(),
+            Some(include_str!(concat!("realtime_sql/", $name, "/down.sql")).to_string())
+        );
+    };
+}
+
 pub struct RawMigration<T: RawSql> {
     name: String,
     _sql: T,
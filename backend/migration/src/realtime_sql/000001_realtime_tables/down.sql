@@ -0,0 +1,8 @@
+DROP TABLE "realtime_dead_letter";
+DROP TABLE "realtime_entity";
+DROP TABLE "alert_translation";
+DROP TABLE "alert_informed_entity";
+DROP TABLE "alert_active_period";
+DROP TABLE "alert";
+DROP TABLE "vehicle_position_history";
+DROP TABLE "vehicle";
@@ -0,0 +1,90 @@
+-- The realtime-only tables, in their own database attached as "realtime".
+-- trip_run ids are in the main database, as are the static ids.
+
+CREATE TABLE "vehicle" (
+    "id" INTEGER PRIMARY KEY AUTOINCREMENT,
+    "vehicle_id" TEXT NOT NULL UNIQUE,
+    "timestamp" BIGINT NOT NULL,
+    "label" TEXT,
+    "license_plate" TEXT,
+    "latitude" REAL,
+    "longitude" REAL,
+    "bearing" REAL,
+    "speed" REAL,
+    "occupancy_status" INTEGER
+);
+CREATE INDEX "idx_vehicle_timestamp" ON "vehicle" ("timestamp");
+
+CREATE TABLE "vehicle_position_history" (
+    "id" INTEGER PRIMARY KEY AUTOINCREMENT,
+    "vehicle_id" TEXT NOT NULL,
+    "timestamp" BIGINT NOT NULL,
+    "latitude" REAL NOT NULL,
+    "longitude" REAL NOT NULL,
+    "bearing" REAL,
+    "speed" REAL,
+    "trip_run_id" BIGINT
+);
+CREATE UNIQUE INDEX "idx_vph_vehicle_timestamp" ON "vehicle_position_history" ("vehicle_id", "timestamp");
+CREATE INDEX "idx_vph_timestamp" ON "vehicle_position_history" ("timestamp");
+
+CREATE TABLE "alert" (
+    "id" INTEGER PRIMARY KEY AUTOINCREMENT,
+    "alert_id" TEXT UNIQUE,
+    "cause" INTEGER,
+    "effect" INTEGER,
+    "header_text" TEXT,
+    "description_text" TEXT,
+    "timestamp" BIGINT
+);
+
+CREATE TABLE "alert_active_period" (
+    "id" INTEGER PRIMARY KEY AUTOINCREMENT,
+    "alert_id" TEXT NOT NULL,
+    "start_timestamp" BIGINT NOT NULL,
+    "end_timestamp" BIGINT NOT NULL,
+    FOREIGN KEY ("alert_id") REFERENCES "alert" ("alert_id") ON DELETE CASCADE
+);
+
+CREATE TABLE "alert_informed_entity" (
+    "id" INTEGER PRIMARY KEY AUTOINCREMENT,
+    "alert_id" TEXT,
+    -- The rest are in the main database, so can't be foreign keys
+    "agency_id" TEXT,
+    "route_id" TEXT,
+    "route_type" INTEGER,
+    "direction_id" INTEGER,
+    "stop_id" TEXT,
+    "trip_run_id" BIGINT,
+    FOREIGN KEY ("alert_id") REFERENCES "alert" ("alert_id") ON DELETE CASCADE
+);
+
+CREATE TABLE "alert_translation" (
+    "id" INTEGER PRIMARY KEY AUTOINCREMENT,
+    "alert_id" TEXT NOT NULL,
+    -- header_text or description_text
+    "field" TEXT NOT NULL,
+    "language" TEXT,
+    "text" TEXT NOT NULL,
+    FOREIGN KEY ("alert_id") REFERENCES "alert" ("alert_id") ON DELETE CASCADE
+);
+CREATE INDEX "idx_at_alert_id" ON "alert_translation" ("alert_id");
+
+CREATE TABLE "realtime_entity" (
+    "entity_id" TEXT NOT NULL PRIMARY KEY,
+    "trip_run_id" BIGINT,
+    "vehicle_id" TEXT,
+    "alert_id" TEXT
+);
+
+CREATE TABLE "realtime_dead_letter" (
+    "id" INTEGER PRIMARY KEY AUTOINCREMENT,
+    "entity_id" TEXT NOT NULL,
+    -- The entity as it was in the feed
+    "entity" TEXT NOT NULL,
+    "error" TEXT NOT NULL,
+    -- Whether the entity came from a differential feed
+    "differential" INTEGER NOT NULL DEFAULT 0,
+    "timestamp" BIGINT NOT NULL
+);
+CREATE INDEX "idx_rdl_timestamp" ON "realtime_dead_letter" ("timestamp");
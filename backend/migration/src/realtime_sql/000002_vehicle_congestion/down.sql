@@ -0,0 +1,2 @@
+ALTER TABLE "vehicle_position_history" DROP COLUMN "congestion_level";
+ALTER TABLE "vehicle" DROP COLUMN "congestion_level";
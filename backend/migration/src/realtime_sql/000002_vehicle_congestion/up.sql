@@ -0,0 +1,3 @@
+-- CongestionLevel from the feed's vehicle positions
+ALTER TABLE "vehicle" ADD COLUMN "congestion_level" INTEGER;
+ALTER TABLE "vehicle_position_history" ADD COLUMN "congestion_level" INTEGER;
@@ -0,0 +1,4 @@
+DROP TABLE "alert_image";
+ALTER TABLE "alert" DROP COLUMN "image_alternative_text";
+ALTER TABLE "alert" DROP COLUMN "url";
+ALTER TABLE "alert" DROP COLUMN "severity_level";
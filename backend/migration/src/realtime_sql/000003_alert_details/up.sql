@@ -0,0 +1,17 @@
+-- alert_translation now also has the url and image_alternative_text fields
+
+-- SeverityLevel from the feed
+ALTER TABLE "alert" ADD COLUMN "severity_level" INTEGER;
+-- In the preferred language, like header_text
+ALTER TABLE "alert" ADD COLUMN "url" TEXT;
+ALTER TABLE "alert" ADD COLUMN "image_alternative_text" TEXT;
+
+CREATE TABLE "alert_image" (
+    "id" INTEGER PRIMARY KEY AUTOINCREMENT,
+    "alert_id" TEXT NOT NULL,
+    "url" TEXT NOT NULL,
+    "media_type" TEXT NOT NULL,
+    "language" TEXT,
+    FOREIGN KEY ("alert_id") REFERENCES "alert" ("alert_id") ON DELETE CASCADE
+);
+CREATE INDEX "idx_ai_alert_id" ON "alert_image" ("alert_id");
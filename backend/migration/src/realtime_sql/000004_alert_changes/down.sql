@@ -0,0 +1,3 @@
+ALTER TABLE "alert" DROP COLUMN "content_hash";
+ALTER TABLE "alert" DROP COLUMN "created_timestamp";
+ALTER TABLE "alert" RENAME COLUMN "updated_timestamp" TO "timestamp";
@@ -0,0 +1,7 @@
+-- Alerts are now updated in place when they change, rather than replaced on every poll
+ALTER TABLE "alert" RENAME COLUMN "timestamp" TO "updated_timestamp";
+ALTER TABLE "alert" ADD COLUMN "created_timestamp" BIGINT;
+-- Of what the feed said of the alert, see `process_alert`
+ALTER TABLE "alert" ADD COLUMN "content_hash" TEXT;
+
+UPDATE "alert" SET "created_timestamp" = "updated_timestamp";
@@ -0,0 +1,2 @@
+DROP INDEX "idx_rdl_entity_id";
+ALTER TABLE "realtime_dead_letter" DROP COLUMN "failures";
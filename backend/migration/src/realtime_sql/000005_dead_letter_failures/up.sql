@@ -0,0 +1,6 @@
+-- An entity that keeps failing is counted on one row, rather than adding a row every poll
+ALTER TABLE "realtime_dead_letter" ADD COLUMN "failures" INTEGER NOT NULL DEFAULT 1;
+
+DELETE FROM "realtime_dead_letter"
+WHERE "id" NOT IN (SELECT MAX("id") FROM "realtime_dead_letter" GROUP BY "entity_id");
+CREATE UNIQUE INDEX "idx_rdl_entity_id" ON "realtime_dead_letter" ("entity_id");
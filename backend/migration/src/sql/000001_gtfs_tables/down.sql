@@ -0,0 +1,14 @@
+-- Dependents are dropped first
+DROP TABLE IF EXISTS "maintenance_time";
+DROP TABLE IF EXISTS "gtfs_stop_times";
+DROP TABLE IF EXISTS "stop_index";
+DROP TABLE IF EXISTS "gtfs_stops";
+DROP TABLE IF EXISTS "gtfs_trips";
+DROP TABLE IF EXISTS "gtfs_routes";
+DROP TABLE IF EXISTS "gtfs_shapes";
+DROP TABLE IF EXISTS "gtfs_feed_info";
+DROP TABLE IF EXISTS "gtfs_calendar_dates";
+DROP TABLE IF EXISTS "gtfs_calendar";
+DROP TABLE IF EXISTS "service";
+DROP TABLE IF EXISTS "gtfs_agency";
+DROP TABLE IF EXISTS "import";
@@ -0,0 +1,5 @@
+DROP TABLE "alert_informed_entity";
+DROP TABLE "alert_active_period";
+DROP TABLE "alert";
+DROP TABLE "trip_run";
+DROP TABLE "vehicle";
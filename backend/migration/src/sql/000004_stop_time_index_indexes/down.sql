@@ -0,0 +1,5 @@
+DROP INDEX "idx_sti_stop_id";
+DROP INDEX "idx_sti_trip_id";
+DROP INDEX "idx_sti_trip_run_id";
+DROP INDEX "idx_sti_arrival_timestamp";
+DROP INDEX "idx_sti_updated_timestamp";
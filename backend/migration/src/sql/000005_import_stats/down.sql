@@ -0,0 +1,2 @@
+ALTER TABLE "import" DROP COLUMN "completed_timestamp";
+ALTER TABLE "import" DROP COLUMN "record_count";
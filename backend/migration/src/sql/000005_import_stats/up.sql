@@ -0,0 +1,3 @@
+-- Record the outcome of each import so operators can see whether syncs ran
+ALTER TABLE "import" ADD COLUMN "record_count" BIGINT;
+ALTER TABLE "import" ADD COLUMN "completed_timestamp" TEXT;
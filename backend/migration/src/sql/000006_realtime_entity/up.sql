@@ -0,0 +1,8 @@
+-- What each entity of a differential realtime feed last changed,
+-- so that it can be undone when the entity is replaced or deleted
+CREATE TABLE "realtime_entity" (
+    "entity_id" TEXT NOT NULL PRIMARY KEY,
+    "trip_run_id" BIGINT,
+    "vehicle_id" TEXT,
+    "alert_id" TEXT
+);
@@ -0,0 +1,2 @@
+-- Set when a trip update says the vehicle won't stop here
+ALTER TABLE "stop_time_index" ADD COLUMN "skipped" INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,54 @@
+-- Trips added by the realtime feed can't be kept once the foreign keys are back
+PRAGMA foreign_keys = OFF;
+
+CREATE TABLE "trip_run_old" (
+    "id" INTEGER PRIMARY KEY AUTOINCREMENT,
+    "trip_id" TEXT NOT NULL,
+    "route_id" TEXT NOT NULL,
+    "direction_id" INTEGER,
+    -- The scheduled date of the trip, which could be the previous day
+    -- this aids in searching from a TripDescriptor
+    "start_date" TEXT NOT NULL,
+    "start_timestamp" BIGINT NOT NULL,
+    "schedule_relationship" INTEGER NOT NULL DEFAULT 0,
+    -- vehicle assigned to this trip if known
+    "vehicle_id" TEXT,
+    UNIQUE ("trip_id", "start_timestamp"),
+    FOREIGN KEY ("trip_id") REFERENCES "gtfs_trips" ("trip_id"),
+    FOREIGN KEY ("route_id") REFERENCES "gtfs_routes" ("route_id"),
+    FOREIGN KEY ("vehicle_id") REFERENCES "vehicle" ("vehicle_id")
+);
+
+INSERT INTO "trip_run_old" SELECT * FROM "trip_run"
+WHERE "trip_id" IN (SELECT "trip_id" FROM "gtfs_trips");
+DROP TABLE "trip_run";
+ALTER TABLE "trip_run_old" RENAME TO "trip_run";
+
+CREATE INDEX "idx_tr_day_route" ON "trip_run" ("route_id", "start_date");
+
+CREATE TABLE "stop_time_index_old" (
+    "id" INTEGER PRIMARY KEY AUTOINCREMENT,
+    "stop_id" TEXT NOT NULL,
+    "stop_sequence" INTEGER NOT NULL,
+    "trip_id" TEXT NOT NULL,
+    "trip_run_id" BIGINT NOT NULL,
+    "arrival_timestamp" BIGINT NOT NULL,
+    "departure_timestamp" BIGINT NOT NULL,
+    "updated_arrival_timestamp" BIGINT,
+    "skipped" INTEGER NOT NULL DEFAULT 0,
+    FOREIGN KEY ("stop_id", "stop_sequence", "trip_id") REFERENCES "gtfs_stop_times" ("stop_id", "stop_sequence", "trip_id"),
+    FOREIGN KEY ("trip_run_id") REFERENCES "trip_run" ("id")
+);
+
+INSERT INTO "stop_time_index_old" SELECT * FROM "stop_time_index"
+WHERE "trip_run_id" IN (SELECT "id" FROM "trip_run");
+DROP TABLE "stop_time_index";
+ALTER TABLE "stop_time_index_old" RENAME TO "stop_time_index";
+
+CREATE INDEX "idx_sti_stop_id" ON "stop_time_index" ("stop_id");
+CREATE INDEX "idx_sti_trip_id" ON "stop_time_index" ("trip_id");
+CREATE INDEX "idx_sti_trip_run_id" ON "stop_time_index" ("trip_run_id");
+CREATE INDEX "idx_sti_arrival_timestamp" ON "stop_time_index" ("arrival_timestamp");
+CREATE INDEX "idx_sti_updated_timestamp" ON "stop_time_index" ("updated_arrival_timestamp");
+
+PRAGMA foreign_keys = ON;
@@ -0,0 +1,2 @@
+DROP INDEX "idx_sti_updated_stop_id";
+ALTER TABLE "stop_time_index" DROP COLUMN "updated_stop_id";
@@ -0,0 +1,4 @@
+-- The stop (e.g. platform) a trip update has reassigned this stop time to
+ALTER TABLE "stop_time_index" ADD COLUMN "updated_stop_id" TEXT;
+
+CREATE INDEX "idx_sti_updated_stop_id" ON "stop_time_index" ("updated_stop_id");
@@ -0,0 +1,2 @@
+-- Departure delays are tracked separately from arrival delays
+ALTER TABLE "stop_time_index" ADD COLUMN "updated_departure_timestamp" BIGINT;
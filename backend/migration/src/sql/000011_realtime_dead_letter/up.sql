@@ -0,0 +1,13 @@
+-- Realtime entities which failed to process, kept so they can be looked at and retried
+CREATE TABLE "realtime_dead_letter" (
+    "id" INTEGER PRIMARY KEY AUTOINCREMENT,
+    "entity_id" TEXT NOT NULL,
+    -- The entity as it was in the feed
+    "entity" TEXT NOT NULL,
+    "error" TEXT NOT NULL,
+    -- Whether the entity came from a differential feed
+    "differential" INTEGER NOT NULL DEFAULT 0,
+    "timestamp" BIGINT NOT NULL
+);
+
+CREATE INDEX "idx_rdl_timestamp" ON "realtime_dead_letter" ("timestamp");
@@ -0,0 +1 @@
+DROP TABLE "vehicle_position_history";
@@ -0,0 +1,16 @@
+-- Where vehicles have been, pruned by maintenance.
+-- Not linked to the vehicle table, as history is kept after the vehicle is removed.
+CREATE TABLE "vehicle_position_history" (
+    "id" INTEGER PRIMARY KEY AUTOINCREMENT,
+    "vehicle_id" TEXT NOT NULL,
+    "timestamp" BIGINT NOT NULL,
+    "latitude" REAL NOT NULL,
+    "longitude" REAL NOT NULL,
+    "bearing" REAL,
+    "speed" REAL,
+    "trip_run_id" BIGINT
+);
+
+-- The same position is usually in several polls of the feed
+CREATE UNIQUE INDEX "idx_vph_vehicle_timestamp" ON "vehicle_position_history" ("vehicle_id", "timestamp");
+CREATE INDEX "idx_vph_timestamp" ON "vehicle_position_history" ("timestamp");
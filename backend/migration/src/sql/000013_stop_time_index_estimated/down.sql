@@ -0,0 +1,2 @@
+ALTER TABLE "stop_time_index" DROP COLUMN "estimated_departure_timestamp";
+ALTER TABLE "stop_time_index" DROP COLUMN "estimated_arrival_timestamp";
@@ -0,0 +1,4 @@
+-- Times estimated from how far along the trip's shape the vehicle is,
+-- for trips which have a vehicle but no predictions in the feed
+ALTER TABLE "stop_time_index" ADD COLUMN "estimated_arrival_timestamp" BIGINT;
+ALTER TABLE "stop_time_index" ADD COLUMN "estimated_departure_timestamp" BIGINT;
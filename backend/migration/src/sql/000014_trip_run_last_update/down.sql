@@ -0,0 +1 @@
+ALTER TABLE "trip_run" DROP COLUMN "last_update_timestamp";
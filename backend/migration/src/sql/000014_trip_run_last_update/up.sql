@@ -0,0 +1,3 @@
+-- Timestamp (millis) of the newest trip update applied to the trip run,
+-- so that older updates arriving late can be ignored
+ALTER TABLE "trip_run" ADD COLUMN "last_update_timestamp" BIGINT;
@@ -0,0 +1,2 @@
+ALTER TABLE "trip_run" DROP COLUMN "headway_secs";
+DROP TABLE IF EXISTS "gtfs_frequencies";
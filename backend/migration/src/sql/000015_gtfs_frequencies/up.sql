@@ -0,0 +1,16 @@
+CREATE TABLE IF NOT EXISTS "gtfs_frequencies" (
+    "id" INTEGER PRIMARY KEY AUTOINCREMENT,
+    "trip_id" TEXT NOT NULL,
+    "start_time" TEXT NOT NULL,
+    "end_time" TEXT NOT NULL,
+    "headway_secs" INTEGER NOT NULL,
+    "exact_times" INTEGER,
+    "import_id" INTEGER NOT NULL,
+    UNIQUE ("trip_id", "start_time"),
+    FOREIGN KEY ("import_id") REFERENCES "import" ("id"),
+    FOREIGN KEY ("trip_id") REFERENCES "gtfs_trips" ("trip_id")
+);
+
+-- Set for runs of frequency based trips which aren't exactly timed,
+-- the vehicle is only expected roughly every headway_secs
+ALTER TABLE "trip_run" ADD COLUMN "headway_secs" INTEGER;
@@ -0,0 +1 @@
+DROP TABLE IF EXISTS "gtfs_transfers";
@@ -0,0 +1,12 @@
+CREATE TABLE IF NOT EXISTS "gtfs_transfers" (
+    "id" INTEGER PRIMARY KEY AUTOINCREMENT,
+    "from_stop_id" TEXT NOT NULL,
+    "to_stop_id" TEXT NOT NULL,
+    "transfer_type" INTEGER NOT NULL,
+    "min_transfer_time" INTEGER,
+    "import_id" INTEGER NOT NULL,
+    UNIQUE ("from_stop_id", "to_stop_id"),
+    FOREIGN KEY ("import_id") REFERENCES "import" ("id"),
+    FOREIGN KEY ("from_stop_id") REFERENCES "gtfs_stops" ("stop_id"),
+    FOREIGN KEY ("to_stop_id") REFERENCES "gtfs_stops" ("stop_id")
+);
@@ -0,0 +1,3 @@
+DROP INDEX IF EXISTS "idx_s_parent_station";
+DROP TABLE IF EXISTS "gtfs_pathways";
+DROP TABLE IF EXISTS "gtfs_levels";
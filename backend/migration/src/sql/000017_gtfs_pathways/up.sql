@@ -0,0 +1,31 @@
+CREATE TABLE IF NOT EXISTS "gtfs_levels" (
+    "id" INTEGER PRIMARY KEY AUTOINCREMENT,
+    "level_id" TEXT NOT NULL UNIQUE,
+    "level_index" REAL NOT NULL,
+    "level_name" TEXT,
+    "import_id" INTEGER NOT NULL,
+    FOREIGN KEY ("import_id") REFERENCES "import" ("id")
+);
+
+CREATE TABLE IF NOT EXISTS "gtfs_pathways" (
+    "id" INTEGER PRIMARY KEY AUTOINCREMENT,
+    "pathway_id" TEXT NOT NULL UNIQUE,
+    "from_stop_id" TEXT NOT NULL,
+    "to_stop_id" TEXT NOT NULL,
+    "pathway_mode" INTEGER NOT NULL,
+    "is_bidirectional" INTEGER NOT NULL,
+    "length" REAL,
+    "traversal_time" INTEGER,
+    "stair_count" INTEGER,
+    "max_slope" REAL,
+    "min_width" REAL,
+    "signposted_as" TEXT,
+    "reversed_signposted_as" TEXT,
+    "import_id" INTEGER NOT NULL,
+    FOREIGN KEY ("import_id") REFERENCES "import" ("id"),
+    FOREIGN KEY ("from_stop_id") REFERENCES "gtfs_stops" ("stop_id"),
+    FOREIGN KEY ("to_stop_id") REFERENCES "gtfs_stops" ("stop_id")
+);
+
+CREATE INDEX IF NOT EXISTS "idx_p_from_stop_id" ON "gtfs_pathways" ("from_stop_id");
+CREATE INDEX IF NOT EXISTS "idx_s_parent_station" ON "gtfs_stops" ("parent_station");
@@ -0,0 +1,2 @@
+DROP TABLE IF EXISTS "gtfs_fare_rules";
+DROP TABLE IF EXISTS "gtfs_fare_attributes";
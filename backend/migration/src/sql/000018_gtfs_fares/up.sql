@@ -0,0 +1,29 @@
+CREATE TABLE IF NOT EXISTS "gtfs_fare_attributes" (
+    "id" INTEGER PRIMARY KEY AUTOINCREMENT,
+    "fare_id" TEXT NOT NULL UNIQUE,
+    -- Kept as written in the feed, so the amount is exact
+    "price" TEXT NOT NULL,
+    "currency_type" TEXT NOT NULL,
+    "payment_method" INTEGER NOT NULL,
+    -- Empty means unlimited transfers
+    "transfers" INTEGER,
+    "agency_id" TEXT,
+    "transfer_duration" INTEGER,
+    "import_id" INTEGER NOT NULL,
+    FOREIGN KEY ("import_id") REFERENCES "import" ("id")
+);
+
+CREATE TABLE IF NOT EXISTS "gtfs_fare_rules" (
+    "id" INTEGER PRIMARY KEY AUTOINCREMENT,
+    "fare_id" TEXT NOT NULL,
+    "route_id" TEXT,
+    "origin_id" TEXT,
+    "destination_id" TEXT,
+    "contains_id" TEXT,
+    "import_id" INTEGER NOT NULL,
+    FOREIGN KEY ("import_id") REFERENCES "import" ("id"),
+    FOREIGN KEY ("fare_id") REFERENCES "gtfs_fare_attributes" ("fare_id")
+);
+
+CREATE INDEX IF NOT EXISTS "idx_fr_route_id" ON "gtfs_fare_rules" ("route_id");
+CREATE INDEX IF NOT EXISTS "idx_fr_origin_destination" ON "gtfs_fare_rules" ("origin_id", "destination_id");
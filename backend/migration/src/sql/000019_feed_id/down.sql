@@ -0,0 +1,18 @@
+-- Only right while a single feed is configured, records of other feeds would clash
+ALTER TABLE "trip_run" DROP COLUMN "feed_id";
+ALTER TABLE "gtfs_fare_rules" DROP COLUMN "feed_id";
+ALTER TABLE "gtfs_fare_attributes" DROP COLUMN "feed_id";
+ALTER TABLE "gtfs_pathways" DROP COLUMN "feed_id";
+ALTER TABLE "gtfs_levels" DROP COLUMN "feed_id";
+ALTER TABLE "gtfs_transfers" DROP COLUMN "feed_id";
+ALTER TABLE "gtfs_frequencies" DROP COLUMN "feed_id";
+ALTER TABLE "gtfs_stop_times" DROP COLUMN "feed_id";
+ALTER TABLE "gtfs_stops" DROP COLUMN "feed_id";
+ALTER TABLE "gtfs_trips" DROP COLUMN "feed_id";
+ALTER TABLE "gtfs_routes" DROP COLUMN "feed_id";
+ALTER TABLE "gtfs_shapes" DROP COLUMN "feed_id";
+ALTER TABLE "gtfs_feed_info" DROP COLUMN "feed_id";
+ALTER TABLE "gtfs_calendar_dates" DROP COLUMN "feed_id";
+ALTER TABLE "gtfs_calendar" DROP COLUMN "feed_id";
+ALTER TABLE "gtfs_agency" DROP COLUMN "feed_id";
+ALTER TABLE "import" DROP COLUMN "feed_id";
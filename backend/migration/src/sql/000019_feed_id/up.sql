@@ -0,0 +1,19 @@
+-- Which configured feed each record came from, so several agencies can be served at once.
+-- Existing data is from the Auckland Transport feed.
+ALTER TABLE "import" ADD COLUMN "feed_id" TEXT NOT NULL DEFAULT 'at';
+ALTER TABLE "gtfs_agency" ADD COLUMN "feed_id" TEXT NOT NULL DEFAULT 'at';
+ALTER TABLE "gtfs_calendar" ADD COLUMN "feed_id" TEXT NOT NULL DEFAULT 'at';
+ALTER TABLE "gtfs_calendar_dates" ADD COLUMN "feed_id" TEXT NOT NULL DEFAULT 'at';
+ALTER TABLE "gtfs_feed_info" ADD COLUMN "feed_id" TEXT NOT NULL DEFAULT 'at';
+ALTER TABLE "gtfs_shapes" ADD COLUMN "feed_id" TEXT NOT NULL DEFAULT 'at';
+ALTER TABLE "gtfs_routes" ADD COLUMN "feed_id" TEXT NOT NULL DEFAULT 'at';
+ALTER TABLE "gtfs_trips" ADD COLUMN "feed_id" TEXT NOT NULL DEFAULT 'at';
+ALTER TABLE "gtfs_stops" ADD COLUMN "feed_id" TEXT NOT NULL DEFAULT 'at';
+ALTER TABLE "gtfs_stop_times" ADD COLUMN "feed_id" TEXT NOT NULL DEFAULT 'at';
+ALTER TABLE "gtfs_frequencies" ADD COLUMN "feed_id" TEXT NOT NULL DEFAULT 'at';
+ALTER TABLE "gtfs_transfers" ADD COLUMN "feed_id" TEXT NOT NULL DEFAULT 'at';
+ALTER TABLE "gtfs_levels" ADD COLUMN "feed_id" TEXT NOT NULL DEFAULT 'at';
+ALTER TABLE "gtfs_pathways" ADD COLUMN "feed_id" TEXT NOT NULL DEFAULT 'at';
+ALTER TABLE "gtfs_fare_attributes" ADD COLUMN "feed_id" TEXT NOT NULL DEFAULT 'at';
+ALTER TABLE "gtfs_fare_rules" ADD COLUMN "feed_id" TEXT NOT NULL DEFAULT 'at';
+ALTER TABLE "trip_run" ADD COLUMN "feed_id" TEXT NOT NULL DEFAULT 'at';
@@ -0,0 +1 @@
+ALTER TABLE "import" DROP COLUMN "file_sha256";
@@ -0,0 +1,2 @@
+-- Hash of the downloaded GTFS zip, so an unchanged file isn't imported again
+ALTER TABLE "import" ADD COLUMN "file_sha256" TEXT;
@@ -0,0 +1,5 @@
+-- The import of each feed whose records are being served, earlier imports may be kept to roll back to
+ALTER TABLE "import" ADD COLUMN "active" INTEGER NOT NULL DEFAULT 0;
+UPDATE "import" SET "active" = 1 WHERE "id" IN (
+    SELECT MAX("id") FROM "import" WHERE "completed_timestamp" IS NOT NULL GROUP BY "feed_id"
+);
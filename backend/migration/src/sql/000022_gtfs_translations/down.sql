@@ -0,0 +1,2 @@
+DROP TABLE IF EXISTS "alert_translation";
+DROP TABLE IF EXISTS "gtfs_translations";
@@ -0,0 +1,29 @@
+CREATE TABLE IF NOT EXISTS "gtfs_translations" (
+    "id" INTEGER PRIMARY KEY AUTOINCREMENT,
+    "table_name" TEXT NOT NULL,
+    "field_name" TEXT NOT NULL,
+    "language" TEXT NOT NULL,
+    "translation" TEXT NOT NULL,
+    -- Either the record is given by its id, or every record with the field value is translated
+    "record_id" TEXT,
+    "record_sub_id" TEXT,
+    "field_value" TEXT,
+    "import_id" INTEGER NOT NULL,
+    "feed_id" TEXT NOT NULL,
+    FOREIGN KEY ("import_id") REFERENCES "import" ("id")
+);
+
+CREATE INDEX IF NOT EXISTS "idx_tr_table_field" ON "gtfs_translations" ("table_name", "field_name");
+
+-- Every translation of an alert's text, alert.header_text and description_text are only the default language
+CREATE TABLE IF NOT EXISTS "alert_translation" (
+    "id" INTEGER PRIMARY KEY AUTOINCREMENT,
+    "alert_id" TEXT NOT NULL,
+    -- header_text or description_text
+    "field" TEXT NOT NULL,
+    "language" TEXT,
+    "text" TEXT NOT NULL,
+    FOREIGN KEY ("alert_id") REFERENCES "alert" ("alert_id") ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS "idx_at_alert_id" ON "alert_translation" ("alert_id");
@@ -0,0 +1,41 @@
+-- Every date each service runs on, from calendar and calendar_dates.
+-- Not a GTFS table, it's rebuilt after every sync.
+CREATE TABLE IF NOT EXISTS "service_dates" (
+    "id" INTEGER PRIMARY KEY AUTOINCREMENT,
+    "service_id" TEXT NOT NULL,
+    "date" INTEGER NOT NULL,
+    UNIQUE ("service_id", "date"),
+    FOREIGN KEY ("service_id") REFERENCES "service" ("service_id")
+);
+
+CREATE INDEX IF NOT EXISTS "idx_sd_date" ON "service_dates" ("date");
+
+-- Filled for what's already imported, so the index can be built before the next sync
+INSERT OR IGNORE INTO "service_dates" ("service_id", "date")
+WITH RECURSIVE "days" ("service_id", "day", "end_day") AS (
+    SELECT
+        "service_id",
+        date(substr("start_date", 1, 4) || '-' || substr("start_date", 5, 2) || '-' || substr("start_date", 7, 2)),
+        date(substr("end_date", 1, 4) || '-' || substr("end_date", 5, 2) || '-' || substr("end_date", 7, 2))
+    FROM "gtfs_calendar"
+    UNION ALL
+    SELECT "service_id", date("day", '+1 day'), "end_day" FROM "days" WHERE "day" < "end_day"
+)
+SELECT "d"."service_id", CAST(strftime('%Y%m%d', "d"."day") AS INTEGER)
+FROM "days" "d" JOIN "gtfs_calendar" "c" ON "c"."service_id" = "d"."service_id"
+WHERE CASE strftime('%w', "d"."day")
+    WHEN '0' THEN "c"."sunday"
+    WHEN '1' THEN "c"."monday"
+    WHEN '2' THEN "c"."tuesday"
+    WHEN '3' THEN "c"."wednesday"
+    WHEN '4' THEN "c"."thursday"
+    WHEN '5' THEN "c"."friday"
+    WHEN '6' THEN "c"."saturday"
+END = 1;
+
+INSERT OR IGNORE INTO "service_dates" ("service_id", "date")
+SELECT "service_id", "date" FROM "gtfs_calendar_dates" WHERE "exception_type" = 1 AND "service_id" IS NOT NULL;
+
+DELETE FROM "service_dates" WHERE ("service_id", "date") IN (
+    SELECT "service_id", "date" FROM "gtfs_calendar_dates" WHERE "exception_type" = 2
+);
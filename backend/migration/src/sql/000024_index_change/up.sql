@@ -0,0 +1,6 @@
+-- Trips changed by imports since the stop time index was built, so only their runs need rebuilding.
+-- A NULL trip_id means the whole index needs rebuilding.
+CREATE TABLE IF NOT EXISTS "index_change" (
+    "id" INTEGER PRIMARY KEY AUTOINCREMENT,
+    "trip_id" TEXT UNIQUE
+);
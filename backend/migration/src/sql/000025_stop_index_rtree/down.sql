@@ -0,0 +1,22 @@
+-- Back to a table with an index on each bound
+CREATE TABLE "stop_index_new" (
+    "id" INTEGER PRIMARY KEY AUTOINCREMENT,
+    "stop_id" TEXT NOT NULL UNIQUE,
+    "min_lat" REAL,
+    "max_lat" REAL,
+    "min_lon" REAL,
+    "max_lon" REAL,
+    FOREIGN KEY ("stop_id") REFERENCES "gtfs_stops" ("stop_id")
+);
+
+INSERT INTO "stop_index_new" ("id", "stop_id", "min_lat", "max_lat", "min_lon", "max_lon")
+SELECT "id", "stop_id", "min_lat", "max_lat", "min_lon", "max_lon" FROM "stop_index"
+WHERE "stop_id" IN (SELECT "stop_id" FROM "gtfs_stops");
+
+DROP TABLE "stop_index";
+ALTER TABLE "stop_index_new" RENAME TO "stop_index";
+
+CREATE INDEX IF NOT EXISTS "idx_si_min_lat" ON "stop_index" ("min_lat");
+CREATE INDEX IF NOT EXISTS "idx_si_max_lat" ON "stop_index" ("max_lat");
+CREATE INDEX IF NOT EXISTS "idx_si_min_lon" ON "stop_index" ("min_lon");
+CREATE INDEX IF NOT EXISTS "idx_si_max_lon" ON "stop_index" ("max_lon");
@@ -0,0 +1,17 @@
+-- An R*Tree, so finding the stops near a point doesn't have to scan the b-tree indexes of each bound.
+-- Virtual tables can't have foreign keys, so stop_id is an auxiliary column.
+-- Bounds are stored as 32-bit floats, rounded outwards, which only makes the boxes slightly bigger.
+ALTER TABLE "stop_index" RENAME TO "stop_index_old";
+
+CREATE VIRTUAL TABLE IF NOT EXISTS "stop_index" USING rtree(
+    "id",
+    "min_lat", "max_lat",
+    "min_lon", "max_lon",
+    +"stop_id" TEXT
+);
+
+INSERT INTO "stop_index" ("id", "min_lat", "max_lat", "min_lon", "max_lon", "stop_id")
+SELECT "id", "min_lat", "max_lat", "min_lon", "max_lon", "stop_id" FROM "stop_index_old"
+WHERE "min_lat" IS NOT NULL AND "max_lat" IS NOT NULL AND "min_lon" IS NOT NULL AND "max_lon" IS NOT NULL;
+
+DROP TABLE "stop_index_old";
@@ -0,0 +1 @@
+ALTER TABLE "maintenance_time" DROP COLUMN "timezone";
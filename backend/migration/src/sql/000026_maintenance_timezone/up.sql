@@ -0,0 +1,3 @@
+-- The maintenance window is the minute of the day in this timezone, so it follows local time through DST.
+-- NULL for windows calculated before, which are in UTC.
+ALTER TABLE "maintenance_time" ADD COLUMN "timezone" TEXT;
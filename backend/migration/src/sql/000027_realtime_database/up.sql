@@ -0,0 +1,42 @@
+-- Realtime-only tables are now in their own database, attached as "realtime",
+-- so realtime writes don't wait on static imports. See RealtimeMigrator.
+-- The vehicle position history and dead letters are copied across by migrate_realtime,
+-- as the realtime database is migrated first. The rest is refilled from the feeds.
+PRAGMA foreign_keys = OFF;
+
+DROP TABLE "alert_informed_entity";
+DROP TABLE "alert_active_period";
+DROP TABLE "alert_translation";
+DROP TABLE "alert";
+DROP TABLE "realtime_entity";
+DROP TABLE "realtime_dead_letter";
+DROP TABLE "vehicle_position_history";
+DROP TABLE "vehicle";
+
+-- Foreign keys can't reference another database, so trip_run is rebuilt without the one to vehicle
+CREATE TABLE "trip_run_new" (
+    "id" INTEGER PRIMARY KEY AUTOINCREMENT,
+    "trip_id" TEXT NOT NULL,
+    "route_id" TEXT NOT NULL,
+    "direction_id" INTEGER,
+    -- The scheduled date of the trip, which could be the previous day
+    -- this aids in searching from a TripDescriptor
+    "start_date" TEXT NOT NULL,
+    "start_timestamp" BIGINT NOT NULL,
+    "schedule_relationship" INTEGER NOT NULL DEFAULT 0,
+    -- vehicle assigned to this trip if known, in realtime.vehicle
+    "vehicle_id" TEXT,
+    "last_update_timestamp" BIGINT,
+    "headway_secs" INTEGER,
+    "feed_id" TEXT NOT NULL DEFAULT 'at',
+    UNIQUE ("trip_id", "start_timestamp"),
+    FOREIGN KEY ("route_id") REFERENCES "gtfs_routes" ("route_id")
+);
+
+INSERT INTO "trip_run_new" SELECT * FROM "trip_run";
+DROP TABLE "trip_run";
+ALTER TABLE "trip_run_new" RENAME TO "trip_run";
+
+CREATE INDEX "idx_tr_day_route" ON "trip_run" ("route_id", "start_date");
+
+PRAGMA foreign_keys = ON;
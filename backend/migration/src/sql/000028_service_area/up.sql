@@ -0,0 +1,7 @@
+-- The convex hull around every stop, rebuilt with the stop index.
+-- Only ever one row.
+CREATE TABLE "service_area" (
+    "id" INTEGER PRIMARY KEY,
+    -- Google encoded polyline of the hull's outline
+    "hull" TEXT NOT NULL
+);
@@ -0,0 +1,2 @@
+DROP TABLE "push_notification";
+DROP TABLE "push_subscription";
@@ -0,0 +1,28 @@
+-- Browsers subscribed (with Web Push) to delays and alerts at a stop or on a route
+CREATE TABLE "push_subscription" (
+    -- Random, as it's all that's needed to unsubscribe
+    "id" TEXT NOT NULL PRIMARY KEY,
+    -- The push service URL from the browser's PushSubscription, and its keys (base64url)
+    "endpoint" TEXT NOT NULL,
+    "p256dh" TEXT NOT NULL,
+    "auth" TEXT NOT NULL,
+    -- Not foreign keys, as the GTFS tables are replaced by each import
+    "stop_id" TEXT,
+    "route_id" TEXT,
+    -- Trips at least this late are notified
+    "delay_threshold_seconds" INTEGER NOT NULL,
+    -- Whether alerts are notified too
+    "alerts" INTEGER NOT NULL DEFAULT 1,
+    "created_timestamp" BIGINT NOT NULL
+);
+CREATE INDEX "idx_ps_endpoint" ON "push_subscription" ("endpoint");
+
+-- What's been sent to each subscription, so it isn't sent again
+CREATE TABLE "push_notification" (
+    "subscription_id" TEXT NOT NULL,
+    -- What it was about, e.g. delay:<trip_run_id> or alert:<alert_id>
+    "key" TEXT NOT NULL,
+    "timestamp" BIGINT NOT NULL,
+    PRIMARY KEY ("subscription_id", "key"),
+    FOREIGN KEY ("subscription_id") REFERENCES "push_subscription" ("id") ON DELETE CASCADE
+);
@@ -0,0 +1 @@
+ALTER TABLE "stop_time_index" DROP COLUMN "cancelled";
@@ -0,0 +1,7 @@
+-- Set while the trip run is canceled or deleted by the feed,
+-- so the stop times can be read without joining trip_run
+ALTER TABLE "stop_time_index" ADD COLUMN "cancelled" INTEGER NOT NULL DEFAULT 0;
+
+-- Canceled = 3, Deleted = 7
+UPDATE "stop_time_index" SET "cancelled" = 1
+WHERE "trip_run_id" IN (SELECT "id" FROM "trip_run" WHERE "schedule_relationship" IN (3, 7));
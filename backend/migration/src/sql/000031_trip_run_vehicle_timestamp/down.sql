@@ -0,0 +1 @@
+ALTER TABLE "trip_run" DROP COLUMN "vehicle_timestamp";
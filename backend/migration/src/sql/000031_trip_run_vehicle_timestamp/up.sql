@@ -0,0 +1,3 @@
+-- Timestamp (millis) of the newest report of the vehicle being on the trip run,
+-- so that vehicle positions and trip updates that disagree go with the newest
+ALTER TABLE "trip_run" ADD COLUMN "vehicle_timestamp" BIGINT;
@@ -0,0 +1 @@
+ALTER TABLE "push_subscription" DROP COLUMN "failures";
@@ -0,0 +1,2 @@
+-- Messages the push service has refused in a row, the subscription is removed after a few
+ALTER TABLE "push_subscription" ADD COLUMN "failures" INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,142 @@
+//! CSV versions of responses, so they can be pulled into spreadsheets as they are
+
+use std::collections::HashMap;
+
+use actix_web::{http::header::ACCEPT, web::Bytes, HttpRequest, HttpResponse};
+use futures_util::stream;
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::error::{NextAtError, NextAtResult};
+
+const CONTENT_TYPE: &str = "text/csv";
+
+#[derive(Deserialize)]
+pub struct CsvQuery {
+    /// `json` (the default) or `csv`
+    format: Option<String>,
+}
+
+impl CsvQuery {
+    /// Whether CSV was asked for, by `format` or otherwise the Accept header
+    pub fn is_csv(&self, req: &HttpRequest) -> NextAtResult<bool> {
+        match self.format.as_deref() {
+            Some("json") => Ok(false),
+            Some("csv") => Ok(true),
+            Some(format) => Err(NextAtError::InvalidData(format!(
+                "Unknown format {}, expected json or csv",
+                format
+            ))),
+            None => Ok(req
+                .headers()
+                .get(ACCEPT)
+                .and_then(|accept| accept.to_str().ok())
+                .is_some_and(|accept| accept.contains(CONTENT_TYPE))),
+        }
+    }
+}
+
+/// The item's fields, those of nested objects named by their path (e.g. `route_trip.route_id`)
+fn flatten(prefix: Option<&str>, value: Value, fields: &mut Vec<(String, Value)>) {
+    match value {
+        Value::Object(object) => {
+            for (name, value) in object {
+                let name = match prefix {
+                    Some(prefix) => format!("{}.{}", prefix, name),
+                    None => name,
+                };
+                flatten(Some(&name), value, fields);
+            }
+        }
+        value => fields.push((prefix.unwrap_or_default().to_string(), value)),
+    }
+}
+
+fn cell(value: Option<&Value>) -> String {
+    match value {
+        None | Some(Value::Null) => String::new(),
+        Some(Value::String(s)) => s.clone(),
+        Some(value) => value.to_string(),
+    }
+}
+
+/// One line of CSV
+fn record(cells: impl IntoIterator<Item = String>) -> NextAtResult<Bytes> {
+    let mut writer = ::csv::Writer::from_writer(vec![]);
+    writer
+        .write_record(cells)
+        .map_err(|e| NextAtError::DataFormat(e.to_string()))?;
+    let line = writer
+        .into_inner()
+        .map_err(|e| NextAtError::DataFormat(e.to_string()))?;
+    Ok(Bytes::from(line))
+}
+
+/// Items as rows, with a column for every field any of them has.
+/// The body is streamed a row at a time.
+pub fn csv_response<T: Serialize>(items: &[T]) -> NextAtResult<HttpResponse> {
+    let rows = items
+        .iter()
+        .map(|item| {
+            let value =
+                serde_json::to_value(item).map_err(|e| NextAtError::DataFormat(e.to_string()))?;
+            let mut fields = vec![];
+            flatten(None, value, &mut fields);
+            Ok(fields)
+        })
+        .collect::<NextAtResult<Vec<_>>>()?;
+    let columns = rows
+        .iter()
+        .flatten()
+        .map(|(name, _)| name.clone())
+        .unique()
+        .collect_vec();
+
+    let header = record(columns.clone());
+    let lines = rows.into_iter().map(move |fields| {
+        let fields = fields.into_iter().collect::<HashMap<_, _>>();
+        record(columns.iter().map(|column| cell(fields.get(column))))
+    });
+    let body = stream::iter(std::iter::once(header).chain(lines));
+
+    Ok(HttpResponse::Ok()
+        .content_type(format!("{}; charset=utf-8", CONTENT_TYPE))
+        .streaming(body))
+}
+
+#[cfg(test)]
+mod test {
+
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_flatten() {
+        let mut fields = vec![];
+        let item = json!({
+            "route_trip": {"route_id": "NX1-203"},
+            "trip_id": "1-NX1-1",
+            "skipped": null,
+        });
+        flatten(None, item, &mut fields);
+        let cells = fields
+            .iter()
+            .map(|(name, value)| (name.as_str(), cell(Some(value))))
+            .collect_vec();
+        assert_eq!(
+            cells,
+            [
+                ("route_trip.route_id", "NX1-203".to_string()),
+                ("skipped", String::new()),
+                ("trip_id", "1-NX1-1".to_string()),
+            ]
+        );
+
+        assert_eq!(
+            record(["a,b".to_string(), "c".to_string()]).unwrap(),
+            "\"a,b\",c\n"
+        );
+    }
+}
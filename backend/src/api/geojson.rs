@@ -0,0 +1,127 @@
+//! GeoJSON versions of responses, so map libraries can use them as they are
+
+use actix_web::{http::header::ACCEPT, HttpRequest, HttpResponse};
+use geo::Point;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::error::{NextAtError, NextAtResult};
+
+const CONTENT_TYPE: &str = "application/geo+json";
+
+#[derive(Deserialize)]
+pub struct FormatQuery {
+    /// `json` (the default) or `geojson`
+    format: Option<String>,
+}
+
+impl FormatQuery {
+    /// Whether GeoJSON was asked for, by `format` or otherwise the Accept header
+    pub fn is_geojson(&self, req: &HttpRequest) -> NextAtResult<bool> {
+        match self.format.as_deref() {
+            Some("json") => Ok(false),
+            Some("geojson") => Ok(true),
+            Some(format) => Err(NextAtError::InvalidData(format!(
+                "Unknown format {}, expected json or geojson",
+                format
+            ))),
+            None => Ok(req
+                .headers()
+                .get(ACCEPT)
+                .and_then(|accept| accept.to_str().ok())
+                .is_some_and(|accept| accept.contains(CONTENT_TYPE))),
+        }
+    }
+}
+
+fn feature(geometry: Value, properties: Value) -> Value {
+    json!({
+        "type": "Feature",
+        "geometry": geometry,
+        "properties": properties,
+    })
+}
+
+fn properties(item: &impl Serialize) -> NextAtResult<Value> {
+    serde_json::to_value(item).map_err(|e| NextAtError::DataFormat(e.to_string()))
+}
+
+fn feature_collection(features: Vec<Value>) -> HttpResponse {
+    HttpResponse::Ok().content_type(CONTENT_TYPE).json(json!({
+        "type": "FeatureCollection",
+        "features": features,
+    }))
+}
+
+/// A feature at the item's `lat` and `lon`, with the rest of its fields as properties.
+/// Items without a location have no geometry.
+pub fn point_feature(item: &impl Serialize) -> NextAtResult<Value> {
+    let mut properties = properties(item)?;
+
+    let mut coordinate = |name: &str| {
+        properties
+            .as_object_mut()
+            .and_then(|p| p.remove(name))
+            .and_then(|c| c.as_f64())
+    };
+    let (lat, lon) = (coordinate("lat"), coordinate("lon"));
+    let geometry = match (lat, lon) {
+        (Some(lat), Some(lon)) => json!({
+            "type": "Point",
+            "coordinates": [lon, lat],
+        }),
+        _ => Value::Null,
+    };
+
+    Ok(feature(geometry, properties))
+}
+
+/// Items as a FeatureCollection of points
+pub fn point_features<'a, T: Serialize + 'a>(
+    items: impl IntoIterator<Item = &'a T>,
+) -> NextAtResult<HttpResponse> {
+    let features = items
+        .into_iter()
+        .map(point_feature)
+        .collect::<NextAtResult<Vec<_>>>()?;
+    Ok(feature_collection(features))
+}
+
+/// Lines, each with an item's fields as its properties
+pub fn line_features<'a, T: Serialize + 'a>(
+    lines: impl IntoIterator<Item = (&'a [Point], &'a T)>,
+) -> NextAtResult<HttpResponse> {
+    let features = lines
+        .into_iter()
+        .map(|(line, item)| {
+            let geometry = json!({
+                "type": "LineString",
+                "coordinates": line.iter().map(|p| [p.x(), p.y()]).collect::<Vec<_>>(),
+            });
+            Ok(feature(geometry, properties(item)?))
+        })
+        .collect::<NextAtResult<Vec<_>>>()?;
+    Ok(feature_collection(features))
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn test_point_feature() {
+        let feature = point_feature(&json!({"id": "7000", "lat": -36.85, "lon": 174.76})).unwrap();
+        assert_eq!(
+            feature,
+            json!({
+                "type": "Feature",
+                "geometry": {"type": "Point", "coordinates": [174.76, -36.85]},
+                "properties": {"id": "7000"},
+            })
+        );
+
+        let feature = point_feature(&json!({"id": "133", "lat": null, "lon": null})).unwrap();
+        assert_eq!(feature["geometry"], Value::Null);
+    }
+}
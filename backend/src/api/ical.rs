@@ -0,0 +1,129 @@
+//! iCalendar versions of responses, for subscribing to in a calendar (RFC 5545)
+
+use chrono::{DateTime, TimeZone, Utc};
+
+use crate::stops::{RouteTripArrival, Stop, StopEvent};
+
+pub const CONTENT_TYPE: &str = "text/calendar; charset=utf-8";
+
+/// How often calendars should fetch the feed again
+const REFRESH_INTERVAL: &str = "PT15M";
+
+/// Lines longer than this many bytes are folded
+const MAX_LINE_LENGTH: usize = 75;
+
+/// Backslashes, semicolons, commas and newlines are escaped in text values
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace('\n', "\\n")
+}
+
+/// Adds the content line, folded so that no line is longer than `MAX_LINE_LENGTH`
+fn push_line(calendar: &mut String, line: &str) {
+    let mut length = 0;
+    for c in line.chars() {
+        if length + c.len_utf8() > MAX_LINE_LENGTH {
+            calendar.push_str("\r\n ");
+            // The space counts towards the line
+            length = 1;
+        }
+        calendar.push(c);
+        length += c.len_utf8();
+    }
+    calendar.push_str("\r\n");
+}
+
+/// UTC date-time, e.g. 20240301T073000Z
+fn date_time(time: DateTime<Utc>) -> String {
+    time.format("%Y%m%dT%H%M%SZ").to_string()
+}
+
+fn timestamp(millis: i64) -> String {
+    date_time(
+        Utc.timestamp_millis_opt(millis)
+            .single()
+            .unwrap_or_default(),
+    )
+}
+
+/// A calendar with an event for each of the stop's departures
+pub fn departures_calendar(
+    stop: &Stop,
+    departures: &[RouteTripArrival],
+    now: DateTime<Utc>,
+) -> String {
+    let mut calendar = String::new();
+    let mut line = |line: String| push_line(&mut calendar, &line);
+
+    line("BEGIN:VCALENDAR".to_string());
+    line("VERSION:2.0".to_string());
+    line("PRODID:-//next-at//departures//EN".to_string());
+    line("CALSCALE:GREGORIAN".to_string());
+    line("METHOD:PUBLISH".to_string());
+    line(format!("X-WR-CALNAME:{}", escape(&stop.name)));
+    line(format!(
+        "REFRESH-INTERVAL;VALUE=DURATION:{}",
+        REFRESH_INTERVAL
+    ));
+    line(format!("X-PUBLISHED-TTL:{}", REFRESH_INTERVAL));
+
+    for departure in departures
+        .iter()
+        .filter(|d| !d.arrival.skipped && !d.arrival.cancelled)
+    {
+        let route = &departure.route_trip;
+        let arrival = &departure.arrival;
+        let expected = timestamp(arrival.expected_timestamp(StopEvent::Departure));
+
+        line("BEGIN:VEVENT".to_string());
+        line(format!(
+            "UID:{}-{}-{}@next-at",
+            arrival.trip_id, arrival.start_timestamp, arrival.stop_sequence
+        ));
+        line(format!("DTSTAMP:{}", date_time(now)));
+        line(format!("DTSTART:{}", expected));
+        line(format!("DTEND:{}", expected));
+        line(format!(
+            "SUMMARY:{}",
+            escape(&format!(
+                "{} to {}",
+                route.route_short_name, route.stop_headsign
+            ))
+        ));
+        line(format!("LOCATION:{}", escape(&stop.name)));
+        if let (Some(lat), Some(lon)) = (stop.lat, stop.lon) {
+            line(format!("GEO:{};{}", lat, lon));
+        }
+        line(format!("DESCRIPTION:{}", escape(&route.route_long_name)));
+        line("END:VEVENT".to_string());
+    }
+
+    line("END:VCALENDAR".to_string());
+    calendar
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn test_push_line() {
+        let mut calendar = String::new();
+        push_line(
+            &mut calendar,
+            &format!("SUMMARY:{}", escape("NX1, to Hibiscus Coast")),
+        );
+        assert_eq!(calendar, "SUMMARY:NX1\\, to Hibiscus Coast\r\n");
+
+        let mut calendar = String::new();
+        let long = format!("DESCRIPTION:{}", "a".repeat(100));
+        push_line(&mut calendar, &long);
+        let lines = calendar.split("\r\n").collect::<Vec<_>>();
+        assert_eq!(lines[0].len(), MAX_LINE_LENGTH);
+        assert!(lines[1].starts_with(' '));
+        assert_eq!(calendar.replace("\r\n ", ""), format!("{}\r\n", long));
+    }
+}
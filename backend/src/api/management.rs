@@ -0,0 +1,387 @@
+use actix_web::{get, post, web, HttpResponse, Responder};
+use chrono::Utc;
+use reqwest::StatusCode;
+use sea_orm::TransactionTrait;
+use serde::Deserialize;
+use serde_json::json;
+use tokio::sync::MutexGuard;
+use url::Url;
+
+use crate::{
+    auth::RequireApiKey,
+    cors::CorsConfig,
+    db,
+    error::{NextAtError, NextAtResult},
+    gtfs,
+    gtfs::{feed::Feed, index::IndexOptions, index_check::DEFAULT_CHECK_DAYS},
+    webhooks::WebhookEvent,
+    ContextData,
+};
+
+#[derive(Deserialize)]
+struct SyncQuery {
+    dry_run: Option<bool>,
+    /// A zip to sync from instead of the feed's configured URL
+    url: Option<String>,
+    /// Or a zip on the server
+    path: Option<String>,
+    /// The feed `url` or `path` is for, if more than one is configured
+    feed: Option<String>,
+}
+
+/// The configured feeds, with the source from the query if there is one
+fn feeds_from_query(query: &SyncQuery, feeds: &[Feed]) -> NextAtResult<Vec<Feed>> {
+    let gtfs_url = match (&query.url, &query.path) {
+        (Some(_), Some(_)) => {
+            return Err(NextAtError::InvalidData(
+                "Only one of url or path can be set".to_string(),
+            ))
+        }
+        (Some(url), None) => Url::parse(url)
+            .map_err(|e| NextAtError::InvalidData(format!("Invalid url: {}", e)))?
+            .to_string(),
+        (None, Some(path)) => std::fs::canonicalize(path)
+            .ok()
+            .and_then(|p| Url::from_file_path(p).ok())
+            .ok_or_else(|| NextAtError::InvalidData(format!("File not found: {}", path)))?
+            .to_string(),
+        (None, None) => return Ok(feeds.to_vec()),
+    };
+
+    let feed_id = match (&query.feed, feeds) {
+        (Some(feed_id), _) => feed_id,
+        (None, [feed]) => &feed.id,
+        (None, _) => {
+            return Err(NextAtError::InvalidData(
+                "feed must be set when more than one is configured".to_string(),
+            ))
+        }
+    };
+    if !feeds.iter().any(|f| &f.id == feed_id) {
+        return Err(NextAtError::NotFound(format!(
+            "Feed not found: {}",
+            feed_id
+        )));
+    }
+
+    Ok(feeds
+        .iter()
+        .cloned()
+        .map(|mut f| {
+            if &f.id == feed_id {
+                f.gtfs_url = gtfs_url.clone();
+            }
+            f
+        })
+        .collect())
+}
+
+/// Only one sync or index build runs at a time, otherwise this is a 409.
+/// None are started once shutting down.
+fn try_lock_sync(ctx: &ContextData) -> NextAtResult<MutexGuard<'_, ()>> {
+    if ctx.shutdown.is_cancelled() {
+        return Err(NextAtError::Response(503, "Shutting down".to_string()));
+    }
+    ctx.sync_lock.try_lock().map_err(|_| {
+        NextAtError::Response(409, "A sync or index build is already running".to_string())
+    })
+}
+
+/// With `dry_run=true` the feeds are downloaded and compared with the current import,
+/// but nothing is imported. Like a sync, it's a 409 while another is running.
+/// A feed can be synced from another zip with `url` (including `file://`) or `path`.
+#[post("/sync")]
+async fn sync_gtfs(
+    query: web::Query<SyncQuery>,
+    ctx: web::Data<ContextData>,
+) -> NextAtResult<impl Responder> {
+    let feeds = feeds_from_query(&query, &ctx.feeds)?;
+
+    // Dry runs download to the same files as each other
+    let _lock = try_lock_sync(&ctx)?;
+
+    if query.dry_run.unwrap_or(false) {
+        let feeds = gtfs::diff::diff_feeds(&ctx.sync, &feeds).await?;
+        return Ok(web::Json(json!({
+            "dryRun": true,
+            "feeds": feeds,
+        })));
+    }
+
+    let synced =
+        gtfs::sync::Sync::sync(&ctx.db, &ctx.sync, &feeds, ctx.sync_progress.clone()).await?;
+    let new_records = synced.new_records;
+    if synced.feed_errors.is_empty() {
+        ctx.health.gtfs_sync.record();
+    }
+    let feed_ids = feeds.iter().map(|f| &f.id).collect::<Vec<_>>();
+    ctx.webhooks.send(
+        WebhookEvent::SyncCompleted,
+        json!({
+            "feeds": feed_ids,
+            "new_records": new_records,
+            "failed_feeds": synced.failed_feed_ids(),
+        }),
+    );
+    ctx.versions.bump_static();
+    synced.check()?;
+    let response = web::Json(json!({
+        "newRecords": new_records,
+    }));
+    Ok(response)
+}
+
+/// What the running sync is doing, or how the last one finished
+#[get("/sync/status")]
+async fn get_sync_status(ctx: web::Data<ContextData>) -> NextAtResult<impl Responder> {
+    Ok(web::Json(ctx.sync_progress.get()))
+}
+
+/// With no options the whole index is rebuilt,
+/// otherwise only `days` days from `from_date` (YYYY-MM-DD) are replaced.
+/// With `incremental=true` only the trips changed by syncs since the last build are.
+#[post("/index-stoptimes")]
+async fn index_stop_times(
+    options: web::Query<IndexOptions>,
+    ctx: web::Data<ContextData>,
+) -> NextAtResult<impl Responder> {
+    if options.days.is_some_and(|days| days < 1) {
+        return Err(NextAtError::InvalidData(
+            "days must be at least 1".to_string(),
+        ));
+    }
+    let _lock = try_lock_sync(&ctx)?;
+    gtfs::index::build_stop_time_index(
+        &ctx.index,
+        options.into_inner(),
+        ctx.index_progress.clone(),
+    )
+    .await?;
+    ctx.health.index_build.record();
+    ctx.versions.bump_static();
+    let response = HttpResponse::Ok().status(StatusCode::NO_CONTENT).finish();
+    Ok(response)
+}
+
+#[post("/index-stops")]
+async fn index_stops(ctx: web::Data<ContextData>) -> NextAtResult<impl Responder> {
+    let _lock = try_lock_sync(&ctx)?;
+    gtfs::index::build_stop_index().await?;
+    ctx.health.index_build.record();
+    ctx.versions.bump_static();
+    let response = HttpResponse::Ok().status(StatusCode::NO_CONTENT).finish();
+    Ok(response)
+}
+
+#[post("/reindex-all")]
+async fn reindex_all(ctx: web::Data<ContextData>) -> NextAtResult<impl Responder> {
+    let _lock = try_lock_sync(&ctx)?;
+    gtfs::index::build_stop_index().await?;
+    gtfs::index::build_stop_time_index(
+        &ctx.index,
+        IndexOptions::default(),
+        ctx.index_progress.clone(),
+    )
+    .await?;
+    ctx.health.index_build.record();
+    ctx.versions.bump_static();
+    let response = HttpResponse::Ok().status(StatusCode::NO_CONTENT).finish();
+    Ok(response)
+}
+
+/// Clears all realtime state, for when the upstream feed has gone bad
+#[post("/reset")]
+async fn reset_realtime(ctx: web::Data<ContextData>) -> NextAtResult<impl Responder> {
+    let tx = ctx.db.begin().await?;
+    gtfs::realtime::reset(&tx).await?;
+    tx.commit().await?;
+    ctx.versions.set_realtime(Utc::now());
+    tracing::warn!("Realtime state has been reset");
+    let response = HttpResponse::Ok().status(StatusCode::NO_CONTENT).finish();
+    Ok(response)
+}
+
+/// What the running stop time index build is doing, or how the last one finished
+#[get("/status")]
+async fn get_index_status(ctx: web::Data<ContextData>) -> NextAtResult<impl Responder> {
+    Ok(web::Json(ctx.index_progress.get()))
+}
+
+/// Stops the running stop time index build, leaving the index as it was before it started
+#[post("/cancel")]
+async fn cancel_index_build(ctx: web::Data<ContextData>) -> NextAtResult<impl Responder> {
+    if !ctx.index_progress.cancel() {
+        return Err(NextAtError::Response(
+            409,
+            "No index build is running".to_string(),
+        ));
+    }
+    let response = HttpResponse::Ok().status(StatusCode::NO_CONTENT).finish();
+    Ok(response)
+}
+
+#[derive(Deserialize)]
+struct IndexCheckQuery {
+    days: Option<usize>,
+}
+
+/// Cross-checks `days` indexed dates, spread from today, against the static tables,
+/// e.g. to know whether the index can be trusted after a crash mid-build
+#[get("/check")]
+async fn check_index(query: web::Query<IndexCheckQuery>) -> NextAtResult<impl Responder> {
+    let days = query.days.unwrap_or(DEFAULT_CHECK_DAYS);
+    if days < 1 {
+        return Err(NextAtError::InvalidData(
+            "days must be at least 1".to_string(),
+        ));
+    }
+    let check = gtfs::index_check::check_stop_time_index(days).await?;
+    Ok(web::Json(check))
+}
+
+#[derive(Deserialize)]
+struct DeadLettersQuery {
+    limit: Option<u64>,
+}
+
+/// Realtime entities which failed to process, most recent first
+#[get("/dead-letters")]
+async fn get_dead_letters(
+    query: web::Query<DeadLettersQuery>,
+    ctx: web::Data<ContextData>,
+) -> NextAtResult<impl Responder> {
+    let dead_letters =
+        gtfs::realtime::list_dead_letters(&ctx.read_db, query.limit.unwrap_or(50)).await?;
+    Ok(web::Json(dead_letters))
+}
+
+/// Processes a failed entity again, e.g. after fixing whatever it tripped over.
+/// It's removed if it succeeds this time.
+#[post("/dead-letters/{id}/reprocess")]
+async fn reprocess_dead_letter(
+    path: web::Path<i64>,
+    ctx: web::Data<ContextData>,
+) -> NextAtResult<impl Responder> {
+    gtfs::realtime::reprocess_dead_letter(&ctx, path.into_inner()).await?;
+    ctx.versions.set_realtime(Utc::now());
+    let response = HttpResponse::Ok().status(StatusCode::NO_CONTENT).finish();
+    Ok(response)
+}
+
+#[derive(Deserialize)]
+struct ImportsQuery {
+    limit: Option<u64>,
+}
+
+#[get("")]
+async fn get_imports(
+    query: web::Query<ImportsQuery>,
+    ctx: web::Data<ContextData>,
+) -> NextAtResult<impl Responder> {
+    let history =
+        gtfs::imports::get_import_history(&ctx.read_db, query.limit.unwrap_or(50)).await?;
+    Ok(web::Json(history))
+}
+
+/// Rolls a feed back to an earlier import, e.g. when a new one turns out to be broken.
+/// Only the last few imports are kept, see `IMPORT_RETENTION`.
+#[post("/{id}/activate")]
+async fn activate_import(
+    path: web::Path<i64>,
+    ctx: web::Data<ContextData>,
+) -> NextAtResult<impl Responder> {
+    let _lock = try_lock_sync(&ctx)?;
+    gtfs::imports::activate_import(&ctx.db, &ctx.sync, path.into_inner()).await?;
+    gtfs::index::build_stop_index().await?;
+    gtfs::index::build_stop_time_index(
+        &ctx.index,
+        IndexOptions::default(),
+        ctx.index_progress.clone(),
+    )
+    .await?;
+    ctx.health.index_build.record();
+    ctx.versions.bump_static();
+    let response = HttpResponse::Ok().status(StatusCode::NO_CONTENT).finish();
+    Ok(response)
+}
+
+/// Writes a snapshot of the database to `BACKUP_DIR` now,
+/// rather than waiting for the maintenance window
+#[post("")]
+async fn backup_database(ctx: web::Data<ContextData>) -> NextAtResult<impl Responder> {
+    let dir = db::backup::backup_dir().ok_or_else(|| {
+        NextAtError::Response(
+            503,
+            "Backups aren't enabled, BACKUP_DIR isn't set".to_string(),
+        )
+    })?;
+    let _lock = ctx
+        .backup_lock
+        .try_lock()
+        .map_err(|_| NextAtError::Response(409, "A backup is already running".to_string()))?;
+    let backup = db::backup::backup_database(dir).await?;
+    Ok(web::Json(backup))
+}
+
+#[get("")]
+async fn get_stats(ctx: web::Data<ContextData>) -> NextAtResult<impl Responder> {
+    let stats = db::stats::get_db_stats(&ctx.read_db).await?;
+    Ok(web::Json(stats))
+}
+
+/// Requests to the publishers since starting, by endpoint
+#[get("/upstream")]
+async fn get_upstream_stats(ctx: web::Data<ContextData>) -> NextAtResult<impl Responder> {
+    Ok(web::Json(ctx.at_client.request_report()))
+}
+
+/// Everything under /management requires an API key,
+/// and each group of routes requires its own scope.
+/// CORS comes first, as preflight requests don't have the key.
+pub fn configure(cfg: &mut web::ServiceConfig, cors: &CorsConfig) {
+    cfg.service(
+        web::scope("/management")
+            .wrap(RequireApiKey::any())
+            .wrap(cors.management())
+            .service(
+                web::scope("/gtfs")
+                    .wrap(RequireApiKey::scope("gtfs"))
+                    .service(sync_gtfs)
+                    .service(get_sync_status)
+                    .service(index_stop_times)
+                    .service(index_stops)
+                    .service(reindex_all),
+            )
+            .service(
+                web::scope("/index")
+                    .wrap(RequireApiKey::scope("index"))
+                    .service(get_index_status)
+                    .service(cancel_index_build)
+                    .service(check_index),
+            )
+            .service(
+                web::scope("/realtime")
+                    .wrap(RequireApiKey::scope("realtime"))
+                    .service(reset_realtime)
+                    .service(get_dead_letters)
+                    .service(reprocess_dead_letter),
+            )
+            .service(
+                web::scope("/imports")
+                    .wrap(RequireApiKey::scope("imports"))
+                    .service(get_imports)
+                    .service(activate_import),
+            )
+            .service(
+                web::scope("/backup")
+                    .wrap(RequireApiKey::scope("backup"))
+                    .service(backup_database),
+            )
+            .service(
+                web::scope("/stats")
+                    .wrap(RequireApiKey::scope("stats"))
+                    .service(get_stats)
+                    .service(get_upstream_stats),
+            ),
+    );
+}
@@ -0,0 +1,62 @@
+use actix_web::{get, web, HttpResponse, Responder};
+
+use crate::{
+    cors::CorsConfig,
+    error::NextAtResult,
+    health::{self, Status},
+    ContextData,
+};
+
+mod csv;
+mod geojson;
+mod ical;
+mod management;
+mod siri;
+mod v1;
+
+/// Version prefixes of the public API, used to recognise versioned routes
+pub const VERSION_PREFIXES: [&str; 1] = ["/v1"];
+
+#[get("/ok")]
+async fn ok() -> NextAtResult<impl Responder> {
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// Per-subsystem health. Only a down database is reported as unhealthy,
+/// a degraded status still returns 200 so that orchestrators don't restart us for a stale feed.
+#[get("/health")]
+async fn get_health(ctx: web::Data<ContextData>) -> NextAtResult<impl Responder> {
+    let report = health::report(&ctx).await;
+    let response = match report.status {
+        Status::Down => HttpResponse::ServiceUnavailable().json(report),
+        _ => HttpResponse::Ok().json(report),
+    };
+    Ok(response)
+}
+
+/// The management and public APIs each have their own CORS settings
+pub fn configure(cfg: &mut web::ServiceConfig, cors: &CorsConfig) {
+    cfg.configure(|cfg| management::configure(cfg, cors))
+        .service(
+            web::scope("/v1")
+                .wrap(cors.public())
+                .configure(v1::configure),
+        )
+        .service(
+            web::scope("")
+                .wrap(cors.public())
+                .service(ok)
+                .service(get_health)
+                // Compatibility layer - unversioned paths are served by v1
+                .configure(v1::configure),
+        );
+}
+
+/// Strips any version prefix from a route pattern, so that `/v1/stops` and `/stops` are treated the same
+pub fn unversioned_pattern(pattern: &str) -> &str {
+    VERSION_PREFIXES
+        .iter()
+        .find_map(|prefix| pattern.strip_prefix(prefix))
+        .filter(|p| p.starts_with('/'))
+        .unwrap_or(pattern)
+}
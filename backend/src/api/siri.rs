@@ -0,0 +1,155 @@
+//! SIRI-Lite versions of responses, for departure boards that only speak SIRI.
+//! See <https://www.transmodel-cen.eu/siri-standard/>.
+
+use chrono::{DateTime, SecondsFormat, TimeZone, Utc};
+use chrono_tz::Tz;
+use serde_json::{json, Value};
+
+use crate::stops::{RouteTripArrival, StopEvent};
+
+/// Delays within this are on time
+const ON_TIME_SECONDS: i64 = 60;
+
+/// The SIRI name of the GTFS route type
+fn vehicle_mode(route_type: i32) -> &'static str {
+    match route_type {
+        0 | 900..=999 => "tram",
+        1 | 400..=499 => "metro",
+        2 | 100..=199 => "rail",
+        4 | 1000..=1299 => "water",
+        6 | 1300..=1399 => "telecabin",
+        7 | 1400..=1499 => "funicular",
+        200..=299 => "coach",
+        _ => "bus",
+    }
+}
+
+/// The time in the agency's timezone when it's known, as ISO 8601
+fn time(timestamp: i64, timezone: Option<Tz>) -> Value {
+    let Some(time) = Utc.timestamp_millis_opt(timestamp).single() else {
+        return Value::Null;
+    };
+    let time = match timezone {
+        Some(tz) => time
+            .with_timezone(&tz)
+            .to_rfc3339_opts(SecondsFormat::Secs, false),
+        None => time.to_rfc3339_opts(SecondsFormat::Secs, true),
+    };
+    json!(time)
+}
+
+/// `ArrivalStatus` or `DepartureStatus` of the event
+fn status(departure: &RouteTripArrival, event: StopEvent) -> &'static str {
+    let arrival = &departure.arrival;
+    let delay = (arrival.expected_timestamp(event) - arrival.timestamp(event)) / 1000;
+    if arrival.skipped || arrival.cancelled {
+        "cancelled"
+    } else if arrival.prediction == "scheduled" {
+        "noReport"
+    } else if delay > ON_TIME_SECONDS {
+        "delayed"
+    } else if delay < -ON_TIME_SECONDS {
+        "early"
+    } else {
+        "onTime"
+    }
+}
+
+fn monitored_stop_visit(stop_id: &str, departure: &RouteTripArrival, now: &str) -> Value {
+    let route = &departure.route_trip;
+    let arrival = &departure.arrival;
+    let timezone = arrival
+        .agency_timezone
+        .as_deref()
+        .and_then(|tz| tz.parse::<Tz>().ok());
+    let start_date = Utc
+        .timestamp_millis_opt(arrival.start_timestamp)
+        .single()
+        .map(|start| match timezone {
+            Some(tz) => start.with_timezone(&tz).date_naive(),
+            None => start.date_naive(),
+        });
+
+    json!({
+        "RecordedAtTime": now,
+        "MonitoringRef": stop_id,
+        "MonitoredVehicleJourney": {
+            "LineRef": route.route_id,
+            "FramedVehicleJourneyRef": {
+                "DataFrameRef": start_date.map(|d| d.to_string()),
+                "DatedVehicleJourneyRef": arrival.trip_id,
+            },
+            "VehicleMode": [vehicle_mode(route.route_type)],
+            "PublishedLineName": [route.route_short_name],
+            "DestinationName": [route.stop_headsign],
+            "Monitored": arrival.prediction != "scheduled",
+            "MonitoredCall": {
+                "StopPointRef": arrival.updated_stop_id.as_deref().unwrap_or(stop_id),
+                "Order": arrival.stop_sequence,
+                "AimedArrivalTime": time(arrival.timestamp(StopEvent::Arrival), timezone),
+                "ExpectedArrivalTime": time(arrival.expected_timestamp(StopEvent::Arrival), timezone),
+                "ArrivalStatus": status(departure, StopEvent::Arrival),
+                "AimedDepartureTime": time(arrival.timestamp(StopEvent::Departure), timezone),
+                "ExpectedDepartureTime": time(arrival.expected_timestamp(StopEvent::Departure), timezone),
+                "DepartureStatus": status(departure, StopEvent::Departure),
+            },
+        },
+    })
+}
+
+/// A StopMonitoringDelivery of the departures from the stop, in the order given
+pub fn stop_monitoring(
+    stop_id: &str,
+    departures: &[RouteTripArrival],
+    now: DateTime<Utc>,
+) -> Value {
+    let now = now.to_rfc3339_opts(SecondsFormat::Secs, true);
+    let visits = departures
+        .iter()
+        .map(|departure| monitored_stop_visit(stop_id, departure, &now))
+        .collect::<Vec<_>>();
+
+    json!({
+        "Siri": {
+            "ServiceDelivery": {
+                "ResponseTimestamp": now,
+                "StopMonitoringDelivery": [{
+                    "version": "2.0",
+                    "ResponseTimestamp": now,
+                    "MonitoredStopVisit": visits,
+                }],
+            },
+        },
+    })
+}
+
+#[cfg(test)]
+mod test {
+
+    use crate::{
+        stops::{flatten_stop_events, get_stop_departures},
+        test_utils::ctx,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_stop_monitoring() {
+        assert_eq!(vehicle_mode(109), "rail");
+        assert_eq!(vehicle_mode(715), "bus");
+
+        let ctx = ctx().await;
+        let stop_id = "7000-0b6a8a4a";
+        let departures = get_stop_departures(&ctx, stop_id, 0).await.unwrap();
+        let departures = flatten_stop_events(departures, StopEvent::Departure);
+
+        let siri = stop_monitoring(stop_id, &departures, Utc::now());
+        let delivery = &siri["Siri"]["ServiceDelivery"]["StopMonitoringDelivery"][0];
+        let journey = &delivery["MonitoredStopVisit"][0]["MonitoredVehicleJourney"];
+        assert_eq!(journey["LineRef"], "NX1-203");
+        assert_eq!(journey["VehicleMode"], json!(["bus"]));
+        assert_eq!(journey["Monitored"], false);
+        assert_eq!(journey["MonitoredCall"]["StopPointRef"], stop_id);
+        assert_eq!(journey["MonitoredCall"]["DepartureStatus"], "noReport");
+    }
+}
@@ -0,0 +1,707 @@
+use actix_web::{
+    delete, get,
+    http::header::ContentType,
+    post,
+    web::{self, Bytes},
+    HttpRequest, HttpResponse, Responder,
+};
+use chrono::{NaiveDate, Utc};
+use serde::Deserialize;
+use serde_json::json;
+
+use super::csv::{self, CsvQuery};
+use super::geojson::{self, FormatQuery};
+use super::ical;
+use super::siri;
+use crate::{
+    cache::ArrivalsKey,
+    error::{NextAtError, NextAtResult},
+    fares,
+    gtfs::realtime,
+    map::{self, BoundingBox, MapStop},
+    notifications::{self, NewSubscription},
+    patterns,
+    service_status::{self, RouteServiceStatus},
+    shapes, stations,
+    stops::{self, MatchedBy, StopEvent, StopRouteTripArrival, TransportMode},
+    tiles::{self, TileId},
+    translations::{translate_routes, translate_stops, Languages},
+    vehicles, ContextData,
+};
+
+#[derive(Deserialize)]
+struct StopsQuery {
+    lat: Option<f64>,
+    lon: Option<f64>,
+    code: Option<String>,
+    /// Only nearby stops served by this mode
+    route_type: Option<TransportMode>,
+}
+
+#[get("/stops")]
+async fn get_stops(
+    req: HttpRequest,
+    query: web::Query<StopsQuery>,
+    format: web::Query<FormatQuery>,
+    ctx: web::Data<ContextData>,
+) -> NextAtResult<impl Responder> {
+    let mut stops = vec![];
+    let mut lat = query.lat;
+    let mut lon = query.lon;
+    
+    if let Some(code) = &query.code {
+        stops = stops::find_stops_by_code(&ctx, code).await?;
+        // And nearby stops if no other location set, only when it's certain which stop was meant
+        if let [stop] = stops.as_slice() {
+            if let (None, None, Some(MatchedBy::Exact), Some(stop_lat), Some(stop_lon)) =
+                (lat, lon, stop.matched_by, stop.lat, stop.lon)
+            {
+                lat = Some(stop_lat);
+                lon = Some(stop_lon);
+            }
+        }
+    }
+
+    let mut out_of_service_area = None;
+    if let (Some(lat), Some(lon)) = (lat, lon) {
+        out_of_service_area = stops::distance_outside_service_area(&ctx, lat, lon).await?;
+    }
+
+    if let (Some(lat), Some(lon), None) = (lat, lon, out_of_service_area) {
+        let mut nearby_stops =
+            stops::get_closest_stops(&ctx, lat, lon, 5, query.route_type).await?;
+        // without the stops already matched
+        nearby_stops.retain(|s| !stops.iter().any(|matched| matched.id == s.id));
+
+        stops.extend(nearby_stops);
+    }
+
+    translate_stops(&ctx, &Languages::from_request(&req), &mut stops).await?;
+
+    if format.is_geojson(&req)? {
+        return geojson::point_features(&stops);
+    }
+    let mut response = json!({
+        "stops": stops,
+    });
+    // So it's clear why there's nothing nearby
+    if let Some(distance) = out_of_service_area {
+        response["out_of_service_area"] = json!({
+            "distance_metres": distance.round(),
+        });
+    }
+    Ok(HttpResponse::Ok().json(response))
+}
+
+#[derive(Deserialize)]
+struct StopsBoxQuery {
+    min_lat: f64,
+    max_lat: f64,
+    min_lon: f64,
+    max_lon: f64,
+    /// Of the map, stops are clustered when it's too far out to show them all
+    zoom: Option<u8>,
+}
+
+#[get("/stops/bbox")]
+async fn get_stops_in_box(
+    req: HttpRequest,
+    query: web::Query<StopsBoxQuery>,
+    format: web::Query<FormatQuery>,
+    ctx: web::Data<ContextData>,
+) -> NextAtResult<impl Responder> {
+    let bbox = BoundingBox {
+        min_lat: query.min_lat,
+        max_lat: query.max_lat,
+        min_lon: query.min_lon,
+        max_lon: query.max_lon,
+    };
+    let mut stops = map::get_stops_in_box(&ctx, bbox, query.zoom).await?;
+    translate_stops(
+        &ctx,
+        &Languages::from_request(&req),
+        stops.iter_mut().filter_map(|s| match s {
+            MapStop::Stop(stop) => Some(stop),
+            MapStop::Cluster(_) => None,
+        }),
+    )
+    .await?;
+
+    if format.is_geojson(&req)? {
+        return geojson::point_features(&stops);
+    }
+    let response = HttpResponse::Ok().json(json!({
+        "stops": stops,
+    }));
+    Ok(response)
+}
+
+#[get("/stops/{stop_id}")]
+async fn get_stop(
+    req: HttpRequest,
+    params: web::Path<(String,)>,
+    ctx: web::Data<ContextData>,
+) -> NextAtResult<impl Responder> {
+    let (stop_id,) = params.into_inner();
+
+    let mut stop = stops::get_stop(&ctx, &stop_id).await?;
+    let mut transfers = stops::get_stop_transfers(&ctx, &stop_id).await?;
+    translate_stops(
+        &ctx,
+        &Languages::from_request(&req),
+        std::iter::once(&mut stop).chain(transfers.iter_mut().map(|t| &mut t.stop)),
+    )
+    .await?;
+    let response = web::Json(json!({
+        "stop": stop,
+        "transfers": transfers,
+    }));
+    Ok(response)
+}
+
+#[get("/stops/{stop_id}/routes")]
+async fn get_stop_routes(
+    req: HttpRequest,
+    params: web::Path<(String,)>,
+    ctx: web::Data<ContextData>,
+) -> NextAtResult<impl Responder> {
+    let (stop_id,) = params.into_inner();
+
+    let mut routes = stops::get_stop_routes(&ctx, &stop_id).await?;
+    translate_routes(&ctx, &Languages::from_request(&req), &mut routes).await?;
+    let response = web::Json(json!({
+        "routes": routes,
+    }));
+    Ok(response)
+}
+
+#[derive(Deserialize)]
+struct StopEventsQuery {
+    /// Also include those that departed in the last this many minutes,
+    /// for showing what's just left
+    departed_minutes: Option<u32>,
+    /// `false` lists them in time order, each with its route,
+    /// rather than grouped by route and headsign
+    group: Option<bool>,
+}
+
+impl StopEventsQuery {
+    fn departed_minutes(&self) -> NextAtResult<u32> {
+        let minutes = self.departed_minutes.unwrap_or(0);
+        if minutes > stops::MAX_DEPARTED_MINUTES {
+            return Err(NextAtError::InvalidData(format!(
+                "departed_minutes can be at most {}",
+                stops::MAX_DEPARTED_MINUTES
+            )));
+        }
+        Ok(minutes)
+    }
+}
+
+/// The stop's arrivals or departures, with route names in the languages asked for
+async fn translated_stop_events(
+    req: &HttpRequest,
+    ctx: &ContextData,
+    stop_id: &str,
+    departed_minutes: u32,
+    event: StopEvent,
+) -> NextAtResult<Vec<StopRouteTripArrival>> {
+    let mut events = match event {
+        StopEvent::Arrival => stops::get_stop_arrivals(ctx, stop_id, departed_minutes).await?,
+        StopEvent::Departure => stops::get_stop_departures(ctx, stop_id, departed_minutes).await?,
+    };
+    translate_routes(
+        ctx,
+        &Languages::from_request(req),
+        events.iter_mut().map(|e| &mut e.route_trip),
+    )
+    .await?;
+    Ok(events)
+}
+
+/// As JSON the response is kept for identical requests, e.g. from departure boards,
+/// until the next realtime update or `ARRIVALS_CACHE_SECONDS` have passed
+async fn stop_events_response(
+    req: &HttpRequest,
+    ctx: &ContextData,
+    stop_id: String,
+    query: &StopEventsQuery,
+    format: &CsvQuery,
+    event: StopEvent,
+) -> NextAtResult<HttpResponse> {
+    let departed_minutes = query.departed_minutes()?;
+
+    if format.is_csv(req)? {
+        let events = translated_stop_events(req, ctx, &stop_id, departed_minutes, event).await?;
+        return csv::csv_response(&stops::flatten_stop_events(events, event));
+    }
+
+    let group = query.group.unwrap_or(true);
+    let key = ArrivalsKey {
+        stop_id: stop_id.clone(),
+        event,
+        departed_minutes,
+        group,
+        languages: Languages::from_request(req),
+        realtime_version: ctx.versions.realtime_version(),
+    };
+    let render = || async {
+        let events = translated_stop_events(req, ctx, &stop_id, departed_minutes, event).await?;
+        let (grouped, flat) = match event {
+            StopEvent::Arrival => ("stop_arrivals", "arrivals"),
+            StopEvent::Departure => ("stop_departures", "departures"),
+        };
+        let response = if group {
+            json!({ grouped: events })
+        } else {
+            json!({ flat: stops::flatten_stop_events(events, event) })
+        };
+        serde_json::to_vec(&response)
+            .map(Bytes::from)
+            .map_err(|e| NextAtError::DataFormat(e.to_string()))
+    };
+    let body = ctx
+        .query_cache
+        .arrivals
+        .get_or_try_insert(key, ctx.versions.static_version(), render)
+        .await?;
+
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::json())
+        .body(body))
+}
+
+#[get("/stops/{stop_id}/arrivals")]
+async fn get_stop_arrivals(
+    req: HttpRequest,
+    params: web::Path<(String,)>,
+    query: web::Query<StopEventsQuery>,
+    format: web::Query<CsvQuery>,
+    ctx: web::Data<ContextData>,
+) -> NextAtResult<impl Responder> {
+    let (stop_id,) = params.into_inner();
+    stop_events_response(&req, &ctx, stop_id, &query, &format, StopEvent::Arrival).await
+}
+
+#[get("/stops/{stop_id}/departures")]
+async fn get_stop_departures(
+    req: HttpRequest,
+    params: web::Path<(String,)>,
+    query: web::Query<StopEventsQuery>,
+    format: web::Query<CsvQuery>,
+    ctx: web::Data<ContextData>,
+) -> NextAtResult<impl Responder> {
+    let (stop_id,) = params.into_inner();
+    stop_events_response(&req, &ctx, stop_id, &query, &format, StopEvent::Departure).await
+}
+
+#[derive(Deserialize)]
+struct NextQuery {
+    /// Comma separated ids or short names of the routes, otherwise every route
+    routes: Option<String>,
+}
+
+/// Only the next arrival of each route, for departure signs which poll often
+#[get("/stops/{stop_id}/next")]
+async fn get_stop_next(
+    params: web::Path<(String,)>,
+    query: web::Query<NextQuery>,
+    ctx: web::Data<ContextData>,
+) -> NextAtResult<impl Responder> {
+    let (stop_id,) = params.into_inner();
+    let routes = query
+        .routes
+        .as_deref()
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|r| !r.is_empty())
+        .map(String::from)
+        .collect::<Vec<_>>();
+
+    let next = stops::get_next_arrivals(&ctx, &stop_id, &routes).await?;
+    Ok(web::Json(json!({
+        "next": next,
+    })))
+}
+
+#[derive(Deserialize)]
+struct StatsQuery {
+    /// The service day, otherwise today
+    date: Option<NaiveDate>,
+}
+
+/// How often each route stops through the day, for summaries rather than the whole timetable
+#[get("/stops/{stop_id}/stats")]
+async fn get_stop_stats(
+    params: web::Path<(String,)>,
+    query: web::Query<StatsQuery>,
+    ctx: web::Data<ContextData>,
+) -> NextAtResult<impl Responder> {
+    let (stop_id,) = params.into_inner();
+
+    let routes = stops::get_stop_stats(&ctx, &stop_id, query.date).await?;
+    Ok(web::Json(json!({
+        "routes": routes,
+    })))
+}
+
+#[derive(Deserialize)]
+struct CalendarQuery {
+    /// Only departures on this route
+    route_id: Option<String>,
+}
+
+/// The next day's departures from the stop, for subscribing to in a calendar
+#[get("/stops/{stop_id}/arrivals.ics")]
+async fn get_stop_calendar(
+    req: HttpRequest,
+    params: web::Path<(String,)>,
+    query: web::Query<CalendarQuery>,
+    ctx: web::Data<ContextData>,
+) -> NextAtResult<impl Responder> {
+    let (stop_id,) = params.into_inner();
+
+    let mut stop = stops::get_stop(&ctx, &stop_id).await?;
+    let mut departures = stops::get_stop_departures(&ctx, &stop_id, 0).await?;
+    let languages = Languages::from_request(&req);
+    translate_stops(&ctx, &languages, std::iter::once(&mut stop)).await?;
+    translate_routes(
+        &ctx,
+        &languages,
+        departures.iter_mut().map(|d| &mut d.route_trip),
+    )
+    .await?;
+    let mut departures = stops::flatten_stop_events(departures, StopEvent::Departure);
+    if let Some(route_id) = &query.route_id {
+        departures.retain(|d| &d.route_trip.route_id == route_id);
+    }
+
+    let response = HttpResponse::Ok()
+        .content_type(ical::CONTENT_TYPE)
+        .body(ical::departures_calendar(&stop, &departures, Utc::now()));
+    Ok(response)
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct StopMonitoringQuery {
+    /// The stop
+    monitoring_ref: String,
+    /// Only departures on this route
+    line_ref: Option<String>,
+    maximum_stop_visits: Option<usize>,
+}
+
+/// Departures from the stop for SIRI-Lite clients
+#[get("/siri/stop-monitoring.json")]
+async fn get_stop_monitoring(
+    req: HttpRequest,
+    query: web::Query<StopMonitoringQuery>,
+    ctx: web::Data<ContextData>,
+) -> NextAtResult<impl Responder> {
+    let stop_id = &query.monitoring_ref;
+
+    let mut departures = stops::get_stop_departures(&ctx, stop_id, 0).await?;
+    translate_routes(
+        &ctx,
+        &Languages::from_request(&req),
+        departures.iter_mut().map(|d| &mut d.route_trip),
+    )
+    .await?;
+    let mut departures = stops::flatten_stop_events(departures, StopEvent::Departure);
+    if let Some(line_ref) = &query.line_ref {
+        departures.retain(|d| &d.route_trip.route_id == line_ref);
+    }
+    if let Some(maximum) = query.maximum_stop_visits {
+        departures.truncate(maximum);
+    }
+
+    let response = web::Json(siri::stop_monitoring(stop_id, &departures, Utc::now()));
+    Ok(response)
+}
+
+#[get("/stops/{station_id}/pathways")]
+async fn get_station_pathways(
+    params: web::Path<(String,)>,
+    ctx: web::Data<ContextData>,
+) -> NextAtResult<impl Responder> {
+    let (station_id,) = params.into_inner();
+
+    let station = stations::get_station_pathways(&ctx, &station_id).await?;
+    let response = web::Json(json!({
+        "locations": station.locations,
+        "levels": station.levels,
+        "pathways": station.pathways,
+    }));
+    Ok(response)
+}
+
+#[derive(Deserialize)]
+struct RoutesQuery {
+    /// Only routes of this mode
+    route_type: Option<TransportMode>,
+}
+
+#[get("/routes")]
+async fn get_routes(
+    req: HttpRequest,
+    query: web::Query<RoutesQuery>,
+    ctx: web::Data<ContextData>,
+) -> NextAtResult<impl Responder> {
+    let mut routes = stops::get_routes(&ctx, query.route_type).await?;
+    translate_routes(&ctx, &Languages::from_request(&req), &mut routes).await?;
+    let mut statuses = service_status::get_route_statuses(&ctx).await?;
+    let routes: Vec<_> = routes
+        .into_iter()
+        .map(|route| RouteServiceStatus {
+            status: statuses.remove(&route.route_id).unwrap_or_default(),
+            route,
+        })
+        .collect();
+    let response = web::Json(json!({
+        "routes": routes,
+    }));
+    Ok(response)
+}
+
+#[get("/routes/{route_id}/fares")]
+async fn get_route_fares(
+    params: web::Path<(String,)>,
+    ctx: web::Data<ContextData>,
+) -> NextAtResult<impl Responder> {
+    let (route_id,) = params.into_inner();
+
+    let fares = fares::get_route_fares(&ctx, &route_id).await?;
+    let response = web::Json(json!({
+        "fares": fares,
+    }));
+    Ok(response)
+}
+
+#[derive(Deserialize)]
+struct FaresQuery {
+    /// Fare zone ids
+    origin: String,
+    destination: String,
+    route_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ShapesQuery {
+    /// Of the map, shapes are simplified to what can be seen at it
+    zoom: Option<u8>,
+}
+
+#[get("/routes/{route_id}/shapes")]
+async fn get_route_shapes(
+    req: HttpRequest,
+    params: web::Path<(String,)>,
+    query: web::Query<ShapesQuery>,
+    format: web::Query<FormatQuery>,
+    ctx: web::Data<ContextData>,
+) -> NextAtResult<impl Responder> {
+    let (route_id,) = params.into_inner();
+
+    let shapes = shapes::get_route_shapes(&ctx, &route_id, query.zoom).await?;
+    if format.is_geojson(&req)? {
+        return geojson::line_features(shapes.iter().map(|s| (s.line.as_slice(), s)));
+    }
+    let response = HttpResponse::Ok().json(json!({
+        "shapes": shapes,
+    }));
+    Ok(response)
+}
+
+#[derive(Deserialize)]
+struct RouteStopsQuery {
+    /// 0 or 1, otherwise both directions
+    direction_id: Option<i32>,
+}
+
+/// The orders of stops the route's trips make, e.g. for a line diagram
+#[get("/routes/{route_id}/stops")]
+async fn get_route_stops(
+    params: web::Path<(String,)>,
+    query: web::Query<RouteStopsQuery>,
+    ctx: web::Data<ContextData>,
+) -> NextAtResult<impl Responder> {
+    let (route_id,) = params.into_inner();
+
+    let patterns = patterns::get_route_patterns(&ctx, &route_id, query.direction_id).await?;
+    let response = web::Json(json!({
+        "patterns": patterns,
+    }));
+    Ok(response)
+}
+
+#[derive(Deserialize)]
+struct SpeedsQuery {
+    /// Unix time in millis, defaults to an hour ago
+    since: Option<i64>,
+}
+
+/// How fast the route's vehicles have been going between each of its stops
+#[get("/routes/{route_id}/speeds")]
+async fn get_route_speeds(
+    params: web::Path<(String,)>,
+    query: web::Query<SpeedsQuery>,
+    ctx: web::Data<ContextData>,
+) -> NextAtResult<impl Responder> {
+    let (route_id,) = params.into_inner();
+
+    let segments = vehicles::get_segment_speeds(&ctx, &route_id, query.since).await?;
+    let response = web::Json(json!({
+        "segments": segments,
+    }));
+    Ok(response)
+}
+
+#[get("/fares")]
+async fn get_fares(
+    query: web::Query<FaresQuery>,
+    ctx: web::Data<ContextData>,
+) -> NextAtResult<impl Responder> {
+    let fares = fares::get_zone_fares(
+        &ctx,
+        &query.origin,
+        &query.destination,
+        query.route_id.as_deref(),
+    )
+    .await?;
+    let response = web::Json(json!({
+        "fares": fares,
+    }));
+    Ok(response)
+}
+
+#[get("/vehicles/{vehicle_id}")]
+async fn get_vehicle(
+    params: web::Path<(String,)>,
+    ctx: web::Data<ContextData>,
+) -> NextAtResult<impl Responder> {
+    let (vehicle_id,) = params.into_inner();
+
+    let vehicle = vehicles::get_vehicle(&ctx, &vehicle_id).await?;
+    let response = web::Json(json!({
+        "vehicle": vehicle,
+    }));
+    Ok(response)
+}
+
+#[derive(Deserialize)]
+struct TrajectoryQuery {
+    /// Unix time in millis, defaults to an hour ago
+    since: Option<i64>,
+}
+
+#[get("/vehicles/{vehicle_id}/trajectory")]
+async fn get_vehicle_trajectory(
+    req: HttpRequest,
+    params: web::Path<(String,)>,
+    query: web::Query<TrajectoryQuery>,
+    format: web::Query<FormatQuery>,
+    ctx: web::Data<ContextData>,
+) -> NextAtResult<impl Responder> {
+    let (vehicle_id,) = params.into_inner();
+
+    let trajectory = vehicles::get_trajectory(&ctx, &vehicle_id, query.since).await?;
+    if format.is_geojson(&req)? {
+        return geojson::point_features(&trajectory);
+    }
+    let response = HttpResponse::Ok().json(json!({
+        "trajectory": trajectory,
+    }));
+    Ok(response)
+}
+
+#[derive(Deserialize)]
+struct TileQuery {
+    /// Include a layer of where vehicles are
+    vehicles: Option<bool>,
+}
+
+#[get("/tiles/{z}/{x}/{y}.mvt")]
+async fn get_tile(
+    params: web::Path<(u8, u32, u32)>,
+    query: web::Query<TileQuery>,
+    ctx: web::Data<ContextData>,
+) -> NextAtResult<impl Responder> {
+    let (z, x, y) = params.into_inner();
+
+    let tile = TileId::new(z, x, y)?;
+    let data = tiles::get_tile(&ctx, tile, query.vehicles.unwrap_or(false)).await?;
+    let response = HttpResponse::Ok()
+        .content_type("application/vnd.mapbox-vector-tile")
+        .body(data);
+    Ok(response)
+}
+
+/// Our realtime data as a GTFS-realtime feed, for standard tooling to consume
+#[get("/gtfs-rt/feed.pb")]
+async fn get_realtime_feed(ctx: web::Data<ContextData>) -> NextAtResult<impl Responder> {
+    let data = realtime::encode_feed(&ctx).await?;
+    let response = HttpResponse::Ok()
+        .content_type("application/x-protobuf")
+        .body(data);
+    Ok(response)
+}
+
+#[get("/subscriptions/key")]
+async fn get_subscription_key(ctx: web::Data<ContextData>) -> NextAtResult<impl Responder> {
+    let response = web::Json(json!({
+        "public_key": notifications::public_key(&ctx)?,
+    }));
+    Ok(response)
+}
+
+#[post("/subscriptions")]
+async fn post_subscription(
+    body: web::Json<NewSubscription>,
+    ctx: web::Data<ContextData>,
+) -> NextAtResult<impl Responder> {
+    let subscription = notifications::create_subscription(&ctx, body.into_inner()).await?;
+    let response = HttpResponse::Created().json(json!({
+        "subscription": subscription,
+    }));
+    Ok(response)
+}
+
+#[delete("/subscriptions/{subscription_id}")]
+async fn delete_subscription(
+    params: web::Path<(String,)>,
+    ctx: web::Data<ContextData>,
+) -> NextAtResult<impl Responder> {
+    let (subscription_id,) = params.into_inner();
+
+    notifications::delete_subscription(&ctx, &subscription_id).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_stops)
+        // Before get_stop, which would match it too
+        .service(get_stops_in_box)
+        .service(get_stop)
+        .service(get_stop_routes)
+        .service(get_stop_arrivals)
+        .service(get_stop_departures)
+        .service(get_stop_next)
+        .service(get_stop_stats)
+        .service(get_stop_calendar)
+        .service(get_stop_monitoring)
+        .service(get_station_pathways)
+        .service(get_routes)
+        .service(get_route_fares)
+        .service(get_route_shapes)
+        .service(get_route_stops)
+        .service(get_route_speeds)
+        .service(get_fares)
+        .service(get_vehicle)
+        .service(get_vehicle_trajectory)
+        .service(get_tile)
+        .service(get_realtime_feed)
+        .service(get_subscription_key)
+        .service(post_subscription)
+        .service(delete_subscription);
+}
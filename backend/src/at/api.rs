@@ -0,0 +1,33 @@
+//! What the rest of the app needs from a publisher's API,
+//! so the realtime feeds can be served from fixtures in tests
+
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Utc};
+use futures_util::future::BoxFuture;
+
+use super::{client::ApiKey, error::AtResult, metrics::EndpointReport, policy::BreakerReport};
+
+pub trait AtApi: Send + Sync {
+    /// Gets a realtime feed from its full URL without parsing it
+    fn get_realtime_feed_json<'a>(
+        &'a self,
+        url: &'a str,
+        api_key: Option<&'a ApiKey>,
+    ) -> BoxFuture<'a, AtResult<String>>;
+
+    /// The publishers' circuit breakers, by host
+    fn breaker_report(&self) -> BTreeMap<String, BreakerReport> {
+        BTreeMap::new()
+    }
+
+    /// Requests made so far, by endpoint
+    fn request_report(&self) -> BTreeMap<String, EndpointReport> {
+        BTreeMap::new()
+    }
+
+    /// When each endpoint last responded successfully, if it has
+    fn last_success(&self) -> BTreeMap<String, Option<DateTime<Utc>>> {
+        BTreeMap::new()
+    }
+}
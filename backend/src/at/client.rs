@@ -1,54 +1,173 @@
-use crate::gtfs::structure::realtime::FeedMessage;
+use std::{collections::BTreeMap, sync::Arc, time::Instant};
 
-use super::error::AtResult;
-use url::Url;
+use chrono::{DateTime, Utc};
+use futures_util::future::BoxFuture;
+use tokio::time::sleep;
 
+use crate::{config, gtfs::structure::realtime::FeedMessage};
+
+use super::{
+    api::AtApi,
+    error::{AtError, AtResult},
+    metrics::{EndpointReport, RequestMetrics},
+    policy::{is_transient, BreakerReport, Breakers, RequestPolicy},
+};
+
+/// AT wraps the feed in a response object, other publishers don't
 #[derive(serde::Deserialize)]
-struct RealtimeResponse<T> {
-    response: T,
+#[serde(untagged)]
+enum RealtimeResponse<T> {
+    Wrapped { response: T },
+    Bare(T),
+}
+
+/// The header API keys are sent in if the feed doesn't set one.
+/// AT's own API wants theirs in `Ocp-Apim-Subscription-Key`.
+pub const DEFAULT_API_KEY_HEADER: &str = "x-api-key";
+
+/// A key for a publisher's API, and the header it goes in
+#[derive(Debug, Clone)]
+pub struct ApiKey {
+    pub header: String,
+    pub key: String,
+}
+
+impl ApiKey {
+    /// The key from `key_var`, in the header from `header_var` or the default
+    pub fn from_env(key_var: &str, header_var: &str) -> Option<Self> {
+        let key = config::var(key_var).ok()?;
+        let header = config::var(header_var).unwrap_or_else(|_| DEFAULT_API_KEY_HEADER.to_string());
+        Some(Self { header, key })
+    }
 }
 
 #[derive(Clone)]
 pub struct AtClient {
     client: reqwest::Client,
+    policy: RequestPolicy,
+    breakers: Arc<Breakers>,
+    metrics: Arc<RequestMetrics>,
 }
 
 impl AtClient {
-    pub fn new() -> AtResult<AtClient> {
-
+    /// With the policy's timeouts, retries and breakers
+    pub fn new(policy: RequestPolicy) -> AtResult<AtClient> {
         let client = AtClient {
             client: reqwest::Client::builder()
-                .build()
-                .unwrap(),
+                .connect_timeout(policy.connect_timeout)
+                .timeout(policy.request_timeout)
+                .build()?,
+            breakers: Arc::new(Breakers::new(&policy)),
+            metrics: Arc::new(RequestMetrics::default()),
+            policy,
         };
 
         Ok(client)
     }
 
-    fn url(path: &str) -> Url {
-        Url::parse("https://at-proxy.heaps.dev/")
-            .unwrap()
-            .join(path)
-            .unwrap()
+    /// Fails straight away while the publisher's breaker is open,
+    /// otherwise counts towards it once any retries are done
+    async fn request(&self, url: &str, api_key: Option<&ApiKey>) -> AtResult<String> {
+        let breaker = self.breakers.for_url(url);
+        breaker.allow()?;
+
+        let result = self.request_with_retries(url, api_key).await;
+        if result.is_ok() {
+            breaker.success();
+        } else {
+            breaker.failure();
+        }
+        result
     }
 
-    async fn request<T>(&self, url: Url) -> AtResult<T>
-    where
-        T: serde::de::DeserializeOwned,
-    {
-        log::debug!("Requesting {}", url);
-        let response = self.client.get(url).send().await?;
+    /// Retries transient failures with backoff, up to `AT_RETRIES` times
+    async fn request_with_retries(&self, url: &str, api_key: Option<&ApiKey>) -> AtResult<String> {
+        let mut delay = self.policy.retry_delay;
+        let mut attempt = 0;
+        loop {
+            let start = Instant::now();
+            let result = self.request_once(url, api_key).await;
+            self.metrics.record(url, start.elapsed(), result.is_ok());
+
+            match result {
+                Err(e) if is_transient(&e) && attempt < self.policy.retries => {
+                    attempt += 1;
+                    tracing::warn!(
+                        "Request to {} failed, retry {} of {} in {} ms: {}",
+                        url,
+                        attempt,
+                        self.policy.retries,
+                        delay.as_millis(),
+                        e
+                    );
+                    sleep(delay).await;
+                    delay *= 2;
+                }
+                result => return result.map_err(AtError::from),
+            }
+        }
+    }
+
+    async fn request_once(&self, url: &str, api_key: Option<&ApiKey>) -> reqwest::Result<String> {
+        tracing::debug!("Requesting {}", url);
+        let mut request = self.client.get(url);
+        if let Some(api_key) = api_key {
+            request = request.header(&api_key.header, &api_key.key);
+        }
+        let response = request.send().await?.error_for_status()?;
 
         let data_str = response.text().await?;
-        log::trace!("Response: {}", data_str);
-        let data = serde_json::from_str(&data_str)?;
+        tracing::trace!("Response: {}", data_str);
+
+        Ok(data_str)
+    }
+
+    /// Gets a realtime feed from its full URL
+    pub async fn get_realtime_feed(
+        &self,
+        url: &str,
+        api_key: Option<&ApiKey>,
+    ) -> AtResult<FeedMessage> {
+        let json = self.get_realtime_feed_json(url, api_key).await?;
+        AtClient::parse_realtime_feed(&json)
+    }
+
+    /// Gets a realtime feed without parsing it, e.g. for recording
+    pub async fn get_realtime_feed_json(
+        &self,
+        url: &str,
+        api_key: Option<&ApiKey>,
+    ) -> AtResult<String> {
+        self.request(url, api_key).await
+    }
+
+    pub fn parse_realtime_feed(json: &str) -> AtResult<FeedMessage> {
+        let feed = match serde_json::from_str(json)? {
+            RealtimeResponse::<FeedMessage>::Wrapped { response } => response,
+            RealtimeResponse::Bare(feed) => feed,
+        };
+        Ok(feed)
+    }
+}
+
+impl AtApi for AtClient {
+    fn get_realtime_feed_json<'a>(
+        &'a self,
+        url: &'a str,
+        api_key: Option<&'a ApiKey>,
+    ) -> BoxFuture<'a, AtResult<String>> {
+        Box::pin(AtClient::get_realtime_feed_json(self, url, api_key))
+    }
+
+    fn breaker_report(&self) -> BTreeMap<String, BreakerReport> {
+        self.breakers.report()
+    }
 
-        Ok(data)
+    fn request_report(&self) -> BTreeMap<String, EndpointReport> {
+        self.metrics.report()
     }
 
-    pub async fn get_realtime_feed(&self) -> AtResult<FeedMessage> {
-        let url = AtClient::url("realtime.json");
-        let RealtimeResponse::<FeedMessage> { response } = self.request(url).await?;
-        Ok(response)
+    fn last_success(&self) -> BTreeMap<String, Option<DateTime<Utc>>> {
+        self.metrics.last_success()
     }
 }
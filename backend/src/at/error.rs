@@ -1,13 +1,17 @@
 #[derive(thiserror::Error, Debug)]
 pub enum AtError {
-    #[error("Init error: {0}")]
-    Init(String),
-
     #[error("HTTP error: {0}")]
     Http(#[from] reqwest::Error),
 
+    #[error("{0} keeps failing, not requesting it for another {1} s")]
+    CircuitOpen(String, u64),
+
     #[error("Deserialize error: {0}")]
     Deserialize(#[from] serde_json::Error),
+
+    #[cfg(test)]
+    #[error("Mock error: {0}")]
+    Mock(String),
 }
 
 pub type AtResult<T> = Result<T, AtError>;
@@ -0,0 +1,139 @@
+//! Counts and latencies of requests to publishers, by endpoint, since starting
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Mutex,
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+/// Upper bounds of the latency histogram buckets, there's another for anything slower
+const LATENCY_BUCKETS_MS: [u64; 8] = [50, 100, 250, 500, 1000, 2500, 5000, 10000];
+
+#[derive(Debug, Default)]
+struct EndpointCounts {
+    requests: u64,
+    errors: u64,
+    total_latency_ms: u64,
+    latency_buckets: [u64; LATENCY_BUCKETS_MS.len() + 1],
+    last_success: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LatencyBucket {
+    /// None for the last bucket, which has no upper bound
+    pub up_to_ms: Option<u64>,
+    pub count: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct EndpointReport {
+    pub requests: u64,
+    pub errors: u64,
+    pub mean_latency_ms: Option<u64>,
+    pub latency_ms: Vec<LatencyBucket>,
+    pub last_success: Option<DateTime<Utc>>,
+}
+
+/// Each attempt is counted, including retries
+#[derive(Debug, Default)]
+pub struct RequestMetrics {
+    endpoints: Mutex<HashMap<String, EndpointCounts>>,
+}
+
+/// The URL without its query, which can have keys in it
+fn endpoint(url: &str) -> String {
+    match url::Url::parse(url) {
+        Ok(mut url) => {
+            url.set_query(None);
+            url.to_string()
+        }
+        Err(_) => url.to_string(),
+    }
+}
+
+impl RequestMetrics {
+    pub fn record(&self, url: &str, latency: Duration, success: bool) {
+        let latency_ms = latency.as_millis() as u64;
+        let bucket = LATENCY_BUCKETS_MS
+            .iter()
+            .position(|b| latency_ms <= *b)
+            .unwrap_or(LATENCY_BUCKETS_MS.len());
+
+        let mut endpoints = self.endpoints.lock().unwrap();
+        let counts = endpoints.entry(endpoint(url)).or_default();
+        counts.requests += 1;
+        counts.total_latency_ms += latency_ms;
+        counts.latency_buckets[bucket] += 1;
+        if success {
+            counts.last_success = Some(Utc::now());
+        } else {
+            counts.errors += 1;
+        }
+    }
+
+    /// When each endpoint last responded successfully, if it has
+    pub fn last_success(&self) -> BTreeMap<String, Option<DateTime<Utc>>> {
+        self.endpoints
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(endpoint, counts)| (endpoint.clone(), counts.last_success))
+            .collect()
+    }
+
+    pub fn report(&self) -> BTreeMap<String, EndpointReport> {
+        self.endpoints
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(endpoint, counts)| {
+                let latency_ms = counts
+                    .latency_buckets
+                    .iter()
+                    .enumerate()
+                    .map(|(i, count)| LatencyBucket {
+                        up_to_ms: LATENCY_BUCKETS_MS.get(i).copied(),
+                        count: *count,
+                    })
+                    .collect();
+                let report = EndpointReport {
+                    requests: counts.requests,
+                    errors: counts.errors,
+                    mean_latency_ms: counts.total_latency_ms.checked_div(counts.requests),
+                    latency_ms,
+                    last_success: counts.last_success,
+                };
+                (endpoint.clone(), report)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn test_record() {
+        let metrics = RequestMetrics::default();
+        let url = "https://example.com/realtime.json?key=secret";
+        metrics.record(url, Duration::from_millis(20), true);
+        metrics.record(url, Duration::from_millis(700), false);
+        metrics.record(url, Duration::from_secs(30), false);
+
+        let report = metrics.report();
+        let endpoint = &report["https://example.com/realtime.json"];
+        assert_eq!(endpoint.requests, 3);
+        assert_eq!(endpoint.errors, 2);
+        assert_eq!(endpoint.mean_latency_ms, Some(10240));
+        assert_eq!(endpoint.latency_ms[0].count, 1);
+        assert_eq!(endpoint.latency_ms[4].count, 1);
+        assert_eq!(endpoint.latency_ms[8].up_to_ms, None);
+        assert_eq!(endpoint.latency_ms[8].count, 1);
+        assert!(endpoint.last_success.is_some());
+    }
+}
@@ -0,0 +1,47 @@
+//! Serves realtime feeds from the JSON fixtures in `fixtures/realtime`
+
+use std::collections::HashMap;
+
+use futures_util::future::{self, BoxFuture};
+
+use super::{
+    api::AtApi,
+    client::ApiKey,
+    error::{AtError, AtResult},
+};
+
+/// Feeds by URL, anything else isn't found
+#[derive(Debug, Default)]
+pub struct MockAt {
+    feeds: HashMap<String, String>,
+}
+
+impl MockAt {
+    /// Serves `fixtures/realtime/<name>.json` at `url`
+    pub fn with_fixture(mut self, url: &str, name: &str) -> Self {
+        let path = format!(
+            "{}/fixtures/realtime/{}.json",
+            env!("CARGO_MANIFEST_DIR"),
+            name
+        );
+        let json = std::fs::read_to_string(&path)
+            .unwrap_or_else(|e| panic!("Failed to read {}: {}", path, e));
+        self.feeds.insert(url.to_string(), json);
+        self
+    }
+}
+
+impl AtApi for MockAt {
+    fn get_realtime_feed_json<'a>(
+        &'a self,
+        url: &'a str,
+        _api_key: Option<&'a ApiKey>,
+    ) -> BoxFuture<'a, AtResult<String>> {
+        let result = self
+            .feeds
+            .get(url)
+            .cloned()
+            .ok_or_else(|| AtError::Mock(format!("No fixture for {}", url)));
+        Box::pin(future::ready(result))
+    }
+}
@@ -1,3 +1,8 @@
+pub mod api;
 pub mod client;
 pub mod entities;
 pub mod error;
+pub mod metrics;
+pub mod policy;
+#[cfg(test)]
+pub mod mock;
@@ -0,0 +1,262 @@
+//! How requests to publishers are timed out and retried,
+//! and the circuit breakers which stop them for a while when a publisher keeps failing
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use reqwest::StatusCode;
+use serde::Serialize;
+
+use crate::config::parse_var;
+
+use super::error::{AtError, AtResult};
+
+/// How long to wait to connect, if `AT_CONNECT_TIMEOUT_MS` isn't set
+const DEFAULT_CONNECT_TIMEOUT_MS: u64 = 5000;
+
+/// How long the whole request can take, including the body, if `AT_REQUEST_TIMEOUT_MS` isn't set
+const DEFAULT_REQUEST_TIMEOUT_MS: u64 = 10000;
+
+/// How many times a transient failure is retried, if `AT_RETRIES` isn't set
+const DEFAULT_RETRIES: u32 = 2;
+
+/// Wait before the first retry, doubling for each one after, if `AT_RETRY_DELAY_MS` isn't set
+const DEFAULT_RETRY_DELAY_MS: u64 = 500;
+
+/// Failed requests in a row before a publisher's breaker opens, if `AT_BREAKER_FAILURES` isn't set
+const DEFAULT_BREAKER_FAILURES: u32 = 5;
+
+/// How long an open breaker waits before letting a request through to try again,
+/// if `AT_BREAKER_COOLDOWN_SECONDS` isn't set
+const DEFAULT_BREAKER_COOLDOWN_SECONDS: u64 = 60;
+
+#[derive(Debug, Clone)]
+pub struct RequestPolicy {
+    pub connect_timeout: Duration,
+    pub request_timeout: Duration,
+    pub retries: u32,
+    pub retry_delay: Duration,
+    pub breaker_failures: u32,
+    pub breaker_cooldown: Duration,
+}
+
+impl RequestPolicy {
+    /// Every problem with the settings is added to `errors`
+    pub fn from_env(errors: &mut Vec<String>) -> Self {
+        let mut breaker_failures =
+            parse_var("AT_BREAKER_FAILURES", DEFAULT_BREAKER_FAILURES, errors);
+        if breaker_failures < 1 {
+            errors.push("AT_BREAKER_FAILURES must be at least 1".to_string());
+            breaker_failures = DEFAULT_BREAKER_FAILURES;
+        }
+
+        Self {
+            connect_timeout: Duration::from_millis(parse_var(
+                "AT_CONNECT_TIMEOUT_MS",
+                DEFAULT_CONNECT_TIMEOUT_MS,
+                errors,
+            )),
+            request_timeout: Duration::from_millis(parse_var(
+                "AT_REQUEST_TIMEOUT_MS",
+                DEFAULT_REQUEST_TIMEOUT_MS,
+                errors,
+            )),
+            retries: parse_var("AT_RETRIES", DEFAULT_RETRIES, errors),
+            retry_delay: Duration::from_millis(parse_var(
+                "AT_RETRY_DELAY_MS",
+                DEFAULT_RETRY_DELAY_MS,
+                errors,
+            )),
+            breaker_failures,
+            breaker_cooldown: Duration::from_secs(parse_var(
+                "AT_BREAKER_COOLDOWN_SECONDS",
+                DEFAULT_BREAKER_COOLDOWN_SECONDS,
+                errors,
+            )),
+        }
+    }
+}
+
+/// Whether a failed request might succeed if it's tried again
+pub fn is_transient(e: &reqwest::Error) -> bool {
+    match e.status() {
+        Some(status) => status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS,
+        None => e.is_timeout() || e.is_connect() || e.is_body(),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BreakerState {
+    Closed,
+    /// Requests fail without being made
+    Open,
+    /// The next request is let through, to see if the publisher has recovered
+    HalfOpen,
+}
+
+#[derive(Debug, Default)]
+struct BreakerCounts {
+    failures: u32,
+    open_until: Option<Instant>,
+}
+
+/// Opens after `failures` failed requests in a row, then lets one request through
+/// each `cooldown` until one succeeds
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    name: String,
+    failures: u32,
+    cooldown: Duration,
+    counts: Mutex<BreakerCounts>,
+}
+
+impl CircuitBreaker {
+    pub fn new(name: &str, failures: u32, cooldown: Duration) -> Self {
+        Self {
+            name: name.to_string(),
+            failures,
+            cooldown,
+            counts: Mutex::new(BreakerCounts::default()),
+        }
+    }
+
+    /// Whether a request can be made now.
+    /// Once the cooldown is up one is let through, and the cooldown starts again,
+    /// so a request that never finishes can't hold the breaker half open.
+    pub fn allow(&self) -> AtResult<()> {
+        let mut counts = self.counts.lock().unwrap();
+        match counts.open_until {
+            Some(until) => {
+                let now = Instant::now();
+                if now < until {
+                    return Err(AtError::CircuitOpen(
+                        self.name.clone(),
+                        (until - now).as_secs() + 1,
+                    ));
+                }
+                counts.open_until = Some(now + self.cooldown);
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    pub fn success(&self) {
+        let mut counts = self.counts.lock().unwrap();
+        if counts.open_until.is_some() {
+            tracing::info!("{} has recovered, closing its circuit breaker", self.name);
+        }
+        *counts = BreakerCounts::default();
+    }
+
+    pub fn failure(&self) {
+        let mut counts = self.counts.lock().unwrap();
+        counts.failures += 1;
+        if counts.open_until.is_none() && counts.failures >= self.failures {
+            tracing::warn!(
+                "{} has failed {} times in a row, pausing requests for {} s",
+                self.name,
+                counts.failures,
+                self.cooldown.as_secs()
+            );
+            counts.open_until = Some(Instant::now() + self.cooldown);
+        }
+    }
+
+    pub fn state(&self) -> BreakerState {
+        match self.counts.lock().unwrap().open_until {
+            None => BreakerState::Closed,
+            Some(until) if Instant::now() < until => BreakerState::Open,
+            Some(_) => BreakerState::HalfOpen,
+        }
+    }
+
+    pub fn report(&self) -> BreakerReport {
+        BreakerReport {
+            state: self.state(),
+            consecutive_failures: self.counts.lock().unwrap().failures,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct BreakerReport {
+    pub state: BreakerState,
+    pub consecutive_failures: u32,
+}
+
+/// A breaker for each publisher's host, so one failing doesn't stop requests to the others
+#[derive(Debug)]
+pub struct Breakers {
+    failures: u32,
+    cooldown: Duration,
+    hosts: Mutex<HashMap<String, Arc<CircuitBreaker>>>,
+}
+
+impl Breakers {
+    pub fn new(policy: &RequestPolicy) -> Self {
+        Self {
+            failures: policy.breaker_failures,
+            cooldown: policy.breaker_cooldown,
+            hosts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn for_url(&self, url: &str) -> Arc<CircuitBreaker> {
+        let host = reqwest::Url::parse(url)
+            .ok()
+            .and_then(|u| u.host_str().map(str::to_string))
+            .unwrap_or_default();
+        self.hosts
+            .lock()
+            .unwrap()
+            .entry(host.clone())
+            .or_insert_with(|| Arc::new(CircuitBreaker::new(&host, self.failures, self.cooldown)))
+            .clone()
+    }
+
+    /// By host, only those that have been requested
+    pub fn report(&self) -> BTreeMap<String, BreakerReport> {
+        self.hosts
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(host, breaker)| (host.clone(), breaker.report()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn test_circuit_breaker() {
+        let breaker = CircuitBreaker::new("test", 2, Duration::from_secs(60));
+
+        breaker.failure();
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        assert!(breaker.allow().is_ok());
+        breaker.failure();
+        assert_eq!(breaker.state(), BreakerState::Open);
+        assert!(matches!(breaker.allow(), Err(AtError::CircuitOpen(_, _))));
+
+        breaker.success();
+        assert_eq!(breaker.state(), BreakerState::Closed);
+
+        // Half open once the cooldown is up, letting a single request through
+        let breaker = CircuitBreaker::new("test", 1, Duration::ZERO);
+        breaker.failure();
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+        assert!(breaker.allow().is_ok());
+        breaker.failure();
+        assert_eq!(breaker.report().consecutive_failures, 2);
+        breaker.success();
+        assert_eq!(breaker.state(), BreakerState::Closed);
+    }
+}
@@ -0,0 +1,202 @@
+use std::{
+    collections::HashSet,
+    future::{ready, Ready},
+};
+
+use actix_web::{
+    body::EitherBody,
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    http::header,
+    web, Error, ResponseError,
+};
+use futures_util::future::LocalBoxFuture;
+
+use crate::{config, error::NextAtError, ContextData};
+
+/// Scope granting access to every route
+const ALL_SCOPES: &str = "*";
+
+const API_KEY_HEADER: &str = "x-api-key";
+
+#[derive(Debug, Clone)]
+struct ApiKey {
+    key: String,
+    scopes: HashSet<String>,
+}
+
+impl ApiKey {
+    fn allows(&self, scope: &str) -> bool {
+        self.scopes.contains(ALL_SCOPES) || self.scopes.contains(scope)
+    }
+}
+
+/// API keys allowed to use the management endpoints
+#[derive(Debug, Clone, Default)]
+pub struct ApiKeys {
+    keys: Vec<ApiKey>,
+}
+
+impl ApiKeys {
+    /// Parses keys in the form `key[:scope|scope...]`, separated by commas.
+    /// A key without scopes is allowed everything.
+    pub fn parse(value: &str) -> Self {
+        let keys = value
+            .split(',')
+            .map(str::trim)
+            .filter(|k| !k.is_empty())
+            .map(|entry| {
+                let (key, scopes) = entry.split_once(':').unwrap_or((entry, ALL_SCOPES));
+                ApiKey {
+                    key: key.to_string(),
+                    scopes: scopes
+                        .split('|')
+                        .map(|s| s.trim().to_string())
+                        .filter(|s| !s.is_empty())
+                        .collect(),
+                }
+            })
+            .collect();
+
+        Self { keys }
+    }
+
+    /// Reads keys from `MANAGEMENT_API_KEYS`
+    pub fn from_env() -> Self {
+        Self::parse(&config::var("MANAGEMENT_API_KEYS").unwrap_or_default())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    fn find(&self, key: &str) -> Option<&ApiKey> {
+        self.keys.iter().find(|k| constant_time_eq(&k.key, key))
+    }
+}
+
+/// Compares without returning early, so the key can't be guessed from response times
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.bytes().zip(b.bytes()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Gets the key from either the `X-Api-Key` header or a bearer token
+fn request_key(req: &ServiceRequest) -> Option<&str> {
+    let headers = req.headers();
+
+    if let Some(key) = headers.get(API_KEY_HEADER).and_then(|v| v.to_str().ok()) {
+        return Some(key.trim());
+    }
+
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(str::trim)
+}
+
+/// Middleware requiring a valid API key, optionally with a particular scope
+pub struct RequireApiKey {
+    scope: Option<&'static str>,
+}
+
+impl RequireApiKey {
+    /// Any valid key is accepted
+    pub fn any() -> Self {
+        Self { scope: None }
+    }
+
+    /// Only keys granted this scope are accepted
+    pub fn scope(scope: &'static str) -> Self {
+        Self { scope: Some(scope) }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RequireApiKey
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = RequireApiKeyMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequireApiKeyMiddleware {
+            service,
+            scope: self.scope,
+        }))
+    }
+}
+
+pub struct RequireApiKeyMiddleware<S> {
+    service: S,
+    scope: Option<&'static str>,
+}
+
+impl<S> RequireApiKeyMiddleware<S> {
+    fn check(&self, req: &ServiceRequest) -> Result<(), NextAtError> {
+        let keys = req
+            .app_data::<web::Data<ContextData>>()
+            .map(|ctx| ctx.api_keys.clone())
+            .unwrap_or_default();
+
+        let api_key = request_key(req)
+            .and_then(|key| keys.find(key))
+            .ok_or_else(|| NextAtError::Response(401, "Missing or invalid API key".to_string()))?;
+
+        match self.scope {
+            Some(scope) if !api_key.allows(scope) => Err(NextAtError::Response(
+                403,
+                format!("API key does not have the '{}' scope", scope),
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<S, B> Service<ServiceRequest> for RequireApiKeyMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        if let Err(e) = self.check(&req) {
+            tracing::warn!("Rejected management request to {}: {}", req.path(), e);
+            let response = e.error_response().map_into_right_body();
+            return Box::pin(async move { Ok(req.into_response(response)) });
+        }
+
+        let fut = self.service.call(req);
+        Box::pin(async move { fut.await.map(ServiceResponse::map_into_left_body) })
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn test_parse_api_keys() {
+        let keys = ApiKeys::parse("admin, syncer:gtfs|imports ,,");
+
+        assert_eq!(keys.keys.len(), 2);
+        assert!(keys.find("admin").unwrap().allows("gtfs"));
+        assert!(keys.find("syncer").unwrap().allows("imports"));
+        assert!(!keys.find("syncer").unwrap().allows("backup"));
+        assert!(keys.find("nope").is_none());
+    }
+}
@@ -0,0 +1,209 @@
+//! Results of hot read queries that only change with the static data, kept in memory.
+//! They're dropped once the static data is synced or re-indexed, or after `QUERY_CACHE_TTL_SECONDS`.
+//! Arrivals responses are also kept, for a few seconds, until the next realtime update.
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    hash::Hash,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use actix_web::web::Bytes;
+
+use crate::{
+    config::parse_var,
+    entity::gtfs_routes,
+    shapes::TripShapeStops,
+    stops::{Stop, StopEvent, StopRoute},
+    translations::Languages,
+};
+
+/// If `QUERY_CACHE_TTL_SECONDS` isn't set
+const DEFAULT_TTL_SECONDS: u64 = 5 * 60;
+
+/// If `ARRIVALS_CACHE_SECONDS` isn't set, it's kept short as how long until each is due changes
+const DEFAULT_ARRIVALS_TTL_SECONDS: u64 = 5;
+
+/// Most results kept by each cache, the oldest make way for new ones
+const MAX_ENTRIES: usize = 10_000;
+
+#[derive(Debug)]
+struct CachedValue<V> {
+    /// Version of the static data the value was read from
+    static_version: i64,
+    cached_at: Instant,
+    value: V,
+}
+
+#[derive(Debug)]
+pub struct QueryCache<K, V> {
+    entries: Mutex<HashMap<K, CachedValue<V>>>,
+    ttl: Duration,
+}
+
+impl<K: Eq + Hash + Clone, V: Clone> QueryCache<K, V> {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            entries: Mutex::new(HashMap::new()),
+            ttl,
+        }
+    }
+
+    fn get(&self, key: &K, static_version: i64) -> Option<V> {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .get(key)
+            .filter(|e| e.static_version == static_version && e.cached_at.elapsed() < self.ttl)
+            .map(|e| e.value.clone())
+    }
+
+    fn insert(&self, key: K, static_version: i64, value: V) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= MAX_ENTRIES && !entries.contains_key(&key) {
+            entries.retain(|_, e| {
+                e.static_version == static_version && e.cached_at.elapsed() < self.ttl
+            });
+            if entries.len() >= MAX_ENTRIES {
+                let oldest = entries
+                    .iter()
+                    .min_by_key(|(_, e)| e.cached_at)
+                    .map(|(k, _)| k.clone());
+                if let Some(oldest) = oldest {
+                    entries.remove(&oldest);
+                }
+            }
+        }
+        entries.insert(
+            key,
+            CachedValue {
+                static_version,
+                cached_at: Instant::now(),
+                value,
+            },
+        );
+    }
+
+    /// The cached value, otherwise the query's result, which is cached if it's not an error
+    pub async fn get_or_try_insert<E, Fut>(
+        &self,
+        key: K,
+        static_version: i64,
+        query: impl FnOnce() -> Fut,
+    ) -> Result<V, E>
+    where
+        Fut: Future<Output = Result<V, E>>,
+    {
+        if let Some(value) = self.get(&key, static_version) {
+            return Ok(value);
+        }
+        let value = query().await?;
+        self.insert(key, static_version, value.clone());
+        Ok(value)
+    }
+
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+}
+
+/// What an arrivals or departures response is rendered from, besides the static data
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ArrivalsKey {
+    pub stop_id: String,
+    pub event: StopEvent,
+    pub departed_minutes: u32,
+    pub group: bool,
+    pub languages: Languages,
+    /// Of the realtime feed that had been applied
+    pub realtime_version: i64,
+}
+
+/// A cache for each of the queries, by id
+#[derive(Debug)]
+pub struct QueryCaches {
+    pub stops: QueryCache<String, Stop>,
+    pub stop_routes: QueryCache<String, Vec<StopRoute>>,
+    pub routes: QueryCache<String, gtfs_routes::Model>,
+    /// For estimating arrivals from each vehicle position, by trip_id
+    pub trip_shape_stops: QueryCache<String, Option<Arc<TripShapeStops>>>,
+    /// Rendered JSON, cleared whenever a realtime feed is applied
+    pub arrivals: QueryCache<ArrivalsKey, Bytes>,
+}
+
+#[derive(Debug, Clone)]
+pub struct CacheConfig {
+    /// Of the query caches
+    pub ttl: Duration,
+    pub arrivals_ttl: Duration,
+}
+
+impl CacheConfig {
+    /// Every problem with the settings is added to `errors`
+    pub fn from_env(errors: &mut Vec<String>) -> Self {
+        Self {
+            ttl: Duration::from_secs(parse_var(
+                "QUERY_CACHE_TTL_SECONDS",
+                DEFAULT_TTL_SECONDS,
+                errors,
+            )),
+            arrivals_ttl: Duration::from_secs(parse_var(
+                "ARRIVALS_CACHE_SECONDS",
+                DEFAULT_ARRIVALS_TTL_SECONDS,
+                errors,
+            )),
+        }
+    }
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            ttl: Duration::from_secs(DEFAULT_TTL_SECONDS),
+            arrivals_ttl: Duration::from_secs(DEFAULT_ARRIVALS_TTL_SECONDS),
+        }
+    }
+}
+
+impl QueryCaches {
+    pub fn new(config: &CacheConfig) -> Self {
+        Self {
+            stops: QueryCache::new(config.ttl),
+            stop_routes: QueryCache::new(config.ttl),
+            routes: QueryCache::new(config.ttl),
+            trip_shape_stops: QueryCache::new(config.ttl),
+            arrivals: QueryCache::new(config.arrivals_ttl),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_get_or_try_insert() {
+        let cache = QueryCache::new(Duration::from_secs(60));
+        let query = |value: u32| move || async move { Ok::<_, ()>(value) };
+
+        assert_eq!(cache.get_or_try_insert("a", 1, query(1)).await, Ok(1));
+        // Cached
+        assert_eq!(cache.get_or_try_insert("a", 1, query(2)).await, Ok(1));
+        // The static data has changed since
+        assert_eq!(cache.get_or_try_insert("a", 2, query(3)).await, Ok(3));
+        // Errors aren't cached
+        assert_eq!(
+            cache
+                .get_or_try_insert("b", 2, || async { Err::<u32, _>(()) })
+                .await,
+            Err(())
+        );
+        assert_eq!(cache.get_or_try_insert("b", 2, query(4)).await, Ok(4));
+
+        let expired = QueryCache::new(Duration::ZERO);
+        expired.insert("a", 1, 1);
+        assert_eq!(expired.get(&"a", 1), None);
+    }
+}
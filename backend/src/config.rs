@@ -0,0 +1,288 @@
+//! Settings from a TOML file, with the environment taking precedence,
+//! checked at startup so that every problem is reported at once
+
+use std::{
+    collections::HashMap,
+    env::{self, VarError},
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::OnceLock,
+    time::Duration,
+};
+
+use toml_edit::{Document, Item, Value};
+
+use crate::{
+    at::{client::AtClient, policy::RequestPolicy},
+    auth::ApiKeys,
+    cache::CacheConfig,
+    cors::CorsConfig,
+    db::config::DbConfig,
+    gtfs::{feed::Feed, index::IndexConfig, realtime::RealtimeConfig, sync::SyncConfig},
+    logging::LogConfig,
+    notifications::NotificationsConfig,
+    telemetry::TelemetryConfig,
+    webhooks::Webhooks,
+};
+
+/// Read if `CONFIG_FILE` isn't set, and skipped if it doesn't exist
+const DEFAULT_CONFIG_FILE: &str = "next-at.toml";
+
+/// If `LISTEN_ADDRESS` isn't set
+const DEFAULT_LISTEN_ADDRESS: &str = "127.0.0.1:8080";
+
+/// How long to wait for the background tasks to stop, if `SHUTDOWN_TIMEOUT_SECONDS` isn't set
+const DEFAULT_SHUTDOWN_TIMEOUT_SECONDS: u64 = 30;
+
+/// The config file's settings, by the name of the environment variable that overrides them
+static FILE_VARS: OnceLock<HashMap<String, String>> = OnceLock::new();
+
+/// A setting from the environment, or from the config file if it isn't set there.
+/// Only the environment is read until `Config::load` has read the file, as in tests.
+pub fn var(name: impl AsRef<str>) -> Result<String, VarError> {
+    let name = name.as_ref();
+    match env::var(name) {
+        Err(VarError::NotPresent) => FILE_VARS
+            .get()
+            .and_then(|vars| vars.get(name).cloned())
+            .ok_or(VarError::NotPresent),
+        result => result,
+    }
+}
+
+/// The setting parsed, or the default if it isn't set.
+/// If it can't be parsed that's added to `errors`, and the default used.
+pub fn parse_var<T: FromStr>(name: &str, default: T, errors: &mut Vec<String>) -> T {
+    match var(name) {
+        Ok(value) => value.trim().parse().unwrap_or_else(|_| {
+            errors.push(format!("Invalid {}: {}", name, value));
+            default
+        }),
+        Err(_) => default,
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+#[error("Invalid config:\n  {}", .0.join("\n  "))]
+pub struct ConfigErrors(Vec<String>);
+
+pub struct Config {
+    /// The config file that was read, if any
+    pub file: Option<PathBuf>,
+    pub log: LogConfig,
+    pub db: DbConfig,
+    pub listen_address: String,
+    pub cors: CorsConfig,
+    /// None if traces and metrics aren't exported
+    pub telemetry: Option<TelemetryConfig>,
+    pub feeds: Vec<Feed>,
+    pub sync: SyncConfig,
+    pub index: IndexConfig,
+    /// With the timeouts, retries and breakers of the `RequestPolicy`
+    pub at_client: AtClient,
+    pub realtime: RealtimeConfig,
+    pub cache: CacheConfig,
+    pub notifications: NotificationsConfig,
+    pub api_keys: ApiKeys,
+    pub webhooks: Webhooks,
+    pub shutdown_timeout: Duration,
+}
+
+/// The file's settings as environment variables. Tables prefix their keys,
+/// so `cache_size_mb` in `[db]` is `DB_CACHE_SIZE_MB`, and arrays are joined with commas.
+fn file_vars(contents: &str) -> Result<Vec<(String, String)>, String> {
+    let document = contents.parse::<Document>().map_err(|e| e.to_string())?;
+    let mut vars = vec![];
+    let mut errors = vec![];
+    for (key, item) in document.iter() {
+        item_vars(&var_name(None, key), item, &mut vars, &mut errors);
+    }
+    match errors.is_empty() {
+        true => Ok(vars),
+        false => Err(errors.join(", ")),
+    }
+}
+
+fn var_name(prefix: Option<&str>, key: &str) -> String {
+    let key = key.to_uppercase().replace('-', "_");
+    match prefix {
+        Some(prefix) => format!("{}_{}", prefix, key),
+        None => key,
+    }
+}
+
+fn item_vars(name: &str, item: &Item, vars: &mut Vec<(String, String)>, errors: &mut Vec<String>) {
+    match item {
+        Item::Table(table) => {
+            for (key, item) in table.iter() {
+                item_vars(&var_name(Some(name), key), item, vars, errors);
+            }
+        }
+        Item::Value(Value::InlineTable(table)) => {
+            for (key, value) in table.iter() {
+                let item = Item::Value(value.clone());
+                item_vars(&var_name(Some(name), key), &item, vars, errors);
+            }
+        }
+        Item::Value(value) => match value_string(value) {
+            Some(value) => vars.push((name.to_string(), value)),
+            None => errors.push(format!("{} can't be a {}", name, value.type_name())),
+        },
+        Item::ArrayOfTables(_) => errors.push(format!("{} can't be an array of tables", name)),
+        Item::None => {}
+    }
+}
+
+fn value_string(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.value().clone()),
+        Value::Integer(i) => Some(i.value().to_string()),
+        Value::Float(f) => Some(f.value().to_string()),
+        Value::Boolean(b) => Some(b.value().to_string()),
+        Value::Datetime(d) => Some(d.value().to_string()),
+        Value::Array(array) => array
+            .iter()
+            .map(value_string)
+            .collect::<Option<Vec<_>>>()
+            .map(|values| values.join(",")),
+        Value::InlineTable(_) => None,
+    }
+}
+
+/// The file's settings, None if it doesn't exist and isn't `required`
+fn load_file(
+    path: &Path,
+    required: bool,
+    errors: &mut Vec<String>,
+) -> Option<Vec<(String, String)>> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == ErrorKind::NotFound && !required => return None,
+        Err(e) => {
+            errors.push(format!("Can't read {}: {}", path.display(), e));
+            return None;
+        }
+    };
+    file_vars(&contents)
+        .map_err(|e| errors.push(format!("{}: {}", path.display(), e)))
+        .ok()
+}
+
+impl Config {
+    /// Reads `CONFIG_FILE` for `var`, then the settings from it and the environment.
+    /// Must be called before anything else reads settings.
+    pub fn load() -> Result<Self, ConfigErrors> {
+        let mut errors = vec![];
+
+        let (path, required) = match env::var("CONFIG_FILE") {
+            Ok(path) => (PathBuf::from(path), true),
+            Err(_) => (PathBuf::from(DEFAULT_CONFIG_FILE), false),
+        };
+        let file_vars = load_file(&path, required, &mut errors);
+        let file = file_vars.is_some().then_some(path);
+        FILE_VARS
+            .set(file_vars.unwrap_or_default().into_iter().collect())
+            .ok();
+
+        let log = LogConfig::from_env(&mut errors);
+        let db = DbConfig::from_env(&mut errors);
+
+        let listen_address =
+            var("LISTEN_ADDRESS").unwrap_or_else(|_| DEFAULT_LISTEN_ADDRESS.to_string());
+        let port = listen_address.rsplit_once(':').map(|(_, port)| port);
+        if port.and_then(|port| port.parse::<u16>().ok()).is_none() {
+            errors.push(format!(
+                "Invalid LISTEN_ADDRESS: {}, expected host:port",
+                listen_address
+            ));
+        }
+
+        let cors = CorsConfig::from_env(&mut errors);
+        let telemetry = TelemetryConfig::from_env(&mut errors);
+
+        let feeds = Feed::from_env(&mut errors);
+        let sync = SyncConfig::from_env(&mut errors);
+        let index = IndexConfig::from_env(&mut errors);
+
+        let at_client = AtClient::new(RequestPolicy::from_env(&mut errors))
+            .map_err(|e| errors.push(format!("Can't create the feed client: {}", e)))
+            .ok();
+
+        let realtime = RealtimeConfig::from_env(&mut errors);
+        let cache = CacheConfig::from_env(&mut errors);
+        let notifications = NotificationsConfig::from_env(&mut errors);
+        let api_keys = ApiKeys::from_env();
+        let webhooks = Webhooks::from_env(&mut errors);
+
+        let shutdown_timeout = Duration::from_secs(parse_var(
+            "SHUTDOWN_TIMEOUT_SECONDS",
+            DEFAULT_SHUTDOWN_TIMEOUT_SECONDS,
+            &mut errors,
+        ));
+
+        match at_client {
+            Some(at_client) if errors.is_empty() => Ok(Self {
+                file,
+                log,
+                db,
+                listen_address,
+                cors,
+                telemetry,
+                feeds,
+                sync,
+                index,
+                at_client,
+                realtime,
+                cache,
+                notifications,
+                api_keys,
+                webhooks,
+                shutdown_timeout,
+            }),
+            _ => Err(ConfigErrors(errors)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn test_file_vars() {
+        let vars = file_vars(
+            r#"
+            database_path = "/data/data.db"
+            feeds = ["at", "metlink"]
+
+            [db]
+            cache_size_mb = 500
+            log_query_params = true
+
+            [feed.metlink]
+            gtfs_url = "https://static.opendata.metlink.org.nz/v1/gtfs/full.zip"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            vars,
+            [
+                ("DATABASE_PATH", "/data/data.db"),
+                ("FEEDS", "at,metlink"),
+                ("DB_CACHE_SIZE_MB", "500"),
+                ("DB_LOG_QUERY_PARAMS", "true"),
+                (
+                    "FEED_METLINK_GTFS_URL",
+                    "https://static.opendata.metlink.org.nz/v1/gtfs/full.zip"
+                ),
+            ]
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+        );
+
+        assert!(file_vars("[[stops]]\nid = 1").is_err());
+        assert!(file_vars("database_path =").is_err());
+    }
+}
@@ -0,0 +1,181 @@
+//! Which other sites can call the API from a browser
+
+use std::str::FromStr;
+
+use actix_cors::Cors;
+use actix_web::http::{header::HeaderName, Method};
+use regex::Regex;
+
+use crate::config;
+
+/// Methods allowed on the public API, if `CORS_METHODS` isn't set
+const DEFAULT_METHODS: &str = "GET, POST, DELETE";
+
+/// Request headers allowed on the public API, if `CORS_HEADERS` isn't set
+const DEFAULT_HEADERS: &str = "accept, content-type";
+
+/// Methods allowed on the management API, if `MANAGEMENT_CORS_METHODS` isn't set
+const DEFAULT_MANAGEMENT_METHODS: &str = "GET, POST, DELETE";
+
+/// Request headers allowed on the management API, if `MANAGEMENT_CORS_HEADERS` isn't set.
+/// The API key is sent in either of the last two.
+const DEFAULT_MANAGEMENT_HEADERS: &str = "accept, content-type, authorization, x-api-key";
+
+/// What a group of routes allows
+#[derive(Debug, Clone)]
+pub struct CorsScope {
+    methods: Vec<Method>,
+    headers: Vec<HeaderName>,
+}
+
+#[derive(Debug, Clone)]
+pub struct CorsConfig {
+    any_origin: bool,
+    origins: Vec<String>,
+    /// Matched against the whole origin, e.g. for preview deployments
+    origin_pattern: Option<Regex>,
+    /// How long browsers can cache a preflight response
+    max_age: Option<usize>,
+    public: CorsScope,
+    management: CorsScope,
+}
+
+fn split_list(list: &str) -> impl Iterator<Item = &str> {
+    list.split(',').map(str::trim).filter(|s| !s.is_empty())
+}
+
+/// Each item of the comma separated variable, or the default's
+fn parse_list<T: FromStr>(name: &str, default: &str, errors: &mut Vec<String>) -> Vec<T> {
+    let list = config::var(name).unwrap_or_else(|_| default.to_string());
+    split_list(&list)
+        .filter_map(|item| {
+            item.parse()
+                .map_err(|_| errors.push(format!("Invalid {}: {}", name, item)))
+                .ok()
+        })
+        .collect()
+}
+
+impl CorsConfig {
+    /// `ALLOW_ORIGIN` is a comma separated list of origins, or `*` for any,
+    /// and `ALLOW_ORIGIN_PATTERN` a regex that other origins are allowed by.
+    /// Every problem with them is added to `errors`.
+    pub fn from_env(errors: &mut Vec<String>) -> Self {
+        let allow_origin = config::var("ALLOW_ORIGIN").unwrap_or_default();
+        let mut any_origin = false;
+        let mut origins = vec![];
+        for origin in split_list(&allow_origin) {
+            if origin == "*" {
+                any_origin = true;
+            } else if url::Url::parse(origin).is_err() {
+                errors.push(format!("Invalid ALLOW_ORIGIN: {}", origin));
+            } else {
+                origins.push(origin.trim_end_matches('/').to_string());
+            }
+        }
+
+        let origin_pattern = config::var("ALLOW_ORIGIN_PATTERN")
+            .ok()
+            .and_then(|pattern| {
+                Regex::new(&format!("^(?:{})$", pattern))
+                    .map_err(|e| errors.push(format!("Invalid ALLOW_ORIGIN_PATTERN: {}", e)))
+                    .ok()
+            });
+
+        let max_age = config::var("CORS_MAX_AGE_SECONDS")
+            .ok()
+            .and_then(|max_age| {
+                max_age
+                    .trim()
+                    .parse()
+                    .map_err(|_| errors.push(format!("Invalid CORS_MAX_AGE_SECONDS: {}", max_age)))
+                    .ok()
+            });
+
+        Self {
+            any_origin,
+            origins,
+            origin_pattern,
+            max_age,
+            public: CorsScope {
+                methods: parse_list("CORS_METHODS", DEFAULT_METHODS, errors),
+                headers: parse_list("CORS_HEADERS", DEFAULT_HEADERS, errors),
+            },
+            management: CorsScope {
+                methods: parse_list(
+                    "MANAGEMENT_CORS_METHODS",
+                    DEFAULT_MANAGEMENT_METHODS,
+                    errors,
+                ),
+                headers: parse_list(
+                    "MANAGEMENT_CORS_HEADERS",
+                    DEFAULT_MANAGEMENT_HEADERS,
+                    errors,
+                ),
+            },
+        }
+    }
+
+    pub fn allows_origin(&self, origin: &str) -> bool {
+        self.any_origin
+            || self.origins.iter().any(|o| o == origin)
+            || self
+                .origin_pattern
+                .as_ref()
+                .is_some_and(|pattern| pattern.is_match(origin))
+    }
+
+    fn cors(&self, scope: &CorsScope) -> Cors {
+        let config = self.clone();
+        Cors::default()
+            .allowed_origin_fn(move |origin, _| {
+                origin
+                    .to_str()
+                    .is_ok_and(|origin| config.allows_origin(origin))
+            })
+            .allowed_methods(scope.methods.clone())
+            .allowed_headers(scope.headers.clone())
+            .max_age(self.max_age)
+    }
+
+    /// For the public API
+    pub fn public(&self) -> Cors {
+        self.cors(&self.public)
+    }
+
+    /// For the management API
+    pub fn management(&self) -> Cors {
+        self.cors(&self.management)
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn test_allows_origin() {
+        let config = CorsConfig {
+            any_origin: false,
+            origins: vec!["https://next-at.nz".to_string()],
+            origin_pattern: Some(
+                Regex::new(r"^(?:https://[a-z0-9-]+\.next-at\.pages\.dev)$").unwrap(),
+            ),
+            max_age: None,
+            public: CorsScope {
+                methods: vec![Method::GET],
+                headers: vec![],
+            },
+            management: CorsScope {
+                methods: vec![Method::GET],
+                headers: vec![],
+            },
+        };
+        assert!(config.allows_origin("https://next-at.nz"));
+        assert!(config.allows_origin("https://pr-12.next-at.pages.dev"));
+        assert!(!config.allows_origin("https://evil.example"));
+        // The pattern has to match all of it
+        assert!(!config.allows_origin("https://pr-12.next-at.pages.dev.evil.example"));
+    }
+}
@@ -0,0 +1,198 @@
+//! Snapshots of the database, so losing it doesn't mean a full re-sync and losing realtime history.
+//! They're written to `BACKUP_DIR`, which can be a mounted bucket to keep them off the server.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use chrono::Utc;
+use rusqlite::{backup::Backup, Connection, DatabaseName};
+use serde::Serialize;
+
+use super::{
+    config::config,
+    util::{open_rusqlite, REALTIME_SCHEMA},
+};
+
+const BACKUP_PREFIX: &str = "next-at-";
+const BACKUP_EXTENSION: &str = ".sqlite";
+/// The realtime database is backed up alongside, named after the main backup with this
+const REALTIME_SUFFIX: &str = "-realtime";
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Database error: {0}")]
+    Database(#[from] rusqlite::Error),
+
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BackupInfo {
+    pub path: String,
+    pub size_bytes: u64,
+    pub realtime_path: String,
+    pub realtime_size_bytes: u64,
+    pub duration_ms: u128,
+}
+
+/// Where backups are written, or None if they're not enabled
+pub fn backup_dir() -> Option<PathBuf> {
+    config().backup_dir.clone()
+}
+
+/// Names sort in the order the backups were made
+fn backup_name() -> String {
+    format!(
+        "{}{}{}",
+        BACKUP_PREFIX,
+        Utc::now().format("%Y%m%dT%H%M%SZ"),
+        BACKUP_EXTENSION
+    )
+}
+
+/// Where the realtime database is backed up to, alongside the main backup
+fn realtime_backup_path(path: &Path) -> PathBuf {
+    let mut name = path.file_stem().unwrap_or_default().to_os_string();
+    name.push(REALTIME_SUFFIX);
+    name.push(BACKUP_EXTENSION);
+    path.with_file_name(name)
+}
+
+/// The backups of the main database in the directory, oldest first
+fn existing_backups(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut backups = vec![];
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        let name = name.to_string_lossy();
+        if name.starts_with(BACKUP_PREFIX)
+            && name.ends_with(BACKUP_EXTENSION)
+            && !name.ends_with(&format!("{}{}", REALTIME_SUFFIX, BACKUP_EXTENSION))
+        {
+            backups.push(dir.join(name.as_ref()));
+        }
+    }
+    backups.sort();
+    Ok(backups)
+}
+
+/// Deletes all but the newest `keep` backups, with their realtime backups
+fn remove_old_backups(dir: &Path, keep: usize) -> Result<()> {
+    let backups = existing_backups(dir)?;
+    let remove = backups.len().saturating_sub(keep);
+    for backup in &backups[..remove] {
+        tracing::info!("Removing old backup {}", backup.display());
+        fs::remove_file(backup)?;
+        let realtime = realtime_backup_path(backup);
+        if realtime.exists() {
+            fs::remove_file(realtime)?;
+        }
+    }
+    Ok(())
+}
+
+/// Copies the database in one step, so the copy is from a single read transaction.
+/// With WAL this doesn't hold up writers, which a step at a time would restart for.
+/// Only named as a backup at `path` once it's complete.
+fn copy_database(source: &Connection, name: DatabaseName, path: &Path) -> Result<()> {
+    let partial_path = path.with_extension("partial");
+    let copy = || -> Result<()> {
+        let mut destination = Connection::open(&partial_path)?;
+        Backup::new_with_names(source, name, &mut destination, DatabaseName::Main)?
+            .run_to_completion(-1, Duration::ZERO, None)?;
+        Ok(())
+    };
+    if let Err(e) = copy() {
+        fs::remove_file(&partial_path).ok();
+        return Err(e);
+    }
+    fs::rename(&partial_path, path)?;
+    Ok(())
+}
+
+fn do_backup_database(dir: &Path) -> Result<BackupInfo> {
+    let start = Instant::now();
+    fs::create_dir_all(dir)?;
+
+    let path = dir.join(backup_name());
+    let realtime_path = realtime_backup_path(&path);
+
+    let source = open_rusqlite()?;
+    // Realtime first, so a main backup always has its realtime one
+    copy_database(
+        &source,
+        DatabaseName::Attached(REALTIME_SCHEMA),
+        &realtime_path,
+    )?;
+    copy_database(&source, DatabaseName::Main, &path)?;
+
+    let backup = BackupInfo {
+        path: path.display().to_string(),
+        size_bytes: fs::metadata(&path)?.len(),
+        realtime_path: realtime_path.display().to_string(),
+        realtime_size_bytes: fs::metadata(&realtime_path)?.len(),
+        duration_ms: start.elapsed().as_millis(),
+    };
+    tracing::info!(
+        "Backed up database to {} ({} bytes) in {} ms",
+        backup.path,
+        backup.size_bytes,
+        backup.duration_ms
+    );
+
+    remove_old_backups(dir, config().backup_retention)?;
+
+    Ok(backup)
+}
+
+/// Writes a snapshot of the database to the directory, removing the oldest past `BACKUP_RETENTION`
+pub async fn backup_database(dir: PathBuf) -> Result<BackupInfo> {
+    tokio::task::spawn_blocking(move || do_backup_database(&dir))
+        .await
+        .unwrap() // spawn result
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn test_remove_old_backups() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in [
+            "next-at-20240103T000000Z.sqlite",
+            "next-at-20240103T000000Z-realtime.sqlite",
+            "next-at-20240101T000000Z.sqlite",
+            "next-at-20240101T000000Z-realtime.sqlite",
+            "next-at-20240102T000000Z.sqlite",
+            "next-at-20240104T000000Z.partial",
+            "other.sqlite",
+        ] {
+            fs::write(dir.path().join(name), "").unwrap();
+        }
+
+        remove_old_backups(dir.path(), 2).unwrap();
+
+        let mut remaining = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        remaining.sort();
+        assert_eq!(
+            remaining,
+            vec![
+                "next-at-20240102T000000Z.sqlite",
+                "next-at-20240103T000000Z-realtime.sqlite",
+                "next-at-20240103T000000Z.sqlite",
+                "next-at-20240104T000000Z.partial",
+                "other.sqlite",
+            ]
+        );
+    }
+}
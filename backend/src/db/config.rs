@@ -0,0 +1,167 @@
+//! SQLite settings from the environment, shared by the sea-orm pools and rusqlite connections
+
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::OnceLock,
+    time::Duration,
+};
+
+use sqlx::sqlite::SqliteJournalMode;
+
+use crate::config::{parse_var, var};
+
+/// Page cache of each connection in MB, if `DB_CACHE_SIZE_MB` isn't set
+const DEFAULT_CACHE_SIZE_MB: i64 = 1000;
+
+/// How long to wait for a lock, if `DB_BUSY_TIMEOUT_MS` isn't set
+const DEFAULT_BUSY_TIMEOUT_MS: u64 = 5000;
+
+/// How many times a write that still found the database locked is retried,
+/// if `DB_BUSY_RETRIES` isn't set
+const DEFAULT_BUSY_RETRIES: u32 = 3;
+
+/// Queries taking longer are logged, if `DB_SLOW_QUERY_MS` isn't set. 0 turns this off.
+const DEFAULT_SLOW_QUERY_MS: u64 = 1000;
+
+/// Bytes of the database memory mapped, if `DB_MMAP_SIZE` isn't set. None by default.
+const DEFAULT_MMAP_SIZE: i64 = 0;
+
+/// Connections in the read pool, if `DB_MAX_CONNECTIONS` isn't set
+const DEFAULT_MAX_CONNECTIONS: u32 = 10;
+
+/// If `DB_JOURNAL_MODE` isn't set. Other modes block readers while writing.
+const DEFAULT_JOURNAL_MODE: &str = "wal";
+
+/// How many backups are kept, if `BACKUP_RETENTION` isn't set
+const DEFAULT_BACKUP_RETENTION: usize = 7;
+
+#[derive(Debug, Clone)]
+pub struct DbConfig {
+    pub path: String,
+    /// `REALTIME_DATABASE_PATH`, or next to the main database if it isn't set,
+    /// e.g. `next-at-realtime.db` for `next-at.db`
+    pub realtime_path: String,
+    pub cache_size_mb: i64,
+    pub busy_timeout: Duration,
+    pub busy_retries: u32,
+    pub slow_query: Duration,
+    /// Whether slow queries are logged with their parameters, which can come from users
+    pub log_query_params: bool,
+    pub mmap_size: i64,
+    pub max_connections: u32,
+    /// As the pragma's value, e.g. `wal`
+    pub journal_mode: String,
+    /// `BACKUP_DIR`, None if backups aren't enabled
+    pub backup_dir: Option<PathBuf>,
+    /// How many backups are kept, at least one
+    pub backup_retention: usize,
+    /// Whether free pages are vacuumed when the database is optimised
+    pub incremental_vacuum: bool,
+}
+
+fn realtime_path_beside(path: &str) -> String {
+    let path = Path::new(path);
+    let mut name = path.file_stem().unwrap_or_default().to_os_string();
+    name.push("-realtime");
+    if let Some(extension) = path.extension() {
+        name.push(".");
+        name.push(extension);
+    }
+    path.with_file_name(name).display().to_string()
+}
+
+impl DbConfig {
+    /// Every problem with the settings is added to `errors`
+    pub fn from_env(errors: &mut Vec<String>) -> Self {
+        let path = var("DATABASE_PATH").unwrap_or_else(|_| {
+            errors.push("DATABASE_PATH must be set".to_string());
+            String::new()
+        });
+        let realtime_path =
+            var("REALTIME_DATABASE_PATH").unwrap_or_else(|_| realtime_path_beside(&path));
+
+        let cache_size_mb = parse_var("DB_CACHE_SIZE_MB", DEFAULT_CACHE_SIZE_MB, errors);
+        if cache_size_mb < 0 {
+            errors.push(format!("Invalid DB_CACHE_SIZE_MB: {}", cache_size_mb));
+        }
+
+        let mmap_size = parse_var("DB_MMAP_SIZE", DEFAULT_MMAP_SIZE, errors);
+        if mmap_size < 0 {
+            errors.push(format!("Invalid DB_MMAP_SIZE: {}", mmap_size));
+        }
+
+        let max_connections = parse_var("DB_MAX_CONNECTIONS", DEFAULT_MAX_CONNECTIONS, errors);
+        if max_connections < 1 {
+            errors.push(format!("Invalid DB_MAX_CONNECTIONS: {}", max_connections));
+        }
+
+        let mut journal_mode = var("DB_JOURNAL_MODE")
+            .unwrap_or_else(|_| DEFAULT_JOURNAL_MODE.to_string())
+            .to_lowercase();
+        if SqliteJournalMode::from_str(&journal_mode).is_err() {
+            errors.push(format!("Invalid DB_JOURNAL_MODE: {}", journal_mode));
+            journal_mode = DEFAULT_JOURNAL_MODE.to_string();
+        }
+
+        let backup_retention = parse_var("BACKUP_RETENTION", DEFAULT_BACKUP_RETENTION, errors);
+        if backup_retention < 1 {
+            errors.push(format!("Invalid BACKUP_RETENTION: {}", backup_retention));
+        }
+
+        let incremental_vacuum = match var("DB_INCREMENTAL_VACUUM").as_deref() {
+            Ok("true" | "1") => true,
+            Ok("false" | "0") | Err(_) => false,
+            Ok(value) => {
+                errors.push(format!("Invalid DB_INCREMENTAL_VACUUM: {}", value));
+                false
+            }
+        };
+
+        Self {
+            path,
+            realtime_path,
+            cache_size_mb,
+            busy_timeout: Duration::from_millis(parse_var(
+                "DB_BUSY_TIMEOUT_MS",
+                DEFAULT_BUSY_TIMEOUT_MS,
+                errors,
+            )),
+            busy_retries: parse_var("DB_BUSY_RETRIES", DEFAULT_BUSY_RETRIES, errors),
+            slow_query: Duration::from_millis(parse_var(
+                "DB_SLOW_QUERY_MS",
+                DEFAULT_SLOW_QUERY_MS,
+                errors,
+            )),
+            log_query_params: parse_var("DB_LOG_QUERY_PARAMS", false, errors),
+            mmap_size,
+            max_connections,
+            journal_mode,
+            backup_dir: var("BACKUP_DIR").ok().map(PathBuf::from),
+            backup_retention,
+            incremental_vacuum,
+        }
+    }
+
+    /// The value of the cache_size pragma, negative as it's in KiB rather than pages
+    pub fn cache_size_pragma(&self) -> String {
+        (-self.cache_size_mb * 1024).to_string()
+    }
+
+    pub fn journal_mode(&self) -> SqliteJournalMode {
+        // Checked when the config was read
+        SqliteJournalMode::from_str(&self.journal_mode).unwrap()
+    }
+}
+
+static CONFIG: OnceLock<DbConfig> = OnceLock::new();
+
+/// Sets the config the database is opened with, once it's been checked
+pub fn init(config: DbConfig) {
+    CONFIG.set(config).ok();
+}
+
+/// The config passed to `init`, or read now if it wasn't, as in tests
+pub fn config() -> &'static DbConfig {
+    CONFIG.get_or_init(|| DbConfig::from_env(&mut vec![]))
+}
@@ -1,6 +1,8 @@
-use sea_orm::{Linked, RelationDef, RelationTrait};
+use sea_orm::{EntityTrait, Linked, RelationDef, RelationTrait};
 
-use crate::entity::{gtfs_agency, gtfs_routes};
+use crate::entity::{
+    gtfs_agency, gtfs_routes, gtfs_stop_times, gtfs_trips, stop_time_index, trip_run,
+};
 
 use crate::entity::prelude::*;
 
@@ -17,3 +19,27 @@ impl Linked for TripAgency {
         ]
     }
 }
+
+/// Stop time index to its scheduled stop time.
+/// Trips added by the realtime feed have no scheduled stop times,
+/// so there's no foreign key to generate this relation from.
+pub fn stop_time_index_stop_time() -> RelationDef {
+    stop_time_index::Entity::belongs_to(gtfs_stop_times::Entity)
+        .from((
+            stop_time_index::Column::TripId,
+            stop_time_index::Column::StopSequence,
+        ))
+        .to((
+            gtfs_stop_times::Column::TripId,
+            gtfs_stop_times::Column::StopSequence,
+        ))
+        .into()
+}
+
+/// Trip run to its scheduled trip, which added trips don't have
+pub fn trip_run_trip() -> RelationDef {
+    trip_run::Entity::belongs_to(gtfs_trips::Entity)
+        .from(trip_run::Column::TripId)
+        .to(gtfs_trips::Column::TripId)
+        .into()
+}
@@ -1,3 +1,9 @@
+pub mod backup;
+pub mod config;
 pub mod error;
 pub mod links;
+pub mod optimise;
+pub mod retry;
+pub mod slow_query;
+pub mod stats;
 pub mod util;
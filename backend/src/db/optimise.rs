@@ -0,0 +1,71 @@
+//! Housekeeping after imports and index builds, which leave a large WAL and stale statistics
+
+use std::time::Instant;
+
+use rusqlite::Connection;
+
+use super::{config::config, util::open_rusqlite};
+
+/// Runs the step, logging how long it took
+fn timed<T>(name: &str, step: impl FnOnce() -> rusqlite::Result<T>) -> rusqlite::Result<T> {
+    let start = Instant::now();
+    let result = step()?;
+    tracing::info!("{} took {} ms", name, start.elapsed().as_millis());
+    Ok(result)
+}
+
+/// Moves the WAL into the database and truncates it.
+/// Readers still on an old snapshot can stop it finishing, which is only logged.
+fn checkpoint(db: &Connection) -> rusqlite::Result<()> {
+    let (busy, log_pages, checkpointed_pages): (i32, i32, i32) =
+        db.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |r| {
+            Ok((r.get(0)?, r.get(1)?, r.get(2)?))
+        })?;
+    if busy != 0 {
+        tracing::warn!(
+            "WAL checkpoint incomplete, {} of {} pages checkpointed",
+            checkpointed_pages,
+            log_pages
+        );
+    }
+    Ok(())
+}
+
+/// Frees unused pages when `DB_INCREMENTAL_VACUUM` is set.
+/// The first time, the database has to be fully vacuumed to allow incremental vacuums.
+fn incremental_vacuum(db: &Connection) -> rusqlite::Result<()> {
+    // 2 is incremental
+    let auto_vacuum: i32 = db.query_row("PRAGMA auto_vacuum", [], |r| r.get(0))?;
+    if auto_vacuum != 2 {
+        tracing::info!("Enabling incremental vacuum, this needs a full vacuum");
+        db.execute_batch("PRAGMA auto_vacuum = INCREMENTAL; VACUUM;")?;
+        return Ok(());
+    }
+
+    let free_pages: i64 = db.query_row("PRAGMA freelist_count", [], |r| r.get(0))?;
+    tracing::info!("Vacuuming {} free pages", free_pages);
+    db.execute_batch("PRAGMA incremental_vacuum")?;
+    Ok(())
+}
+
+fn do_optimise_database() -> rusqlite::Result<()> {
+    let db = open_rusqlite()?;
+
+    timed("WAL checkpoint", || checkpoint(&db))?;
+    timed("ANALYZE", || db.execute_batch("ANALYZE"))?;
+
+    if config().incremental_vacuum {
+        timed("Vacuum", || incremental_vacuum(&db))?;
+        // Vacuuming goes through the WAL too
+        timed("WAL checkpoint", || checkpoint(&db))?;
+    }
+
+    Ok(())
+}
+
+/// Checkpoints the WAL, updates the query planner's statistics and optionally vacuums
+pub async fn optimise_database() -> rusqlite::Result<()> {
+    tokio::task::spawn_blocking(do_optimise_database)
+        .await
+        .unwrap() // spawn result
+}
@@ -0,0 +1,112 @@
+//! Retrying writes which still found the database locked after the busy timeout,
+//! e.g. while a long index transaction commits
+
+use std::{future::Future, time::Duration};
+
+use rusqlite::ErrorCode;
+use sea_orm::{DbErr, RuntimeErr};
+
+use super::config::config;
+
+/// Wait before the first retry, doubling for each one after
+const RETRY_DELAY: Duration = Duration::from_millis(200);
+
+/// Errors which can be because another connection held the lock
+pub trait Busy {
+    fn is_busy(&self) -> bool;
+}
+
+/// SQLITE_BUSY or SQLITE_LOCKED, including their extended codes
+fn is_busy_code(code: i32) -> bool {
+    matches!(code & 0xff, 5 | 6)
+}
+
+impl Busy for rusqlite::Error {
+    fn is_busy(&self) -> bool {
+        matches!(
+            self.sqlite_error_code(),
+            Some(ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked)
+        )
+    }
+}
+
+impl Busy for DbErr {
+    fn is_busy(&self) -> bool {
+        match self {
+            DbErr::Conn(RuntimeErr::SqlxError(e))
+            | DbErr::Exec(RuntimeErr::SqlxError(e))
+            | DbErr::Query(RuntimeErr::SqlxError(e)) => e
+                .as_database_error()
+                .and_then(|e| e.code())
+                .and_then(|code| code.parse().ok())
+                .is_some_and(is_busy_code),
+            _ => false,
+        }
+    }
+}
+
+/// Runs the write, running it again with backoff while it fails as busy,
+/// up to `DB_BUSY_RETRIES` times.
+/// It should be a whole transaction, so that a retry starts from scratch.
+pub async fn retry_busy<T, E, F, Fut>(name: &str, mut write: F) -> Result<T, E>
+where
+    E: Busy + std::fmt::Display,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let retries = config().busy_retries;
+    let mut delay = RETRY_DELAY;
+    let mut attempt = 0;
+    loop {
+        match write().await {
+            Err(e) if e.is_busy() && attempt < retries => {
+                attempt += 1;
+                tracing::warn!(
+                    "{} found the database busy, retry {} of {} in {} ms: {}",
+                    name,
+                    attempt,
+                    retries,
+                    delay.as_millis(),
+                    e
+                );
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_retry_busy() {
+        let busy = || rusqlite::Error::SqliteFailure(rusqlite::ffi::Error::new(5), None);
+
+        let mut attempts = 0;
+        let result = retry_busy("Test", || {
+            attempts += 1;
+            let result = if attempts < 3 {
+                Err(busy())
+            } else {
+                Ok(attempts)
+            };
+            async move { result }
+        })
+        .await;
+        assert_eq!(result.unwrap(), 3);
+
+        // Other errors aren't retried
+        let mut attempts = 0;
+        let result: rusqlite::Result<()> = retry_busy("Test", || {
+            attempts += 1;
+            async { Err(rusqlite::Error::QueryReturnedNoRows) }
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+    }
+}
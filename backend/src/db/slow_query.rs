@@ -0,0 +1,43 @@
+//! Logs sea-orm queries which take longer than `DB_SLOW_QUERY_MS`, to find what holds up the API
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use sea_orm::{metric::Info, DatabaseConnection};
+
+use super::config::config;
+
+static SLOW_QUERIES: AtomicU64 = AtomicU64::new(0);
+
+/// How many slow queries there have been since starting
+pub fn slow_queries() -> u64 {
+    SLOW_QUERIES.load(Ordering::Relaxed)
+}
+
+fn on_query(info: &Info<'_>) {
+    let config = config();
+    if info.elapsed < config.slow_query {
+        return;
+    }
+    SLOW_QUERIES.fetch_add(1, Ordering::Relaxed);
+
+    // Without the parameters, the SQL has placeholders
+    let sql = if config.log_query_params {
+        info.statement.to_string()
+    } else {
+        info.statement.sql.clone()
+    };
+    tracing::warn!(
+        "Slow query took {} ms{}: {}",
+        info.elapsed.as_millis(),
+        if info.failed { " and failed" } else { "" },
+        sql
+    );
+}
+
+/// Starts logging the connection's slow queries, unless `DB_SLOW_QUERY_MS` is 0
+pub fn log_slow_queries(db: &mut DatabaseConnection) {
+    if config().slow_query.is_zero() {
+        return;
+    }
+    db.set_metric_callback(on_query);
+}
@@ -0,0 +1,135 @@
+use std::fs;
+
+use chrono::DateTime;
+use sea_orm::{ConnectionTrait, DatabaseConnection, DbBackend, Statement};
+use serde::Serialize;
+
+use super::{
+    error::DbResult,
+    slow_query::slow_queries,
+    util::{database_path, realtime_database_path},
+};
+
+/// Tables worth keeping an eye on, in the order they're reported
+const TABLES: [&str; 24] = [
+    "import",
+    "gtfs_feed_info",
+    "gtfs_agency",
+    "gtfs_calendar",
+    "gtfs_calendar_dates",
+    "gtfs_routes",
+    "gtfs_trips",
+    "gtfs_shapes",
+    "gtfs_stops",
+    "gtfs_stop_times",
+    "gtfs_frequencies",
+    "gtfs_transfers",
+    "gtfs_levels",
+    "gtfs_pathways",
+    "gtfs_fare_attributes",
+    "gtfs_fare_rules",
+    "gtfs_translations",
+    "service_dates",
+    "stop_index",
+    "trip_run",
+    "stop_time_index",
+    "vehicle",
+    "alert",
+    "alert_translation",
+    "alert_image",
+];
+
+#[derive(Serialize)]
+pub struct TableCount {
+    pub table: &'static str,
+    pub rows: i64,
+}
+
+#[derive(Serialize)]
+pub struct IndexHorizon {
+    pub first_timestamp: Option<i64>,
+    pub last_timestamp: Option<i64>,
+    /// GTFS format dates (YYYYMMDD) of the first and last indexed trip runs
+    pub first_date: Option<String>,
+    pub last_date: Option<String>,
+    pub days: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct DbStats {
+    pub tables: Vec<TableCount>,
+    pub index_horizon: IndexHorizon,
+    pub db_file_bytes: Option<u64>,
+    pub wal_file_bytes: Option<u64>,
+    pub realtime_db_file_bytes: Option<u64>,
+    pub realtime_wal_file_bytes: Option<u64>,
+    /// Queries over `DB_SLOW_QUERY_MS` since starting
+    pub slow_queries: u64,
+}
+
+/// Gets the first column of the first row, None if there are no rows or it's null
+async fn query_value<T>(db: &DatabaseConnection, sql: &str) -> DbResult<Option<T>>
+where
+    T: sea_orm::TryGetable,
+{
+    let row = db
+        .query_one(Statement::from_string(DbBackend::Sqlite, sql))
+        .await?;
+
+    match row {
+        Some(row) => Ok(row.try_get_by_index(0)?),
+        None => Ok(None),
+    }
+}
+
+fn file_size(path: &str) -> Option<u64> {
+    fs::metadata(path).ok().map(|m| m.len())
+}
+
+pub async fn get_db_stats(db: &DatabaseConnection) -> DbResult<DbStats> {
+    let mut tables = vec![];
+    for table in TABLES {
+        let rows = query_value::<i64>(db, &format!(r#"SELECT COUNT(*) FROM "{}""#, table))
+            .await?
+            .unwrap_or(0);
+        tables.push(TableCount { table, rows });
+    }
+
+    // min/max are cheap here as they're indexed
+    let first_timestamp =
+        query_value::<i64>(db, r#"SELECT MIN("arrival_timestamp") FROM "stop_time_index""#)
+            .await?;
+    let last_timestamp =
+        query_value::<i64>(db, r#"SELECT MAX("arrival_timestamp") FROM "stop_time_index""#)
+            .await?;
+    let first_date =
+        query_value::<String>(db, r#"SELECT MIN("start_date") FROM "trip_run""#).await?;
+    let last_date =
+        query_value::<String>(db, r#"SELECT MAX("start_date") FROM "trip_run""#).await?;
+
+    let days = match (first_timestamp, last_timestamp) {
+        (Some(first), Some(last)) => DateTime::from_timestamp_millis(first)
+            .zip(DateTime::from_timestamp_millis(last))
+            .map(|(first, last)| (last - first).num_days() + 1),
+        _ => None,
+    };
+
+    let db_path = database_path();
+    let realtime_db_path = realtime_database_path();
+
+    Ok(DbStats {
+        tables,
+        index_horizon: IndexHorizon {
+            first_timestamp,
+            last_timestamp,
+            first_date,
+            last_date,
+            days,
+        },
+        db_file_bytes: file_size(&db_path),
+        wal_file_bytes: file_size(&format!("{}-wal", db_path)),
+        realtime_db_file_bytes: file_size(&realtime_db_path),
+        realtime_wal_file_bytes: file_size(&format!("{}-wal", realtime_db_path)),
+        slow_queries: slow_queries(),
+    })
+}
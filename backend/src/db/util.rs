@@ -1,42 +1,205 @@
-use std::{env, ops::Deref};
+use std::ops::Deref;
 
-use rusqlite::{params_from_iter, ParamsFromIter};
+use migration::{MigratorTrait, RealtimeMigrator};
+use rusqlite::{params_from_iter, DatabaseName, ParamsFromIter};
 use sea_orm::{
     sea_query::{sea_value_to_json_value, QueryStatementWriter, SqliteQueryBuilder},
-    DatabaseConnection, SqlxSqliteConnector,
+    ConnectionTrait, DatabaseConnection, DbBackend, DbErr, RuntimeErr, SqlxSqliteConnector,
+    Statement, TransactionTrait,
 };
 use sea_orm::{
-    sea_query::{Expr, IntoColumnRef, Nullable, SimpleExpr},
+    sea_query::{Alias, Expr, Func, IntoColumnRef, Nullable, SimpleExpr},
     ActiveValue,
 };
-use sqlx::{
-    sqlite::{SqliteConnectOptions, SqliteJournalMode, SqliteSynchronous},
-    SqlitePool,
-};
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions, SqliteSynchronous};
 
-pub async fn open_seaorm() -> DatabaseConnection {
-    let db_path = env::var("DATABASE_PATH").expect("DATABASE_PATH must be set");
+use super::{config::config, slow_query::log_slow_queries};
 
-    // Create via sqlx so we can customise the options
-    let options = SqliteConnectOptions::new()
-        .filename(db_path.clone())
+/// What the realtime database is attached as, though its tables can be used unqualified
+pub const REALTIME_SCHEMA: &str = "realtime";
+
+pub fn database_path() -> String {
+    config().path.clone()
+}
+
+pub fn realtime_database_path() -> String {
+    config().realtime_path.clone()
+}
+
+fn seaorm_options() -> SqliteConnectOptions {
+    seaorm_options_for(database_path())
+}
+
+fn seaorm_options_for(db_path: String) -> SqliteConnectOptions {
+    let config = config();
+
+    SqliteConnectOptions::new()
+        .filename(db_path)
         .create_if_missing(true)
-        .journal_mode(SqliteJournalMode::Wal)
+        .journal_mode(config.journal_mode())
         .synchronous(SqliteSynchronous::Normal) // with WAL, worst that could happen is a rollback of last tx
-        .pragma("cache_size", "-1000000"); // 1GB memory cache
+        .busy_timeout(config.busy_timeout)
+        .pragma("cache_size", config.cache_size_pragma())
+        .pragma("mmap_size", config.mmap_size.to_string())
+}
+
+/// Attaches the realtime database to each connection of the pool.
+/// Writes to its tables only lock it, not the main database.
+fn attach_realtime(pool: SqlitePoolOptions, writable: bool) -> SqlitePoolOptions {
+    pool.after_connect(move |conn, _meta| {
+        Box::pin(async move {
+            sqlx::query(&format!("ATTACH DATABASE ? AS {}", REALTIME_SCHEMA))
+                .bind(realtime_database_path())
+                .execute(&mut *conn)
+                .await?;
+            // These are per database, and read only connections can't change them
+            if writable {
+                let pragmas = [
+                    ("journal_mode", config().journal_mode.as_str()),
+                    ("synchronous", "NORMAL"),
+                ];
+                for (pragma, value) in pragmas {
+                    sqlx::query(&format!(
+                        "PRAGMA {}.{} = {}",
+                        REALTIME_SCHEMA, pragma, value
+                    ))
+                    .execute(&mut *conn)
+                    .await?;
+                }
+            }
+            Ok(())
+        })
+    })
+}
+
+/// Creates and migrates the realtime database, which has to be done from a connection of its own
+/// as tables are created in the main database of the connection.
+pub async fn migrate_realtime() -> Result<(), DbErr> {
+    let pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect_with(seaorm_options_for(realtime_database_path()))
+        .await
+        .map_err(|e| DbErr::Conn(RuntimeErr::SqlxError(e)))?;
+
+    let db = SqlxSqliteConnector::from_sqlx_sqlite_pool(pool);
+    RealtimeMigrator::up(&db, None).await?;
+    copy_old_realtime_tables(&db).await?;
+    db.close().await
+}
 
-    let pool = SqlitePool::connect_with(options).await.unwrap();
+/// What the main database is attached as while the realtime one is migrated
+const OLD_SCHEMA: &str = "old";
 
-    SqlxSqliteConnector::from_sqlx_sqlite_pool(pool)
+/// The vehicle position history and dead letters were kept in the main database,
+/// until its migration 27 dropped them. They're copied across while they're still there,
+/// as the rest is refilled from the feeds. Copying again keeps what's already been copied.
+async fn copy_old_realtime_tables(db: &DatabaseConnection) -> Result<(), DbErr> {
+    // The pool has one connection, so it stays attached
+    db.execute(Statement::from_sql_and_values(
+        DbBackend::Sqlite,
+        &format!("ATTACH DATABASE ? AS {}", OLD_SCHEMA),
+        [database_path().into()],
+    ))
+    .await?;
+
+    let old_tables = db
+        .query_one(Statement::from_string(
+            DbBackend::Sqlite,
+            format!(
+                "SELECT COUNT(*) AS count FROM {}.sqlite_master WHERE type = 'table'
+                AND name IN ('vehicle_position_history', 'realtime_dead_letter')",
+                OLD_SCHEMA
+            ),
+        ))
+        .await?
+        .map(|row| row.try_get::<i64>("", "count"))
+        .transpose()?
+        .unwrap_or_default();
+    if old_tables == 2 {
+        tracing::info!(
+            "Copying vehicle position history and dead letters to the realtime database"
+        );
+        let tx = db.begin().await?;
+        tx.execute_unprepared(&format!(
+            r#"INSERT OR IGNORE INTO "vehicle_position_history"
+                ("vehicle_id", "timestamp", "latitude", "longitude", "bearing", "speed", "trip_run_id")
+            SELECT "vehicle_id", "timestamp", "latitude", "longitude", "bearing", "speed", "trip_run_id"
+            FROM {}."vehicle_position_history""#,
+            OLD_SCHEMA
+        ))
+        .await?;
+        // There's only the latest for each entity now
+        tx.execute_unprepared(&format!(
+            r#"INSERT OR IGNORE INTO "realtime_dead_letter"
+                ("entity_id", "entity", "error", "differential", "timestamp")
+            SELECT "entity_id", "entity", "error", "differential", "timestamp"
+            FROM {old}."realtime_dead_letter"
+            WHERE "id" IN (SELECT MAX("id") FROM {old}."realtime_dead_letter" GROUP BY "entity_id")"#,
+            old = OLD_SCHEMA
+        ))
+        .await?;
+        tx.commit().await?;
+    }
+
+    db.execute_unprepared(&format!("DETACH DATABASE {}", OLD_SCHEMA))
+        .await?;
+    Ok(())
+}
+
+/// The connection for writes, e.g. from the realtime feeds and syncs.
+/// SQLite only has one writer at a time anyway, so there's only one connection
+/// and writes queue for it rather than failing as busy.
+/// The realtime database is attached, see `attach_realtime`.
+pub async fn open_seaorm() -> DatabaseConnection {
+    // Create via sqlx so we can customise the options
+    let pool = attach_realtime(SqlitePoolOptions::new(), true)
+        .max_connections(1)
+        .connect_with(seaorm_options())
+        .await
+        .unwrap();
+
+    let mut db = SqlxSqliteConnector::from_sqlx_sqlite_pool(pool);
+    log_slow_queries(&mut db);
+    db
+}
+
+/// Connections for the API to read from, which aren't held up by big write transactions with WAL.
+/// They can't write, so must be opened after the database has been migrated.
+pub async fn open_seaorm_read_only() -> DatabaseConnection {
+    let options = seaorm_options()
+        .create_if_missing(false)
+        .pragma("query_only", "ON");
+
+    let pool = attach_realtime(SqlitePoolOptions::new(), false)
+        .max_connections(config().max_connections)
+        .connect_with(options)
+        .await
+        .unwrap();
+
+    let mut db = SqlxSqliteConnector::from_sqlx_sqlite_pool(pool);
+    log_slow_queries(&mut db);
+    db
 }
 
 pub fn open_rusqlite() -> Result<rusqlite::Connection, rusqlite::Error> {
-    let db_path = env::var("DATABASE_PATH").expect("DATABASE_PATH must be set");
+    let db_path = database_path();
+
+    let config = config();
 
     let conn = rusqlite::Connection::open(db_path)?;
-    conn.pragma_update(None, "journal_mode", "WAL")?;
+    conn.pragma_update(None, "journal_mode", &config.journal_mode)?;
     conn.pragma_update(None, "synchronous", "NORMAL")?;
-    conn.pragma_update(None, "cache_size", "-1000000")?;
+    // So maintenance covers it too, e.g. checkpoints and ANALYZE
+    conn.execute(
+        &format!("ATTACH DATABASE ?1 AS {}", REALTIME_SCHEMA),
+        [realtime_database_path()],
+    )?;
+    let realtime = Some(DatabaseName::Attached(REALTIME_SCHEMA));
+    conn.pragma_update(realtime, "journal_mode", &config.journal_mode)?;
+    conn.pragma_update(realtime, "synchronous", "NORMAL")?;
+    conn.pragma_update(None, "cache_size", config.cache_size_pragma())?;
+    conn.pragma_update(None, "mmap_size", config.mmap_size)?;
+    conn.busy_timeout(config.busy_timeout)?;
     // rusqlite is used for bulk imports, disabling FKs is faster for this
     conn.pragma_update(None, "foreign_keys", "OFF")?;
 
@@ -58,15 +221,15 @@ impl<Q: QueryStatementWriter> SeaRusqliteAdapter for Q {
     ) -> Result<SeaRusqlitePrepared<'conn>, rusqlite::Error> {
         let (sql, values) = self.build(SqliteQueryBuilder);
 
-        log::debug!("Prepared SQL: {}", sql);
-        log::debug!("Prepared values: {:?}", values);
+        tracing::debug!("Prepared SQL: {}", sql);
+        tracing::debug!("Prepared values: {:?}", values);
 
         let json_values: Vec<_> = values
             .into_iter()
             .map(|v| sea_value_to_json_value(&v))
             .collect();
         let params = params_from_iter(json_values);
-        log::debug!("Prepared params: {:?}", params);
+        tracing::debug!("Prepared params: {:?}", params);
 
         Ok(SeaRusqlitePrepared {
             statement: db.prepare_cached(&sql)?,
@@ -121,6 +284,17 @@ pub fn null() -> SimpleExpr {
     sea_orm::Value::Int(None).into()
 }
 
+/// NULL when the text is empty or only whitespace, so a fallback can be coalesced
+pub fn blank_to_null<T>(x: T) -> SimpleExpr
+where
+    T: Into<SimpleExpr>,
+{
+    Func::cust(Alias::new("NULLIF"))
+        .arg(Func::cust(Alias::new("TRIM")).arg(x))
+        .arg("")
+        .into()
+}
+
 pub fn pow<T>(x: T, v: usize) -> SimpleExpr
 where
     T: Into<SimpleExpr>,
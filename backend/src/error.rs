@@ -2,10 +2,10 @@ use std::num::ParseIntError;
 
 use actix_web::{HttpResponse, ResponseError};
 use reqwest::StatusCode;
-use serde_json::json;
+use serde::Serialize;
 
-use crate::gtfs;
 use crate::{at::error::AtError, db::error::DbError, gtfs::sync::GtfsSyncError};
+use crate::{db, gtfs, request_id};
 
 #[allow(dead_code)]
 #[derive(thiserror::Error, Debug)]
@@ -28,9 +28,21 @@ pub enum NextAtError {
     #[error("GTFS index error: {0}")]
     GtfsIndex(#[from] gtfs::index::Error),
 
+    #[error("Realtime error: {0}")]
+    Realtime(#[from] gtfs::realtime::Error),
+
     #[error(transparent)]
     Request(#[from] reqwest::Error),
 
+    #[error("Not found: {0}")]
+    NotFound(String),
+
+    #[error("Invalid data: {0}")]
+    InvalidData(String),
+
+    #[error("Backup error: {0}")]
+    Backup(#[from] db::backup::Error),
+
     #[error("Error response: {0} {1}")]
     Response(u16, String),
 }
@@ -41,24 +53,92 @@ impl From<ParseIntError> for NextAtError {
     }
 }
 
-impl ResponseError for NextAtError {
-    fn error_response(&self) -> actix_web::HttpResponse<actix_web::body::BoxBody> {
+/// Body of every error response
+#[derive(Serialize)]
+struct ErrorEnvelope {
+    error: ErrorBody,
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    code: &'static str,
+    message: String,
+    request_id: String,
+}
+
+impl NextAtError {
+    /// Machine readable error code, stable across releases
+    pub fn code(&self) -> &'static str {
         match self {
-            NextAtError::Response(_, message) => {
-                HttpResponse::build(self.status_code()).json(json!({ "error": message }))
-            }
-            other => {
-                log::error!("{}", other);
-                actix_web::HttpResponse::InternalServerError().finish()
+            NextAtError::NotFound(_) => "not_found",
+            NextAtError::InvalidData(_) | NextAtError::DataFormat(_) => "invalid_request",
+            NextAtError::Realtime(gtfs::realtime::Error::NotFound(_)) => "not_found",
+            NextAtError::Realtime(gtfs::realtime::Error::InvalidData(_)) => "invalid_request",
+            NextAtError::At(_) | NextAtError::Request(_) => "upstream_error",
+            NextAtError::Db(_) | NextAtError::Database(_) => "database_error",
+            NextAtError::GtfsSync(_) => "gtfs_sync_error",
+            NextAtError::GtfsIndex(_) => "gtfs_index_error",
+            NextAtError::Realtime(_) => "realtime_error",
+            NextAtError::Backup(_) => "backup_error",
+            NextAtError::Response(status, _) => match *status {
+                400 => "bad_request",
+                401 => "unauthorized",
+                403 => "forbidden",
+                404 => "not_found",
+                409 => "conflict",
+                503 => "unavailable",
+                _ => "error",
+            },
+        }
+    }
+
+    /// Message that is safe to show to clients.
+    /// Internal errors are only described in the logs.
+    fn public_message(&self) -> String {
+        match self.status_code() {
+            s if s.is_server_error() && !matches!(self, NextAtError::Response(..)) => {
+                "Internal server error".to_string()
             }
+            _ => match self {
+                NextAtError::Response(_, message) => message.clone(),
+                other => other.to_string(),
+            },
         }
     }
+}
+
+impl ResponseError for NextAtError {
+    fn error_response(&self) -> actix_web::HttpResponse<actix_web::body::BoxBody> {
+        let request_id = request_id::current().unwrap_or_else(request_id::new_id);
+        let status = self.status_code();
+
+        // The request id is included by the log format
+        if status.is_server_error() {
+            tracing::error!("{}", self);
+        } else {
+            tracing::debug!("{}", self);
+        }
+
+        HttpResponse::build(status).json(ErrorEnvelope {
+            error: ErrorBody {
+                code: self.code(),
+                message: self.public_message(),
+                request_id,
+            },
+        })
+    }
 
     fn status_code(&self) -> reqwest::StatusCode {
         match self {
             NextAtError::Response(status, _) => {
                 StatusCode::from_u16(*status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
             }
+            NextAtError::NotFound(_)
+            | NextAtError::Realtime(gtfs::realtime::Error::NotFound(_)) => StatusCode::NOT_FOUND,
+            NextAtError::InvalidData(_)
+            | NextAtError::DataFormat(_)
+            | NextAtError::Realtime(gtfs::realtime::Error::InvalidData(_)) => StatusCode::BAD_REQUEST,
+            NextAtError::At(_) | NextAtError::Request(_) => StatusCode::BAD_GATEWAY,
             _ => reqwest::StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
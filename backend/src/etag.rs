@@ -0,0 +1,181 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    future::{ready, Ready},
+    hash::{Hash, Hasher},
+};
+
+use actix_web::{
+    body::{BoxBody, MessageBody},
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    http::{
+        header::{self, HeaderValue},
+        Method,
+    },
+    web, Error, HttpResponse,
+};
+use chrono::Utc;
+use futures_util::future::LocalBoxFuture;
+
+use crate::{api::unversioned_pattern, ContextData};
+
+/// Middleware that adds ETags to cacheable GET endpoints
+/// and answers a matching `If-None-Match` with a 304, without calling the handler.
+///
+/// The ETag is derived from the data versions rather than the response body
+/// so that polling clients don't cost a database query.
+pub struct ETag;
+
+impl<S, B> Transform<S, ServiceRequest> for ETag
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = Error;
+    type Transform = ETagMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(ETagMiddleware { service }))
+    }
+}
+
+pub struct ETagMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for ETagMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let etag = cache_key(&req);
+
+        if let Some(etag) = &etag {
+            let if_none_match = req
+                .headers()
+                .get(header::IF_NONE_MATCH)
+                .and_then(|v| v.to_str().ok());
+
+            if if_none_match.is_some_and(|v| etag_matches(v, etag)) {
+                let response = HttpResponse::NotModified()
+                    .insert_header((header::ETAG, etag.clone()))
+                    .finish();
+                return Box::pin(async move { Ok(req.into_response(response)) });
+            }
+        }
+
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            let mut res = fut.await?.map_into_boxed_body();
+
+            if let Some(etag) = etag {
+                if res.status().is_success() {
+                    if let Ok(value) = HeaderValue::from_str(&etag) {
+                        res.headers_mut().insert(header::ETAG, value);
+                    }
+                    // Names are translated into the client's language, and some routes
+                    // have other formats
+                    res.headers_mut().insert(
+                        header::VARY,
+                        HeaderValue::from_static("Accept-Language, Accept"),
+                    );
+                }
+            }
+
+            Ok(res)
+        })
+    }
+}
+
+/// The data version(s) a route's response depends on, or None if it shouldn't be cached
+fn cache_key(req: &ServiceRequest) -> Option<String> {
+    if req.method() != Method::GET {
+        return None;
+    }
+
+    let ctx = req.app_data::<web::Data<ContextData>>()?;
+    let versions = &ctx.versions;
+    let pattern = req.match_pattern()?;
+
+    let mut hasher = DefaultHasher::new();
+    pattern.hash(&mut hasher);
+    req.uri().to_string().hash(&mut hasher);
+    // The same URL can be translated, or in another format
+    for name in [header::ACCEPT_LANGUAGE, header::ACCEPT] {
+        req.headers()
+            .get(name)
+            .map(|v| v.as_bytes())
+            .hash(&mut hasher);
+    }
+
+    match unversioned_pattern(&pattern) {
+        // Only change when static data is synced
+        "/stops"
+        | "/stops/{stop_id}"
+        | "/stops/{stop_id}/routes"
+        | "/stops/{station_id}/pathways"
+        | "/routes/{route_id}/fares"
+        | "/routes/{route_id}/stops"
+        | "/fares" => {
+            versions.static_version().hash(&mut hasher);
+        }
+        "/stops/{stop_id}/arrivals"
+        | "/stops/{stop_id}/departures"
+        | "/stops/{stop_id}/next"
+        | "/stops/{stop_id}/arrivals.ics"
+        | "/siri/stop-monitoring.json"
+        | "/routes" => {
+            versions.static_version().hash(&mut hasher);
+            versions.realtime_version().hash(&mut hasher);
+            // arrivals and alerts drop off as time passes, even without a realtime update
+            (Utc::now().timestamp() / 60).hash(&mut hasher);
+        }
+        // Only changes as the feed is processed
+        "/vehicles/{vehicle_id}/trajectory" | "/routes/{route_id}/speeds" | "/gtfs-rt/feed.pb" => {
+            versions.realtime_version().hash(&mut hasher);
+        }
+        _ => return None,
+    }
+
+    Some(format!("W/\"{:016x}\"", hasher.finish()))
+}
+
+/// Whether an `If-None-Match` header value matches the etag.
+/// Uses weak comparison, as is required for `If-None-Match`.
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    let strip_weak = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    let etag = strip_weak(etag);
+
+    if_none_match
+        .split(',')
+        .any(|tag| tag.trim() == "*" || strip_weak(tag) == etag)
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn test_etag_matches() {
+        let etag = r#"W/"0123456789abcdef""#;
+
+        assert!(etag_matches(etag, etag));
+        assert!(etag_matches(r#""0123456789abcdef""#, etag));
+        assert!(etag_matches(r#""other", W/"0123456789abcdef""#, etag));
+        assert!(etag_matches("*", etag));
+        assert!(!etag_matches(r#"W/"fedcba9876543210""#, etag));
+    }
+}
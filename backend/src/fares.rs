@@ -0,0 +1,112 @@
+use itertools::Itertools;
+use sea_orm::{ColumnTrait, Condition, EntityTrait, QueryFilter};
+use serde::Serialize;
+
+use crate::{
+    entity::{gtfs_fare_attributes, gtfs_fare_rules, gtfs_routes},
+    error::{NextAtError, NextAtResult},
+    ContextData,
+};
+
+/// A fare as returned in the API, with the rule it applies by
+#[derive(Debug, Serialize, Clone)]
+pub struct Fare {
+    pub fare_id: String,
+    /// As written in the feed, in `currency`
+    pub price: String,
+    pub currency: String,
+    /// 0 is paid on board, 1 before boarding
+    pub payment_method: i32,
+    /// How many transfers are allowed, unlimited if null
+    pub transfers: Option<i32>,
+    /// Seconds before a transfer expires
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub transfer_duration: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub route_id: Option<String>,
+    /// Zone the trip starts in
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub origin_id: Option<String>,
+    /// Zone the trip ends in
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub destination_id: Option<String>,
+    /// Zone the trip passes through
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub contains_id: Option<String>,
+}
+
+impl Fare {
+    fn new(rule: gtfs_fare_rules::Model, attributes: gtfs_fare_attributes::Model) -> Self {
+        Self {
+            fare_id: attributes.fare_id,
+            price: attributes.price,
+            currency: attributes.currency_type,
+            payment_method: attributes.payment_method,
+            transfers: attributes.transfers,
+            transfer_duration: attributes.transfer_duration,
+            route_id: rule.route_id,
+            origin_id: rule.origin_id,
+            destination_id: rule.destination_id,
+            contains_id: rule.contains_id,
+        }
+    }
+
+    fn price_value(&self) -> f64 {
+        self.price.parse().unwrap_or(f64::MAX)
+    }
+}
+
+/// A rule matches when the column is either the value or not set
+fn matches_or_any(column: gtfs_fare_rules::Column, value: &str) -> Condition {
+    Condition::any().add(column.eq(value)).add(column.is_null())
+}
+
+/// Fares of the rules matching the condition, cheapest first
+async fn find_fares(ctx: &ContextData, condition: Condition) -> NextAtResult<Vec<Fare>> {
+    let fares = gtfs_fare_rules::Entity::find()
+        .filter(condition)
+        .find_also_related(gtfs_fare_attributes::Entity)
+        .all(&ctx.read_db)
+        .await?
+        .into_iter()
+        // rules for fares that aren't in fare_attributes.txt can't be priced
+        .filter_map(|(rule, attributes)| Some(Fare::new(rule, attributes?)))
+        .sorted_by(|a, b| a.price_value().total_cmp(&b.price_value()))
+        .unique_by(|f| f.fare_id.clone())
+        .collect();
+
+    Ok(fares)
+}
+
+/// Fares that can be paid to ride the route
+pub async fn get_route_fares(ctx: &ContextData, route_id: &str) -> NextAtResult<Vec<Fare>> {
+    use gtfs_fare_rules as fr;
+    use gtfs_routes as r;
+
+    r::Entity::find()
+        .filter(r::Column::RouteId.eq(route_id))
+        .one(&ctx.read_db)
+        .await?
+        .ok_or_else(|| NextAtError::NotFound(format!("Route not found: {}", route_id)))?;
+
+    find_fares(ctx, matches_or_any(fr::Column::RouteId, route_id)).await
+}
+
+/// Fares for travelling from one fare zone to another, optionally only on one route
+pub async fn get_zone_fares(
+    ctx: &ContextData,
+    origin_id: &str,
+    destination_id: &str,
+    route_id: Option<&str>,
+) -> NextAtResult<Vec<Fare>> {
+    use gtfs_fare_rules as fr;
+
+    let mut condition = Condition::all()
+        .add(matches_or_any(fr::Column::OriginId, origin_id))
+        .add(matches_or_any(fr::Column::DestinationId, destination_id));
+    if let Some(route_id) = route_id {
+        condition = condition.add(matches_or_any(fr::Column::RouteId, route_id));
+    }
+
+    find_fares(ctx, condition).await
+}
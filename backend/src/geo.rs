@@ -1,4 +1,10 @@
-use geo::{HaversineDestination, Point, Rect};
+use geo::{
+    Closest, ClosestPoint, Contains, HaversineDestination, HaversineDistance, LineString, Point,
+    Polygon, Rect, Simplify,
+};
+
+/// Metres in a degree of latitude, near enough
+const METRES_PER_DEGREE: f64 = 111_320.0;
 
 pub fn get_bounding_box(center: Point, min_radius_metres: f64) -> Rect {
     // pythagoras
@@ -13,6 +19,130 @@ pub fn get_bounding_box(center: Point, min_radius_metres: f64) -> Rect {
     )
 }
 
+/// In metres
+pub fn line_length(line: &[Point]) -> f64 {
+    line.windows(2)
+        .map(|segment| segment[0].haversine_distance(&segment[1]))
+        .sum()
+}
+
+/// How far along the line (in metres) is the closest point on it to `point`.
+/// None if there isn't a line.
+pub fn distance_along(line: &[Point], point: Point) -> Option<f64> {
+    let mut travelled = 0.0;
+    let mut closest: Option<(f64, f64)> = None; // (distance from point, distance along)
+
+    for segment in line.windows(2) {
+        let (a, b) = (segment[0], segment[1]);
+
+        // Near enough to flat over a single segment, once longitude is scaled
+        let scale = a.y().to_radians().cos();
+        let (dx, dy) = ((b.x() - a.x()) * scale, b.y() - a.y());
+        let (px, py) = ((point.x() - a.x()) * scale, point.y() - a.y());
+        let length_2 = dx * dx + dy * dy;
+        let t = if length_2 > 0.0 {
+            ((px * dx + py * dy) / length_2).clamp(0.0, 1.0)
+        } else {
+            0.0
+        };
+        let on_segment = Point::new(a.x() + (b.x() - a.x()) * t, a.y() + (b.y() - a.y()) * t);
+
+        let distance = point.haversine_distance(&on_segment);
+        let is_closer = match closest {
+            Some((closest_distance, _)) => distance < closest_distance,
+            None => true,
+        };
+        if is_closer {
+            closest = Some((distance, travelled + a.haversine_distance(&on_segment)));
+        }
+
+        travelled += a.haversine_distance(&b);
+    }
+
+    closest.map(|(_, along)| along)
+}
+
+/// Adds a value to a Google encoded polyline
+fn encode_value(encoded: &mut String, value: i64) {
+    // Zigzag, so small negative values are short too
+    let mut value = (if value < 0 { !(value << 1) } else { value << 1 }) as u64;
+    while value >= 0x20 {
+        encoded.push(char::from((0x20 | (value & 0x1f)) as u8 + 63));
+        value >>= 5;
+    }
+    encoded.push(char::from(value as u8 + 63));
+}
+
+/// The next value of a Google encoded polyline, None if it's cut short or isn't valid
+fn decode_value(bytes: &mut impl Iterator<Item = u8>) -> Option<i64> {
+    let mut result = 0u64;
+    let mut shift = 0;
+    loop {
+        let chunk = bytes.next()?.checked_sub(63).filter(|c| *c < 0x40)?;
+        if shift >= 64 {
+            return None;
+        }
+        result |= u64::from(chunk & 0x1f) << shift;
+        shift += 5;
+        if chunk < 0x20 {
+            break;
+        }
+    }
+    let value = (result >> 1) as i64;
+    Some(if result & 1 == 1 { !value } else { value })
+}
+
+/// The line as a Google encoded polyline, to `precision` decimal places (usually 5),
+/// see <https://developers.google.com/maps/documentation/utilities/polylinealgorithm>
+pub fn encode_polyline(line: &[Point], precision: u32) -> String {
+    let factor = 10f64.powi(precision as i32);
+    let mut encoded = String::new();
+    let (mut previous_lat, mut previous_lon) = (0, 0);
+    for point in line {
+        let lat = (point.y() * factor).round() as i64;
+        let lon = (point.x() * factor).round() as i64;
+        encode_value(&mut encoded, lat - previous_lat);
+        encode_value(&mut encoded, lon - previous_lon);
+        (previous_lat, previous_lon) = (lat, lon);
+    }
+    encoded
+}
+
+/// The line of a Google encoded polyline, None if it isn't valid
+pub fn decode_polyline(encoded: &str, precision: u32) -> Option<Vec<Point>> {
+    let factor = 10f64.powi(precision as i32);
+    let mut bytes = encoded.bytes().peekable();
+    let mut line = vec![];
+    let (mut lat, mut lon) = (0i64, 0i64);
+    while bytes.peek().is_some() {
+        lat = lat.checked_add(decode_value(&mut bytes)?)?;
+        lon = lon.checked_add(decode_value(&mut bytes)?)?;
+        line.push(Point::new(lon as f64 / factor, lat as f64 / factor));
+    }
+    Some(line)
+}
+
+/// The line with fewer points, none of those left out more than about `tolerance_metres` from it
+/// (Ramer–Douglas–Peucker)
+pub fn simplify(line: &[Point], tolerance_metres: f64) -> Vec<Point> {
+    LineString::from(line.to_vec())
+        .simplify(&(tolerance_metres / METRES_PER_DEGREE))
+        .into_points()
+}
+
+/// How far (in metres) the point is outside of the area, 0 if it's inside.
+/// None if the area is empty.
+pub fn distance_outside(area: &Polygon, point: Point) -> Option<f64> {
+    if area.contains(&point) {
+        return Some(0.0);
+    }
+    match area.exterior().closest_point(&point) {
+        Closest::Intersection(_) => Some(0.0),
+        Closest::SinglePoint(closest) => Some(point.haversine_distance(&closest)),
+        Closest::Indeterminate => None,
+    }
+}
+
 #[cfg(test)]
 mod test {
 
@@ -27,4 +157,80 @@ mod test {
 
         println!("{:?}", bounding_box);
     }
+
+    #[test]
+    fn test_distance_along() {
+        let line = [
+            Point::new(174.0, -36.0),
+            Point::new(174.01, -36.0),
+            Point::new(174.01, -36.01),
+        ];
+        let first_leg = line[0].haversine_distance(&line[1]);
+
+        // halfway along the first segment, a little off the line
+        let along = distance_along(&line, Point::new(174.005, -35.9999)).unwrap();
+        assert!((along - first_leg / 2.0).abs() < 5.0);
+
+        // past the end
+        let along = distance_along(&line, Point::new(174.01, -36.02)).unwrap();
+        let total = first_leg + line[1].haversine_distance(&line[2]);
+        assert!((along - total).abs() < 1.0);
+
+        assert!(distance_along(&line[..1], line[0]).is_none());
+    }
+
+    #[test]
+    fn test_polyline() {
+        // Google's example
+        let line = [
+            Point::new(-120.2, 38.5),
+            Point::new(-120.95, 40.7),
+            Point::new(-126.453, 43.252),
+        ];
+        let encoded = encode_polyline(&line, 5);
+        assert_eq!(encoded, "_p~iF~ps|U_ulLnnqC_mqNvxq`@");
+
+        let decoded = decode_polyline(&encoded, 5).unwrap();
+        assert_eq!(decoded.len(), 3);
+        for (a, b) in decoded.iter().zip(line) {
+            assert!((a.x() - b.x()).abs() < 1e-9 && (a.y() - b.y()).abs() < 1e-9);
+        }
+
+        assert!(decode_polyline("_p~iF~ps|U_", 5).is_none());
+        assert_eq!(decode_polyline("", 5), Some(vec![]));
+    }
+
+    #[test]
+    fn test_simplify() {
+        // Only a metre off a straight line in the middle
+        let line = [
+            Point::new(174.0, -36.0),
+            Point::new(174.005, -36.00001),
+            Point::new(174.01, -36.0),
+            Point::new(174.01, -36.01),
+        ];
+        assert_eq!(simplify(&line, 10.0), [line[0], line[2], line[3]]);
+        assert_eq!(simplify(&line, 0.1), line);
+    }
+
+    #[test]
+    fn test_distance_outside() {
+        let area = Polygon::new(
+            LineString::from(vec![
+                (174.0, -37.0),
+                (175.0, -37.0),
+                (175.0, -36.0),
+                (174.0, -36.0),
+            ]),
+            vec![],
+        );
+        assert_eq!(distance_outside(&area, Point::new(174.5, -36.5)), Some(0.0));
+
+        // A tenth of a degree south of the bottom edge
+        let distance = distance_outside(&area, Point::new(174.5, -37.1)).unwrap();
+        assert!((distance - 11_120.0).abs() < 10.0);
+
+        let empty = Polygon::new(LineString::new(vec![]), vec![]);
+        assert!(distance_outside(&empty, Point::new(174.5, -36.5)).is_none());
+    }
 }
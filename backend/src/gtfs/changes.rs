@@ -0,0 +1,128 @@
+//! Which trips an import changes, so the stop time index only has to rebuild their runs
+
+use rusqlite::Connection;
+
+use super::shadow::shadow_table;
+
+/// Keys of the feed's records which differ between the table and its shadow table,
+/// comparing only the columns which matter to the index
+fn changed_keys(table: &str, key: &str, columns: &[&str]) -> String {
+    let shadow = shadow_table(table);
+    let columns = columns.join(", ");
+    format!(
+        r#"
+        SELECT {key} FROM (
+            SELECT {key}, {columns} FROM "{shadow}" WHERE feed_id = ?1
+            EXCEPT SELECT {key}, {columns} FROM "{table}" WHERE feed_id = ?1
+        )
+        UNION
+        SELECT {key} FROM (
+            SELECT {key}, {columns} FROM "{table}" WHERE feed_id = ?1
+            EXCEPT SELECT {key}, {columns} FROM "{shadow}" WHERE feed_id = ?1
+        )"#
+    )
+}
+
+/// Trips of the feed, before or after the import, with the column in the keys
+fn trips_where_in(column: &str, keys: &str) -> String {
+    let shadow = shadow_table("gtfs_trips");
+    format!(
+        r#"
+        SELECT trip_id FROM "gtfs_trips" WHERE feed_id = ?1 AND {column} IN ({keys})
+        UNION
+        SELECT trip_id FROM "{shadow}" WHERE feed_id = ?1 AND {column} IN ({keys})"#
+    )
+}
+
+/// Records the trips that the import in the shadow tables changes, before they're swapped in.
+/// Returns the number of trips.
+pub fn record_changed_trips(db: &Connection, feed_id: &str) -> rusqlite::Result<usize> {
+    // Times are relative to the agency's timezone
+    let agencies_changed: bool = db.query_row(
+        &format!(
+            "SELECT EXISTS ({})",
+            changed_keys("gtfs_agency", "agency_id", &["agency_timezone"])
+        ),
+        [feed_id],
+        |row| row.get(0),
+    )?;
+    if agencies_changed {
+        tracing::info!("{} feed's agencies changed", feed_id);
+        record_full_rebuild(db)?;
+        return Ok(0);
+    }
+
+    let changed_services = format!(
+        "{} UNION {}",
+        changed_keys(
+            "gtfs_calendar",
+            "service_id",
+            &[
+                "monday",
+                "tuesday",
+                "wednesday",
+                "thursday",
+                "friday",
+                "saturday",
+                "sunday",
+                "start_date",
+                "end_date",
+            ],
+        ),
+        changed_keys(
+            "gtfs_calendar_dates",
+            "service_id",
+            &["date", "exception_type"]
+        ),
+    );
+    let changed_routes = changed_keys("gtfs_routes", "route_id", &["agency_id"]);
+
+    let changed_trips = [
+        changed_keys(
+            "gtfs_trips",
+            "trip_id",
+            &["route_id", "service_id", "direction_id"],
+        ),
+        changed_keys(
+            "gtfs_stop_times",
+            "trip_id",
+            &["stop_id", "stop_sequence", "arrival_time", "departure_time"],
+        ),
+        changed_keys(
+            "gtfs_frequencies",
+            "trip_id",
+            &["start_time", "end_time", "headway_secs", "exact_times"],
+        ),
+        trips_where_in("service_id", &changed_services),
+        trips_where_in("route_id", &changed_routes),
+    ]
+    .join(" UNION ");
+
+    let count = db.execute(
+        &format!("INSERT OR IGNORE INTO index_change (trip_id) {changed_trips}"),
+        [feed_id],
+    )?;
+
+    Ok(count)
+}
+
+/// Records that the whole index needs rebuilding, e.g. when a feed is removed
+pub fn record_full_rebuild(db: &Connection) -> rusqlite::Result<()> {
+    db.execute("INSERT INTO index_change (trip_id) VALUES (NULL)", [])?;
+    Ok(())
+}
+
+/// The trips changed since the index was built, or None if the whole index needs rebuilding
+pub fn changed_trips(db: &Connection) -> rusqlite::Result<Option<Vec<String>>> {
+    let trip_ids = db
+        .prepare("SELECT trip_id FROM index_change")?
+        .query_map([], |row| row.get::<_, Option<String>>(0))?
+        .collect::<rusqlite::Result<Option<Vec<_>>>>()?;
+    Ok(trip_ids)
+}
+
+/// Once the index has been rebuilt for them
+pub fn clear_changes(db: &Connection) -> rusqlite::Result<()> {
+    db.execute("DELETE FROM index_change", [])?;
+    Ok(())
+}
@@ -0,0 +1,155 @@
+use std::path::Path;
+
+use itertools::Itertools;
+use rusqlite::{vtab::csvtab, Connection};
+use sea_orm::{ColumnTrait, EntityName, Iden, Iterable};
+use serde::Serialize;
+use tokio::task;
+
+use crate::{
+    db::util::open_rusqlite,
+    entity::{gtfs_routes, gtfs_stops, gtfs_trips},
+    gtfs::{
+        download::download_gtfs_zip,
+        feed::Feed,
+        progress::SyncProgress,
+        sync::{get_gtfs_files_from_zip, GtfsSyncResult, SyncConfig},
+        validate::{validate_gtfs_files, Problem, Severities},
+    },
+};
+
+/// Records of one kind that an import would change
+#[derive(Debug, Serialize, Default)]
+pub struct Changes {
+    pub added: u64,
+    pub changed: u64,
+    pub removed: u64,
+}
+
+/// What importing the feed as it is now would do
+#[derive(Debug, Serialize)]
+pub struct FeedDiff {
+    pub feed_id: String,
+    /// The import would be rejected if any of these are errors
+    pub problems: Vec<Problem>,
+    pub routes: Changes,
+    pub stops: Changes,
+    pub trips: Changes,
+}
+
+/// Compares a file against what's currently imported from the feed, by its id column.
+/// Only the columns in the file's header are compared.
+fn diff_table<C: ColumnTrait>(
+    db: &Connection,
+    dir: &Path,
+    feed_id: &str,
+    key: C,
+) -> rusqlite::Result<Changes> {
+    let table = C::EntityName::default().table_name().to_string();
+    let filename = format!("{}.txt", table.trim_start_matches("gtfs_"));
+    let path = dir.join(filename).to_str().unwrap().to_string(); // only if somehow invalid utf-8
+    let csv_table = format!("{}_new", table);
+
+    // Only created in the temp schema, so nothing is written to the database
+    db.execute_batch(&format!(
+        "CREATE VIRTUAL TABLE temp.{csv_table} USING csv(filename='{path}', header=yes);"
+    ))?;
+
+    let header = db
+        .prepare(&format!(
+            "SELECT name FROM pragma_table_info('{csv_table}', 'temp')"
+        ))?
+        .query_map([], |row| row.get::<_, String>(0))?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let key = key.to_string();
+    let current = format!("(SELECT * FROM main.{table} WHERE feed_id = ?1)");
+
+    // Values from the csv are all text. Compared with the imported column, they're converted
+    // to its type the same way they are on import, so e.g. -36.80 is the same as -36.8.
+    // Empty and null are both a missing value.
+    let differs = C::iter()
+        .map(|c| c.to_string())
+        .filter(|c| !["id", "import_id", "feed_id"].contains(&c.as_str()))
+        .filter(|c| header.contains(c))
+        .map(|c| {
+            format!("NOT (n.{c} = o.{c} OR (IFNULL(n.{c}, '') = '' AND IFNULL(o.{c}, '') = ''))")
+        })
+        .join(" OR ");
+
+    let count = |sql: String| db.query_row(&sql, [feed_id], |row| row.get::<_, u64>(0));
+
+    let changes = Changes {
+        added: count(format!(
+            "SELECT COUNT(*) FROM temp.{csv_table} WHERE {key} NOT IN (SELECT {key} FROM {current})"
+        ))?,
+        changed: count(format!(
+            "SELECT COUNT(*) FROM temp.{csv_table} n JOIN {current} o ON o.{key} = n.{key} WHERE {differs}"
+        ))?,
+        removed: count(format!(
+            "SELECT COUNT(*) FROM {current} WHERE {key} NOT IN (SELECT {key} FROM temp.{csv_table})"
+        ))?,
+    };
+
+    db.execute_batch(&format!("DROP TABLE temp.{csv_table};"))?;
+
+    Ok(changes)
+}
+
+fn diff_files(feed_id: String, dir: &Path, severities: &Severities) -> GtfsSyncResult<FeedDiff> {
+    let problems = validate_gtfs_files(dir, severities)?;
+
+    // Nothing to compare against
+    if problems.iter().any(|p| p.check == "missing_files") {
+        return Ok(FeedDiff {
+            feed_id,
+            problems,
+            routes: Changes::default(),
+            stops: Changes::default(),
+            trips: Changes::default(),
+        });
+    }
+
+    let db = open_rusqlite()?;
+    csvtab::load_module(&db)?;
+
+    Ok(FeedDiff {
+        routes: diff_table(&db, dir, &feed_id, gtfs_routes::Column::RouteId)?,
+        stops: diff_table(&db, dir, &feed_id, gtfs_stops::Column::StopId)?,
+        trips: diff_table(&db, dir, &feed_id, gtfs_trips::Column::TripId)?,
+        feed_id,
+        problems,
+    })
+}
+
+/// Downloads each feed and reports how it differs from the current import, without importing it
+pub async fn diff_feeds(config: &SyncConfig, feeds: &[Feed]) -> GtfsSyncResult<Vec<FeedDiff>> {
+    let mut diffs = vec![];
+
+    for feed in feeds {
+        // Downloaded separately so a sync's partial download and progress aren't disturbed
+        let Some(downloaded) = download_gtfs_zip(
+            config,
+            &format!("{}-dry-run", feed.id),
+            &feed.gtfs_url,
+            None,
+            &SyncProgress::default(),
+        )
+        .await?
+        else {
+            continue;
+        };
+        let tmp_dir = get_gtfs_files_from_zip(&downloaded.path).await;
+        downloaded.remove().await;
+        let tmp_dir = tmp_dir?;
+
+        let feed_id = feed.id.clone();
+        let severities = config.severities.clone();
+        let diff = task::spawn_blocking(move || diff_files(feed_id, tmp_dir.path(), &severities))
+            .await
+            .unwrap()?; // unwrap spawn error
+        diffs.push(diff);
+    }
+
+    Ok(diffs)
+}
@@ -0,0 +1,346 @@
+use std::{
+    future::Future,
+    io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use reqwest::{
+    header::{HeaderName, CONTENT_RANGE, ETAG, IF_RANGE, LAST_MODIFIED, RANGE},
+    StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::{AsyncReadExt, AsyncWriteExt},
+    time::{sleep, timeout},
+};
+use url::Url;
+
+use crate::db::util::database_path;
+
+use super::{
+    progress::SyncProgress,
+    sync::{GtfsSyncResult, SyncConfig},
+};
+
+/// Wait between resuming attempts
+const RETRY_DELAY: Duration = Duration::from_secs(10);
+
+/// Connecting to the server is given up on after this long
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The response, and each part of its body, must arrive within this.
+/// A stalled download is then resumed rather than holding up the sync.
+const READ_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The partial download state is saved each time this much more has been written
+const SAVE_EVERY_BYTES: u64 = 8 * 1024 * 1024;
+
+/// Progress of a partly downloaded file, saved alongside it
+/// so the download can carry on after a failure or restart
+#[derive(Debug, Serialize, Deserialize)]
+struct PartialState {
+    url: String,
+    /// Strong ETag (or Last-Modified) of the response, so the rest is only sent
+    /// if the file hasn't changed. Downloads without one aren't resumed.
+    validator: Option<String>,
+    last_modified: Option<String>,
+    /// Bytes known to be written, anything past this is discarded
+    length: u64,
+    /// SHA-256 of the first `length` bytes, to check the partial file is intact
+    sha256: String,
+}
+
+/// A complete download
+pub struct Downloaded {
+    pub path: PathBuf,
+    pub last_modified: Option<String>,
+    /// SHA-256 of the whole file
+    pub sha256: String,
+}
+
+impl Downloaded {
+    /// Deletes the file once it's no longer needed
+    pub async fn remove(self) {
+        if let Err(e) = fs::remove_file(&self.path).await {
+            tracing::warn!("Error removing {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Files are downloaded to `GTFS_DOWNLOAD_DIR`, or next to the database
+fn download_dir(config: &SyncConfig) -> PathBuf {
+    config.download_dir.clone().unwrap_or_else(|| {
+        Path::new(&database_path())
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_default()
+    })
+}
+
+fn state_path(path: &Path) -> PathBuf {
+    path.with_extension("part.json")
+}
+
+fn hex(hasher: &Sha256) -> String {
+    format!("{:x}", hasher.clone().finalize())
+}
+
+/// Reads the state of a previous attempt, if it's for the same url and the file is intact.
+/// The file is truncated to what's known to be written, and its hash so far is returned.
+async fn resume_state(path: &Path, url: &str) -> Option<(PartialState, Sha256)> {
+    let state: PartialState =
+        serde_json::from_slice(&fs::read(state_path(path)).await.ok()?).ok()?;
+    if state.url != url || state.validator.is_none() {
+        return None;
+    }
+
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .await
+        .ok()?;
+    if file.metadata().await.ok()?.len() < state.length {
+        return None;
+    }
+    file.set_len(state.length).await.ok()?;
+
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let read = file.read(&mut buf).await.ok()?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+    }
+
+    if hex(&hasher) != state.sha256 {
+        tracing::warn!("Partial download of {} is corrupt, starting again", url);
+        return None;
+    }
+
+    Some((state, hasher))
+}
+
+async fn save_state(path: &Path, state: &PartialState) -> GtfsSyncResult<()> {
+    fs::write(state_path(path), serde_json::to_vec(state).unwrap()).await?;
+    Ok(())
+}
+
+/// Where a `bytes <start>-<end>/<total>` Content-Range starts
+fn content_range_start(range: &str) -> Option<u64> {
+    range
+        .strip_prefix("bytes ")?
+        .split('-')
+        .next()?
+        .parse()
+        .ok()
+}
+
+/// Fails with a timeout if nothing is received within `READ_TIMEOUT`
+async fn read_within_timeout<T>(
+    url: &str,
+    read: impl Future<Output = reqwest::Result<T>>,
+) -> GtfsSyncResult<T> {
+    match timeout(READ_TIMEOUT, read).await {
+        Ok(result) => Ok(result?),
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("Nothing received from {} for {:?}", url, READ_TIMEOUT),
+        )
+        .into()),
+    }
+}
+
+async fn try_download(
+    url: &str,
+    path: &Path,
+    if_modified_since: Option<&str>,
+    progress: &SyncProgress,
+) -> GtfsSyncResult<Option<Downloaded>> {
+    let resume = resume_state(path, url).await;
+
+    let client = reqwest::Client::builder()
+        .connect_timeout(CONNECT_TIMEOUT)
+        .build()?;
+    let mut request = client.get(url);
+    if let Some((state, _)) = &resume {
+        tracing::info!("Resuming download of {} from {} bytes", url, state.length);
+        request = request.header(RANGE, format!("bytes={}-", state.length));
+        if let Some(validator) = &state.validator {
+            request = request.header(IF_RANGE, validator);
+        }
+    }
+    let mut resp = read_within_timeout(url, request.send())
+        .await?
+        .error_for_status()?;
+
+    let header = |name: HeaderName| {
+        resp.headers()
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string())
+    };
+    let last_modified = header(LAST_MODIFIED);
+    // Weak ETags can't be used with If-Range
+    let validator = header(ETAG)
+        .filter(|etag| !etag.starts_with("W/"))
+        .or_else(|| last_modified.clone());
+    let range_start = header(CONTENT_RANGE).and_then(|range| content_range_start(&range));
+
+    // Check the last modified header here - Azure block storage seems to ignore If-Modified-Since/If-None-Match!
+    if if_modified_since.is_some() && if_modified_since == last_modified.as_deref() {
+        fs::remove_file(state_path(path)).await.ok();
+        fs::remove_file(path).await.ok();
+        return Ok(None);
+    }
+
+    // The server sends everything again if the file has changed (or it can't do ranges)
+    let (mut file, mut state, mut hasher) = match resume {
+        Some((state, hasher)) if resp.status() == StatusCode::PARTIAL_CONTENT => {
+            // Anywhere else would leave a gap in the file, or repeat part of it
+            if range_start != Some(state.length) {
+                fs::remove_file(state_path(path)).await.ok();
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "Resuming {} from {} bytes, the server sent a range from {:?}",
+                        url, state.length, range_start
+                    ),
+                )
+                .into());
+            }
+            let file = OpenOptions::new().append(true).open(path).await?;
+            (file, state, hasher)
+        }
+        _ => {
+            let state = PartialState {
+                url: url.to_string(),
+                validator,
+                last_modified,
+                length: 0,
+                sha256: hex(&Sha256::new()),
+            };
+            (File::create(path).await?, state, Sha256::new())
+        }
+    };
+
+    // Only the rest of the file is sent when resuming
+    let total = resp.content_length().map(|l| l + state.length);
+    progress.downloaded(state.length, total);
+
+    let mut saved_length = state.length;
+    let result = async {
+        while let Some(chunk) = read_within_timeout(url, resp.chunk()).await? {
+            file.write_all(&chunk).await?;
+            hasher.update(&chunk);
+            state.length += chunk.len() as u64;
+            progress.downloaded(state.length, total);
+
+            if state.length - saved_length >= SAVE_EVERY_BYTES {
+                file.flush().await?;
+                state.sha256 = hex(&hasher);
+                save_state(path, &state).await?;
+                saved_length = state.length;
+            }
+        }
+        file.flush().await?;
+        GtfsSyncResult::Ok(())
+    }
+    .await;
+
+    state.sha256 = hex(&hasher);
+    if let Err(e) = result {
+        // What was written before the error is intact
+        if file.flush().await.is_ok() {
+            save_state(path, &state).await?;
+        }
+        return Err(e);
+    }
+
+    // Complete, so there's nothing to resume
+    fs::remove_file(state_path(path)).await.ok();
+
+    Ok(Some(Downloaded {
+        path: path.to_path_buf(),
+        last_modified: state.last_modified,
+        sha256: state.sha256,
+    }))
+}
+
+/// Local files are copied rather than used in place, as the download is removed after the import
+async fn copy_local_zip(
+    url: &Url,
+    path: &Path,
+    if_modified_since: Option<&str>,
+    progress: &SyncProgress,
+) -> GtfsSyncResult<Option<Downloaded>> {
+    let source = url.to_file_path().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid file URL: {}", url),
+        )
+    })?;
+
+    let modified = fs::metadata(&source).await?.modified()?;
+    let last_modified = Some(DateTime::<Utc>::from(modified).to_rfc2822());
+    if if_modified_since.is_some() && if_modified_since == last_modified.as_deref() {
+        return Ok(None);
+    }
+
+    let bytes = fs::read(&source).await?;
+    fs::write(path, &bytes).await?;
+    progress.downloaded(bytes.len() as u64, Some(bytes.len() as u64));
+
+    Ok(Some(Downloaded {
+        path: path.to_path_buf(),
+        last_modified,
+        sha256: format!("{:x}", Sha256::digest(&bytes)),
+    }))
+}
+
+/// Downloads a GTFS zip to a file named after the feed, unless it hasn't been modified.
+/// Interrupted downloads are resumed with range requests, including those from before a restart.
+/// `file://` URLs are read from the local filesystem.
+pub async fn download_gtfs_zip(
+    config: &SyncConfig,
+    feed_id: &str,
+    url: &str,
+    if_modified_since: Option<&str>,
+    progress: &SyncProgress,
+) -> GtfsSyncResult<Option<Downloaded>> {
+    let retries = config.download_retries;
+
+    let dir = download_dir(config);
+    fs::create_dir_all(&dir).await?;
+    let path = dir.join(format!("{}.gtfs.zip", feed_id));
+
+    if let Ok(url) = Url::parse(url) {
+        if url.scheme() == "file" {
+            return copy_local_zip(&url, &path, if_modified_since, progress).await;
+        }
+    }
+
+    let mut attempt = 0;
+    loop {
+        match try_download(url, &path, if_modified_since, progress).await {
+            Err(e) if attempt < retries => {
+                attempt += 1;
+                tracing::warn!(
+                    "Error downloading {} (attempt {} of {}): {}",
+                    url,
+                    attempt,
+                    retries + 1,
+                    e
+                );
+                sleep(RETRY_DELAY).await;
+            }
+            result => return result,
+        }
+    }
+}
@@ -0,0 +1,119 @@
+use url::Url;
+
+use super::realtime::FeedSource;
+use crate::{at::client::ApiKey, config};
+
+/// Id of the feed when `FEEDS` isn't set
+pub const DEFAULT_FEED_ID: &str = "at";
+
+/// Auckland Transport's static GTFS, if `GTFS_URL` isn't set
+const DEFAULT_GTFS_URL: &str = "https://gtfs.at.govt.nz/gtfs.zip";
+
+/// Proxy to the AT API, if `API_URL` isn't set.
+/// AT's own is `https://api.at.govt.nz/` with `REALTIME_URL=realtime/legacy`
+/// and `API_KEY_HEADER=Ocp-Apim-Subscription-Key`.
+const DEFAULT_API_URL: &str = "https://at-proxy.heaps.dev/";
+
+/// The combined AT realtime feed, relative to the API
+const DEFAULT_REALTIME_FEED: &str = "realtime.json";
+
+/// A publisher's GTFS data, static and realtime.
+/// The tables are keyed by GTFS ids alone, so an import fails if its ids clash with another feed's.
+#[derive(Debug, Clone)]
+pub struct Feed {
+    /// Stored with everything imported from the feed
+    pub id: String,
+    /// The static GTFS zip, which may be a local `file://` URL
+    pub gtfs_url: String,
+    pub realtime: Vec<FeedSource>,
+}
+
+fn parse_url(name: &str, url: &str, errors: &mut Vec<String>) -> Option<Url> {
+    Url::parse(url)
+        .map_err(|e| errors.push(format!("Invalid {}: {} ({})", name, url, e)))
+        .ok()
+}
+
+impl Feed {
+    /// The AT feed, with `GTFS_URL`, `API_URL` and the `REALTIME` variables overriding the defaults.
+    /// `API_KEY` is sent in the `API_KEY_HEADER` header.
+    fn default_from_env(errors: &mut Vec<String>) -> Option<Self> {
+        let gtfs_url = config::var("GTFS_URL").unwrap_or_else(|_| DEFAULT_GTFS_URL.to_string());
+        parse_url("GTFS_URL", &gtfs_url, errors);
+        let api_url = config::var("API_URL").unwrap_or_else(|_| DEFAULT_API_URL.to_string());
+        let api_url = parse_url("API_URL", &api_url, errors)?;
+
+        Some(Self {
+            id: DEFAULT_FEED_ID.to_string(),
+            gtfs_url,
+            realtime: FeedSource::from_env(
+                DEFAULT_FEED_ID,
+                "REALTIME",
+                &api_url,
+                ApiKey::from_env("API_KEY", "API_KEY_HEADER"),
+                Some(DEFAULT_REALTIME_FEED),
+                errors,
+            ),
+        })
+    }
+
+    /// A feed configured with `FEED_<ID>_GTFS_URL` and optionally `FEED_<ID>_API_URL`
+    /// (which relative realtime URLs are resolved against), `FEED_<ID>_API_KEY`
+    /// (sent in `FEED_<ID>_API_KEY_HEADER`) and the `FEED_<ID>_REALTIME` variables
+    fn from_env_id(id: &str, errors: &mut Vec<String>) -> Option<Self> {
+        let prefix = format!("FEED_{}", id.to_uppercase().replace('-', "_"));
+
+        let Ok(gtfs_url) = config::var(format!("{}_GTFS_URL", prefix)) else {
+            errors.push(format!("{}_GTFS_URL must be set", prefix));
+            return None;
+        };
+        parse_url(&format!("{}_GTFS_URL", prefix), &gtfs_url, errors)?;
+        let api_url =
+            config::var(format!("{}_API_URL", prefix)).unwrap_or_else(|_| gtfs_url.clone());
+        let api_url = parse_url(&format!("{}_API_URL", prefix), &api_url, errors)?;
+
+        Some(Self {
+            id: id.to_string(),
+            realtime: FeedSource::from_env(
+                id,
+                &format!("{}_REALTIME", prefix),
+                &api_url,
+                ApiKey::from_env(
+                    &format!("{}_API_KEY", prefix),
+                    &format!("{}_API_KEY_HEADER", prefix),
+                ),
+                None,
+                errors,
+            ),
+            gtfs_url,
+        })
+    }
+
+    /// Feeds are listed by id in `FEEDS`, separated by commas.
+    /// Without it, only the AT feed is used.
+    /// Problems are added to `errors` rather than the feed being skipped,
+    /// as a missing feed would have everything imported from it removed.
+    pub fn from_env(errors: &mut Vec<String>) -> Vec<Self> {
+        match config::var("FEEDS") {
+            Ok(ids) => {
+                let ids = ids
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|id| !id.is_empty())
+                    .collect::<Vec<_>>();
+                if ids.is_empty() {
+                    errors.push("FEEDS must list at least one feed".to_string());
+                }
+                for (i, id) in ids.iter().enumerate() {
+                    if ids[..i].contains(id) {
+                        errors.push(format!("FEEDS lists {} more than once", id));
+                    }
+                }
+                ids.into_iter()
+                    .filter_map(|id| Self::from_env_id(id, errors))
+                    .collect()
+            }
+            Err(_) => Self::default_from_env(errors).into_iter().collect(),
+        }
+    }
+}
@@ -0,0 +1,99 @@
+use std::time::Duration;
+
+use sea_orm::{ColumnTrait, DatabaseConnection, EntityTrait, QueryFilter};
+use serde::Serialize;
+
+use crate::entity::gtfs_feed_info;
+
+use super::sync::GtfsSyncResult;
+
+/// How long the webhook has to respond
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The version of a feed and the period it covers, from feed_info.txt
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct FeedVersion {
+    pub version: Option<String>,
+    /// YYYYMMDD
+    pub start_date: Option<i32>,
+    /// YYYYMMDD
+    pub end_date: Option<i32>,
+}
+
+impl From<gtfs_feed_info::Model> for FeedVersion {
+    fn from(info: gtfs_feed_info::Model) -> Self {
+        Self {
+            version: info.feed_version,
+            start_date: info.feed_start_date,
+            end_date: info.feed_end_date,
+        }
+    }
+}
+
+impl FeedVersion {
+    /// Of the feed's records being served, if it has a feed_info.txt
+    pub async fn current(db: &DatabaseConnection, feed_id: &str) -> GtfsSyncResult<Option<Self>> {
+        let info = gtfs_feed_info::Entity::find()
+            .filter(gtfs_feed_info::Column::FeedId.eq(feed_id))
+            .one(db)
+            .await?;
+        Ok(info.map(Self::from))
+    }
+}
+
+/// Sent to the webhook, and logged, when a feed's version changes
+#[derive(Debug, Serialize)]
+struct VersionChange<'a> {
+    feed_id: &'a str,
+    import_id: i64,
+    previous: Option<&'a FeedVersion>,
+    current: Option<&'a FeedVersion>,
+}
+
+async fn call_webhook(url: &str, change: &VersionChange<'_>) -> reqwest::Result<()> {
+    reqwest::Client::new()
+        .post(url)
+        .timeout(WEBHOOK_TIMEOUT)
+        .json(change)
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+/// Logs any change to the feed's version or dates after an import.
+/// When the version changes, the change is also posted to `webhook_url` if it's set.
+pub async fn notify_version_change(
+    webhook_url: Option<&str>,
+    feed_id: &str,
+    import_id: i64,
+    previous: Option<FeedVersion>,
+    current: Option<FeedVersion>,
+) {
+    if previous == current {
+        return;
+    }
+
+    let change = VersionChange {
+        feed_id,
+        import_id,
+        previous: previous.as_ref(),
+        current: current.as_ref(),
+    };
+    tracing::info!(
+        "Feed version changed: {}",
+        serde_json::to_string(&change).unwrap()
+    );
+
+    let version = |v: Option<&FeedVersion>| v.and_then(|v| v.version.clone());
+    if version(change.previous) == version(change.current) {
+        return;
+    }
+
+    if let Some(url) = webhook_url {
+        // An import isn't failed by a webhook that's down
+        if let Err(e) = call_webhook(url, &change).await {
+            tracing::warn!("Error calling the feed version webhook: {}", e);
+        }
+    }
+}
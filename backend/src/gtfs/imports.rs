@@ -0,0 +1,75 @@
+use sea_orm::{ColumnTrait, DatabaseConnection, EntityTrait, QueryFilter, QueryOrder, QuerySelect};
+use serde::Serialize;
+
+use crate::{
+    entity::{import, prelude::Import},
+    error::{NextAtError, NextAtResult},
+    gtfs::sync::{self, SyncConfig},
+};
+
+#[derive(Serialize)]
+pub struct ImportHistory {
+    /// The import whose data is currently being served
+    pub active_import: Option<import::Model>,
+    /// Most recent first
+    pub imports: Vec<import::Model>,
+}
+
+/// The most recent import whose records are being served
+pub async fn get_active_import(
+    db: &DatabaseConnection,
+) -> Result<Option<import::Model>, sea_orm::DbErr> {
+    Import::find()
+        .filter(import::Column::Active.eq(1))
+        .order_by_desc(import::Column::Id)
+        .one(db)
+        .await
+}
+
+pub async fn get_import_history(
+    db: &DatabaseConnection,
+    limit: u64,
+) -> Result<ImportHistory, sea_orm::DbErr> {
+    let imports = Import::find()
+        .order_by_desc(import::Column::Id)
+        .limit(limit)
+        .all(db)
+        .await?;
+
+    let active_import = get_active_import(db).await?;
+
+    Ok(ImportHistory {
+        active_import,
+        imports,
+    })
+}
+
+/// Serves an earlier import of a feed again, if its records are still kept
+pub async fn activate_import(
+    db: &DatabaseConnection,
+    config: &SyncConfig,
+    import_id: i64,
+) -> NextAtResult<()> {
+    let import = Import::find_by_id(import_id)
+        .one(db)
+        .await?
+        .ok_or_else(|| NextAtError::NotFound(format!("Import not found: {}", import_id)))?;
+
+    if import.active == 1 {
+        return Err(NextAtError::InvalidData(format!(
+            "Import is already active: {}",
+            import_id
+        )));
+    }
+
+    if !sync::activate_import(config, import.id, import.feed_id).await? {
+        return Err(NextAtError::NotFound(format!(
+            "Import is no longer kept: {}",
+            import_id
+        )));
+    }
+
+    tracing::warn!("Import {} has been made active again", import_id);
+
+    Ok(())
+}
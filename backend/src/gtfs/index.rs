@@ -1,32 +1,52 @@
-use std::{collections::HashMap, ops::Sub, time::Instant};
+use std::{
+    collections::HashMap,
+    ops::Sub,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, SyncSender},
+        Arc,
+    },
+    thread,
+    time::Instant,
+};
 
 use crate::entity::prelude::*;
 use crate::{
+    config::parse_var,
     db::{
         self,
         util::{null, SeaRusqliteAdapter},
     },
     entity::*,
-    geo::get_bounding_box,
-    gtfs::utils::GtfsDateTimeParser,
+    geo::{encode_polyline, get_bounding_box},
+    gtfs::{
+        changes::{changed_trips, clear_changes},
+        progress::IndexProgress,
+        utils::GtfsDateTimeParser,
+    },
 };
-use chrono::{Datelike, NaiveDate, Utc, Weekday};
-use geo::Point;
-use migration::raw::RawSql;
-use migration::{Sql000003StopTimeIndexTable, Sql000004StopTimeIndexIndexes};
+use chrono::{NaiveDate, TimeZone, Timelike, Utc};
+use chrono_tz::Tz;
+use geo::{ConvexHull, MultiPoint, Point};
+use itertools::Itertools;
 use rusqlite::params;
-use sea_orm::sea_query::any;
-use sea_orm::sea_query::OnConflict;
-use sea_orm::{sea_query::all, QueryOrder};
+use sea_orm::sea_query::{Alias, Expr, OnConflict};
+use sea_orm::QueryOrder;
 use sea_orm::{
-    sea_query::{IntoCondition, Query, UnionType},
-    ColumnTrait, EntityTrait, QueryFilter, QuerySelect, QueryTrait, RelationTrait, Select,
+    sea_query::Query, ColumnTrait, EntityTrait, QueryFilter, QuerySelect, QueryTrait,
+    RelationTrait, Select,
 };
 
+use serde::Deserialize;
+use tracing::Span;
+
 use super::utils::DateError;
 
 const SEARCH_DISTANCE_METRES: f64 = 1000.0;
 
+/// Decimal places of coordinates in the service area's polyline
+pub const SERVICE_AREA_PRECISION: u32 = 5;
+
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
     #[error("Database error: {0}")]
@@ -38,6 +58,9 @@ pub enum Error {
     #[error("Error parsing date: {0}")]
     Date(#[from] DateError),
 
+    #[error("Index build cancelled")]
+    Cancelled,
+
     #[error("{0}")]
     Other(String),
 }
@@ -50,72 +73,30 @@ fn prepare_stop_times_for_date(dt: &NaiveDate) -> Select<gtfs_stop_times::Entity
     use sea_orm::JoinType::*;
 
     // aliases
-    use gtfs_calendar as c;
-    use gtfs_calendar_dates as cd;
     use gtfs_routes as r;
     use gtfs_stop_times as st;
     use gtfs_trips as t;
+    use service_dates as sd;
 
-    let calendar_day_col = match dt.weekday() {
-        Weekday::Mon => gtfs_calendar::Column::Monday,
-        Weekday::Tue => gtfs_calendar::Column::Tuesday,
-        Weekday::Wed => gtfs_calendar::Column::Wednesday,
-        Weekday::Thu => gtfs_calendar::Column::Thursday,
-        Weekday::Fri => gtfs_calendar::Column::Friday,
-        Weekday::Sat => gtfs_calendar::Column::Saturday,
-        Weekday::Sun => gtfs_calendar::Column::Sunday,
-    };
     let gtfs_date: i32 = dt.format("%Y%m%d").to_string().parse().unwrap();
 
+    // The calendars are already expanded into service_dates during sync
     StopTime::find()
         .join(LeftJoin, st::Relation::GtfsTrips.def())
         .join(LeftJoin, t::Relation::GtfsRoutes.def())
         .join(LeftJoin, r::Relation::GtfsAgency.def())
-        .join(LeftJoin, t::Relation::Service.def())
-        .join(LeftJoin, service::Relation::GtfsCalendar.def())
-        .join(
-            LeftJoin,
-            service::Relation::GtfsCalendarDates
-                .def()
-                // Custom condition - join to the row for this date
-                .on_condition(move |_, _| cd::Column::Date.eq(gtfs_date).into_condition()),
-        )
-        .filter(any![
-            // Regular services
-            all![
-                c::Column::StartDate.lte(gtfs_date),
-                c::Column::EndDate.gte(gtfs_date),
-                calendar_day_col.eq(1),
-                // except those cancelled for this date
-                any![
-                    cd::Column::ExceptionType.is_null(),
-                    cd::Column::ExceptionType.ne(2) // cancelled
-                ]
-            ],
-            // Added services
-            cd::Column::ExceptionType.eq(1)
-        ])
+        .join(InnerJoin, t::Relation::Service.def())
+        .join(InnerJoin, service::Relation::ServiceDates.def())
+        .filter(sd::Column::Date.eq(gtfs_date))
 }
 
-fn prepare_last_calendar_date() -> Select<gtfs_calendar_dates::Entity> {
-    let mut calendar_dates = gtfs_calendar_dates::Entity::find()
+/// The last date any service runs on
+fn prepare_last_service_date() -> Select<service_dates::Entity> {
+    service_dates::Entity::find()
         .select_only()
-        .column(gtfs_calendar_dates::Column::Date);
-
-    // union only seems to be possible with seaquery directly
-    // (this adds to the underlying query)
-    sea_orm::QuerySelect::query(&mut calendar_dates)
-        .union(
-            UnionType::All,
-            Query::select()
-                .column(gtfs_calendar::Column::EndDate)
-                .from(gtfs_calendar::Entity)
-                .to_owned(),
-        )
-        .order_by(gtfs_calendar_dates::Column::Date, sea_orm::Order::Desc)
-        .limit(1);
-
-    calendar_dates
+        .column(service_dates::Column::Date)
+        .order_by_desc(service_dates::Column::Date)
+        .limit(1)
 }
 
 struct CountLogger {
@@ -136,7 +117,7 @@ impl CountLogger {
     fn log(&mut self) {
         self.count += 1;
         if self.count % 100_000 == 0 {
-            log::info!(
+            tracing::info!(
                 "Inserted {} {} ({} ms)",
                 self.count,
                 self.name,
@@ -147,59 +128,567 @@ impl CountLogger {
     }
 }
 
-fn do_build_stop_time_index() -> Result<()> {
-    const MAX_DAYS: i32 = 21;
+/// A period in which a trip's stop times repeat, from frequencies.txt
+struct Frequency {
+    start_time: String,
+    end_time: String,
+    headway_secs: i64,
+    /// Whether runs depart exactly every headway, rather than roughly
+    exact_times: bool,
+}
 
-    let mut db = db::util::open_rusqlite()?;
+/// The frequencies of each frequency based trip
+fn load_frequencies(tx: &rusqlite::Connection) -> Result<HashMap<String, Vec<Frequency>>> {
+    let mut frequencies_query = GtfsFrequencies::find()
+        .select_only()
+        .columns([
+            gtfs_frequencies::Column::TripId,
+            gtfs_frequencies::Column::StartTime,
+            gtfs_frequencies::Column::EndTime,
+            gtfs_frequencies::Column::HeadwaySecs,
+            gtfs_frequencies::Column::ExactTimes,
+        ])
+        .into_query()
+        .prepare(tx)?;
+    let mut rows = frequencies_query.query()?;
+
+    let mut frequencies: HashMap<String, Vec<Frequency>> = HashMap::new();
+    while let Some(r) = rows.next()? {
+        let trip_id: String = r.get(0)?;
+        let frequency = Frequency {
+            start_time: r.get(1)?,
+            end_time: r.get(2)?,
+            headway_secs: r.get(3)?,
+            exact_times: r.get::<_, Option<i32>>(4)? == Some(1),
+        };
+
+        if frequency.headway_secs <= 0 {
+            tracing::warn!("Ignoring frequency of trip {} without a headway", trip_id);
+            continue;
+        }
+
+        frequencies.entry(trip_id).or_default().push(frequency);
+    }
+
+    Ok(frequencies)
+}
+
+/// A stop time of a frequency based trip, which each of its runs is offset from
+struct TemplateStop {
+    stop_id: String,
+    stop_sequence: i32,
+    trip_id: String,
+    route_id: String,
+    direction_id: i32,
+    feed_id: String,
+    agency_timezone: String,
+    arrival_millis: i64,
+    departure_millis: i64,
+}
+
+/// Counts a stop time in its 10 minute period of the day, in the local time of its agency
+fn count_period(period_counts: &mut HashMap<i64, i32>, timezone: Tz, arrival_millis: i64) {
+    let Some(local) = timezone.timestamp_millis_opt(arrival_millis).single() else {
+        return;
+    };
+    let period = local.num_seconds_from_midnight() as i64 / 600;
+    period_counts
+        .entry(period)
+        .and_modify(|c| *c += 1)
+        .or_insert(1);
+}
+
+/// Name of a table while it's being rebuilt, before it replaces the table
+fn new_table(table: &str) -> String {
+    format!("{}_new", table)
+}
+
+/// Creates an empty copy of the table to build into, while the table itself is still served.
+/// It has no indexes (yet) to make inserts faster.
+/// The definitions are read from the database so they always match the migrated schema,
+/// the index definitions are returned so they can be re-created afterwards.
+fn create_new_table(tx: &rusqlite::Connection, table: &str) -> Result<Vec<String>> {
+    let mut table_sql = None;
+    let mut index_sqls = vec![];
+    {
+        let mut schema = tx.prepare(
+            "SELECT type, sql FROM sqlite_master
+            WHERE tbl_name = ?1 AND sql IS NOT NULL",
+        )?;
+        let mut rows = schema.query([table])?;
+        while let Some(r) = rows.next()? {
+            let kind: String = r.get(0)?;
+            let sql: String = r.get(1)?;
+            match kind.as_str() {
+                "table" => table_sql = Some(sql),
+                _ => index_sqls.push(sql),
+            }
+        }
+    }
+    let table_sql = table_sql.ok_or_else(|| Error::Other(format!("{} table not found", table)))?;
+    let definition = &table_sql[table_sql.find('(').unwrap()..];
+
+    let new_table = new_table(table);
+    tx.execute_batch(&format!(
+        r#"
+        DROP TABLE IF EXISTS "{new_table}";
+        CREATE TABLE "{new_table}" {definition};
+    "#
+    ))?;
+    // Ids carry on from the table's rather than starting again,
+    // as the realtime tables and notifications sent still refer to the old ones
+    if definition.to_uppercase().contains("AUTOINCREMENT") {
+        tx.execute(
+            "INSERT INTO sqlite_sequence (name, seq)
+            SELECT ?1, seq FROM sqlite_sequence WHERE name = ?2",
+            [new_table.as_str(), table],
+        )?;
+    }
+
+    Ok(index_sqls)
+}
+
+/// Replaces the tables with the ones that have been built, then re-creates their indexes.
+/// Until this is committed, readers keep seeing the previous index.
+fn swap_in_new_tables(
+    tx: &rusqlite::Connection,
+    tables: &[&str],
+    index_sqls: &[String],
+) -> Result<()> {
+    for table in tables {
+        let new_table = new_table(table);
+        tx.execute_batch(&format!(
+            r#"
+            DROP TABLE "{table}";
+            ALTER TABLE "{new_table}" RENAME TO "{table}";
+        "#
+        ))?;
+    }
+
+    tracing::info!("Re-creating indexes");
+    for sql in index_sqls {
+        tx.execute_batch(sql)?;
+    }
+
+    Ok(())
+}
+
+/// Number of days indexed from yesterday, if `INDEX_DAYS` isn't set
+const DEFAULT_INDEX_DAYS: i32 = 21;
+
+/// The most changed trips to rebuild incrementally, if `INDEX_INCREMENTAL_MAX_TRIPS` isn't set.
+/// With more it's quicker to rebuild the whole index.
+const DEFAULT_INCREMENTAL_MAX_TRIPS: usize = 2000;
+
+/// Number of threads reading stop times for the index, if `INDEX_WORKERS` isn't set
+const DEFAULT_INDEX_WORKERS: usize = 4;
+
+/// How the stop time index is built
+#[derive(Debug, Clone)]
+pub struct IndexConfig {
+    /// Number of days indexed from yesterday
+    pub days: i32,
+    /// The most changed trips rebuilt incrementally
+    pub incremental_max_trips: usize,
+    /// Number of threads reading stop times
+    pub workers: usize,
+}
+
+impl IndexConfig {
+    /// Every problem with the settings is added to `errors`
+    pub fn from_env(errors: &mut Vec<String>) -> Self {
+        let mut days = parse_var("INDEX_DAYS", DEFAULT_INDEX_DAYS, errors);
+        if days < 1 {
+            errors.push(format!("Invalid INDEX_DAYS: {}, expected at least 1", days));
+            days = DEFAULT_INDEX_DAYS;
+        }
+
+        let mut workers = parse_var("INDEX_WORKERS", DEFAULT_INDEX_WORKERS, errors);
+        if workers < 1 {
+            errors.push("INDEX_WORKERS must be at least 1".to_string());
+            workers = DEFAULT_INDEX_WORKERS;
+        }
+
+        Self {
+            days,
+            incremental_max_trips: parse_var(
+                "INDEX_INCREMENTAL_MAX_TRIPS",
+                DEFAULT_INCREMENTAL_MAX_TRIPS,
+                errors,
+            ),
+            workers,
+        }
+    }
+}
+
+impl Default for IndexConfig {
+    fn default() -> Self {
+        Self {
+            days: DEFAULT_INDEX_DAYS,
+            incremental_max_trips: DEFAULT_INCREMENTAL_MAX_TRIPS,
+            workers: DEFAULT_INDEX_WORKERS,
+        }
+    }
+}
+
+/// Which part of the stop time index to build.
+/// The default rebuilds the whole index from yesterday.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct IndexOptions {
+    /// First date to index, defaults to yesterday
+    pub from_date: Option<NaiveDate>,
+    /// Number of days to index, defaults to `INDEX_DAYS`
+    pub days: Option<i32>,
+    /// Re-index dates that have already been indexed
+    #[serde(default)]
+    pub force: bool,
+    /// Only rebuild the runs of trips changed by syncs since the index was built.
+    /// The whole index is rebuilt instead if it's empty or too much has changed.
+    #[serde(default)]
+    pub incremental: bool,
+}
+
+impl IndexOptions {
+    /// A partial build only replaces the requested dates and leaves the rest of the index alone
+    fn is_partial(&self) -> bool {
+        self.from_date.is_some() || self.days.is_some()
+    }
+}
+
+/// The changed trips to rebuild, or None if the whole index needs rebuilding
+fn trips_to_rebuild(db: &rusqlite::Connection, max_trips: usize) -> Result<Option<Vec<String>>> {
+    let indexed: i64 = Query::select()
+        .expr(Expr::col(trip_run::Column::Id).count())
+        .from(TripRun)
+        .prepare(db)?
+        .query_row(|r| r.get(0))?;
+    if indexed == 0 {
+        tracing::info!("Stop index is empty, building all of it");
+        return Ok(None);
+    }
+
+    match changed_trips(db)? {
+        None => {
+            tracing::info!("Changes need the whole stop index rebuilt");
+            Ok(None)
+        }
+        Some(trip_ids) if trip_ids.len() > max_trips => {
+            tracing::info!(
+                "{} trips changed, rebuilding the whole stop index",
+                trip_ids.len()
+            );
+            Ok(None)
+        }
+        Some(trip_ids) => Ok(Some(trip_ids)),
+    }
+}
+
+/// Removes every run of the trips from the index so they can be rebuilt
+fn clear_trips(tx: &rusqlite::Connection, trip_ids: &[String]) -> Result<()> {
+    Query::delete()
+        .from_table(StopTimeIndex)
+        .and_where(stop_time_index::Column::TripId.is_in(trip_ids.iter().cloned()))
+        .prepare(tx)?
+        .execute()?;
+
+    Query::delete()
+        .from_table(TripRun)
+        .and_where(trip_run::Column::TripId.is_in(trip_ids.iter().cloned()))
+        .prepare(tx)?
+        .execute()?;
+
+    Ok(())
+}
+
+/// Counts a stop time written, and stops the build if it's been cancelled
+fn row_written(progress: &IndexProgress) -> Result<()> {
+    progress.row_written();
+    if progress.is_cancelled() {
+        return Err(Error::Cancelled);
+    }
+    Ok(())
+}
+
+/// A stop time of a trip run, as it's written to the index
+struct RunStop {
+    stop_id: String,
+    stop_sequence: i32,
+    arrival_millis: i64,
+    departure_millis: i64,
+}
+
+/// A run of a trip on a date, with its stop times
+struct Run {
+    trip_id: String,
+    route_id: String,
+    direction_id: i32,
+    feed_id: String,
+    start_millis: i64,
+    /// Set for runs of frequency based trips that aren't exactly timed
+    headway_secs: Option<i64>,
+    /// Of the agency, which the times are local to
+    timezone: Tz,
+    stops: Vec<RunStop>,
+}
+
+/// The runs of a date, ready to be written
+type DayRuns = (NaiveDate, Vec<Run>);
+
+/// Reads the runs of every trip on the date, or only of the trips if they're given
+fn read_runs_for_date(
+    db: &rusqlite::Connection,
+    date: &NaiveDate,
+    trip_ids: Option<&[String]>,
+    frequencies: &HashMap<String, Vec<Frequency>>,
+    gtfs_date_time: &mut GtfsDateTimeParser,
+) -> Result<Vec<Run>> {
+    let mut day_query = prepare_stop_times_for_date(date);
+    if let Some(trip_ids) = trip_ids {
+        day_query =
+            day_query.filter(gtfs_stop_times::Column::TripId.is_in(trip_ids.iter().cloned()));
+    }
+
+    let mut day_data_query = day_query
+        .select_only()
+        .columns([
+            gtfs_stop_times::Column::StopId,
+            gtfs_stop_times::Column::StopSequence,
+            gtfs_stop_times::Column::TripId,
+            gtfs_stop_times::Column::ArrivalTime,
+            gtfs_stop_times::Column::DepartureTime,
+        ])
+        .column(gtfs_agency::Column::AgencyTimezone)
+        .columns([
+            gtfs_trips::Column::RouteId,
+            gtfs_trips::Column::DirectionId,
+            gtfs_trips::Column::FeedId,
+        ])
+        // So that we get the trip start time before the rest of the stops
+        // which lets us create a trip run to correlate with the rest of the stops
+        .order_by_asc(gtfs_stop_times::Column::TripId)
+        .order_by_asc(gtfs_stop_times::Column::StopSequence)
+        .into_query()
+        .prepare(db)?;
+
+    let mut runs: Vec<Run> = vec![];
+
+    let mut day_data = day_data_query.query()?;
+
+    // Stop times of frequency based trips, these are only run once expanded below
+    let mut templates: Vec<TemplateStop> = vec![];
+
+    while let Some(r) = day_data.next()? {
+        let stop_id: String = r.get(0)?;
+        let stop_sequence: i32 = r.get(1)?;
+        let trip_id: String = r.get(2)?;
+        let arrival_time: String = r.get(3)?;
+        let departure_time: String = r.get(4)?;
+        let agency_timezone: String = r.get(5)?;
+        let route_id: String = r.get(6)?;
+        let direction_id: i32 = r.get(7)?;
+        let feed_id: String = r.get(8)?;
+
+        let arrival_time = gtfs_date_time.parse_time(date, &arrival_time, &agency_timezone)?;
+        let departure_time = gtfs_date_time.parse_time(date, &departure_time, &agency_timezone)?;
+
+        if frequencies.contains_key(&trip_id) {
+            templates.push(TemplateStop {
+                stop_id,
+                stop_sequence,
+                trip_id,
+                route_id,
+                direction_id,
+                feed_id,
+                agency_timezone,
+                arrival_millis: arrival_time.timestamp_millis(),
+                departure_millis: departure_time.timestamp_millis(),
+            });
+            continue;
+        }
+
+        if stop_sequence == 1 {
+            // The trip run starts at the departure from the first stop
+            runs.push(Run {
+                trip_id,
+                route_id,
+                direction_id,
+                feed_id,
+                start_millis: departure_time.timestamp_millis(),
+                headway_secs: None,
+                timezone: departure_time.timezone(),
+                stops: vec![],
+            });
+        }
+
+        let run = runs
+            .last_mut()
+            .ok_or_else(|| Error::Other("No trip run id".to_string()))?;
+
+        run.stops.push(RunStop {
+            stop_id,
+            stop_sequence,
+            arrival_millis: arrival_time.timestamp_millis(),
+            departure_millis: departure_time.timestamp_millis(),
+        });
+    }
+
+    // Each frequency based trip gets a run every headway through each of its periods.
+    // Stop times keep their offset from the template's first departure.
+    // Runs that aren't exactly timed are still indexed, but keep the headway
+    // so they can be shown as roughly every so often.
+    for (trip_id, stops) in &templates.iter().group_by(|s| &s.trip_id) {
+        let stops = stops.collect_vec();
+        let first = stops[0];
+
+        for frequency in &frequencies[trip_id] {
+            let start =
+                gtfs_date_time.parse_time(date, &frequency.start_time, &first.agency_timezone)?;
+            let timezone = start.timezone();
+            let start = start.timestamp_millis();
+            let end = gtfs_date_time
+                .parse_time(date, &frequency.end_time, &first.agency_timezone)?
+                .timestamp_millis();
+            let headway_secs = (!frequency.exact_times).then_some(frequency.headway_secs);
+
+            for run_start in (start..end).step_by(frequency.headway_secs as usize * 1000) {
+                let stops = stops
+                    .iter()
+                    .map(|stop| RunStop {
+                        stop_id: stop.stop_id.clone(),
+                        stop_sequence: stop.stop_sequence,
+                        arrival_millis: run_start + stop.arrival_millis - first.departure_millis,
+                        departure_millis: run_start + stop.departure_millis
+                            - first.departure_millis,
+                    })
+                    .collect();
+
+                runs.push(Run {
+                    trip_id: trip_id.clone(),
+                    route_id: first.route_id.clone(),
+                    direction_id: first.direction_id,
+                    feed_id: first.feed_id.clone(),
+                    start_millis: run_start,
+                    headway_secs,
+                    timezone,
+                    stops,
+                });
+            }
+        }
+    }
+
+    Ok(runs)
+}
+
+/// Reads the runs of dates until there are none left, sending each date's to be written.
+/// Stops early if the build has, i.e. nothing is receiving them.
+fn read_dates(
+    dates: &[NaiveDate],
+    next_date: &AtomicUsize,
+    trip_ids: Option<&[String]>,
+    frequencies: &HashMap<String, Vec<Frequency>>,
+    sender: &SyncSender<Result<DayRuns>>,
+) -> Result<()> {
+    let db = db::util::open_rusqlite()?;
+    let mut gtfs_date_time = GtfsDateTimeParser::new();
+
+    while let Some(date) = dates.get(next_date.fetch_add(1, Ordering::Relaxed)) {
+        let runs = read_runs_for_date(&db, date, trip_ids, frequencies, &mut gtfs_date_time)?;
+        if sender.send(Ok((*date, runs))).is_err() {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+fn do_build_stop_time_index(
+    config: &IndexConfig,
+    options: IndexOptions,
+    progress: &IndexProgress,
+) -> Result<()> {
+    let db = db::util::open_rusqlite()?;
 
     // for speed
     db.pragma_update(None, "foreign_keys", "OFF")?;
 
-    let mut gtfs_date_time = GtfsDateTimeParser::new();
+    let gtfs_date_time = GtfsDateTimeParser::new();
 
-    let last_date_i: i32 = prepare_last_calendar_date()
+    let last_date_i: i32 = prepare_last_service_date()
         .into_query()
         .prepare(&db)?
         .query_row(|r| r.get(0))?;
     let last_date = gtfs_date_time.parse_date(&last_date_i.to_string())?;
 
-    let start_date = Utc::now()
-        .sub(chrono::Duration::days(1))
-        .naive_local()
-        .date();
+    let start_date = options.from_date.unwrap_or_else(|| {
+        Utc::now()
+            .sub(chrono::Duration::days(1))
+            .naive_local()
+            .date()
+    });
+    let days = options.days.unwrap_or(config.days);
+    if days < 1 {
+        return Err(Error::Other(format!("Invalid number of days: {}", days)));
+    }
+    let partial = options.is_partial();
+    let changed_trip_ids = if options.incremental && !partial {
+        trips_to_rebuild(&db, config.incremental_max_trips)?
+    } else {
+        None
+    };
 
-    // tx rolled back on drop if not committed
-    let tx = db.transaction()?;
-    {
-        Query::delete()
-            .from_table(TripRun)
-            .prepare(&tx)?
-            .execute()?;
+    let full = !partial && changed_trip_ids.is_none();
 
-        // Stupid hack that works - drop the table (faster than deleting rows)
-        // And recreate it without indexes (yet) to make inserts faster
-        log::info!("Deleting existing stop index");
-        tx.execute_batch(&Sql000003StopTimeIndexTable::down_sql().unwrap())?;
-        tx.execute_batch(&Sql000003StopTimeIndexTable::up_sql())?;
+    // A full build goes into new tables, which replace the index once they're built
+    let (trip_run_table, stop_time_index_table) = if full {
+        (new_table("trip_run"), new_table("stop_time_index"))
+    } else {
+        ("trip_run".to_string(), "stop_time_index".to_string())
+    };
+
+    // only re-created after a full build
+    let mut index_sqls = vec![];
+
+    // Not a rusqlite transaction so that a full build can commit as it goes.
+    // Rolled back when the connection is dropped if not committed.
+    db.execute_batch("BEGIN")?;
+    let tx = &db;
+    {
+        if let Some(trip_ids) = &changed_trip_ids {
+            tracing::info!("Rebuilding stop index for {} changed trips", trip_ids.len());
+            clear_trips(tx, trip_ids)?;
+        } else if partial {
+            tracing::info!(
+                "Rebuilding stop index for {} days from {}",
+                days,
+                start_date
+            );
+        } else {
+            // Runs added by the realtime feed are replaced along with the rest
+            tracing::info!("Building a new stop index");
+            index_sqls.extend(create_new_table(tx, "trip_run")?);
+            index_sqls.extend(create_new_table(tx, "stop_time_index")?);
+        }
 
         // Prepare trip run insert
         let mut insert_into_trip_run = Query::insert()
-            .into_table(TripRun)
+            .into_table(Alias::new(&trip_run_table))
             .columns([
                 trip_run::Column::TripId,
                 trip_run::Column::RouteId,
                 trip_run::Column::DirectionId,
                 trip_run::Column::StartDate,
                 trip_run::Column::StartTimestamp,
+                trip_run::Column::HeadwaySecs,
+                trip_run::Column::FeedId,
             ])
-            .values_panic(vec![null(); 5]) // placeholders
+            .values_panic(vec![null(); 7]) // placeholders
             .returning_col(trip_run::Column::Id)
-            .prepare(&tx)?
+            .prepare(tx)?
             .into_inner();
 
         // Prepare stop time index insert
         let mut insert_into_index = Query::insert()
-            .into_table(stop_time_index::Entity)
+            .into_table(Alias::new(&stop_time_index_table))
             .columns([
                 stop_time_index::Column::StopId,
                 stop_time_index::Column::StopSequence,
@@ -209,140 +698,231 @@ fn do_build_stop_time_index() -> Result<()> {
                 stop_time_index::Column::DepartureTimestamp,
             ])
             .values_panic(vec![null(); 6]) // placeholders
-            .prepare(&tx)?
+            .prepare(tx)?
             .into_inner();
 
         // we keep track of the number of stop times in each 10 minute period of the day
         // so that we can find the ideal maintenance window
         let mut period_counts = (0..144).map(|i| (i, 0)).collect::<HashMap<_, _>>();
+        // and which timezone the periods are in, the most common if there's more than one
+        let mut timezone_counts = HashMap::<&str, usize>::new();
+
+        let frequencies = load_frequencies(tx)?;
+
+        let mut dates = vec![];
+        let all_dates = start_date
+            .iter_days()
+            .take(days as usize)
+            .take_while(|date| *date <= last_date);
+        for date in all_dates {
+            let gtfs_date = date.format("%Y%m%d").to_string();
+            if partial && !clear_date(tx, &gtfs_date, options.force)? {
+                tracing::info!("Stop index for {} already built, skipping", date);
+                continue;
+            }
+            dates.push(date);
+        }
+
+        // Reading the stop times of each date is spread across threads,
+        // but they're all written here as SQLite only has the one writer
+        let workers = config.workers.clamp(1, dates.len().max(1));
+        let next_date = AtomicUsize::new(0);
+        let (sender, receiver) = mpsc::sync_channel(workers);
+        thread::scope(|scope| {
+            for _ in 0..workers {
+                let sender = sender.clone();
+                let (dates, next_date, frequencies) = (&dates, &next_date, &frequencies);
+                let trip_ids = changed_trip_ids.as_deref();
+                scope.spawn(move || {
+                    if let Err(e) = read_dates(dates, next_date, trip_ids, frequencies, &sender) {
+                        // Nothing is listening if the build has already stopped
+                        let _ = sender.send(Err(e));
+                    }
+                });
+            }
+            // So that receiving ends once the workers have
+            drop(sender);
 
-        let mut day_count = 0;
-        let mut date = start_date;
+            for day in receiver {
+                let (date, runs) = day?;
 
-        while date <= last_date && day_count < MAX_DAYS {
-            day_count += 1;
+                // Nothing reads the new tables yet, so other writers aren't held up for the whole build
+                if full {
+                    tx.execute_batch("COMMIT; BEGIN")?;
+                }
 
-            log::info!("Building stop index for {}", date);
+                let gtfs_date = date.format("%Y%m%d").to_string();
+                progress.date(date);
 
-            let mut day_data_query = prepare_stop_times_for_date(&date)
-                .select_only()
-                .columns([
-                    gtfs_stop_times::Column::StopId,
-                    gtfs_stop_times::Column::StopSequence,
-                    gtfs_stop_times::Column::TripId,
-                    gtfs_stop_times::Column::ArrivalTime,
-                    gtfs_stop_times::Column::DepartureTime,
-                ])
-                .column(gtfs_agency::Column::AgencyTimezone)
-                .columns([gtfs_trips::Column::RouteId, gtfs_trips::Column::DirectionId])
-                // So that we get the trip start time before the rest of the stops
-                // which lets us create a trip run to correlate with the rest of the stops
-                .order_by_asc(gtfs_stop_times::Column::TripId)
-                .order_by_asc(gtfs_stop_times::Column::StopSequence)
-                .into_query()
-                .prepare(&tx)?;
-
-            let mut count = CountLogger::new("stop times");
-
-            let mut trip_run_id: Option<i64> = None;
-
-            let mut day_data = day_data_query.query()?;
-
-            while let Some(r) = day_data.next()? {
-                let stop_id: String = r.get(0)?;
-                let stop_sequence: i32 = r.get(1)?;
-                let trip_id: String = r.get(2)?;
-                let arrival_time: String = r.get(3)?;
-                let departure_time: String = r.get(4)?;
-                let agency_timezone: String = r.get(5)?;
-                let route_id: String = r.get(6)?;
-                let direction_id: i32 = r.get(7)?;
-
-                let arrival_time =
-                    gtfs_date_time.parse_time(&date, &arrival_time, &agency_timezone)?;
-                let departure_time =
-                    gtfs_date_time.parse_time(&date, &departure_time, &agency_timezone)?;
-
-                if stop_sequence == 1 {
-                    // The trip run starts at the departure from the first stop
-                    let id: i64 = insert_into_trip_run.query_row(
+                tracing::info!("Building stop index for {}", date);
+
+                let mut count = CountLogger::new("stop times");
+
+                for run in runs {
+                    *timezone_counts.entry(run.timezone.name()).or_default() += 1;
+
+                    let trip_run_id: i64 = insert_into_trip_run.query_row(
                         params![
-                            trip_id,
-                            route_id,
-                            direction_id,
-                            date.format("%Y%m%d").to_string(), // gtfs format
-                            departure_time.timestamp_millis(),
+                            run.trip_id,
+                            run.route_id,
+                            run.direction_id,
+                            gtfs_date,
+                            run.start_millis,
+                            run.headway_secs,
+                            run.feed_id,
                         ],
                         |r| r.get(0),
                     )?;
-                    trip_run_id = Some(id);
-                }
 
-                let trip_run_id =
-                    trip_run_id.ok_or_else(|| Error::Other("No trip run id".to_string()))?;
-
-                let arrival_time_millis = arrival_time.timestamp_millis();
-                let departure_time_millis = departure_time.timestamp_millis();
-                let period = arrival_time_millis % 86400000 / 600000;
-                period_counts
-                    .entry(period)
-                    .and_modify(|c| *c += 1)
-                    .or_insert(1);
-
-                // Prepared query
-                // Check order is the same as declared in insert_into_index
-                insert_into_index.execute(params![
-                    stop_id,
-                    stop_sequence,
-                    trip_id,
-                    trip_run_id,
-                    arrival_time_millis,
-                    departure_time_millis,
-                ])?;
-
-                count.log();
+                    for stop in run.stops {
+                        count_period(&mut period_counts, run.timezone, stop.arrival_millis);
+
+                        // Prepared query
+                        // Check order is the same as declared in insert_into_index
+                        insert_into_index.execute(params![
+                            stop.stop_id,
+                            stop.stop_sequence,
+                            run.trip_id,
+                            trip_run_id,
+                            stop.arrival_millis,
+                            stop.departure_millis,
+                        ])?;
+
+                        count.log();
+
+                        row_written(progress)?;
+                    }
+                }
             }
 
-            date = date.succ_opt().unwrap();
-        }
+            Ok::<_, Error>(())
+        })?;
 
-        // find ideal maintenance time
-        // we just choose a time slot with the least stop times
-        let min_period = period_counts
-            .iter()
-            .min_by_key(|(_, &count)| count)
-            .expect("No periods. Not initialised?");
+        // A partial or incremental build leaves the maintenance window and indexes as they were
+        if full {
+            // find ideal maintenance time
+            // we just choose a time slot with the least stop times
+            let min_period = period_counts
+                .iter()
+                .min_by_key(|(_, &count)| count)
+                .expect("No periods. Not initialised?");
+            let timezone = timezone_counts
+                .into_iter()
+                .max_by_key(|(_, count)| *count)
+                .map_or("UTC", |(timezone, _)| timezone);
 
-        Query::insert()
-            .into_table(maintenance_time::Entity)
-            .columns([
-                maintenance_time::Column::Id,
-                maintenance_time::Column::MinuteOfDay,
-            ])
-            .values_panic([1.into(), (min_period.0 * 10).into()])
-            .on_conflict(
-                OnConflict::column(maintenance_time::Column::Id)
-                    .update_column(maintenance_time::Column::MinuteOfDay)
-                    .to_owned(),
-            )
-            .prepare(&tx)?
-            .execute()?;
+            Query::insert()
+                .into_table(maintenance_time::Entity)
+                .columns([
+                    maintenance_time::Column::Id,
+                    maintenance_time::Column::MinuteOfDay,
+                    maintenance_time::Column::Timezone,
+                ])
+                .values_panic([1.into(), (min_period.0 * 10).into(), timezone.into()])
+                .on_conflict(
+                    OnConflict::column(maintenance_time::Column::Id)
+                        .update_columns([
+                            maintenance_time::Column::MinuteOfDay,
+                            maintenance_time::Column::Timezone,
+                        ])
+                        .to_owned(),
+                )
+                .prepare(tx)?
+                .execute()?;
+
+            tracing::info!("Swapping in the new stop index");
+            swap_in_new_tables(tx, &["stop_time_index", "trip_run"], &index_sqls)?;
+        }
 
-        log::info!("Re-creating indexes");
-        tx.execute_batch(&Sql000004StopTimeIndexIndexes::up_sql())?;
+        // Everything changed has been rebuilt
+        if !partial {
+            clear_changes(tx)?;
+        }
     }
-    log::info!("Committing transaction");
-    tx.commit()?;
+    tracing::info!("Committing transaction");
+    db.execute_batch("COMMIT")?;
 
     Ok(())
 }
 
-pub async fn build_stop_time_index() -> Result<()> {
+/// Removes the index for a date so it can be rebuilt.
+/// Returns false if the date is already indexed and `force` isn't set.
+fn clear_date(tx: &rusqlite::Connection, gtfs_date: &str, force: bool) -> Result<bool> {
+    let indexed: i64 = Query::select()
+        .expr(Expr::col(trip_run::Column::Id).count())
+        .from(TripRun)
+        .and_where(trip_run::Column::StartDate.eq(gtfs_date))
+        .prepare(tx)?
+        .query_row(|r| r.get(0))?;
+
+    if indexed == 0 {
+        return Ok(true);
+    }
+    if !force {
+        return Ok(false);
+    }
+
+    Query::delete()
+        .from_table(StopTimeIndex)
+        .and_where(
+            stop_time_index::Column::TripRunId.in_subquery(
+                Query::select()
+                    .column(trip_run::Column::Id)
+                    .from(TripRun)
+                    .and_where(trip_run::Column::StartDate.eq(gtfs_date))
+                    .to_owned(),
+            ),
+        )
+        .prepare(tx)?
+        .execute()?;
+
+    Query::delete()
+        .from_table(TripRun)
+        .and_where(trip_run::Column::StartDate.eq(gtfs_date))
+        .prepare(tx)?
+        .execute()?;
+
+    Ok(true)
+}
+
+#[tracing::instrument(skip_all, fields(incremental = options.incremental))]
+pub async fn build_stop_time_index(
+    config: &IndexConfig,
+    options: IndexOptions,
+    progress: Arc<IndexProgress>,
+) -> Result<()> {
+    progress.started();
+
     // Uses rusqlite directly in a background thread
     // This is much faster than going through the orm async layers
-    tokio::task::spawn_blocking(do_build_stop_time_index)
-        .await
-        .unwrap() // spawn result
+    let config = config.clone();
+    let build_progress = progress.clone();
+    let span = Span::current();
+    let result = tokio::task::spawn_blocking(move || {
+        span.in_scope(|| do_build_stop_time_index(&config, options, &build_progress))
+    })
+    .await
+    .unwrap(); // spawn result
+
+    progress.finished(result.as_ref().err().map(|e| e.to_string()));
+    result
+}
+
+/// Indexes any days of the horizon that aren't yet, i.e. the next day when run daily.
+/// Past days are pruned by the realtime cleanup.
+pub async fn extend_stop_time_index(
+    config: &IndexConfig,
+    progress: Arc<IndexProgress>,
+) -> Result<()> {
+    build_stop_time_index(
+        config,
+        IndexOptions {
+            days: Some(config.days),
+            ..Default::default()
+        },
+        progress,
+    )
+    .await
 }
 
 fn do_build_stop_index() -> Result<()> {
@@ -353,23 +933,13 @@ fn do_build_stop_index() -> Result<()> {
 
     let tx = db.transaction()?;
     {
-        Query::delete()
-            .from_table(StopIndex)
-            .prepare(&tx)?
-            .execute()?;
+        // An R*Tree, so there's no entity for it
+        tx.execute("DELETE FROM stop_index", [])?;
 
-        let mut insert_index = Query::insert()
-            .into_table(StopIndex)
-            .columns([
-                stop_index::Column::StopId,
-                stop_index::Column::MinLat,
-                stop_index::Column::MaxLat,
-                stop_index::Column::MinLon,
-                stop_index::Column::MaxLon,
-            ])
-            .values_panic([null(), null(), null(), null(), null()]) // placeholders
-            .prepare(&tx)?
-            .into_inner();
+        let mut insert_index = tx.prepare(
+            "INSERT INTO stop_index (stop_id, min_lat, max_lat, min_lon, max_lon)
+            VALUES (?1, ?2, ?3, ?4, ?5)",
+        )?;
 
         let mut stops_query = GtfsStops::find()
             .select_only()
@@ -381,24 +951,43 @@ fn do_build_stop_index() -> Result<()> {
             .into_query()
             .prepare(&tx)?;
         let mut stops_rows = stops_query.query()?;
+        let mut points = vec![];
 
         while let Some(stop) = stops_rows.next()? {
             let stop_id: String = stop.get(0)?;
             let lat = stop.get(1)?;
             let lon = stop.get(2)?;
 
-            let bounding_box = get_bounding_box(Point::new(lon, lat), SEARCH_DISTANCE_METRES);
+            let point = Point::new(lon, lat);
+            points.push(point);
+
+            let bounding_box = get_bounding_box(point, SEARCH_DISTANCE_METRES);
             let min = bounding_box.min();
             let max = bounding_box.max();
 
             insert_index.execute(params![stop_id, min.y, max.y, min.x, max.x,])?;
         }
+
+        // The area stops are in, so it's known when a location is too far from any
+        tx.execute("DELETE FROM service_area", [])?;
+        if !points.is_empty() {
+            let hull = MultiPoint::from(points).convex_hull();
+            let hull = encode_polyline(
+                &hull.exterior().points().collect_vec(),
+                SERVICE_AREA_PRECISION,
+            );
+            tx.execute(
+                "INSERT INTO service_area (id, hull) VALUES (1, ?1)",
+                params![hull],
+            )?;
+        }
     }
     tx.commit()?;
 
     Ok(())
 }
 
+#[tracing::instrument]
 pub async fn build_stop_index() -> Result<()> {
     tokio::task::spawn_blocking(do_build_stop_index)
         .await
@@ -429,4 +1018,28 @@ mod tests {
         println!("{}", query2.build(DbBackend::Sqlite));
         todo!()
     }
+
+    #[test]
+    fn test_new_table_keeps_ids() {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE run (id INTEGER PRIMARY KEY AUTOINCREMENT, trip_id TEXT);
+            INSERT INTO run (trip_id) VALUES ('a'), ('b');
+            DELETE FROM run;",
+        )
+        .unwrap();
+
+        let index_sqls = create_new_table(&conn, "run").unwrap();
+        conn.execute(
+            &format!("INSERT INTO {} (trip_id) VALUES ('c')", new_table("run")),
+            [],
+        )
+        .unwrap();
+        swap_in_new_tables(&conn, &["run"], &index_sqls).unwrap();
+
+        let id: i64 = conn
+            .query_row("SELECT id FROM run WHERE trip_id = 'c'", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(id, 3);
+    }
 }
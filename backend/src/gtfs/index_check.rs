@@ -0,0 +1,230 @@
+//! Cross-checks the stop time index against the static tables, e.g. after a build was interrupted.
+//! Only scheduled trip runs are checked, realtime changes to them aren't expected to match.
+
+use chrono::{Duration, NaiveDate, Utc};
+use rusqlite::{Connection, ToSql};
+use serde::Serialize;
+
+use crate::db;
+
+use super::index::Error;
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Number of indexed dates checked, if not set in the request
+pub const DEFAULT_CHECK_DAYS: usize = 3;
+
+/// How many offending trips are included in a problem
+const MAX_EXAMPLES: usize = 5;
+
+/// A check of the index for a date.
+/// Each query selects the trip id of every offending run, given the date as YYYYMMDD (`?1`)
+/// and the earliest (`?2`) and latest (`?3`) times its runs could stop, in millis.
+struct Check {
+    name: &'static str,
+    description: &'static str,
+    sql: &'static str,
+}
+
+const CHECKS: [Check; 5] = [
+    Check {
+        name: "missing_trips",
+        description: "trips running on the date without a run in the index",
+        sql: "SELECT t.trip_id FROM gtfs_trips t
+            JOIN service_dates sd ON sd.service_id = t.service_id
+            WHERE sd.date = CAST(?1 AS INTEGER)
+            AND EXISTS (SELECT 1 FROM gtfs_stop_times st WHERE st.trip_id = t.trip_id)
+            AND t.trip_id NOT IN (SELECT trip_id FROM trip_run WHERE start_date = ?1)",
+    },
+    Check {
+        name: "unexpected_trips",
+        description: "trip runs for trips not running on the date",
+        sql: "SELECT tr.trip_id FROM trip_run tr
+            WHERE tr.start_date = ?1 AND tr.schedule_relationship = 0
+            AND tr.trip_id NOT IN (
+                SELECT t.trip_id FROM gtfs_trips t
+                JOIN service_dates sd ON sd.service_id = t.service_id
+                WHERE sd.date = CAST(?1 AS INTEGER)
+            )",
+    },
+    Check {
+        name: "stop_counts",
+        description: "trip runs with a different number of stop times to their trip",
+        sql: "SELECT tr.trip_id FROM trip_run tr
+            WHERE tr.start_date = ?1 AND tr.schedule_relationship = 0
+            AND (SELECT COUNT(*) FROM stop_time_index sti WHERE sti.trip_run_id = tr.id)
+                != (SELECT COUNT(*) FROM gtfs_stop_times st WHERE st.trip_id = tr.trip_id)",
+    },
+    Check {
+        name: "stop_order",
+        description: "trip runs with times going backwards or repeated stop sequences",
+        sql: "SELECT DISTINCT trip_id FROM (
+                SELECT sti.trip_id, sti.stop_sequence, sti.arrival_timestamp,
+                    sti.departure_timestamp,
+                    LAG(sti.stop_sequence) OVER run AS previous_sequence,
+                    LAG(sti.departure_timestamp) OVER run AS previous_departure
+                FROM stop_time_index sti
+                JOIN trip_run tr ON tr.id = sti.trip_run_id
+                WHERE tr.start_date = ?1 AND tr.schedule_relationship = 0
+                WINDOW run AS (PARTITION BY sti.trip_run_id ORDER BY sti.stop_sequence)
+            )
+            WHERE stop_sequence = previous_sequence
+            OR arrival_timestamp < previous_departure
+            OR departure_timestamp < arrival_timestamp",
+    },
+    Check {
+        name: "service_day",
+        description: "trip runs with times outside the service day",
+        sql: "SELECT DISTINCT sti.trip_id FROM stop_time_index sti
+            JOIN trip_run tr ON tr.id = sti.trip_run_id
+            WHERE tr.start_date = ?1 AND tr.schedule_relationship = 0
+            AND (
+                sti.arrival_timestamp NOT BETWEEN ?2 AND ?3
+                OR sti.departure_timestamp NOT BETWEEN ?2 AND ?3
+            )",
+    },
+];
+
+/// Something wrong with the index for a date
+#[derive(Debug, Clone, Serialize)]
+pub struct IndexProblem {
+    /// YYYYMMDD
+    pub date: String,
+    pub check: &'static str,
+    pub description: &'static str,
+    pub count: usize,
+    /// Up to a few of the offending trip ids
+    pub examples: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct IndexCheck {
+    /// YYYYMMDD
+    pub dates: Vec<String>,
+    /// Scheduled trip runs on the dates
+    pub trip_runs: i64,
+    pub problems: Vec<IndexProblem>,
+}
+
+/// Up to `count` of the dates, spread evenly from the first to the last
+fn sample_dates(dates: &[String], count: usize) -> Vec<String> {
+    if dates.len() <= count {
+        return dates.to_vec();
+    }
+    if count == 1 {
+        return dates[..1].to_vec();
+    }
+    (0..count)
+        .map(|i| dates[i * (dates.len() - 1) / (count - 1)].clone())
+        .collect()
+}
+
+/// The earliest and latest a run starting on the date could stop, in millis.
+/// GTFS times can run past midnight into the next day, and are in the agency's timezone,
+/// so this allows for any offset from UTC.
+fn service_day_bounds(date: &str) -> Result<(i64, i64)> {
+    let date = NaiveDate::parse_from_str(date, "%Y%m%d")
+        .map_err(|e| Error::Other(format!("Invalid indexed date {}: {}", date, e)))?;
+    let midnight = date.and_hms_opt(0, 0, 0).unwrap().and_utc();
+    let earliest = midnight - Duration::try_hours(14).unwrap();
+    let latest = midnight + Duration::try_hours(48 + 12).unwrap();
+    Ok((earliest.timestamp_millis(), latest.timestamp_millis()))
+}
+
+fn do_check_stop_time_index(days: usize) -> Result<IndexCheck> {
+    let db = db::util::open_rusqlite()?;
+
+    // Past days are partly removed by the realtime cleanup, so wouldn't match
+    let today = Utc::now().format("%Y%m%d").to_string();
+    let indexed_dates = db
+        .prepare(
+            "SELECT DISTINCT start_date FROM trip_run
+            WHERE start_date >= ?1 AND schedule_relationship = 0
+            ORDER BY start_date",
+        )?
+        .query_map([&today], |r| r.get::<_, String>(0))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    let dates = sample_dates(&indexed_dates, days);
+
+    let mut trip_runs = 0;
+    let mut problems = vec![];
+    for date in &dates {
+        tracing::info!("Checking stop index for {}", date);
+        trip_runs += db.query_row(
+            "SELECT COUNT(*) FROM trip_run WHERE start_date = ?1 AND schedule_relationship = 0",
+            [date],
+            |r| r.get::<_, i64>(0),
+        )?;
+        problems.extend(check_date(&db, date)?);
+    }
+
+    for problem in &problems {
+        tracing::warn!(
+            "Stop index for {} has {} {} ({}), e.g. {}",
+            problem.date,
+            problem.count,
+            problem.description,
+            problem.check,
+            problem.examples.join(", ")
+        );
+    }
+
+    Ok(IndexCheck {
+        dates,
+        trip_runs,
+        problems,
+    })
+}
+
+fn check_date(db: &Connection, date: &str) -> Result<Vec<IndexProblem>> {
+    let (earliest, latest) = service_day_bounds(date)?;
+    let params: [&dyn ToSql; 3] = [&date, &earliest, &latest];
+
+    let mut problems = vec![];
+    for check in &CHECKS {
+        let mut statement = db.prepare(check.sql)?;
+        // Not every check uses the bounds, and binding unused parameters is an error
+        let params = &params[..statement.parameter_count()];
+        let trip_ids = statement
+            .query_map(params, |r| r.get::<_, String>(0))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        if trip_ids.is_empty() {
+            continue;
+        }
+        problems.push(IndexProblem {
+            date: date.to_string(),
+            check: check.name,
+            description: check.description,
+            count: trip_ids.len(),
+            examples: trip_ids.into_iter().take(MAX_EXAMPLES).collect(),
+        });
+    }
+
+    Ok(problems)
+}
+
+/// Checks a sample of `days` indexed dates from today, returning any problems found
+pub async fn check_stop_time_index(days: usize) -> Result<IndexCheck> {
+    tokio::task::spawn_blocking(move || do_check_stop_time_index(days))
+        .await
+        .unwrap() // spawn result
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn test_sample_dates() {
+        let dates = (1..=9).map(|d| format!("2024010{}", d)).collect::<Vec<_>>();
+
+        assert_eq!(
+            sample_dates(&dates, 3),
+            vec!["20240101", "20240105", "20240109"]
+        );
+        assert_eq!(sample_dates(&dates, 1), vec!["20240101"]);
+        assert_eq!(sample_dates(&dates[..2], 3), vec!["20240101", "20240102"]);
+    }
+}
@@ -1,5 +1,16 @@
+pub mod changes;
+pub mod diff;
+pub mod download;
+pub mod feed;
+pub mod feed_version;
+pub mod imports;
 pub mod index;
+pub mod index_check;
+pub mod progress;
 pub mod realtime;
+pub mod service_dates;
+pub mod shadow;
 pub mod structure;
 pub mod sync;
+pub mod validate;
 mod utils;
@@ -0,0 +1,218 @@
+use std::sync::{
+    atomic::{AtomicBool, AtomicU64, Ordering},
+    Mutex,
+};
+
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::Serialize;
+
+/// What a GTFS sync is currently doing
+#[derive(Debug, Serialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncPhase {
+    #[default]
+    Idle,
+    Downloading,
+    Extracting,
+    Validating,
+    Importing,
+    FindingChanges,
+    SwappingTables,
+    BuildingServiceTable,
+    RemovingOldFeeds,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct FileProgress {
+    pub file: String,
+    /// Only known once the file is done
+    pub rows: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Clone, Default)]
+pub struct SyncStatus {
+    pub phase: SyncPhase,
+    pub phase_started: Option<DateTime<Utc>>,
+    /// The feed being synced
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub feed_id: Option<String>,
+    pub started: Option<DateTime<Utc>>,
+    pub finished: Option<DateTime<Utc>>,
+    pub bytes_downloaded: u64,
+    /// If the server says how big the file is
+    pub bytes_total: Option<u64>,
+    /// Files imported so far for the feed, the last is in progress if it has no rows
+    pub files: Vec<FileProgress>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+}
+
+fn log_phase(status: &SyncStatus) {
+    if let Some(started) = status.phase_started {
+        tracing::info!(
+            phase = ?status.phase,
+            elapsed_ms = (Utc::now() - started).num_milliseconds(),
+            "Sync phase finished"
+        );
+    }
+}
+
+/// Progress of the running (or last) sync, safe to share between tasks
+#[derive(Debug, Default)]
+pub struct SyncProgress(Mutex<SyncStatus>);
+
+impl SyncProgress {
+    pub fn get(&self) -> SyncStatus {
+        self.0.lock().unwrap().clone()
+    }
+
+    fn update(&self, f: impl FnOnce(&mut SyncStatus)) {
+        f(&mut self.0.lock().unwrap());
+    }
+
+    pub fn started(&self) {
+        self.update(|s| {
+            *s = SyncStatus {
+                started: Some(Utc::now()),
+                ..Default::default()
+            }
+        });
+    }
+
+    /// The download and file progress is reset for each feed
+    pub fn feed_started(&self, feed_id: &str) {
+        self.update(|s| {
+            s.feed_id = Some(feed_id.to_string());
+            s.bytes_downloaded = 0;
+            s.bytes_total = None;
+            s.files.clear();
+        });
+    }
+
+    /// Each phase is logged as it finishes, with how long it took
+    pub fn phase(&self, phase: SyncPhase) {
+        self.update(|s| {
+            log_phase(s);
+            s.phase = phase;
+            s.phase_started = Some(Utc::now());
+        });
+    }
+
+    pub fn downloaded(&self, bytes: u64, total: Option<u64>) {
+        self.update(|s| {
+            s.bytes_downloaded = bytes;
+            s.bytes_total = total;
+        });
+    }
+
+    pub fn file_started(&self, file: &str) {
+        self.update(|s| {
+            s.files.push(FileProgress {
+                file: file.to_string(),
+                rows: None,
+            })
+        });
+    }
+
+    pub fn file_done(&self, rows: u64) {
+        self.update(|s| {
+            if let Some(file) = s.files.last_mut() {
+                file.rows = Some(rows);
+            }
+        });
+    }
+
+    pub fn finished(&self, error: Option<String>) {
+        self.update(|s| {
+            log_phase(s);
+            s.phase = SyncPhase::Idle;
+            s.phase_started = None;
+            s.finished = Some(Utc::now());
+            s.last_error = error;
+        });
+    }
+}
+
+#[derive(Debug, Serialize, Clone, Default)]
+pub struct IndexStatus {
+    pub running: bool,
+    /// The date being indexed
+    pub date: Option<NaiveDate>,
+    /// Stop times written so far
+    pub rows_written: u64,
+    pub started: Option<DateTime<Utc>>,
+    pub finished: Option<DateTime<Utc>>,
+    /// Seconds since the build started, or that it took
+    pub elapsed_secs: Option<i64>,
+    /// Whether the last build was stopped by a cancellation
+    pub cancelled: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+}
+
+/// Progress of the running (or last) stop time index build, which can be asked to stop
+#[derive(Debug, Default)]
+pub struct IndexProgress {
+    status: Mutex<IndexStatus>,
+    /// Counted outside the lock, it's updated for every row
+    rows_written: AtomicU64,
+    cancel: AtomicBool,
+}
+
+impl IndexProgress {
+    pub fn get(&self) -> IndexStatus {
+        let mut status = self.status.lock().unwrap().clone();
+        status.rows_written = self.rows_written.load(Ordering::Relaxed);
+        status.elapsed_secs = status
+            .started
+            .map(|started| (status.finished.unwrap_or_else(Utc::now) - started).num_seconds());
+        status
+    }
+
+    fn update(&self, f: impl FnOnce(&mut IndexStatus)) {
+        f(&mut self.status.lock().unwrap());
+    }
+
+    pub fn started(&self) {
+        self.cancel.store(false, Ordering::Relaxed);
+        self.rows_written.store(0, Ordering::Relaxed);
+        self.update(|s| {
+            *s = IndexStatus {
+                running: true,
+                started: Some(Utc::now()),
+                ..Default::default()
+            }
+        });
+    }
+
+    pub fn date(&self, date: NaiveDate) {
+        self.update(|s| s.date = Some(date));
+    }
+
+    pub fn row_written(&self) {
+        self.rows_written.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn finished(&self, error: Option<String>) {
+        let cancelled = self.is_cancelled();
+        self.update(|s| {
+            s.running = false;
+            s.finished = Some(Utc::now());
+            s.cancelled = cancelled;
+            s.last_error = error;
+        });
+    }
+
+    /// Asks the running build to stop. Returns false if there isn't one.
+    pub fn cancel(&self) -> bool {
+        if !self.status.lock().unwrap().running {
+            return false;
+        }
+        self.cancel.store(true, Ordering::Relaxed);
+        true
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel.load(Ordering::Relaxed)
+    }
+}
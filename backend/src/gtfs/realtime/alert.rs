@@ -1,47 +1,149 @@
 use std::ops::Add;
 
 use crate::entity::alert_active_period;
-use crate::entity::{alert, alert_informed_entity};
+use crate::entity::{alert, alert_image, alert_informed_entity, alert_translation};
 use crate::gtfs::realtime::utils::find_trip_run;
-use crate::gtfs::structure::realtime::FeedEntity;
+use crate::gtfs::structure::realtime::{Alert, FeedEntity, TimeRange};
+use crate::translations::Languages;
 use chrono::Utc;
 use sea_orm::ActiveValue::NotSet;
 use sea_orm::QueryTrait;
 use sea_orm::{
-    ActiveModelTrait, ColumnTrait, DatabaseTransaction, EntityTrait, QueryFilter, QuerySelect, Set,
-    TransactionTrait,
+    ActiveModelTrait, ColumnTrait, ConnectionTrait, DatabaseTransaction, EntityTrait, QueryFilter,
+    QuerySelect, Set, TransactionTrait,
 };
+use sha2::{Digest, Sha256};
 
 use super::error::RtResult;
 
-pub async fn process_alert(tx: &DatabaseTransaction, entity: FeedEntity) -> RtResult<()> {
+/// Identifies what the feed said of an alert, so it's only rewritten when that changes.
+/// Also covers the language, which decides the text that's kept.
+fn content_hash(alert: &Alert, language: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(language.as_bytes());
+    hasher.update(format!("{:?}", alert).as_bytes());
+    format!("{:x}", hasher.finalize())
+}
+
+/// Stores the alert, or updates the one with the same id if it's changed.
+/// Its text is kept in `language`, with every translation in alert_translation.
+pub async fn process_alert(
+    tx: &DatabaseTransaction,
+    entity: FeedEntity,
+    language: &str,
+) -> RtResult<()> {
     let alert = entity.alert.expect("Expected alert to be set");
 
     use alert::*;
 
+    let hash = content_hash(&alert, language);
+    let languages = Languages::new([language]);
+    let now = Utc::now().timestamp_millis();
+
     let sp = tx.begin().await?;
 
+    let existing = alert::Entity::find()
+        .filter(Column::AlertId.eq(entity.id.clone()))
+        .one(tx)
+        .await?;
+    if existing
+        .as_ref()
+        .is_some_and(|a| a.content_hash.as_deref() == Some(hash.as_str()))
+    {
+        // Periods without an end only last until tomorrow, so need pushing back
+        let active_periods = alert.active_period.map(Vec::from).unwrap_or_default();
+        if active_periods.iter().any(|p| p.end.is_none()) {
+            replace_active_periods(tx, &entity.id, active_periods).await?;
+        }
+        sp.commit().await?;
+        return Ok(());
+    }
+
     alert_informed_entity::Entity::delete_many()
         .filter(alert_informed_entity::Column::AlertId.eq(entity.id.clone()))
         .exec(tx)
         .await?;
 
-    alert::Entity::delete_many()
-        .filter(Column::AlertId.eq(entity.id.clone()))
+    alert_translation::Entity::delete_many()
+        .filter(alert_translation::Column::AlertId.eq(entity.id.clone()))
+        .exec(tx)
+        .await?;
+
+    alert_image::Entity::delete_many()
+        .filter(alert_image::Column::AlertId.eq(entity.id.clone()))
         .exec(tx)
         .await?;
 
-    alert::ActiveModel {
+    let model = alert::ActiveModel {
         id: NotSet,
         alert_id: Set(Some(entity.id.clone())),
         cause: Set(alert.cause.map(|c| c as i32)),
         effect: Set(alert.effect.map(|e| e as i32)),
-        header_text: Set(alert.header_text.map(|t| t.get("en")).flatten()),
-        description_text: Set(alert.description_text.map(|t| t.get("en")).flatten()),
-        timestamp: Set(Some(Utc::now().timestamp_millis())),
+        header_text: Set(alert
+            .header_text
+            .as_ref()
+            .and_then(|t| t.get_preferred(&languages))),
+        description_text: Set(alert
+            .description_text
+            .as_ref()
+            .and_then(|t| t.get_preferred(&languages))),
+        severity_level: Set(alert.severity_level.map(|s| s as i32)),
+        url: Set(alert.url.as_ref().and_then(|t| t.get_preferred(&languages))),
+        image_alternative_text: Set(alert
+            .image_alternative_text
+            .as_ref()
+            .and_then(|t| t.get_preferred(&languages))),
+        content_hash: Set(Some(hash)),
+        created_timestamp: Set(Some(now)),
+        updated_timestamp: Set(Some(now)),
+    };
+    match existing {
+        // Keeps its id and when it was first seen
+        Some(existing) => {
+            alert::ActiveModel {
+                id: Set(existing.id),
+                created_timestamp: NotSet,
+                ..model
+            }
+            .update(tx)
+            .await?;
+        }
+        None => {
+            model.insert(tx).await?;
+        }
+    }
+
+    let texts = [
+        ("header_text", &alert.header_text),
+        ("description_text", &alert.description_text),
+        ("url", &alert.url),
+        ("image_alternative_text", &alert.image_alternative_text),
+    ];
+    for (field, text) in texts {
+        for translation in text.iter().flat_map(|t| t.translations()) {
+            alert_translation::ActiveModel {
+                id: NotSet,
+                alert_id: Set(entity.id.clone()),
+                field: Set(field.to_string()),
+                language: Set(translation.language.clone()),
+                text: Set(translation.text.clone()),
+            }
+            .insert(tx)
+            .await?;
+        }
+    }
+
+    for image in alert.image.iter().flat_map(|i| i.localized_images()) {
+        alert_image::ActiveModel {
+            id: NotSet,
+            alert_id: Set(entity.id.clone()),
+            url: Set(image.url.clone()),
+            media_type: Set(image.media_type.clone()),
+            language: Set(image.language.clone()),
+        }
+        .insert(tx)
+        .await?;
     }
-    .insert(tx)
-    .await?;
 
     if let Some(entities) = alert.informed_entity {
         for informed in entities {
@@ -65,30 +167,59 @@ pub async fn process_alert(tx: &DatabaseTransaction, entity: FeedEntity) -> RtRe
         }
     }
 
-    if let Some(active_periods) = alert.active_period {
-        for active_period in active_periods {
-            let start = active_period.start.unwrap_or(0);
-            // technically until the end of time if set but we'll assume tomorrow
-            let end = active_period.end.unwrap_or_else(|| {
-                Utc::now().add(chrono::Duration::days(1)).timestamp_millis() as u64
-            });
+    let active_periods = alert.active_period.map(Vec::from).unwrap_or_default();
+    replace_active_periods(tx, &entity.id, active_periods).await?;
 
-            alert_active_period::ActiveModel {
-                id: NotSet,
-                alert_id: Set(entity.id.clone()),
-                start_timestamp: Set(start as i64),
-                end_timestamp: Set(end as i64),
-            }
-            .insert(tx)
-            .await?;
+    sp.commit().await?;
+
+    Ok(())
+}
+
+async fn replace_active_periods(
+    tx: &DatabaseTransaction,
+    alert_id: &str,
+    active_periods: Vec<TimeRange>,
+) -> RtResult<()> {
+    alert_active_period::Entity::delete_many()
+        .filter(alert_active_period::Column::AlertId.eq(alert_id))
+        .exec(tx)
+        .await?;
+
+    for active_period in active_periods {
+        let start = active_period.start.unwrap_or(0);
+        // technically until the end of time if set but we'll assume tomorrow
+        let end = active_period
+            .end
+            .unwrap_or_else(|| Utc::now().add(chrono::Duration::days(1)).timestamp_millis() as u64);
+
+        alert_active_period::ActiveModel {
+            id: NotSet,
+            alert_id: Set(alert_id.to_string()),
+            start_timestamp: Set(start as i64),
+            end_timestamp: Set(end as i64),
         }
+        .insert(tx)
+        .await?;
     }
 
-    sp.commit().await?;
-
     Ok(())
 }
 
+/// Those of the alerts that are stored
+pub async fn find_alerts(
+    db: &impl ConnectionTrait,
+    alert_ids: &[String],
+) -> RtResult<Vec<alert::Model>> {
+    if alert_ids.is_empty() {
+        return Ok(vec![]);
+    }
+    let alerts = alert::Entity::find()
+        .filter(alert::Column::AlertId.is_in(alert_ids.iter().map(String::as_str)))
+        .all(db)
+        .await?;
+    Ok(alerts)
+}
+
 pub async fn cleanup_alerts(tx: &DatabaseTransaction) -> RtResult<()> {
     let sp = tx.begin().await?;
 
@@ -113,3 +244,61 @@ pub async fn cleanup_alerts(tx: &DatabaseTransaction) -> RtResult<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+
+    use sea_orm::DatabaseConnection;
+    use serde_json::json;
+
+    use super::*;
+    use crate::test_utils::ctx;
+
+    async fn process(db: &DatabaseConnection, header: &str) -> alert::Model {
+        let entity: FeedEntity = serde_json::from_value(json!({
+            "id": "detour",
+            "alert": {
+                "active_period": [{"start": 0}],
+                "informed_entity": [{"route_id": "NX1-203"}],
+                "effect": "DETOUR",
+                "header_text": {"translation": [{"text": header, "language": "en"}]}
+            }
+        }))
+        .unwrap();
+        let tx = db.begin().await.unwrap();
+        process_alert(&tx, entity, "en").await.unwrap();
+        tx.commit().await.unwrap();
+
+        find_alerts(db, &["detour".to_string()])
+            .await
+            .unwrap()
+            .remove(0)
+    }
+
+    #[tokio::test]
+    async fn test_process_alert() {
+        let ctx = ctx().await;
+
+        let first = process(&ctx.db, "Northern Express detoured").await;
+        assert_eq!(first.created_timestamp, first.updated_timestamp);
+
+        // The same again isn't rewritten
+        let same = process(&ctx.db, "Northern Express detoured").await;
+        assert_eq!(same, first);
+
+        let changed = process(&ctx.db, "Northern Express detoured via Fanshawe St").await;
+        assert_eq!(changed.id, first.id);
+        assert_eq!(changed.created_timestamp, first.created_timestamp);
+        assert_ne!(changed.content_hash, first.content_hash);
+        assert_eq!(
+            changed.header_text.as_deref(),
+            Some("Northern Express detoured via Fanshawe St")
+        );
+        let informed = alert_informed_entity::Entity::find()
+            .filter(alert_informed_entity::Column::AlertId.eq("detour"))
+            .all(&ctx.db)
+            .await
+            .unwrap();
+        assert_eq!(informed.len(), 1);
+    }
+}
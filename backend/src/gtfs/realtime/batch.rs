@@ -0,0 +1,359 @@
+use std::collections::HashMap;
+
+use sea_orm::sea_query::{Alias, Expr, Func, OnConflict};
+use sea_orm::{ColumnTrait, ConnectionTrait, EntityTrait, IntoActiveModel, QueryFilter, Set};
+
+use super::error::RtResult;
+use crate::{
+    entity::{stop_time_index, trip_run, vehicle, vehicle_position_history},
+    gtfs::structure::realtime::trip_descriptor::ScheduleRelationship,
+};
+
+/// Rows per statement, well within SQLite's limit on bound parameters
+const CHUNK_SIZE: usize = 500;
+
+/// Whether the feed has taken the trip run off, by its schedule relationship
+fn is_cancelled(schedule_relationship: i32) -> bool {
+    schedule_relationship == ScheduleRelationship::Canceled as i32
+        || schedule_relationship == ScheduleRelationship::Deleted as i32
+}
+
+/// Changes accumulated while processing a feed.
+/// They're written together at the end, so each table gets a few multi-row statements
+/// instead of one (or more) per entity.
+#[derive(Debug, Default)]
+pub struct WriteBatch {
+    /// Vehicles a trip refers to, which need to exist for the foreign key
+    known_vehicles: HashMap<String, vehicle::ActiveModel>,
+    /// Vehicle positions, where unknown values keep what was there before
+    vehicles: HashMap<String, vehicle::ActiveModel>,
+    positions: Vec<vehicle_position_history::ActiveModel>,
+    trip_runs: HashMap<i64, trip_run::Model>,
+    /// Trip runs where only the vehicle has changed
+    vehicle_assignments: HashMap<i64, trip_run::Model>,
+    stop_times: HashMap<i64, stop_time_index::Model>,
+    /// Stop times where only the estimated times have changed
+    estimates: HashMap<i64, stop_time_index::Model>,
+    /// How many trip updates were older than what had already been applied
+    stale_trip_updates: usize,
+}
+
+impl WriteBatch {
+    pub fn ensure_vehicle(&mut self, vehicle_id: String, label: Option<String>, timestamp: i64) {
+        self.known_vehicles
+            .entry(vehicle_id.clone())
+            .or_insert(vehicle::ActiveModel {
+                vehicle_id: Set(vehicle_id),
+                label: Set(label),
+                timestamp: Set(timestamp),
+                ..Default::default()
+            });
+    }
+
+    /// The vehicle must have vehicle_id, label, license_plate, position, congestion_level
+    /// and timestamp set
+    pub fn upsert_vehicle(&mut self, vehicle_id: String, vehicle: vehicle::ActiveModel) {
+        self.vehicles.insert(vehicle_id, vehicle);
+    }
+
+    /// The position must have everything but the id set
+    pub fn record_position(&mut self, position: vehicle_position_history::ActiveModel) {
+        self.positions.push(position);
+    }
+
+    /// The trip run including any changes that haven't been written yet
+    pub fn pending_trip_run(&self, trip_run: trip_run::Model) -> trip_run::Model {
+        self.trip_runs
+            .get(&trip_run.id)
+            .or_else(|| self.vehicle_assignments.get(&trip_run.id))
+            .cloned()
+            .unwrap_or(trip_run)
+    }
+
+    /// The trip run should be from `pending_trip_run`, as it replaces any pending changes
+    pub fn update_trip_run(&mut self, trip_run: trip_run::Model) {
+        self.vehicle_assignments.remove(&trip_run.id);
+        self.trip_runs.insert(trip_run.id, trip_run);
+    }
+
+    /// Trip runs with changes that haven't been written yet that the vehicle is on
+    pub fn pending_vehicle_trip_runs(&self, vehicle_id: &str) -> Vec<trip_run::Model> {
+        self.trip_runs
+            .values()
+            .chain(self.vehicle_assignments.values())
+            .filter(|tr| tr.vehicle_id.as_deref() == Some(vehicle_id))
+            .cloned()
+            .collect()
+    }
+
+    /// Changes the trip run's vehicle without touching anything else about it,
+    /// so it can't undo changes made elsewhere
+    fn set_vehicle(
+        &mut self,
+        mut trip_run: trip_run::Model,
+        vehicle_id: Option<String>,
+        timestamp: Option<i64>,
+    ) {
+        if let Some(pending) = self.trip_runs.get_mut(&trip_run.id) {
+            pending.vehicle_id = vehicle_id;
+            pending.vehicle_timestamp = timestamp;
+            return;
+        }
+        trip_run.vehicle_id = vehicle_id;
+        trip_run.vehicle_timestamp = timestamp;
+        self.vehicle_assignments.insert(trip_run.id, trip_run);
+    }
+
+    /// Puts the vehicle on the trip run, as it was reported to be at `timestamp`
+    pub fn assign_vehicle(
+        &mut self,
+        trip_run: trip_run::Model,
+        vehicle_id: String,
+        timestamp: i64,
+    ) {
+        self.set_vehicle(trip_run, Some(vehicle_id), Some(timestamp));
+    }
+
+    /// Takes the trip run's vehicle off it
+    pub fn detach_vehicle(&mut self, trip_run: trip_run::Model) {
+        self.set_vehicle(trip_run, None, None);
+    }
+
+    /// The stop times including any changes that haven't been written yet
+    pub fn pending_stop_times(
+        &self,
+        stop_times: Vec<stop_time_index::Model>,
+    ) -> Vec<stop_time_index::Model> {
+        stop_times
+            .into_iter()
+            .map(|st| self.stop_times.get(&st.id).cloned().unwrap_or(st))
+            .collect()
+    }
+
+    pub fn update_stop_time(&mut self, stop_time: stop_time_index::Model) {
+        self.stop_times.insert(stop_time.id, stop_time);
+    }
+
+    pub fn estimate_stop_time(&mut self, stop_time: stop_time_index::Model) {
+        self.estimates.insert(stop_time.id, stop_time);
+    }
+
+    pub fn skip_stale_trip_update(&mut self) {
+        self.stale_trip_updates += 1;
+    }
+
+    /// Throws away pending changes to a trip run, e.g. because it has been reset
+    pub fn discard_trip_run(&mut self, trip_run_id: i64) {
+        self.trip_runs.remove(&trip_run_id);
+        self.vehicle_assignments.remove(&trip_run_id);
+        self.stop_times
+            .retain(|_, st| st.trip_run_id != trip_run_id);
+    }
+
+    pub fn discard_vehicle(&mut self, vehicle_id: &str) {
+        self.known_vehicles.remove(vehicle_id);
+        self.vehicles.remove(vehicle_id);
+        self.positions
+            .retain(|p| p.vehicle_id.as_ref() != vehicle_id);
+    }
+
+    pub async fn flush(self, db: &impl ConnectionTrait) -> RtResult<()> {
+        tracing::debug!(
+            "Writing {} vehicles, {} trip runs, {} stop times",
+            self.known_vehicles.len() + self.vehicles.len(),
+            self.trip_runs.len() + self.vehicle_assignments.len(),
+            self.stop_times.len() + self.estimates.len()
+        );
+
+        if self.stale_trip_updates > 0 {
+            tracing::info!("Discarded {} stale trip updates", self.stale_trip_updates);
+        }
+
+        // vehicles first, as trip runs refer to them
+        let known_vehicles = self.known_vehicles.into_values().collect::<Vec<_>>();
+        for chunk in known_vehicles.chunks(CHUNK_SIZE) {
+            vehicle::Entity::insert_many(chunk.to_vec())
+                .on_conflict(
+                    OnConflict::column(vehicle::Column::VehicleId)
+                        .do_nothing()
+                        .to_owned(),
+                )
+                .exec_without_returning(db)
+                .await?;
+        }
+
+        let mut keep_unknown = OnConflict::column(vehicle::Column::VehicleId);
+        keep_unknown.update_column(vehicle::Column::Timestamp);
+        for column in [
+            vehicle::Column::Label,
+            vehicle::Column::LicensePlate,
+            vehicle::Column::Latitude,
+            vehicle::Column::Longitude,
+            vehicle::Column::Bearing,
+            vehicle::Column::Speed,
+            vehicle::Column::CongestionLevel,
+        ] {
+            keep_unknown.value(
+                column,
+                Func::coalesce([
+                    Expr::col((Alias::new("excluded"), column)).into(),
+                    Expr::col((vehicle::Entity, column)).into(),
+                ]),
+            );
+        }
+        let vehicles = self.vehicles.into_values().collect::<Vec<_>>();
+        for chunk in vehicles.chunks(CHUNK_SIZE) {
+            vehicle::Entity::insert_many(chunk.to_vec())
+                .on_conflict(keep_unknown.clone())
+                .exec_without_returning(db)
+                .await?;
+        }
+
+        for chunk in self.positions.chunks(CHUNK_SIZE) {
+            vehicle_position_history::Entity::insert_many(chunk.to_vec())
+                .on_conflict(
+                    OnConflict::columns([
+                        vehicle_position_history::Column::VehicleId,
+                        vehicle_position_history::Column::Timestamp,
+                    ])
+                    .do_nothing()
+                    .to_owned(),
+                )
+                .exec_without_returning(db)
+                .await?;
+        }
+
+        // The rows already exist, so these only ever update
+        let trip_runs = self.trip_runs.into_values().collect::<Vec<_>>();
+        for chunk in trip_runs.chunks(CHUNK_SIZE) {
+            trip_run::Entity::insert_many(chunk.iter().cloned().map(|tr| tr.into_active_model()))
+                .on_conflict(
+                    OnConflict::column(trip_run::Column::Id)
+                        .update_columns([
+                            trip_run::Column::ScheduleRelationship,
+                            trip_run::Column::VehicleId,
+                            trip_run::Column::VehicleTimestamp,
+                            trip_run::Column::LastUpdateTimestamp,
+                        ])
+                        .to_owned(),
+                )
+                .exec_without_returning(db)
+                .await?;
+        }
+
+        // Their stop times follow, so they can be read without joining trip_run
+        let (cancelled, running): (Vec<_>, Vec<_>) = trip_runs
+            .iter()
+            .partition(|tr| is_cancelled(tr.schedule_relationship));
+        for (trip_runs, cancelled) in [(cancelled, true), (running, false)] {
+            for chunk in trip_runs.chunks(CHUNK_SIZE) {
+                stop_time_index::Entity::update_many()
+                    .col_expr(
+                        stop_time_index::Column::Cancelled,
+                        Expr::value(cancelled as i32),
+                    )
+                    .filter(stop_time_index::Column::TripRunId.is_in(chunk.iter().map(|tr| tr.id)))
+                    .filter(stop_time_index::Column::Cancelled.ne(cancelled as i32))
+                    .exec(db)
+                    .await?;
+            }
+        }
+
+        let vehicle_assignments = self.vehicle_assignments.into_values().collect::<Vec<_>>();
+        for chunk in vehicle_assignments.chunks(CHUNK_SIZE) {
+            trip_run::Entity::insert_many(chunk.iter().cloned().map(|tr| tr.into_active_model()))
+                .on_conflict(
+                    OnConflict::column(trip_run::Column::Id)
+                        .update_columns([
+                            trip_run::Column::VehicleId,
+                            trip_run::Column::VehicleTimestamp,
+                        ])
+                        .to_owned(),
+                )
+                .exec_without_returning(db)
+                .await?;
+        }
+
+        let stop_times = self.stop_times.into_values().collect::<Vec<_>>();
+        for chunk in stop_times.chunks(CHUNK_SIZE) {
+            stop_time_index::Entity::insert_many(
+                chunk.iter().cloned().map(|st| st.into_active_model()),
+            )
+            .on_conflict(
+                OnConflict::column(stop_time_index::Column::Id)
+                    .update_columns([
+                        stop_time_index::Column::UpdatedArrivalTimestamp,
+                        stop_time_index::Column::UpdatedDepartureTimestamp,
+                        stop_time_index::Column::Skipped,
+                        stop_time_index::Column::UpdatedStopId,
+                    ])
+                    .to_owned(),
+            )
+            .exec_without_returning(db)
+            .await?;
+        }
+
+        let estimates = self.estimates.into_values().collect::<Vec<_>>();
+        for chunk in estimates.chunks(CHUNK_SIZE) {
+            stop_time_index::Entity::insert_many(
+                chunk.iter().cloned().map(|st| st.into_active_model()),
+            )
+            .on_conflict(
+                OnConflict::column(stop_time_index::Column::Id)
+                    .update_columns([
+                        stop_time_index::Column::EstimatedArrivalTimestamp,
+                        stop_time_index::Column::EstimatedDepartureTimestamp,
+                    ])
+                    .to_owned(),
+            )
+            .exec_without_returning(db)
+            .await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use sea_orm::QuerySelect;
+
+    use super::*;
+    use crate::test_utils::ctx;
+
+    #[tokio::test]
+    async fn test_cancelled_stop_times() {
+        let ctx = ctx().await;
+        let cancelled = || async {
+            stop_time_index::Entity::find()
+                .select_only()
+                .column(stop_time_index::Column::Cancelled)
+                .filter(stop_time_index::Column::TripRunId.eq(1))
+                .into_tuple::<i32>()
+                .all(&ctx.db)
+                .await
+                .unwrap()
+        };
+        let mut trip_run = trip_run::Entity::find_by_id(1)
+            .one(&ctx.db)
+            .await
+            .unwrap()
+            .unwrap();
+
+        trip_run.schedule_relationship = ScheduleRelationship::Canceled as i32;
+        let mut batch = WriteBatch::default();
+        batch.update_trip_run(trip_run.clone());
+        batch.flush(&ctx.db).await.unwrap();
+        let stop_times = cancelled().await;
+        assert!(!stop_times.is_empty());
+        assert!(stop_times.iter().all(|c| *c == 1));
+
+        // Reinstated
+        trip_run.schedule_relationship = ScheduleRelationship::Scheduled as i32;
+        let mut batch = WriteBatch::default();
+        batch.update_trip_run(trip_run);
+        batch.flush(&ctx.db).await.unwrap();
+        assert!(cancelled().await.iter().all(|c| *c == 0));
+    }
+}
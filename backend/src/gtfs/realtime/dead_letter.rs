@@ -0,0 +1,187 @@
+use std::collections::HashMap;
+
+use chrono::Utc;
+use sea_orm::sea_query::{Expr, OnConflict};
+use sea_orm::{
+    ColumnTrait, ConnectionTrait, DatabaseConnection, EntityTrait, QueryFilter, QueryOrder,
+    QuerySelect, Set, TransactionTrait,
+};
+use serde_json::Value;
+
+use super::batch::WriteBatch;
+use super::error::{Error, RtResult};
+use super::{process_differential_entity, process_entity};
+use crate::entity::realtime_dead_letter;
+use crate::gtfs::structure::realtime::FeedEntity;
+use crate::ContextData;
+
+/// An entity which couldn't be processed
+#[derive(Debug, Clone)]
+pub struct Failure {
+    pub entity_id: String,
+    pub error: String,
+}
+
+/// The entities of a feed as they were received, by id
+fn raw_entities(json: &str) -> serde_json::Result<HashMap<String, Value>> {
+    let mut feed: Value = serde_json::from_str(json)?;
+    // AT wraps the feed in a response object
+    if let Some(response) = feed.get_mut("response") {
+        feed = response.take();
+    }
+
+    let entities = match feed.get_mut("entity").map(Value::take) {
+        Some(Value::Array(entities)) => entities,
+        _ => vec![],
+    };
+
+    Ok(entities
+        .into_iter()
+        .filter_map(|e| Some((e.get("id")?.as_str()?.to_string(), e)))
+        .collect())
+}
+
+/// Stores the failed entities from the feed, so they aren't just lost.
+/// An entity that's failed before replaces its previous dead letter and adds to its failures.
+pub async fn record(
+    db: &impl ConnectionTrait,
+    json: &str,
+    failures: Vec<Failure>,
+    differential: bool,
+) -> RtResult<()> {
+    if failures.is_empty() {
+        return Ok(());
+    }
+    tracing::warn!("{} entities failed to process", failures.len());
+
+    let mut entities = raw_entities(json).map_err(|e| Error::InvalidData(e.to_string()))?;
+    let timestamp = Utc::now().timestamp_millis();
+
+    let dead_letters = failures
+        .into_iter()
+        .filter_map(|failure| {
+            let entity = entities.remove(&failure.entity_id)?;
+            Some(realtime_dead_letter::ActiveModel {
+                entity_id: Set(failure.entity_id),
+                entity: Set(entity.to_string()),
+                error: Set(failure.error),
+                differential: Set(differential as i32),
+                timestamp: Set(timestamp),
+                ..Default::default()
+            })
+        })
+        .collect::<Vec<_>>();
+
+    if !dead_letters.is_empty() {
+        use realtime_dead_letter::Column;
+
+        realtime_dead_letter::Entity::insert_many(dead_letters)
+            .on_conflict(
+                OnConflict::column(Column::EntityId)
+                    .update_columns([
+                        Column::Entity,
+                        Column::Error,
+                        Column::Differential,
+                        Column::Timestamp,
+                    ])
+                    .value(Column::Failures, Expr::col(Column::Failures).add(1))
+                    .to_owned(),
+            )
+            .exec_without_returning(db)
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Removes dead letters that last failed before `older_than`
+pub async fn cleanup_dead_letters(db: &impl ConnectionTrait, older_than: i64) -> RtResult<()> {
+    realtime_dead_letter::Entity::delete_many()
+        .filter(realtime_dead_letter::Column::Timestamp.lt(older_than))
+        .exec(db)
+        .await?;
+    Ok(())
+}
+
+/// Most recent first
+pub async fn list_dead_letters(
+    db: &DatabaseConnection,
+    limit: u64,
+) -> RtResult<Vec<realtime_dead_letter::Model>> {
+    let dead_letters = realtime_dead_letter::Entity::find()
+        .order_by_desc(realtime_dead_letter::Column::Id)
+        .limit(limit)
+        .all(db)
+        .await?;
+    Ok(dead_letters)
+}
+
+/// Tries processing the entity again, removing it if it succeeds
+pub async fn reprocess_dead_letter(ctx: &ContextData, id: i64) -> RtResult<()> {
+    let dead_letter = realtime_dead_letter::Entity::find_by_id(id)
+        .one(&ctx.db)
+        .await?
+        .ok_or_else(|| Error::NotFound(format!("Dead letter not found: {}", id)))?;
+
+    let entity: FeedEntity =
+        serde_json::from_str(&dead_letter.entity).map_err(|e| Error::InvalidData(e.to_string()))?;
+
+    let tx = ctx.db.begin().await?;
+    let mut batch = WriteBatch::default();
+    if dead_letter.differential != 0 {
+        process_differential_entity(ctx, &tx, &mut batch, entity).await?;
+    } else {
+        process_entity(ctx, &tx, &mut batch, entity).await?;
+    }
+    batch.flush(&tx).await?;
+    realtime_dead_letter::Entity::delete_by_id(id)
+        .exec(&tx)
+        .await?;
+    tx.commit().await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use crate::test_utils::ctx;
+
+    #[test]
+    fn test_raw_entities() {
+        let json =
+            r#"{"response": {"header": {}, "entity": [{"id": "a", "alert": {}}, {"id": "b"}]}}"#;
+        let entities = raw_entities(json).unwrap();
+        assert_eq!(entities.len(), 2);
+        assert_eq!(entities["a"]["alert"], serde_json::json!({}));
+
+        let bare = r#"{"header": {}, "entity": [{"id": "c"}]}"#;
+        assert!(raw_entities(bare).unwrap().contains_key("c"));
+    }
+
+    #[tokio::test]
+    async fn test_record() {
+        let ctx = ctx().await;
+        let json = r#"{"header": {}, "entity": [{"id": "a", "alert": {}}]}"#;
+        let failure = || Failure {
+            entity_id: "a".to_string(),
+            error: "Bad alert".to_string(),
+        };
+
+        record(&ctx.db, json, vec![failure()], false).await.unwrap();
+        record(&ctx.db, json, vec![failure()], false).await.unwrap();
+        let dead_letters = list_dead_letters(&ctx.db, 10).await.unwrap();
+        assert_eq!(dead_letters.len(), 1);
+        assert_eq!(dead_letters[0].failures, 2);
+
+        cleanup_dead_letters(&ctx.db, dead_letters[0].timestamp)
+            .await
+            .unwrap();
+        assert_eq!(list_dead_letters(&ctx.db, 10).await.unwrap().len(), 1);
+        cleanup_dead_letters(&ctx.db, dead_letters[0].timestamp + 1)
+            .await
+            .unwrap();
+        assert!(list_dead_letters(&ctx.db, 10).await.unwrap().is_empty());
+    }
+}
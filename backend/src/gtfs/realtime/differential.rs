@@ -0,0 +1,188 @@
+use sea_orm::sea_query::{Expr, OnConflict};
+use sea_orm::{
+    ColumnTrait, DatabaseTransaction, EntityTrait, QueryFilter, QuerySelect, QueryTrait, Set,
+};
+
+use super::batch::WriteBatch;
+use super::error::RtResult;
+use crate::entity::{
+    alert, alert_active_period, alert_informed_entity, realtime_entity, stop_time_index, trip_run,
+    vehicle,
+};
+use crate::gtfs::structure::realtime::trip_descriptor::ScheduleRelationship;
+
+/// What processing a feed entity changed
+#[derive(Debug, Default)]
+pub struct Applied {
+    pub trip_run_id: Option<i64>,
+    pub vehicle_id: Option<String>,
+    pub alert_id: Option<String>,
+}
+
+/// Records what an entity changed, so it can be undone by a later version of the entity
+pub async fn remember(tx: &DatabaseTransaction, entity_id: &str, applied: Applied) -> RtResult<()> {
+    realtime_entity::Entity::insert(realtime_entity::ActiveModel {
+        entity_id: Set(entity_id.to_string()),
+        trip_run_id: Set(applied.trip_run_id),
+        vehicle_id: Set(applied.vehicle_id),
+        alert_id: Set(applied.alert_id),
+    })
+    .on_conflict(
+        OnConflict::column(realtime_entity::Column::EntityId)
+            .update_columns([
+                realtime_entity::Column::TripRunId,
+                realtime_entity::Column::VehicleId,
+                realtime_entity::Column::AlertId,
+            ])
+            .to_owned(),
+    )
+    .exec_without_returning(tx)
+    .await?;
+
+    Ok(())
+}
+
+/// Undoes whatever the previous version of an entity changed, if there was one.
+/// Its alert is kept if `keep_alert`, for when the new version updates it.
+pub async fn forget(
+    tx: &DatabaseTransaction,
+    batch: &mut WriteBatch,
+    entity_id: &str,
+    keep_alert: bool,
+) -> RtResult<()> {
+    let Some(previous) = realtime_entity::Entity::find_by_id(entity_id.to_string())
+        .one(tx)
+        .await?
+    else {
+        return Ok(());
+    };
+
+    if let Some(alert_id) = previous.alert_id.filter(|_| !keep_alert) {
+        delete_alert(tx, &alert_id).await?;
+    }
+    if let Some(trip_run_id) = previous.trip_run_id {
+        batch.discard_trip_run(trip_run_id);
+        reset_trip_run(tx, trip_run_id).await?;
+    }
+    if let Some(vehicle_id) = previous.vehicle_id {
+        batch.discard_vehicle(&vehicle_id);
+        delete_vehicle(tx, &vehicle_id).await?;
+    }
+
+    realtime_entity::Entity::delete_by_id(entity_id.to_string())
+        .exec(tx)
+        .await?;
+
+    Ok(())
+}
+
+async fn delete_alert(tx: &DatabaseTransaction, alert_id: &str) -> RtResult<()> {
+    alert_informed_entity::Entity::delete_many()
+        .filter(alert_informed_entity::Column::AlertId.eq(alert_id))
+        .exec(tx)
+        .await?;
+    alert_active_period::Entity::delete_many()
+        .filter(alert_active_period::Column::AlertId.eq(alert_id))
+        .exec(tx)
+        .await?;
+    alert::Entity::delete_many()
+        .filter(alert::Column::AlertId.eq(alert_id))
+        .exec(tx)
+        .await?;
+
+    Ok(())
+}
+
+/// Puts a trip run back to how it is in the schedule
+async fn reset_trip_run(tx: &DatabaseTransaction, trip_run_id: i64) -> RtResult<()> {
+    // Duplicated and added trips only exist because the feed told us about them
+    let from_feed = [
+        ScheduleRelationship::Duplicated as i32,
+        ScheduleRelationship::Added as i32,
+    ];
+    let feed_trip_runs = trip_run::Entity::find_by_id(trip_run_id)
+        .select_only()
+        .column(trip_run::Column::Id)
+        .filter(trip_run::Column::ScheduleRelationship.is_in(from_feed))
+        .into_query();
+    stop_time_index::Entity::delete_many()
+        .filter(stop_time_index::Column::TripRunId.in_subquery(feed_trip_runs))
+        .exec(tx)
+        .await?;
+    trip_run::Entity::delete_many()
+        .filter(trip_run::Column::Id.eq(trip_run_id))
+        .filter(trip_run::Column::ScheduleRelationship.is_in(from_feed))
+        .exec(tx)
+        .await?;
+
+    trip_run::Entity::update_many()
+        .col_expr(
+            trip_run::Column::ScheduleRelationship,
+            Expr::value(ScheduleRelationship::Scheduled as i32),
+        )
+        .col_expr(
+            trip_run::Column::VehicleId,
+            Expr::value(Option::<String>::None),
+        )
+        .col_expr(
+            trip_run::Column::VehicleTimestamp,
+            Expr::value(Option::<i64>::None),
+        )
+        .col_expr(
+            trip_run::Column::LastUpdateTimestamp,
+            Expr::value(Option::<i64>::None),
+        )
+        .filter(trip_run::Column::Id.eq(trip_run_id))
+        .exec(tx)
+        .await?;
+
+    stop_time_index::Entity::update_many()
+        .col_expr(
+            stop_time_index::Column::UpdatedArrivalTimestamp,
+            Expr::value(Option::<i64>::None),
+        )
+        .col_expr(
+            stop_time_index::Column::UpdatedDepartureTimestamp,
+            Expr::value(Option::<i64>::None),
+        )
+        .col_expr(stop_time_index::Column::Skipped, Expr::value(0))
+        .col_expr(stop_time_index::Column::Cancelled, Expr::value(0))
+        .col_expr(
+            stop_time_index::Column::UpdatedStopId,
+            Expr::value(Option::<String>::None),
+        )
+        .col_expr(
+            stop_time_index::Column::EstimatedArrivalTimestamp,
+            Expr::value(Option::<i64>::None),
+        )
+        .col_expr(
+            stop_time_index::Column::EstimatedDepartureTimestamp,
+            Expr::value(Option::<i64>::None),
+        )
+        .filter(stop_time_index::Column::TripRunId.eq(trip_run_id))
+        .exec(tx)
+        .await?;
+
+    Ok(())
+}
+
+async fn delete_vehicle(tx: &DatabaseTransaction, vehicle_id: &str) -> RtResult<()> {
+    trip_run::Entity::update_many()
+        .col_expr(
+            trip_run::Column::VehicleId,
+            Expr::value(Option::<String>::None),
+        )
+        .col_expr(
+            trip_run::Column::VehicleTimestamp,
+            Expr::value(Option::<i64>::None),
+        )
+        .filter(trip_run::Column::VehicleId.eq(vehicle_id))
+        .exec(tx)
+        .await?;
+    vehicle::Entity::delete_many()
+        .filter(vehicle::Column::VehicleId.eq(vehicle_id))
+        .exec(tx)
+        .await?;
+
+    Ok(())
+}
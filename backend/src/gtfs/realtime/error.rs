@@ -1,5 +1,7 @@
 use std::num::ParseFloatError;
 
+use crate::db::retry::Busy;
+
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
     #[error("AT error: {0}")]
@@ -23,6 +25,9 @@ pub enum Error {
 
     #[error("Parse Error: {0}")]
     Parse(#[from] ParseFloatError),
+
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
 }
 
 impl From<Error> for std::io::Error {
@@ -31,4 +36,10 @@ impl From<Error> for std::io::Error {
     }
 }
 
+impl Busy for Error {
+    fn is_busy(&self) -> bool {
+        matches!(self, Error::Db(e) if e.is_busy())
+    }
+}
+
 pub type RtResult<T> = Result<T, Error>;
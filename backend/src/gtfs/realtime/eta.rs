@@ -0,0 +1,219 @@
+use std::sync::Arc;
+
+use geo::Point;
+use sea_orm::{ColumnTrait, ConnectionTrait, DbErr, EntityTrait, QueryFilter, QueryOrder};
+
+use super::batch::WriteBatch;
+use super::error::RtResult;
+use crate::entity::{stop_time_index, trip_run};
+use crate::geo::distance_along;
+use crate::shapes::trip_shape_stops;
+use crate::ContextData;
+
+/// A stop of the trip, with how far along the shape it is
+#[derive(Debug, Clone, Copy)]
+struct ScheduledStop {
+    along: f64,
+    arrival: i64,
+    departure: i64,
+}
+
+/// How late (in millis) a vehicle `along` metres down the shape at time `at` is.
+/// Between stops the scheduled time is interpolated.
+/// None if the vehicle has passed the last stop.
+fn delay_at(stops: &[ScheduledStop], along: f64, at: i64) -> Option<i64> {
+    let first = stops.first()?;
+    if along <= first.along {
+        // Yet to start, it's only late once it should have left
+        return Some((at - first.departure).max(0));
+    }
+
+    stops.windows(2).find_map(|pair| {
+        let (from, to) = (pair[0], pair[1]);
+        if along > to.along {
+            return None;
+        }
+        let fraction = if to.along > from.along {
+            (along - from.along) / (to.along - from.along)
+        } else {
+            0.0
+        };
+        let scheduled = from.departure + ((to.arrival - from.departure) as f64 * fraction) as i64;
+        Some(at - scheduled)
+    })
+}
+
+/// Estimates when the vehicle will get to the rest of the trip's stops,
+/// from where it is along the trip's shape compared to the schedule.
+/// Estimates for the stops it's passed are cleared.
+/// Predictions from the feed are better, so nothing is estimated if the trip has any.
+pub async fn estimate_from_position(
+    ctx: &ContextData,
+    db: &impl ConnectionTrait,
+    batch: &mut WriteBatch,
+    trip_run: &trip_run::Model,
+    position: Point,
+    at: i64,
+) -> RtResult<()> {
+    let stop_times = stop_time_index::Entity::find()
+        .filter(stop_time_index::Column::TripRunId.eq(trip_run.id))
+        .order_by_asc(stop_time_index::Column::StopSequence)
+        .all(db)
+        .await?;
+    if stop_times.iter().any(|st| {
+        st.updated_arrival_timestamp.is_some() || st.updated_departure_timestamp.is_some()
+    }) {
+        return Ok(());
+    }
+
+    // The same for every position of the trip's vehicle, until the static data changes
+    let shape_stops = ctx
+        .query_cache
+        .trip_shape_stops
+        .get_or_try_insert(
+            trip_run.trip_id.clone(),
+            ctx.versions.static_version(),
+            || async {
+                let shape_stops = trip_shape_stops(db, &trip_run.trip_id).await?;
+                Ok::<_, DbErr>(shape_stops.map(Arc::new))
+            },
+        )
+        .await?;
+    let Some(shape_stops) = shape_stops else {
+        return Ok(());
+    };
+
+    // Every stop needs a place on the shape to compare against
+    let Some(stops) = stop_times
+        .iter()
+        .map(|st| {
+            Some(ScheduledStop {
+                along: *shape_stops.stops_along.get(&st.stop_id)?,
+                arrival: st.arrival_timestamp,
+                departure: st.departure_timestamp,
+            })
+        })
+        .collect::<Option<Vec<_>>>()
+    else {
+        return Ok(());
+    };
+
+    let Some(along) = distance_along(&shape_stops.shape, position) else {
+        return Ok(());
+    };
+    let Some(delay) = delay_at(&stops, along, at) else {
+        return Ok(());
+    };
+
+    for (mut stop_time, stop) in stop_times.into_iter().zip(stops) {
+        if stop.along < along {
+            if stop_time.estimated_arrival_timestamp.is_some()
+                || stop_time.estimated_departure_timestamp.is_some()
+            {
+                stop_time.estimated_arrival_timestamp = None;
+                stop_time.estimated_departure_timestamp = None;
+                batch.estimate_stop_time(stop_time);
+            }
+            continue;
+        }
+        stop_time.estimated_arrival_timestamp = Some(stop_time.arrival_timestamp + delay);
+        stop_time.estimated_departure_timestamp = Some(stop_time.departure_timestamp + delay);
+        batch.estimate_stop_time(stop_time);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use crate::test_utils::ctx;
+
+    #[test]
+    fn test_delay_at() {
+        let stops = [
+            ScheduledStop {
+                along: 0.0,
+                arrival: 1000,
+                departure: 1000,
+            },
+            ScheduledStop {
+                along: 100.0,
+                arrival: 2000,
+                departure: 2500,
+            },
+            ScheduledStop {
+                along: 300.0,
+                arrival: 4500,
+                departure: 4500,
+            },
+        ];
+
+        // halfway between the last two stops, scheduled at 3500
+        assert_eq!(delay_at(&stops, 200.0, 4000), Some(500));
+        // early
+        assert_eq!(delay_at(&stops, 50.0, 1000), Some(-500));
+        // waiting to start
+        assert_eq!(delay_at(&stops, 0.0, 500), Some(0));
+        assert_eq!(delay_at(&stops, 0.0, 1200), Some(200));
+        // finished
+        assert_eq!(delay_at(&stops, 400.0, 5000), None);
+    }
+
+    #[tokio::test]
+    async fn test_estimate_from_position() {
+        let ctx = ctx().await;
+        // A shape through the NX1 trip's stops, with an estimate left from before
+        ctx.db
+            .execute_unprepared(
+                "PRAGMA foreign_keys = OFF;
+                UPDATE gtfs_trips SET shape_id = 'nx1' WHERE trip_id = '1-NX1-1';
+                INSERT INTO gtfs_shapes (shape_id, shape_pt_sequence, shape_pt_lat, shape_pt_lon, import_id)
+                VALUES ('nx1', 1, -36.84318, 174.76754, 1), ('nx1', 2, -36.84479, 174.76592, 1),
+                    ('nx1', 3, -36.85087, 174.76431, 1);
+                PRAGMA foreign_keys = ON;
+                UPDATE stop_time_index SET estimated_arrival_timestamp = 0,
+                    estimated_departure_timestamp = 0
+                WHERE trip_run_id = 1;",
+            )
+            .await
+            .unwrap();
+        let stop_times = || {
+            stop_time_index::Entity::find()
+                .filter(stop_time_index::Column::TripRunId.eq(1))
+                .order_by_asc(stop_time_index::Column::StopSequence)
+                .all(&ctx.db)
+        };
+        let scheduled = stop_times().await.unwrap();
+        let trip_run = trip_run::Entity::find_by_id(1)
+            .one(&ctx.db)
+            .await
+            .unwrap()
+            .unwrap();
+
+        // At Lower Albert Street, a minute late
+        let mut batch = WriteBatch::default();
+        let at = scheduled[1].arrival_timestamp + 60_000;
+        let position = Point::new(174.76592, -36.84479);
+        estimate_from_position(&ctx, &ctx.db, &mut batch, &trip_run, position, at)
+            .await
+            .unwrap();
+        batch.flush(&ctx.db).await.unwrap();
+
+        let estimated = stop_times()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|st| st.estimated_arrival_timestamp)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            estimated,
+            [
+                None,
+                Some(scheduled[1].arrival_timestamp + 60_000),
+                Some(scheduled[2].arrival_timestamp + 60_000)
+            ]
+        );
+    }
+}
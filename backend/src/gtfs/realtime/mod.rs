@@ -1,94 +1,649 @@
 mod alert;
+mod batch;
+mod dead_letter;
+mod differential;
 mod error;
+mod eta;
+mod publish;
+mod reconcile;
+mod recorder;
+mod source;
 mod trip_update;
 mod utils;
 mod vehicle;
 use crate::gtfs::realtime::vehicle::process_vehicle;
+use std::collections::HashSet;
 use std::time::Duration;
 
-use chrono::{TimeZone, Utc};
+use chrono::{DateTime, TimeZone, Utc};
+pub use dead_letter::{list_dead_letters, reprocess_dead_letter};
 pub use error::Error;
-use sea_orm::{DatabaseTransaction, TransactionTrait};
-use tokio::time::sleep;
+use futures_util::{future, stream, StreamExt};
+use itertools::Itertools;
+pub use publish::encode_feed;
+use sea_orm::{
+    sea_query::{any, Expr},
+    ColumnTrait, DatabaseTransaction, EntityTrait, QueryFilter, QuerySelect, QueryTrait,
+    TransactionTrait,
+};
+use serde_json::json;
+pub use source::FeedSource;
+use tracing::Instrument;
 
 use crate::{
-    gtfs::realtime::alert::process_alert, gtfs::realtime::trip_update::process_trip_update,
+    at::client::AtClient,
+    config::{parse_var, var},
+    db::retry::{retry_busy, Busy},
+    geo::decode_polyline,
+    gtfs::realtime::alert::{find_alerts, process_alert},
+    gtfs::realtime::trip_update::process_trip_update,
+    request_id,
+    supervisor::sleep_until_shutdown,
+    webhooks::WebhookEvent,
     ContextData,
 };
 
+use self::batch::WriteBatch;
+use self::dead_letter::Failure;
+use self::differential::Applied;
 use self::error::RtResult;
+use self::recorder::{Recorder, Replay};
 
-use super::structure::realtime::FeedEntity;
+use super::structure::realtime::{feed_header::Incrementality, FeedEntity, FeedMessage};
 
 async fn process_shape(_tx: &DatabaseTransaction, entity: FeedEntity) -> RtResult<()> {
-    log::info!("Got a shape, but this is not implemented: {:?}", entity);
+    let shape = entity.shape.expect("Expected shape to be set");
+    let line = shape
+        .encoded_polyline
+        .as_deref()
+        .and_then(|polyline| decode_polyline(polyline, 5))
+        .ok_or_else(|| Error::InvalidData("Shape has no valid polyline".to_string()))?;
+    tracing::info!(
+        "Got shape {:?} of {} points, but storing shapes is not implemented",
+        shape.shape_id,
+        line.len()
+    );
     Ok(())
 }
 
-pub async fn monitor_firehose(ctx: &ContextData) -> RtResult<()> {
-    log::info!("Firehose monitor is running");
+/// Applies a single entity, returning what it changed
+async fn process_entity(
+    ctx: &ContextData,
+    tx: &DatabaseTransaction,
+    batch: &mut WriteBatch,
+    entity: FeedEntity,
+) -> RtResult<Applied> {
+    let mut applied = Applied::default();
 
-    let last_update_time = Utc.timestamp_opt(0, 0).unwrap();
+    if entity.alert.is_some() {
+        // alerts are stored by entity id
+        applied.alert_id = Some(entity.id.clone());
+        process_alert(tx, entity, &ctx.realtime.alert_language).await?;
+    } else if entity.trip_update.is_some() {
+        applied.trip_run_id = process_trip_update(tx, batch, entity).await?;
+    } else if let Some(vehicle) = &entity.vehicle {
+        applied.vehicle_id = vehicle.vehicle.as_ref().and_then(|v| v.id.clone());
+        process_vehicle(ctx, tx, batch, entity).await?;
+    } else if entity.shape.is_some() {
+        process_shape(tx, entity).await?;
+    }
 
-    loop {
-        let updates = match ctx.at_client.get_realtime_feed().await {
-            Ok(updates) => updates,
-            Err(e) => {
-                log::error!("Error getting realtime feed: {}", e);
-                sleep(Duration::from_secs(30)).await;
-                continue;
+    Ok(applied)
+}
+
+/// In a differential feed, an entity replaces (or deletes) the previous entity with the same id
+async fn process_differential_entity(
+    ctx: &ContextData,
+    tx: &DatabaseTransaction,
+    batch: &mut WriteBatch,
+    entity: FeedEntity,
+) -> RtResult<()> {
+    let entity_id = entity.id.clone();
+
+    let deleted = entity.is_deleted.unwrap_or(false);
+    // Alerts are stored by entity id, so a new version of one updates it
+    let keep_alert = !deleted && entity.alert.is_some();
+    differential::forget(tx, batch, &entity_id, keep_alert).await?;
+    if deleted {
+        return Ok(());
+    }
+
+    let applied = process_entity(ctx, tx, batch, entity).await?;
+    differential::remember(tx, &entity_id, applied).await
+}
+
+/// How many partitions of a feed are processed at once, if `REALTIME_CONCURRENCY` isn't set
+const DEFAULT_REALTIME_CONCURRENCY: usize = 3;
+
+/// How long a vehicle is kept after it was last seen, if `VEHICLE_RETENTION_MINUTES` isn't set
+const DEFAULT_VEHICLE_RETENTION_MINUTES: i64 = 60;
+
+/// How long vehicle positions are kept, if `VEHICLE_HISTORY_RETENTION_MINUTES` isn't set
+const DEFAULT_VEHICLE_HISTORY_RETENTION_MINUTES: i64 = 24 * 60;
+
+/// How long entities that failed to process are kept, if `DEAD_LETTER_RETENTION_MINUTES` isn't set
+const DEFAULT_DEAD_LETTER_RETENTION_MINUTES: i64 = 7 * 24 * 60;
+
+/// How long after they start duplicated and added trips are kept,
+/// if `FEED_TRIP_RETENTION_MINUTES` isn't set
+const DEFAULT_FEED_TRIP_RETENTION_MINUTES: i64 = 12 * 60;
+
+/// How long past stop times are kept in the index, if `STOP_TIME_RETENTION_MINUTES` isn't set
+const DEFAULT_STOP_TIME_RETENTION_MINUTES: i64 = 36 * 60;
+
+/// Longer retention periods are rejected, as their cutoffs would be out of range
+const MAX_RETENTION_MINUTES: i64 = 100 * 365 * 24 * 60;
+
+/// Language of an alert's header_text, description_text and url, if `ALERT_LANGUAGE` isn't set.
+/// Every translation is kept in alert_translation.
+const DEFAULT_ALERT_LANGUAGE: &str = "en";
+
+/// How feeds are processed, and how long what's learnt from them is kept
+#[derive(Debug, Clone)]
+pub struct RealtimeConfig {
+    concurrency: usize,
+    /// Each fetched feed is recorded to disk, if set
+    recorder: Option<Recorder>,
+    /// Recorded snapshots are processed instead of polling, if set
+    replay: Option<Replay>,
+    /// Retention periods, in minutes
+    vehicle_retention: i64,
+    vehicle_history_retention: i64,
+    dead_letter_retention: i64,
+    feed_trip_retention: i64,
+    stop_time_retention: i64,
+    /// The language of the text stored with alerts
+    alert_language: String,
+}
+
+/// A retention period in minutes, which can't be negative or longer than `MAX_RETENTION_MINUTES`
+fn retention_minutes(name: &str, default: i64, errors: &mut Vec<String>) -> i64 {
+    let minutes = parse_var(name, default, errors);
+    if !(0..=MAX_RETENTION_MINUTES).contains(&minutes) {
+        errors.push(format!(
+            "Invalid {}: {}, expected 0 to {}",
+            name, minutes, MAX_RETENTION_MINUTES
+        ));
+        return default;
+    }
+    minutes
+}
+
+impl RealtimeConfig {
+    /// Every problem with the settings is added to `errors`
+    pub fn from_env(errors: &mut Vec<String>) -> Self {
+        let mut concurrency =
+            parse_var("REALTIME_CONCURRENCY", DEFAULT_REALTIME_CONCURRENCY, errors);
+        if concurrency < 1 {
+            errors.push("REALTIME_CONCURRENCY must be at least 1".to_string());
+            concurrency = DEFAULT_REALTIME_CONCURRENCY;
+        }
+
+        Self {
+            concurrency,
+            recorder: Recorder::from_env(errors),
+            replay: Replay::from_env(errors),
+            vehicle_retention: retention_minutes(
+                "VEHICLE_RETENTION_MINUTES",
+                DEFAULT_VEHICLE_RETENTION_MINUTES,
+                errors,
+            ),
+            vehicle_history_retention: retention_minutes(
+                "VEHICLE_HISTORY_RETENTION_MINUTES",
+                DEFAULT_VEHICLE_HISTORY_RETENTION_MINUTES,
+                errors,
+            ),
+            dead_letter_retention: retention_minutes(
+                "DEAD_LETTER_RETENTION_MINUTES",
+                DEFAULT_DEAD_LETTER_RETENTION_MINUTES,
+                errors,
+            ),
+            feed_trip_retention: retention_minutes(
+                "FEED_TRIP_RETENTION_MINUTES",
+                DEFAULT_FEED_TRIP_RETENTION_MINUTES,
+                errors,
+            ),
+            stop_time_retention: retention_minutes(
+                "STOP_TIME_RETENTION_MINUTES",
+                DEFAULT_STOP_TIME_RETENTION_MINUTES,
+                errors,
+            ),
+            alert_language: var("ALERT_LANGUAGE")
+                .unwrap_or_else(|_| DEFAULT_ALERT_LANGUAGE.to_string()),
+        }
+    }
+}
+
+impl Default for RealtimeConfig {
+    fn default() -> Self {
+        Self {
+            concurrency: DEFAULT_REALTIME_CONCURRENCY,
+            recorder: None,
+            replay: None,
+            vehicle_retention: DEFAULT_VEHICLE_RETENTION_MINUTES,
+            vehicle_history_retention: DEFAULT_VEHICLE_HISTORY_RETENTION_MINUTES,
+            dead_letter_retention: DEFAULT_DEAD_LETTER_RETENTION_MINUTES,
+            feed_trip_retention: DEFAULT_FEED_TRIP_RETENTION_MINUTES,
+            stop_time_retention: DEFAULT_STOP_TIME_RETENTION_MINUTES,
+            alert_language: DEFAULT_ALERT_LANGUAGE.to_string(),
+        }
+    }
+}
+
+/// Entities which can be applied independently of the other kinds
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Partition {
+    Alerts,
+    TripUpdates,
+    Vehicles,
+    Other,
+}
+
+impl Partition {
+    fn of(entity: &FeedEntity) -> Self {
+        if entity.alert.is_some() {
+            Partition::Alerts
+        } else if entity.trip_update.is_some() {
+            Partition::TripUpdates
+        } else if entity.vehicle.is_some() {
+            Partition::Vehicles
+        } else {
+            Partition::Other
+        }
+    }
+}
+
+/// Applies the entities of one partition in their own transaction,
+/// returning the ones which failed
+#[tracing::instrument(level = "debug", skip_all, fields(partition = ?partition, entities = entities.len()))]
+async fn process_partition(
+    ctx: &ContextData,
+    partition: Partition,
+    entities: Vec<FeedEntity>,
+    differential: bool,
+) -> RtResult<Vec<Failure>> {
+    tracing::debug!("Processing {} {:?} entities", entities.len(), partition);
+
+    retry_busy(&format!("Processing {:?} entities", partition), || {
+        apply_partition(ctx, &entities, differential)
+    })
+    .await
+}
+
+/// The transaction for `process_partition`.
+/// The database being busy fails the whole transaction, so that it can be retried.
+async fn apply_partition(
+    ctx: &ContextData,
+    entities: &[FeedEntity],
+    differential: bool,
+) -> RtResult<Vec<Failure>> {
+    let tx = ctx.db.begin().await?;
+    let mut batch = WriteBatch::default();
+    let mut failures = vec![];
+    for entity in entities.iter().cloned() {
+        let entity_id = entity.id.clone();
+        let span = tracing::trace_span!("entity", id = %entity_id);
+        let result: RtResult<()> = async {
+            if differential {
+                process_differential_entity(ctx, &tx, &mut batch, entity).await
+            } else {
+                process_entity(ctx, &tx, &mut batch, entity)
+                    .await
+                    .map(|_| ())
             }
-        };
+        }
+        .instrument(span)
+        .await;
 
-        if updates.header.timestamp <= Some(last_update_time) {
-            log::debug!("No new updates");
-            sleep(Duration::from_secs(15)).await;
-            continue;
+        if let Err(e) = result {
+            if e.is_busy() {
+                return Err(e);
+            }
+            tracing::error!("Error processing entity: {}", e);
+            failures.push(Failure {
+                entity_id,
+                error: e.to_string(),
+            });
         }
+    }
+    batch.flush(&tx).await?;
+    tx.commit().await?;
+
+    Ok(failures)
+}
 
-        let count = updates.entity.len();
+/// Applies all the entities in a feed message,
+/// with each kind of entity processed concurrently in its own transaction.
+/// `json` is the feed as received, for keeping any entities that fail.
+async fn process_feed(ctx: &ContextData, updates: FeedMessage, json: &str) -> RtResult<()> {
+    let count = updates.entity.len();
+    let differential = updates.header.incrementality == Some(Incrementality::Differential);
+    let concurrency = ctx.realtime.concurrency;
 
-        log::debug!("Start processing updates");
+    tracing::debug!("Start processing updates");
 
-        let tx = ctx.db.begin().await?;
+    // Webhooks are sent the alerts that weren't already stored
+    let alert_ids = if ctx.webhooks.is_empty() {
+        vec![]
+    } else {
+        updates
+            .entity
+            .iter()
+            .filter(|e| e.alert.is_some())
+            .map(|e| e.id.clone())
+            .collect_vec()
+    };
+    let known_alerts = find_alerts(&ctx.db, &alert_ids)
+        .await?
+        .into_iter()
+        .filter_map(|a| a.alert_id)
+        .collect::<HashSet<_>>();
+
+    let partitions = updates.entity.into_iter().into_group_map_by(Partition::of);
+
+    let results = stream::iter(partitions)
+        .map(|(partition, entities)| process_partition(ctx, partition, entities, differential))
+        .buffer_unordered(concurrency)
+        .collect::<Vec<_>>()
+        .await;
+
+    // The other partitions are still committed if one fails
+    let failures = results
+        .into_iter()
+        .collect::<RtResult<Vec<_>>>()?
+        .into_iter()
+        .flatten()
+        .collect_vec();
+    retry_busy("Recording dead letters", || {
+        dead_letter::record(&ctx.db, json, failures.clone(), differential)
+    })
+    .await?;
+    retry_busy("Detaching vehicles from finished trips", || {
+        reconcile::detach_finished(&ctx.db)
+    })
+    .await?;
+
+    for alert in find_alerts(&ctx.db, &alert_ids).await? {
+        if alert
+            .alert_id
+            .as_ref()
+            .is_some_and(|id| !known_alerts.contains(id))
         {
-            for entity in updates.entity {
-
-                let result: RtResult<()> = {
-                    if entity.alert.is_some() {
-                        process_alert(&tx, entity.clone()).await
-                    } else if entity.trip_update.is_some() {
-                        process_trip_update(&tx, entity.clone()).await
-                    } else if entity.vehicle.is_some() {
-                        process_vehicle(&tx, entity.clone()).await
-                    } else if entity.shape.is_some() {
-                        process_shape(&tx, entity.clone()).await
-                    } else {
-                        Ok(())
-                    }
-                };
-
-                match result {
-                    Ok(()) => {}
-                    Err(e) => {
-                        log::error!("Error processing entity: {}", e);
-                        continue;
-                    }
-                };
-            }
+            ctx.webhooks.send(
+                WebhookEvent::Alert,
+                json!({
+                    "alert_id": alert.alert_id,
+                    "cause": alert.cause,
+                    "effect": alert.effect,
+                    "severity_level": alert.severity_level,
+                    "header_text": alert.header_text,
+                    "description_text": alert.description_text,
+                    "url": alert.url,
+                }),
+            );
+        }
+    }
+
+    if let Some(timestamp) = updates.header.timestamp {
+        ctx.versions.set_realtime(timestamp);
+    }
+    // Rendered from what's just changed
+    ctx.query_cache.arrivals.clear();
+
+    tracing::debug!("End processing - {} updates", count);
+
+    Ok(())
+}
+
+/// Gets a feed once, processing it if it's newer than `last_update_time`.
+/// Returns how long to wait before polling again.
+#[tracing::instrument(name = "feed_poll", skip_all, fields(feed_id = %source.feed_id, source = source.name))]
+async fn poll_feed(
+    ctx: &ContextData,
+    source: &FeedSource,
+    recorder: Option<&Recorder>,
+    last_update_time: &mut DateTime<Utc>,
+) -> RtResult<Duration> {
+    let json = match ctx
+        .at_client
+        .get_realtime_feed_json(&source.url, source.api_key.as_ref())
+        .await
+    {
+        Ok(json) => json,
+        Err(e) => {
+            tracing::error!("Error getting {} feed: {}", source.name, e);
+            return Ok(Duration::from_secs(30));
+        }
+    };
+
+    if let Some(recorder) = recorder {
+        if let Err(e) = recorder.record(&format!("{} {}", source.feed_id, source.name), &json) {
+            tracing::warn!("Error recording {} feed: {}", source.name, e);
+        }
+    }
+
+    let updates = match AtClient::parse_realtime_feed(&json) {
+        Ok(updates) => {
+            ctx.health.realtime_poll.record();
+            updates
+        }
+        Err(e) => {
+            tracing::error!("Error getting {} feed: {}", source.name, e);
+            return Ok(Duration::from_secs(30));
         }
-        tx.commit().await?;
+    };
 
-        log::debug!("End processing - {} updates", count);
+    if updates.header.timestamp <= Some(*last_update_time) {
+        tracing::debug!("No new {} updates", source.name);
+        return Ok(Duration::from_secs(15));
+    }
+    if let Some(timestamp) = updates.header.timestamp {
+        *last_update_time = timestamp;
+    }
+
+    // Tag everything logged while processing this poll
+    let poll_id = format!("firehose-{}", request_id::new_id());
+    request_id::scope(poll_id, process_feed(ctx, updates, &json)).await?;
+
+    // TODO delay heuristic?
+
+    Ok(source.interval)
+}
+
+/// Polls one feed forever, processing it whenever it has changed
+async fn monitor_feed(
+    ctx: &ContextData,
+    source: FeedSource,
+    recorder: Option<&Recorder>,
+) -> RtResult<()> {
+    tracing::info!(
+        "Polling {} {} feed every {} seconds",
+        source.feed_id,
+        source.name,
+        source.interval.as_secs()
+    );
+
+    let mut last_update_time = Utc.timestamp_opt(0, 0).unwrap();
+
+    loop {
+        let wait = poll_feed(ctx, &source, recorder, &mut last_update_time).await?;
+        if !sleep_until_shutdown(wait, &ctx.shutdown).await {
+            return Ok(());
+        }
+    }
+}
 
-        // TODO delay heuristic?
+/// Polls each configured feed on its own schedule
+pub async fn monitor_firehose(ctx: &ContextData) -> RtResult<()> {
+    tracing::info!("Firehose monitor is running");
 
-        sleep(Duration::from_secs(31)).await;
+    if let Some(replay) = &ctx.realtime.replay {
+        replay.run(ctx).await?;
+        // Nothing else to do, but stopping would restart the replay
+        ctx.shutdown.cancelled().await;
+        return Ok(());
     }
+
+    let feeds = ctx
+        .feeds
+        .iter()
+        .flat_map(|feed| feed.realtime.clone())
+        .map(|source| monitor_feed(ctx, source, ctx.realtime.recorder.as_ref()));
+    future::try_join_all(feeds).await?;
+
+    Ok(())
+}
+
+/// Throws away everything learnt from the realtime feed, including where vehicles have been,
+/// leaving only the scheduled data from the static index
+pub async fn reset(tx: &DatabaseTransaction) -> RtResult<()> {
+    use crate::entity::{
+        alert, alert_active_period, alert_image, alert_informed_entity, alert_translation,
+        realtime_entity, stop_time_index, trip_run, vehicle, vehicle_position_history,
+    };
+    use crate::gtfs::structure::realtime::trip_descriptor::ScheduleRelationship;
+
+    // Duplicated and added trips only exist because the feed told us about them
+    let from_feed = [
+        ScheduleRelationship::Duplicated as i32,
+        ScheduleRelationship::Added as i32,
+    ];
+    let feed_trip_runs = trip_run::Entity::find()
+        .select_only()
+        .column(trip_run::Column::Id)
+        .filter(trip_run::Column::ScheduleRelationship.is_in(from_feed))
+        .into_query();
+    stop_time_index::Entity::delete_many()
+        .filter(stop_time_index::Column::TripRunId.in_subquery(feed_trip_runs))
+        .exec(tx)
+        .await?;
+    trip_run::Entity::delete_many()
+        .filter(trip_run::Column::ScheduleRelationship.is_in(from_feed))
+        .exec(tx)
+        .await?;
+
+    trip_run::Entity::update_many()
+        .col_expr(
+            trip_run::Column::ScheduleRelationship,
+            Expr::value(ScheduleRelationship::Scheduled as i32),
+        )
+        .col_expr(
+            trip_run::Column::VehicleId,
+            Expr::value(Option::<String>::None),
+        )
+        .col_expr(
+            trip_run::Column::VehicleTimestamp,
+            Expr::value(Option::<i64>::None),
+        )
+        .col_expr(
+            trip_run::Column::LastUpdateTimestamp,
+            Expr::value(Option::<i64>::None),
+        )
+        .exec(tx)
+        .await?;
+
+    stop_time_index::Entity::update_many()
+        .col_expr(
+            stop_time_index::Column::UpdatedArrivalTimestamp,
+            Expr::value(Option::<i64>::None),
+        )
+        .col_expr(
+            stop_time_index::Column::UpdatedDepartureTimestamp,
+            Expr::value(Option::<i64>::None),
+        )
+        .col_expr(stop_time_index::Column::Skipped, Expr::value(0))
+        .col_expr(stop_time_index::Column::Cancelled, Expr::value(0))
+        .col_expr(
+            stop_time_index::Column::UpdatedStopId,
+            Expr::value(Option::<String>::None),
+        )
+        .col_expr(
+            stop_time_index::Column::EstimatedArrivalTimestamp,
+            Expr::value(Option::<i64>::None),
+        )
+        .col_expr(
+            stop_time_index::Column::EstimatedDepartureTimestamp,
+            Expr::value(Option::<i64>::None),
+        )
+        .filter(any![
+            stop_time_index::Column::UpdatedArrivalTimestamp.is_not_null(),
+            stop_time_index::Column::UpdatedDepartureTimestamp.is_not_null(),
+            stop_time_index::Column::Skipped.ne(0),
+            stop_time_index::Column::Cancelled.ne(0),
+            stop_time_index::Column::UpdatedStopId.is_not_null(),
+            stop_time_index::Column::EstimatedArrivalTimestamp.is_not_null(),
+            stop_time_index::Column::EstimatedDepartureTimestamp.is_not_null()
+        ])
+        .exec(tx)
+        .await?;
+
+    alert_informed_entity::Entity::delete_many()
+        .exec(tx)
+        .await?;
+    alert_active_period::Entity::delete_many().exec(tx).await?;
+    alert_translation::Entity::delete_many().exec(tx).await?;
+    alert_image::Entity::delete_many().exec(tx).await?;
+    alert::Entity::delete_many().exec(tx).await?;
+    vehicle::Entity::delete_many().exec(tx).await?;
+    // Trajectories and segment speeds are worked out from it
+    vehicle_position_history::Entity::delete_many()
+        .exec(tx)
+        .await?;
+    realtime_entity::Entity::delete_many().exec(tx).await?;
+
+    Ok(())
+}
+
+/// The cutoff for a retention period in minutes, in millis
+fn retained_since(minutes: i64) -> i64 {
+    (Utc::now() - chrono::Duration::minutes(minutes)).timestamp_millis()
 }
 
-pub async fn cleanup(db: &DatabaseTransaction) -> RtResult<()> {
+pub async fn cleanup(config: &RealtimeConfig, db: &DatabaseTransaction) -> RtResult<()> {
     alert::cleanup_alerts(db).await?;
-    // TODO other types
+
+    let vehicles_seen_since = retained_since(config.vehicle_retention);
+    vehicle::cleanup_vehicles(db, vehicles_seen_since).await?;
+
+    let positions_since = retained_since(config.vehicle_history_retention);
+    vehicle::cleanup_position_history(db, positions_since).await?;
+
+    let feed_trips_since = retained_since(config.feed_trip_retention);
+    let stop_times_since = retained_since(config.stop_time_retention);
+    trip_update::cleanup_trip_runs(db, feed_trips_since, stop_times_since).await?;
+
+    let dead_letters_since = retained_since(config.dead_letter_retention);
+    dead_letter::cleanup_dead_letters(db, dead_letters_since).await?;
+
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+
+    use sea_orm::PaginatorTrait;
+
+    use super::*;
+    use crate::{at::mock::MockAt, entity::alert, test_utils::ctx_with_at};
+
+    #[tokio::test]
+    async fn test_poll_feed() {
+        let url = "https://example.com/realtime.json";
+        let ctx = ctx_with_at(MockAt::default().with_fixture(url, "alert")).await;
+        let source = FeedSource::new("combined", url.to_string(), 30);
+
+        let mut last_update_time = Utc.timestamp_opt(0, 0).unwrap();
+        let wait = poll_feed(&ctx, &source, None, &mut last_update_time)
+            .await
+            .unwrap();
+        assert_eq!(wait, source.interval);
+        assert!(ctx.health.realtime_poll.get().is_some());
+        assert_eq!(alert::Entity::find().count(&ctx.db).await.unwrap(), 1);
+
+        // The same feed again has nothing new
+        let wait = poll_feed(&ctx, &source, None, &mut last_update_time)
+            .await
+            .unwrap();
+        assert_eq!(wait, Duration::from_secs(15));
+    }
+}
@@ -0,0 +1,516 @@
+//! What we know of realtime, as a GTFS-realtime feed of our own.
+//! Field numbers are those of gtfs-realtime.proto.
+
+use std::collections::HashMap;
+
+use chrono::{DateTime, Duration, Utc};
+use itertools::Itertools;
+use sea_orm::{
+    sea_query::{all, any},
+    ColumnTrait, EntityTrait, QueryFilter, QueryOrder, QuerySelect, QueryTrait,
+};
+
+use crate::{
+    entity::{
+        alert, alert_active_period, alert_image, alert_informed_entity, alert_translation,
+        stop_time_index, trip_run, vehicle,
+    },
+    error::NextAtResult,
+    gtfs::structure::realtime::{
+        feed_header::Incrementality, trip_descriptor::ScheduleRelationship,
+        trip_update::stop_time_update::ScheduleRelationship as StopTimeRelationship,
+    },
+    protobuf::{write_bytes_field, write_float_field, write_int_field, write_varint_field},
+    ContextData,
+};
+
+/// Trips whose stop times are all longer ago than this are left out
+const TRIP_MAX_AGE_MINUTES: i64 = 60;
+
+/// Vehicles that haven't reported their position for longer are left out
+const VEHICLE_MAX_AGE_MINUTES: i64 = 5;
+
+fn seconds(millis: i64) -> u64 {
+    (millis / 1000).max(0) as u64
+}
+
+fn trip_descriptor(trip_run: &trip_run::Model) -> Vec<u8> {
+    let mut buf = vec![];
+    write_bytes_field(&mut buf, 1, trip_run.trip_id.as_bytes());
+    write_bytes_field(&mut buf, 3, trip_run.start_date.as_bytes());
+    write_int_field(&mut buf, 4, trip_run.schedule_relationship.into());
+    write_bytes_field(&mut buf, 5, trip_run.route_id.as_bytes());
+    if let Some(direction_id) = trip_run.direction_id {
+        write_int_field(&mut buf, 6, direction_id.into());
+    }
+    buf
+}
+
+fn vehicle_descriptor(id: &str, label: Option<&str>, license_plate: Option<&str>) -> Vec<u8> {
+    let mut buf = vec![];
+    write_bytes_field(&mut buf, 1, id.as_bytes());
+    if let Some(label) = label {
+        write_bytes_field(&mut buf, 2, label.as_bytes());
+    }
+    if let Some(license_plate) = license_plate {
+        write_bytes_field(&mut buf, 3, license_plate.as_bytes());
+    }
+    buf
+}
+
+/// Both the delay and the time, in seconds
+fn stop_time_event(scheduled: i64, updated: i64) -> Vec<u8> {
+    let mut buf = vec![];
+    write_int_field(&mut buf, 1, (updated - scheduled) / 1000);
+    write_int_field(&mut buf, 2, updated / 1000);
+    buf
+}
+
+/// None if the feeds haven't changed the stop time
+fn stop_time_update(stop_time: &stop_time_index::Model) -> Option<Vec<u8>> {
+    let skipped = stop_time.skipped != 0;
+    if !skipped
+        && stop_time.updated_arrival_timestamp.is_none()
+        && stop_time.updated_departure_timestamp.is_none()
+        && stop_time.updated_stop_id.is_none()
+    {
+        return None;
+    }
+
+    let mut buf = vec![];
+    write_varint_field(&mut buf, 1, stop_time.stop_sequence as u64);
+    if let Some(arrival) = stop_time.updated_arrival_timestamp {
+        let event = stop_time_event(stop_time.arrival_timestamp, arrival);
+        write_bytes_field(&mut buf, 2, &event);
+    }
+    if let Some(departure) = stop_time.updated_departure_timestamp {
+        let event = stop_time_event(stop_time.departure_timestamp, departure);
+        write_bytes_field(&mut buf, 3, &event);
+    }
+    write_bytes_field(&mut buf, 4, stop_time.stop_id.as_bytes());
+    if skipped {
+        write_int_field(&mut buf, 5, StopTimeRelationship::Skipped as i64);
+    }
+    if let Some(stop_id) = &stop_time.updated_stop_id {
+        // StopTimeProperties.assigned_stop_id
+        let mut properties = vec![];
+        write_bytes_field(&mut properties, 1, stop_id.as_bytes());
+        write_bytes_field(&mut buf, 6, &properties);
+    }
+    Some(buf)
+}
+
+fn trip_update(trip_run: &trip_run::Model, stop_times: &[stop_time_index::Model]) -> Vec<u8> {
+    let mut buf = vec![];
+    write_bytes_field(&mut buf, 1, &trip_descriptor(trip_run));
+    for update in stop_times.iter().filter_map(stop_time_update) {
+        write_bytes_field(&mut buf, 2, &update);
+    }
+    if let Some(vehicle_id) = &trip_run.vehicle_id {
+        write_bytes_field(&mut buf, 3, &vehicle_descriptor(vehicle_id, None, None));
+    }
+    if let Some(timestamp) = trip_run.last_update_timestamp {
+        write_varint_field(&mut buf, 4, seconds(timestamp));
+    }
+    buf
+}
+
+fn vehicle_position(vehicle: &vehicle::Model, trip_run: Option<&trip_run::Model>) -> Vec<u8> {
+    let mut buf = vec![];
+    if let Some(trip_run) = trip_run {
+        write_bytes_field(&mut buf, 1, &trip_descriptor(trip_run));
+    }
+    if let (Some(lat), Some(lon)) = (vehicle.latitude, vehicle.longitude) {
+        let mut position = vec![];
+        write_float_field(&mut position, 1, lat as f32);
+        write_float_field(&mut position, 2, lon as f32);
+        if let Some(bearing) = vehicle.bearing {
+            write_float_field(&mut position, 3, bearing as f32);
+        }
+        if let Some(speed) = vehicle.speed {
+            write_float_field(&mut position, 5, speed as f32);
+        }
+        write_bytes_field(&mut buf, 2, &position);
+    }
+    write_varint_field(&mut buf, 5, seconds(vehicle.timestamp));
+    if let Some(congestion_level) = vehicle.congestion_level {
+        write_int_field(&mut buf, 6, congestion_level.into());
+    }
+    let descriptor = vehicle_descriptor(
+        &vehicle.vehicle_id,
+        vehicle.label.as_deref(),
+        vehicle.license_plate.as_deref(),
+    );
+    write_bytes_field(&mut buf, 8, &descriptor);
+    if let Some(occupancy_status) = vehicle.occupancy_status {
+        write_int_field(&mut buf, 9, occupancy_status.into());
+    }
+    buf
+}
+
+/// Texts and their languages
+fn translated_string<'a>(
+    translations: impl IntoIterator<Item = (&'a str, Option<&'a str>)>,
+) -> Vec<u8> {
+    let mut buf = vec![];
+    for (text, language) in translations {
+        let mut translation = vec![];
+        write_bytes_field(&mut translation, 1, text.as_bytes());
+        if let Some(language) = language {
+            write_bytes_field(&mut translation, 2, language.as_bytes());
+        }
+        write_bytes_field(&mut buf, 1, &translation);
+    }
+    buf
+}
+
+/// The alert's text in each language it came in, otherwise the text we kept
+fn alert_text(
+    text: Option<&str>,
+    field: &str,
+    translations: &[alert_translation::Model],
+) -> Option<Vec<u8>> {
+    let translations = translations
+        .iter()
+        .filter(|t| t.field == field)
+        .map(|t| (t.text.as_str(), t.language.as_deref()))
+        .collect_vec();
+    if !translations.is_empty() {
+        Some(translated_string(translations))
+    } else {
+        text.map(|text| translated_string([(text, None)]))
+    }
+}
+
+struct AlertParts<'a> {
+    active_periods: &'a [alert_active_period::Model],
+    informed_entities: &'a [alert_informed_entity::Model],
+    translations: &'a [alert_translation::Model],
+    images: &'a [alert_image::Model],
+    trip_runs: &'a HashMap<i64, trip_run::Model>,
+}
+
+fn alert_message(alert: &alert::Model, parts: AlertParts) -> Vec<u8> {
+    let mut buf = vec![];
+    for period in parts.active_periods {
+        let mut range = vec![];
+        write_varint_field(&mut range, 1, seconds(period.start_timestamp));
+        write_varint_field(&mut range, 2, seconds(period.end_timestamp));
+        write_bytes_field(&mut buf, 1, &range);
+    }
+    for informed in parts.informed_entities {
+        let mut selector = vec![];
+        if let Some(agency_id) = &informed.agency_id {
+            write_bytes_field(&mut selector, 1, agency_id.as_bytes());
+        }
+        if let Some(route_id) = &informed.route_id {
+            write_bytes_field(&mut selector, 2, route_id.as_bytes());
+        }
+        if let Some(route_type) = informed.route_type {
+            write_int_field(&mut selector, 3, route_type.into());
+        }
+        let trip_run = informed.trip_run_id.and_then(|id| parts.trip_runs.get(&id));
+        if let Some(trip_run) = trip_run {
+            write_bytes_field(&mut selector, 4, &trip_descriptor(trip_run));
+        }
+        if let Some(stop_id) = &informed.stop_id {
+            write_bytes_field(&mut selector, 5, stop_id.as_bytes());
+        }
+        if let Some(direction_id) = informed.direction_id {
+            write_int_field(&mut selector, 6, direction_id.into());
+        }
+        write_bytes_field(&mut buf, 5, &selector);
+    }
+    if let Some(cause) = alert.cause {
+        write_int_field(&mut buf, 6, cause.into());
+    }
+    if let Some(effect) = alert.effect {
+        write_int_field(&mut buf, 7, effect.into());
+    }
+    let url = alert_text(alert.url.as_deref(), "url", parts.translations);
+    if let Some(url) = url {
+        write_bytes_field(&mut buf, 8, &url);
+    }
+    let header = alert_text(
+        alert.header_text.as_deref(),
+        "header_text",
+        parts.translations,
+    );
+    if let Some(header) = header {
+        write_bytes_field(&mut buf, 10, &header);
+    }
+    let description = alert_text(
+        alert.description_text.as_deref(),
+        "description_text",
+        parts.translations,
+    );
+    if let Some(description) = description {
+        write_bytes_field(&mut buf, 11, &description);
+    }
+    if let Some(severity_level) = alert.severity_level {
+        write_int_field(&mut buf, 14, severity_level.into());
+    }
+    if !parts.images.is_empty() {
+        let mut image = vec![];
+        for localized in parts.images {
+            let mut localized_image = vec![];
+            write_bytes_field(&mut localized_image, 1, localized.url.as_bytes());
+            write_bytes_field(&mut localized_image, 2, localized.media_type.as_bytes());
+            if let Some(language) = &localized.language {
+                write_bytes_field(&mut localized_image, 3, language.as_bytes());
+            }
+            write_bytes_field(&mut image, 1, &localized_image);
+        }
+        write_bytes_field(&mut buf, 15, &image);
+    }
+    let alternative_text = alert_text(
+        alert.image_alternative_text.as_deref(),
+        "image_alternative_text",
+        parts.translations,
+    );
+    if let Some(alternative_text) = alternative_text {
+        write_bytes_field(&mut buf, 16, &alternative_text);
+    }
+    buf
+}
+
+/// A FeedEntity of the trip update (3), vehicle position (4) or alert (5)
+fn feed_entity(id: &str, field: u32, message: &[u8]) -> Vec<u8> {
+    let mut buf = vec![];
+    write_bytes_field(&mut buf, 1, id.as_bytes());
+    write_bytes_field(&mut buf, field, message);
+    buf
+}
+
+/// Trip runs the feeds have changed, that haven't long finished
+async fn trip_updates(ctx: &ContextData, now: DateTime<Utc>) -> NextAtResult<Vec<Vec<u8>>> {
+    use stop_time_index as sti;
+    use trip_run as tr;
+
+    let since = (now - Duration::minutes(TRIP_MAX_AGE_MINUTES)).timestamp_millis();
+
+    let updated = sti::Entity::find()
+        .select_only()
+        .column(sti::Column::TripRunId)
+        .filter(any![
+            sti::Column::UpdatedArrivalTimestamp.is_not_null(),
+            sti::Column::UpdatedDepartureTimestamp.is_not_null(),
+            sti::Column::UpdatedStopId.is_not_null(),
+            sti::Column::Skipped.ne(0),
+        ])
+        .filter(sti::Column::ArrivalTimestamp.gte(since))
+        .into_query();
+    // Cancelled trips needn't have any stop times changed
+    let trip_runs = tr::Entity::find()
+        .filter(any![
+            tr::Column::Id.in_subquery(updated),
+            all![
+                tr::Column::ScheduleRelationship.ne(ScheduleRelationship::Scheduled as i32),
+                tr::Column::StartTimestamp.gte(since),
+            ],
+        ])
+        .order_by_asc(tr::Column::Id)
+        .all(&ctx.read_db)
+        .await?;
+
+    let mut stop_times = sti::Entity::find()
+        .filter(sti::Column::TripRunId.is_in(trip_runs.iter().map(|tr| tr.id)))
+        .order_by_asc(sti::Column::TripRunId)
+        .order_by_asc(sti::Column::StopSequence)
+        .all(&ctx.read_db)
+        .await?
+        .into_iter()
+        .into_group_map_by(|st| st.trip_run_id);
+
+    let entities = trip_runs
+        .iter()
+        .map(|trip_run| {
+            let stop_times = stop_times.remove(&trip_run.id).unwrap_or_default();
+            feed_entity(
+                &format!("trip-{}", trip_run.id),
+                3,
+                &trip_update(trip_run, &stop_times),
+            )
+        })
+        .collect();
+    Ok(entities)
+}
+
+/// Vehicles that have reported their position lately, on the latest trip they've been assigned
+async fn vehicle_positions(ctx: &ContextData, now: DateTime<Utc>) -> NextAtResult<Vec<Vec<u8>>> {
+    let since = (now - Duration::minutes(VEHICLE_MAX_AGE_MINUTES)).timestamp_millis();
+    let vehicles = vehicle::Entity::find()
+        .filter(vehicle::Column::Timestamp.gte(since))
+        .order_by_asc(vehicle::Column::VehicleId)
+        .all(&ctx.read_db)
+        .await?;
+
+    // Later trips replace earlier ones
+    let trip_runs = trip_run::Entity::find()
+        .filter(trip_run::Column::VehicleId.is_in(vehicles.iter().map(|v| v.vehicle_id.as_str())))
+        .order_by_asc(trip_run::Column::StartTimestamp)
+        .all(&ctx.read_db)
+        .await?
+        .into_iter()
+        .filter_map(|tr| Some((tr.vehicle_id.clone()?, tr)))
+        .collect::<HashMap<_, _>>();
+
+    let entities = vehicles
+        .iter()
+        .map(|vehicle| {
+            let trip_run = trip_runs.get(&vehicle.vehicle_id);
+            feed_entity(
+                &format!("vehicle-{}", vehicle.vehicle_id),
+                4,
+                &vehicle_position(vehicle, trip_run),
+            )
+        })
+        .collect();
+    Ok(entities)
+}
+
+async fn alerts(ctx: &ContextData) -> NextAtResult<Vec<Vec<u8>>> {
+    let alerts = alert::Entity::find()
+        .order_by_asc(alert::Column::Id)
+        .all(&ctx.read_db)
+        .await?;
+    let active_periods = alert_active_period::Entity::find()
+        .all(&ctx.read_db)
+        .await?
+        .into_iter()
+        .into_group_map_by(|p| p.alert_id.clone());
+    let informed_entities = alert_informed_entity::Entity::find()
+        .all(&ctx.read_db)
+        .await?
+        .into_iter()
+        .filter_map(|ie| Some((ie.alert_id.clone()?, ie)))
+        .into_group_map();
+    let translations = alert_translation::Entity::find()
+        .order_by_asc(alert_translation::Column::Id)
+        .all(&ctx.read_db)
+        .await?
+        .into_iter()
+        .into_group_map_by(|t| t.alert_id.clone());
+    let images = alert_image::Entity::find()
+        .order_by_asc(alert_image::Column::Id)
+        .all(&ctx.read_db)
+        .await?
+        .into_iter()
+        .into_group_map_by(|i| i.alert_id.clone());
+
+    let trip_run_ids = informed_entities
+        .values()
+        .flatten()
+        .filter_map(|ie| ie.trip_run_id)
+        .collect_vec();
+    let trip_runs = trip_run::Entity::find()
+        .filter(trip_run::Column::Id.is_in(trip_run_ids))
+        .all(&ctx.read_db)
+        .await?
+        .into_iter()
+        .map(|tr| (tr.id, tr))
+        .collect::<HashMap<_, _>>();
+
+    let entities = alerts
+        .iter()
+        .filter_map(|alert| {
+            let alert_id = alert.alert_id.as_ref()?;
+            let parts = AlertParts {
+                active_periods: active_periods
+                    .get(alert_id)
+                    .map(Vec::as_slice)
+                    .unwrap_or_default(),
+                informed_entities: informed_entities
+                    .get(alert_id)
+                    .map(Vec::as_slice)
+                    .unwrap_or_default(),
+                translations: translations
+                    .get(alert_id)
+                    .map(Vec::as_slice)
+                    .unwrap_or_default(),
+                images: images.get(alert_id).map(Vec::as_slice).unwrap_or_default(),
+                trip_runs: &trip_runs,
+            };
+            Some(feed_entity(alert_id, 5, &alert_message(alert, parts)))
+        })
+        .collect();
+    Ok(entities)
+}
+
+/// A FeedMessage of the trip updates, vehicle positions and alerts we have now,
+/// as a full dataset whatever the feeds they came from were
+pub async fn encode_feed(ctx: &ContextData) -> NextAtResult<Vec<u8>> {
+    let now = Utc::now();
+
+    let mut header = vec![];
+    write_bytes_field(&mut header, 1, b"2.0");
+    write_int_field(&mut header, 2, Incrementality::FullDataset as i64);
+    write_varint_field(&mut header, 3, seconds(now.timestamp_millis()));
+
+    let mut buf = vec![];
+    write_bytes_field(&mut buf, 1, &header);
+    let entities = [
+        trip_updates(ctx, now).await?,
+        vehicle_positions(ctx, now).await?,
+        alerts(ctx).await?,
+    ];
+    for entity in entities.iter().flatten() {
+        write_bytes_field(&mut buf, 2, entity);
+    }
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod test {
+
+    use sea_orm::ConnectionTrait;
+
+    use crate::test_utils::ctx;
+
+    use super::*;
+
+    fn contains(data: &[u8], text: &str) -> bool {
+        data.windows(text.len()).any(|w| w == text.as_bytes())
+    }
+
+    #[tokio::test]
+    async fn test_encode_feed() {
+        // Two minutes late, at 1120 seconds
+        assert_eq!(
+            stop_time_event(1_000_000, 1_120_000),
+            [0x08, 120, 0x10, 0xe0, 0x08]
+        );
+
+        let ctx = ctx().await;
+        ctx.db
+            .execute_unprepared(
+                "UPDATE stop_time_index SET updated_arrival_timestamp = arrival_timestamp + 120000
+                WHERE trip_run_id = 1 AND stop_sequence = 2;
+                INSERT INTO vehicle (vehicle_id, timestamp, latitude, longitude)
+                VALUES ('59A1', strftime('%s', 'now') * 1000, -36.84, 174.76);
+                UPDATE trip_run SET vehicle_id = '59A1' WHERE id = 1;
+                INSERT INTO alert (alert_id, header_text, url, severity_level)
+                VALUES ('lifts', 'Lifts out of service', 'https://at.govt.nz/lifts', 3);
+                INSERT INTO alert_image (alert_id, url, media_type, language)
+                VALUES ('lifts', 'https://at.govt.nz/lifts.png', 'image/png', 'en');",
+            )
+            .await
+            .unwrap();
+
+        let feed = encode_feed(&ctx).await.unwrap();
+        for expected in [
+            "trip-1",
+            "1-NX1-1",
+            "4018-7ef4a7b7",
+            "vehicle-59A1",
+            "Lifts out of service",
+            "https://at.govt.nz/lifts",
+            "https://at.govt.nz/lifts.png",
+            "image/png",
+        ] {
+            assert!(contains(&feed, expected), "{} isn't in the feed", expected);
+        }
+        // Nothing has changed the other trip
+        assert!(!contains(&feed, "1-WEST-1"));
+    }
+}
@@ -0,0 +1,194 @@
+//! Which trip each vehicle is on, for when vehicle positions and trip updates disagree.
+//! The newest report wins, a vehicle is only on one trip run at a time,
+//! and vehicles are taken off trip runs once they've finished.
+
+use chrono::Utc;
+use itertools::Itertools;
+use sea_orm::sea_query::{Expr, Func, Query, SimpleExpr};
+use sea_orm::{ColumnTrait, ConnectionTrait, EntityTrait, QueryFilter};
+
+use super::batch::WriteBatch;
+use super::error::RtResult;
+use crate::entity::{stop_time_index, trip_run};
+
+/// How long after its last stop a trip run keeps its vehicle
+const FINISHED_TRIP_GRACE_MINUTES: i64 = 10;
+
+/// Whether the report from `timestamp` of the vehicle being on the trip run is the newest.
+/// If it is, the vehicle is taken off any other trip runs it was on,
+/// otherwise the conflict is logged and the report should be ignored.
+/// The trip run should be from [`WriteBatch::pending_trip_run`].
+pub async fn reconcile_vehicle(
+    tx: &impl ConnectionTrait,
+    batch: &mut WriteBatch,
+    trip_run: &trip_run::Model,
+    vehicle_id: &str,
+    timestamp: i64,
+) -> RtResult<bool> {
+    if let (Some(current), Some(reported)) = (&trip_run.vehicle_id, trip_run.vehicle_timestamp) {
+        if current != vehicle_id && reported > timestamp {
+            tracing::info!(
+                trip_run_id = trip_run.id,
+                vehicle_id,
+                current_vehicle_id = current,
+                "Ignoring vehicle reported on a trip run at {}, which had another vehicle reported at {}",
+                timestamp,
+                reported
+            );
+            return Ok(false);
+        }
+    }
+
+    let written = trip_run::Entity::find()
+        .filter(trip_run::Column::VehicleId.eq(vehicle_id))
+        .filter(trip_run::Column::Id.ne(trip_run.id))
+        .all(tx)
+        .await?
+        .into_iter()
+        .map(|tr| batch.pending_trip_run(tr));
+    let others = written
+        .chain(batch.pending_vehicle_trip_runs(vehicle_id))
+        .filter(|tr| tr.id != trip_run.id && tr.vehicle_id.as_deref() == Some(vehicle_id))
+        .unique_by(|tr| tr.id)
+        .collect_vec();
+
+    if let Some(newer) = others.iter().find(|tr| {
+        tr.vehicle_timestamp
+            .is_some_and(|reported| reported > timestamp)
+    }) {
+        tracing::info!(
+            trip_run_id = trip_run.id,
+            vehicle_id,
+            current_trip_run_id = newer.id,
+            "Ignoring vehicle reported on a trip run at {}, as it was reported on another at {:?}",
+            timestamp,
+            newer.vehicle_timestamp
+        );
+        return Ok(false);
+    }
+
+    for other in others {
+        tracing::debug!(
+            trip_run_id = other.id,
+            vehicle_id,
+            "Vehicle has moved on to trip run {}",
+            trip_run.id
+        );
+        batch.detach_vehicle(other);
+    }
+    Ok(true)
+}
+
+/// Takes vehicles off trip runs whose last stop was a while ago,
+/// as feeds don't always say when a vehicle has finished a trip
+pub async fn detach_finished(db: &impl ConnectionTrait) -> RtResult<()> {
+    use stop_time_index as sti;
+
+    let finished_before =
+        (Utc::now() - chrono::Duration::minutes(FINISHED_TRIP_GRACE_MINUTES)).timestamp_millis();
+    // Trip runs without stop times never finish
+    let last_stop = Query::select()
+        .expr(Func::max(Func::coalesce([
+            Expr::col((sti::Entity, sti::Column::UpdatedArrivalTimestamp)).into(),
+            Expr::col((sti::Entity, sti::Column::EstimatedArrivalTimestamp)).into(),
+            Expr::col((sti::Entity, sti::Column::ArrivalTimestamp)).into(),
+        ])))
+        .from(sti::Entity)
+        .and_where(
+            Expr::col((sti::Entity, sti::Column::TripRunId))
+                .equals((trip_run::Entity, trip_run::Column::Id)),
+        )
+        .to_owned();
+
+    let detached = trip_run::Entity::update_many()
+        .col_expr(
+            trip_run::Column::VehicleId,
+            Expr::value(Option::<String>::None),
+        )
+        .col_expr(
+            trip_run::Column::VehicleTimestamp,
+            Expr::value(Option::<i64>::None),
+        )
+        .filter(trip_run::Column::VehicleId.is_not_null())
+        .filter(
+            Expr::expr(SimpleExpr::SubQuery(
+                None,
+                Box::new(last_stop.into_sub_query_statement()),
+            ))
+            .lt(finished_before),
+        )
+        .exec(db)
+        .await?;
+    if detached.rows_affected > 0 {
+        tracing::info!(
+            "Took vehicles off {} finished trip runs",
+            detached.rows_affected
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+
+    use sea_orm::ConnectionTrait;
+
+    use super::*;
+    use crate::test_utils::ctx;
+
+    async fn trip_run(db: &impl ConnectionTrait, id: i64) -> trip_run::Model {
+        trip_run::Entity::find_by_id(id)
+            .one(db)
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_vehicle() {
+        let ctx = ctx().await;
+        let now = Utc::now().timestamp_millis();
+
+        let mut batch = WriteBatch::default();
+        let nx1 = batch.pending_trip_run(trip_run(&ctx.db, 1).await);
+        assert!(reconcile_vehicle(&ctx.db, &mut batch, &nx1, "bus", now)
+            .await
+            .unwrap());
+        batch.assign_vehicle(nx1, "bus".to_string(), now);
+
+        // An older report of it on another trip is ignored
+        let west = batch.pending_trip_run(trip_run(&ctx.db, 2).await);
+        assert!(
+            !reconcile_vehicle(&ctx.db, &mut batch, &west, "bus", now - 1000)
+                .await
+                .unwrap()
+        );
+
+        // A newer one moves it, even before the first has been written
+        assert!(
+            reconcile_vehicle(&ctx.db, &mut batch, &west, "bus", now + 1000)
+                .await
+                .unwrap()
+        );
+        batch.assign_vehicle(west, "bus".to_string(), now + 1000);
+        batch.flush(&ctx.db).await.unwrap();
+
+        assert_eq!(trip_run(&ctx.db, 1).await.vehicle_id, None);
+        let west = trip_run(&ctx.db, 2).await;
+        assert_eq!(west.vehicle_id.as_deref(), Some("bus"));
+        assert_eq!(west.vehicle_timestamp, Some(now + 1000));
+
+        // Neither trip has finished yet
+        detach_finished(&ctx.db).await.unwrap();
+        assert!(trip_run(&ctx.db, 2).await.vehicle_id.is_some());
+        ctx.db
+            .execute_unprepared(
+                "UPDATE stop_time_index SET arrival_timestamp = arrival_timestamp - 3600000",
+            )
+            .await
+            .unwrap();
+        detach_finished(&ctx.db).await.unwrap();
+        assert_eq!(trip_run(&ctx.db, 2).await.vehicle_id, None);
+    }
+}
@@ -0,0 +1,184 @@
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use chrono::Utc;
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use tokio::time::sleep;
+
+use super::error::RtResult;
+use super::process_feed;
+use crate::{
+    at::client::AtClient,
+    config::{parse_var, var},
+    request_id, ContextData,
+};
+
+const SNAPSHOT_EXTENSION: &str = ".json.gz";
+
+/// How many snapshots are kept, if `REALTIME_RECORD_MAX_SNAPSHOTS` isn't set
+const DEFAULT_MAX_SNAPSHOTS: usize = 10_000;
+
+/// How much faster than real time snapshots are replayed, if `REALTIME_REPLAY_SPEED` isn't set
+const DEFAULT_REPLAY_SPEED: f64 = 10.0;
+
+/// Writes each fetched feed to disk as it was received, keeping only the most recent snapshots
+#[derive(Debug, Clone)]
+pub struct Recorder {
+    dir: PathBuf,
+    max_snapshots: usize,
+}
+
+impl Recorder {
+    /// Recording is enabled by setting `REALTIME_RECORD_DIR`
+    pub fn from_env(errors: &mut Vec<String>) -> Option<Self> {
+        let dir = var("REALTIME_RECORD_DIR").ok()?;
+        let max_snapshots = parse_var(
+            "REALTIME_RECORD_MAX_SNAPSHOTS",
+            DEFAULT_MAX_SNAPSHOTS,
+            errors,
+        );
+
+        Some(Self {
+            dir: dir.into(),
+            max_snapshots,
+        })
+    }
+
+    pub fn record(&self, source_name: &str, json: &str) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+
+        // Named so they sort in the order they were recorded
+        let file_name = format!(
+            "{}-{}{}",
+            Utc::now().timestamp_millis(),
+            source_name.replace(' ', "_"),
+            SNAPSHOT_EXTENSION
+        );
+        let file = File::create(self.dir.join(file_name))?;
+        let mut encoder = GzEncoder::new(file, Compression::default());
+        encoder.write_all(json.as_bytes())?;
+        encoder.finish()?;
+
+        self.rotate()
+    }
+
+    /// Deletes the oldest snapshots beyond the limit
+    fn rotate(&self) -> io::Result<()> {
+        let snapshots = snapshots(&self.dir)?;
+        let excess = snapshots.len().saturating_sub(self.max_snapshots);
+        for path in &snapshots[..excess] {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+}
+
+/// Snapshot files, oldest first
+fn snapshots(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut paths = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.to_string_lossy().ends_with(SNAPSHOT_EXTENSION))
+        .collect::<Vec<_>>();
+    paths.sort();
+    Ok(paths)
+}
+
+/// When the snapshot was recorded (in millis), from its file name
+fn recorded_at(path: &Path) -> Option<i64> {
+    path.file_name()?.to_str()?.split('-').next()?.parse().ok()
+}
+
+fn read_snapshot(path: &Path) -> io::Result<String> {
+    let mut json = String::new();
+    GzDecoder::new(File::open(path)?).read_to_string(&mut json)?;
+    Ok(json)
+}
+
+/// Feeds recorded snapshots through processing instead of polling
+#[derive(Debug, Clone)]
+pub struct Replay {
+    dir: PathBuf,
+    speed: f64,
+}
+
+impl Replay {
+    /// Replay is enabled by setting `REALTIME_REPLAY_DIR`
+    pub fn from_env(errors: &mut Vec<String>) -> Option<Self> {
+        let dir = var("REALTIME_REPLAY_DIR").ok()?;
+        let mut speed = parse_var("REALTIME_REPLAY_SPEED", DEFAULT_REPLAY_SPEED, errors);
+        if speed <= 0.0 || !speed.is_finite() {
+            errors.push(format!("Invalid REALTIME_REPLAY_SPEED: {}", speed));
+            speed = DEFAULT_REPLAY_SPEED;
+        }
+
+        Some(Self {
+            dir: dir.into(),
+            speed,
+        })
+    }
+
+    /// Processes each snapshot in order, waiting between them for the time between
+    /// when they were recorded, divided by the speed
+    pub async fn run(&self, ctx: &ContextData) -> RtResult<()> {
+        let snapshots = snapshots(&self.dir)?;
+        tracing::info!(
+            "Replaying {} snapshots from {} at {}x",
+            snapshots.len(),
+            self.dir.display(),
+            self.speed
+        );
+
+        let mut previous = None;
+        for path in snapshots {
+            let recorded = recorded_at(&path);
+            if let (Some(previous), Some(recorded)) = (previous, recorded) {
+                let wait = (recorded - previous).max(0) as f64 / self.speed;
+                sleep(Duration::from_millis(wait as u64)).await;
+            }
+            previous = recorded;
+
+            tracing::debug!("Replaying {}", path.display());
+            let json = read_snapshot(&path)?;
+            let updates = AtClient::parse_realtime_feed(&json)?;
+
+            let replay_id = format!("replay-{}", request_id::new_id());
+            request_id::scope(replay_id, process_feed(ctx, updates, &json)).await?;
+        }
+
+        tracing::info!("Replay finished");
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn test_record_and_rotate() {
+        let dir = tempfile::tempdir().unwrap();
+        let recorder = Recorder {
+            dir: dir.path().to_path_buf(),
+            max_snapshots: 2,
+        };
+
+        for i in 0..3 {
+            recorder
+                .record("trip updates", &format!("{{\"n\": {}}}", i))
+                .unwrap();
+            // so each has its own timestamp
+            std::thread::sleep(Duration::from_millis(2));
+        }
+
+        let snapshots = snapshots(dir.path()).unwrap();
+        assert_eq!(snapshots.len(), 2);
+        assert!(recorded_at(&snapshots[0]).is_some());
+        assert_eq!(read_snapshot(&snapshots[0]).unwrap(), "{\"n\": 1}");
+        assert_eq!(read_snapshot(&snapshots[1]).unwrap(), "{\"n\": 2}");
+    }
+}
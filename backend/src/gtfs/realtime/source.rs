@@ -0,0 +1,98 @@
+use std::time::Duration;
+
+use url::Url;
+
+use crate::{at::client::ApiKey, config};
+
+/// How often a feed is polled, if its interval isn't set
+const DEFAULT_POLL_SECONDS: u64 = 31;
+
+/// A realtime feed to poll
+#[derive(Debug, Clone)]
+pub struct FeedSource {
+    /// The [Feed](crate::gtfs::feed::Feed) the realtime data belongs to
+    pub feed_id: String,
+    pub name: &'static str,
+    pub url: String,
+    pub interval: Duration,
+    /// For publishers that need one
+    pub api_key: Option<ApiKey>,
+}
+
+impl FeedSource {
+    pub(super) fn new(name: &'static str, url: String, interval: u64) -> Self {
+        Self {
+            feed_id: String::new(),
+            name,
+            url,
+            interval: Duration::from_secs(interval),
+            api_key: None,
+        }
+    }
+
+    fn from_env_var(name: &'static str, prefix: &str, errors: &mut Vec<String>) -> Option<Self> {
+        let url = config::var(format!("{}_URL", prefix)).ok()?;
+        let interval_var = format!("{}_INTERVAL_SECONDS", prefix);
+        let interval = match config::var(&interval_var) {
+            Ok(s) => s.trim().parse().unwrap_or_else(|_| {
+                errors.push(format!(
+                    "Invalid {}: {}, expected a number",
+                    interval_var, s
+                ));
+                DEFAULT_POLL_SECONDS
+            }),
+            Err(_) => DEFAULT_POLL_SECONDS,
+        };
+
+        Some(Self::new(name, url, interval))
+    }
+
+    /// Separate feeds are set with `<prefix>_TRIP_UPDATES_URL`, `<prefix>_VEHICLE_POSITIONS_URL`
+    /// and `<prefix>_ALERTS_URL` (each with an optional `_INTERVAL_SECONDS`).
+    /// If none of them are set, a combined feed is read from `<prefix>_URL`, or `default_url`.
+    /// Relative URLs are resolved against `base_url`.
+    pub fn from_env(
+        feed_id: &str,
+        prefix: &str,
+        base_url: &Url,
+        api_key: Option<ApiKey>,
+        default_url: Option<&str>,
+        errors: &mut Vec<String>,
+    ) -> Vec<Self> {
+        let mut sources = [
+            ("trip updates", "TRIP_UPDATES"),
+            ("vehicle positions", "VEHICLE_POSITIONS"),
+            ("alerts", "ALERTS"),
+        ]
+        .into_iter()
+        .filter_map(|(name, kind)| {
+            Self::from_env_var(name, &format!("{}_{}", prefix, kind), errors)
+        })
+        .collect::<Vec<_>>();
+
+        if sources.is_empty() {
+            sources.extend(Self::from_env_var("combined", prefix, errors).or_else(|| {
+                default_url.map(|url| Self::new("combined", url.to_string(), DEFAULT_POLL_SECONDS))
+            }));
+        }
+
+        sources
+            .into_iter()
+            .filter_map(|mut source| {
+                source.url = match base_url.join(&source.url) {
+                    Ok(url) => url.to_string(),
+                    Err(e) => {
+                        errors.push(format!(
+                            "Invalid {} {} feed URL: {} ({})",
+                            feed_id, source.name, source.url, e
+                        ));
+                        return None;
+                    }
+                };
+                source.feed_id = feed_id.to_string();
+                source.api_key = api_key.clone();
+                Some(source)
+            })
+            .collect()
+    }
+}
@@ -1,27 +1,30 @@
-use crate::db::util::OptionMapSet;
 use chrono::Duration;
+use chrono::TimeZone;
 use chrono::Utc;
 use itertools::Itertools;
-use sea_orm::sea_query::all;
 use sea_orm::sea_query::OnConflict;
+use sea_orm::sea_query::{all, any};
 use sea_orm::ActiveModelTrait;
 use sea_orm::ConnectionTrait;
 use sea_orm::QueryOrder;
+use sea_orm::QuerySelect;
+use sea_orm::QueryTrait;
 use sea_orm::Set;
-use sea_orm::TryIntoModel;
 
 use super::error::Error;
 use super::error::RtResult;
+use super::reconcile::reconcile_vehicle;
 use crate::db::links::TripAgency;
-use crate::db::util::col;
+use crate::entity::gtfs_routes;
 use crate::entity::gtfs_stop_times;
 use crate::entity::prelude::*;
 use crate::entity::stop_time_index;
 use crate::entity::trip_run;
-use crate::entity::vehicle;
 use crate::gtfs::realtime::FeedEntity;
 use crate::gtfs::structure::realtime::trip_descriptor::ScheduleRelationship;
+use crate::gtfs::structure::realtime::trip_update::stop_time_update::ScheduleRelationship as StopTimeRelationship;
 use crate::gtfs::structure::realtime::TripDescriptor;
+use crate::gtfs::structure::realtime::TripUpdate;
 use crate::gtfs::structure::realtime::VehicleDescriptor;
 use crate::gtfs::utils::GtfsDateTimeParser;
 use sea_orm::ColumnTrait;
@@ -29,7 +32,8 @@ use sea_orm::EntityTrait;
 use sea_orm::IntoActiveModel;
 use sea_orm::QueryFilter;
 
-use super::utils::find_trip_run;
+use super::batch::WriteBatch;
+use super::utils::{find_trip_run, route_timezone};
 
 async fn duplicate_trip_run(
     db: &impl ConnectionTrait,
@@ -91,6 +95,7 @@ async fn duplicate_trip_run(
         start_date: Set(start_date),
         start_timestamp: Set(new_date_time.timestamp_millis()),
         schedule_relationship: Set(ScheduleRelationship::Duplicated as i32),
+        feed_id: Set(trip.feed_id.clone()),
         ..Default::default()
     };
 
@@ -145,137 +150,354 @@ async fn duplicate_trip_run(
     Ok(trip_run)
 }
 
-pub async fn process_trip_update(db: &impl ConnectionTrait, entity: FeedEntity) -> RtResult<()> {
+/// Creates (or refreshes) a trip run for a trip that isn't in the schedule at all,
+/// using the absolute times given in its stop time updates
+async fn add_trip_run(
+    db: &impl ConnectionTrait,
+    trip_update: &TripUpdate,
+) -> RtResult<trip_run::Model> {
+    let trip = &trip_update.trip;
+
+    let trip_id = trip
+        .trip_id
+        .clone()
+        .ok_or_else(|| Error::InvalidData("Need trip_id to add trip".to_string()))?;
+    let route_id = trip
+        .route_id
+        .clone()
+        .ok_or_else(|| Error::InvalidData("Need route_id to add trip".to_string()))?;
+    let tz = route_timezone(db, &route_id).await?;
+    let feed_id = GtfsRoutes::find()
+        .filter(gtfs_routes::Column::RouteId.eq(&route_id))
+        .one(db)
+        .await?
+        .map(|r| r.feed_id)
+        .ok_or_else(|| Error::NotFound(format!("Route not found: {}", route_id)))?;
+
+    let mut stop_times = vec![];
+    let updates = trip_update.stop_time_update.clone().into_iter().flatten();
+    for (i, update) in updates.enumerate() {
+        let Some(stop_id) = update.stop_id else {
+            continue;
+        };
+        // times are in seconds
+        let arrival = update.arrival.and_then(|e| e.time);
+        let departure = update.departure.and_then(|e| e.time);
+        let Some(arrival) = arrival.or(departure).map(|t| t * 1000) else {
+            continue;
+        };
+        let departure = departure.map(|t| t * 1000).unwrap_or(arrival);
+
+        stop_times.push(stop_time_index::ActiveModel {
+            stop_id: Set(stop_id),
+            stop_sequence: Set(update
+                .stop_sequence
+                .map(|s| s as i32)
+                .unwrap_or(i as i32 + 1)),
+            trip_id: Set(trip_id.clone()),
+            arrival_timestamp: Set(arrival),
+            departure_timestamp: Set(departure),
+            skipped: Set(
+                (update.schedule_relationship == Some(StopTimeRelationship::Skipped)) as i32,
+            ),
+            ..Default::default()
+        });
+    }
+
+    let start_timestamp = stop_times
+        .iter()
+        .filter_map(|st| st.departure_timestamp.clone().take())
+        .min()
+        .ok_or_else(|| Error::InvalidData(format!("No stop times for added trip: {}", trip_id)))?;
+
+    let start_date = match &trip.start_date {
+        Some(start_date) => start_date.clone(),
+        None => Utc
+            .timestamp_millis_opt(start_timestamp)
+            .single()
+            .ok_or_else(|| Error::InvalidData("Invalid start time".to_string()))?
+            .with_timezone(&tz)
+            .format("%Y%m%d")
+            .to_string(),
+    };
+
+    // An added trip is sent again with each update, replace what we had
+    let existing_trip_run = TripRun::find()
+        .filter(all![
+            trip_run::Column::TripId.eq(&trip_id),
+            trip_run::Column::StartDate.eq(&start_date)
+        ])
+        .one(db)
+        .await?;
+
+    let trip_run = match existing_trip_run {
+        Some(existing_trip_run)
+            if is_stale(
+                &existing_trip_run,
+                trip_update.timestamp.map(|t| t.timestamp_millis()),
+            ) =>
+        {
+            // Left for process_trip_update to skip
+            return Ok(existing_trip_run);
+        }
+        Some(existing_trip_run) => {
+            StopTimeIndex::delete_many()
+                .filter(stop_time_index::Column::TripRunId.eq(existing_trip_run.id))
+                .exec(db)
+                .await?;
+
+            let mut trip_run = existing_trip_run.into_active_model();
+            trip_run.route_id = Set(route_id);
+            trip_run.direction_id = Set(trip.direction_id.map(|d| d as i32));
+            trip_run.start_timestamp = Set(start_timestamp);
+            trip_run.update(db).await?
+        }
+        None => {
+            trip_run::ActiveModel {
+                trip_id: Set(trip_id),
+                route_id: Set(route_id),
+                direction_id: Set(trip.direction_id.map(|d| d as i32)),
+                start_date: Set(start_date),
+                start_timestamp: Set(start_timestamp),
+                schedule_relationship: Set(ScheduleRelationship::Added as i32),
+                feed_id: Set(feed_id),
+                ..Default::default()
+            }
+            .insert(db)
+            .await?
+        }
+    };
+
+    for mut stop_time in stop_times {
+        stop_time.trip_run_id = Set(trip_run.id);
+        stop_time.insert(db).await?;
+    }
+
+    Ok(trip_run)
+}
+
+/// Whether the trip run has already had a newer update than one from `timestamp`
+fn is_stale(trip_run: &trip_run::Model, timestamp: Option<i64>) -> bool {
+    matches!(
+        (trip_run.last_update_timestamp, timestamp),
+        (Some(last), Some(timestamp)) if timestamp < last
+    )
+}
+
+/// Returns the id of the trip run that was updated, if any
+pub async fn process_trip_update(
+    db: &impl ConnectionTrait,
+    batch: &mut WriteBatch,
+    entity: FeedEntity,
+) -> RtResult<Option<i64>> {
     let trip_update = entity.trip_update.expect("Expected trip_update to be set");
 
     let sr = trip_update.trip.schedule_relationship;
+    let timestamp = trip_update.timestamp.map(|t| t.timestamp_millis());
     let mut trip_run = match sr {
         Some(ScheduleRelationship::Scheduled | ScheduleRelationship::Canceled) | Some(ScheduleRelationship::Deleted) => {
-            let mut trip_run = find_trip_run(db, trip_update.trip)
-                .await?
-                .into_active_model();
-            trip_run.schedule_relationship = Set(sr.unwrap() as i32);
-            trip_run.save(db).await?
+            batch.pending_trip_run(find_trip_run(db, trip_update.trip).await?)
+        }
+        Some(ScheduleRelationship::Duplicated) => {
+            batch.pending_trip_run(duplicate_trip_run(db, &trip_update.trip).await?)
+        }
+        Some(ScheduleRelationship::Added) => {
+            batch.pending_trip_run(add_trip_run(db, &trip_update).await?)
         }
-        Some(ScheduleRelationship::Duplicated) => duplicate_trip_run(db, &trip_update.trip)
-            .await?
-            .into_active_model(),
         _ => {
-            log::info!(
+            tracing::info!(
                 "Got unimplemented trip schedule relationship: {:?}",
                 trip_update
             );
-            return Ok(());
+            return Ok(None);
         }
     };
 
-    trip_run = match trip_update.vehicle {
-        Some(VehicleDescriptor {
-            id: Some(vehicle_id),
-            label: vehicle_label,
-            ..
-        }) => {
-            let vehicle_model = vehicle::ActiveModel {
-                vehicle_id: Set(vehicle_id.clone()),
-                label: vehicle_label.map_set(),
-                timestamp: Set(Utc::now().timestamp_millis()),
-                ..Default::default()
-            };
-
-            // ensure vehicle actually exists for FK
-            log::debug!("Inserting vehicle: {:?}", vehicle_model);
-            Vehicle::insert(vehicle_model)
-                .on_conflict(
-                    OnConflict::column(vehicle::Column::VehicleId)
-                        .do_nothing()
-                        .to_owned(),
-                )
-                .exec_without_returning(db)
-                .await?;
+    // e.g. a delayed replay from the proxy, which would undo fresher predictions
+    if is_stale(&trip_run, timestamp) {
+        tracing::debug!(
+            "Skipping stale update for trip run {} from {:?}",
+            trip_run.id,
+            timestamp
+        );
+        batch.skip_stale_trip_update();
+        return Ok(Some(trip_run.id));
+    }
+    trip_run.last_update_timestamp = timestamp.or(trip_run.last_update_timestamp);
+    if let Some(
+        sr @ (ScheduleRelationship::Scheduled
+        | ScheduleRelationship::Canceled
+        | ScheduleRelationship::Deleted),
+    ) = sr
+    {
+        trip_run.schedule_relationship = sr as i32;
+    }
 
-            trip_run.vehicle_id = Set(Some(vehicle_id));
-            trip_run.save(db).await?
+    if let Some(VehicleDescriptor {
+        id: Some(vehicle_id),
+        label: vehicle_label,
+        ..
+    }) = trip_update.vehicle
+    {
+        // ensure vehicle actually exists for FK
+        let now = Utc::now().timestamp_millis();
+        batch.ensure_vehicle(vehicle_id.clone(), vehicle_label, now);
+        let reported = timestamp.unwrap_or(now);
+        if reconcile_vehicle(db, batch, &trip_run, &vehicle_id, reported).await? {
+            trip_run.vehicle_id = Some(vehicle_id);
+            trip_run.vehicle_timestamp = Some(reported);
         }
-        _ => trip_run,
-    };
+    }
+
+    let trip_run_id = trip_run.id;
+    batch.update_trip_run(trip_run);
 
-    let trip_run = trip_run.try_into_model()?;
+    if sr == Some(ScheduleRelationship::Added) {
+        // The stop times were created from the update, there are no delays to apply
+        return Ok(Some(trip_run_id));
+    }
+
+    let Some(stop_time_updates) = trip_update.stop_time_update else {
+        return Ok(Some(trip_run_id));
+    };
 
     let stop_times = StopTimeIndex::find()
-        .filter(stop_time_index::Column::TripRunId.eq(trip_run.id))
+        .filter(stop_time_index::Column::TripRunId.eq(trip_run_id))
         .order_by_asc(stop_time_index::Column::StopSequence)
         .all(db)
         .await?;
+    let mut stop_times = batch.pending_stop_times(stop_times);
+    let original_stop_times = stop_times.clone();
 
-    if let Some(stop_time_updates) = trip_update.stop_time_update {
-        // per spec they're supposed to be sorted anyway
-        let updates = stop_time_updates
-            .into_iter()
-            .sorted_by_key(|u| u.stop_sequence)
-            .collect::<Vec<_>>();
-
-        for update in updates {
-            let stop_time = match (update.stop_sequence, update.stop_id) {
-                (Some(seq), _) => stop_times
-                    .iter()
-                    .find(|st| st.stop_sequence == seq as i32)
-                    .ok_or_else(|| Error::NotFound(format!("Stop time not found: {}", seq)))?,
-                (_, Some(stop_id)) => stop_times
-                    .iter()
-                    .find(|st| st.stop_id == stop_id)
-                    .ok_or_else(|| Error::NotFound(format!("Stop time not found: {}", stop_id)))?,
-                _ => {
-                    return Err(Error::InvalidData(
-                        "Need stop_sequence or stop_id".to_string(),
-                    ))
-                }
-            };
-
-            if let Some(arrival) = update.arrival {
-                let delay = arrival
-                    .delay
-                    .map(|d| d as i64)
-                    .or_else(|| arrival.time.map(|t| t - stop_time.arrival_timestamp));
-                if let Some(delay) = delay {
-                    // Update this and subsequent stop time arrivals
-
-                    StopTimeIndex::update_many()
-                        .col_expr(
-                            stop_time_index::Column::UpdatedArrivalTimestamp,
-                            col(stop_time_index::Column::ArrivalTimestamp).add(delay * 1000),
-                        )
-                        .filter(all![
-                            stop_time_index::Column::TripRunId.eq(trip_run.id), // gte not gt!
-                            stop_time_index::Column::StopSequence.gte(stop_time.stop_sequence)
-                        ])
-                        .exec(db)
-                        .await?;
+    // per spec they're supposed to be sorted anyway
+    let updates = stop_time_updates
+        .into_iter()
+        .sorted_by_key(|u| u.stop_sequence)
+        .collect::<Vec<_>>();
+
+    for update in updates {
+        let skipped = update.schedule_relationship == Some(StopTimeRelationship::Skipped);
+        let assigned_stop_id = update
+            .stop_time_properties
+            .as_ref()
+            .and_then(|p| p.assigned_stop_id.clone());
+
+        let i = match (update.stop_sequence, update.stop_id) {
+            (Some(seq), _) => stop_times
+                .iter()
+                .position(|st| st.stop_sequence == seq as i32)
+                .ok_or_else(|| Error::NotFound(format!("Stop time not found: {}", seq)))?,
+            (_, Some(stop_id)) => stop_times
+                .iter()
+                // the stop id might be the one it has been reassigned to
+                .position(|st| {
+                    st.stop_id == stop_id || st.updated_stop_id.as_ref() == Some(&stop_id)
+                })
+                .ok_or_else(|| Error::NotFound(format!("Stop time not found: {}", stop_id)))?,
+            _ => {
+                return Err(Error::InvalidData(
+                    "Need stop_sequence or stop_id".to_string(),
+                ))
+            }
+        };
+
+        let stop_time = &mut stop_times[i];
+        stop_time.skipped = skipped as i32;
+        // Only recorded if it's actually a different stop
+        stop_time.updated_stop_id = assigned_stop_id.filter(|id| *id != stop_time.stop_id);
+
+        if let Some(arrival) = update.arrival {
+            let delay = arrival
+                .delay
+                .map(|d| d as i64)
+                .or_else(|| arrival.time.map(|t| t - stop_times[i].arrival_timestamp));
+            if let Some(delay) = delay {
+                // Update this and subsequent stop time arrivals
+                // (and departures, which can't be earlier than the arrival)
+                for st in &mut stop_times[i..] {
+                    st.updated_arrival_timestamp = Some(st.arrival_timestamp + delay * 1000);
+                    st.updated_departure_timestamp = Some(st.departure_timestamp + delay * 1000);
                 }
             }
+        }
 
-            if let Some(departure) = update.departure {
-                let delay = departure
-                    .delay
-                    .map(|d| d as i64)
-                    .or_else(|| departure.time.map(|t| t - stop_time.departure_timestamp));
-                if let Some(delay) = delay {
-                    // Update subsequent stop arrivals based on previous departure delay
-
-                    StopTimeIndex::update_many()
-                        .col_expr(
-                            stop_time_index::Column::UpdatedArrivalTimestamp,
-                            col(stop_time_index::Column::ArrivalTimestamp).add(delay * 1000),
-                        )
-                        .filter(all![
-                            stop_time_index::Column::TripRunId.eq(trip_run.id), // gt not gte!
-                            stop_time_index::Column::StopSequence.gt(stop_time.stop_sequence)
-                        ])
-                        .exec(db)
-                        .await?;
+        if let Some(departure) = update.departure {
+            let delay = departure.delay.map(|d| d as i64).or_else(|| {
+                departure
+                    .time
+                    .map(|t| t - stop_times[i].departure_timestamp)
+            });
+            if let Some(delay) = delay {
+                // Update this and subsequent stop departures
+                for st in &mut stop_times[i..] {
+                    st.updated_departure_timestamp = Some(st.departure_timestamp + delay * 1000);
+                }
+                // Update subsequent stop arrivals based on previous departure delay
+                for st in &mut stop_times[i + 1..] {
+                    st.updated_arrival_timestamp = Some(st.arrival_timestamp + delay * 1000);
                 }
             }
         }
     }
 
+    // Only the stop times which actually changed need writing
+    for (stop_time, original) in stop_times.into_iter().zip(original_stop_times) {
+        if stop_time != original {
+            batch.update_stop_time(stop_time);
+        }
+    }
+
+    Ok(Some(trip_run_id))
+}
+
+/// Removes duplicated and added trip runs which started before `feed_trips_since`,
+/// and stop times before `stop_times_since` along with any trip runs left without stop times
+pub async fn cleanup_trip_runs(
+    db: &impl ConnectionTrait,
+    feed_trips_since: i64,
+    stop_times_since: i64,
+) -> RtResult<()> {
+    let old_feed_trip = || {
+        all![
+            trip_run::Column::ScheduleRelationship.is_in([
+                ScheduleRelationship::Duplicated as i32,
+                ScheduleRelationship::Added as i32,
+            ]),
+            trip_run::Column::StartTimestamp.lt(feed_trips_since)
+        ]
+    };
+
+    StopTimeIndex::delete_many()
+        .filter(any![
+            stop_time_index::Column::TripRunId.in_subquery(
+                TripRun::find()
+                    .select_only()
+                    .column(trip_run::Column::Id)
+                    .filter(old_feed_trip())
+                    .into_query()
+            ),
+            stop_time_index::Column::ArrivalTimestamp.lt(stop_times_since)
+        ])
+        .exec(db)
+        .await?;
+
+    let deleted = TripRun::delete_many()
+        .filter(any![
+            old_feed_trip(),
+            // the rest of the trip may still be in the index
+            all![
+                trip_run::Column::StartTimestamp.lt(stop_times_since),
+                trip_run::Column::Id.not_in_subquery(
+                    StopTimeIndex::find()
+                        .select_only()
+                        .column(stop_time_index::Column::TripRunId)
+                        .into_query()
+                )
+            ]
+        ])
+        .exec(db)
+        .await?;
+    tracing::info!("Removed {} old trip runs", deleted.rows_affected);
+
     Ok(())
 }
@@ -16,11 +16,29 @@ use sea_orm::RelationTrait;
 use sea_orm::SelectColumns;
 use sea_orm::{ConnectionTrait, EntityTrait, JoinType};
 
+/// The timezone of the agency running a route
+pub async fn route_timezone(tx: &impl ConnectionTrait, route_id: &str) -> RtResult<Tz> {
+    use gtfs_routes::Entity as Route;
+
+    let (timezone,): (String,) = Route::find()
+        .join(JoinType::InnerJoin, gtfs_routes::Relation::GtfsAgency.def())
+        .filter(gtfs_routes::Column::RouteId.eq(route_id))
+        .select_only()
+        .select_column(gtfs_agency::Column::AgencyTimezone)
+        .into_tuple()
+        .one(tx)
+        .await?
+        .ok_or_else(|| Error::NotFound(format!("Route not found: {}", route_id)))?;
+
+    timezone
+        .parse()
+        .map_err(|e| Error::InvalidData(format!("Invalid timezone: {}", e)))
+}
+
 pub async fn find_trip_run(
     tx: &impl ConnectionTrait,
     trip_descriptor: TripDescriptor,
 ) -> RtResult<trip_run::Model> {
-    use gtfs_routes::Entity as Route;
     use gtfs_trips::Entity as Trip;
     use trip_run::Entity as TripRun;
 
@@ -45,19 +63,8 @@ pub async fn find_trip_run(
 
     // We now have the route, either via the trip or directly
     // which means we can get the timezone
-    let (timezone,): (String,) = Route::find()
-        .join(JoinType::InnerJoin, gtfs_routes::Relation::GtfsAgency.def())
-        .filter(gtfs_routes::Column::RouteId.eq(route_id.clone()))
-        .select_only()
-        .select_column(gtfs_agency::Column::AgencyTimezone)
-        .into_tuple()
-        .one(tx)
-        .await?
-        .ok_or_else(|| Error::NotFound(format!("Route not found: {} for {:?}", route_id.clone(), trip_descriptor)))?;
-
-    let tz: Tz = timezone
-        .parse()
-        .map_err(|e| Error::InvalidData(format!("Invalid timezone: {}", e)))?;
+    let tz = route_timezone(tx, &route_id).await?;
+    let timezone = tz.name();
 
     // Gather as much as we can about when the trip is
     let mut trip_time = Utc::now().with_timezone(&tz);
@@ -70,7 +77,7 @@ pub async fn find_trip_run(
             .ok_or_else(|| Error::InvalidData("Invalid date".to_string()))?;
     }
     if let Some(start_time) = trip_descriptor.start_time {
-        let start_time = date_parser.parse_time(&trip_time.date_naive(), &start_time, &timezone)?;
+        let start_time = date_parser.parse_time(&trip_time.date_naive(), &start_time, timezone)?;
         trip_time = start_time;
     }
 
@@ -1,14 +1,24 @@
+use super::batch::WriteBatch;
 use super::error::RtResult;
+use super::eta;
+use super::reconcile::reconcile_vehicle;
 use super::utils::find_trip_run;
-use crate::db::util::OptionMapSet;
 use crate::entity::prelude::*;
-use crate::entity::vehicle;
+use crate::entity::{trip_run, vehicle, vehicle_position_history};
 use crate::gtfs::structure::realtime::FeedEntity;
+use crate::ContextData;
+use geo::Point;
 use sea_orm::prelude::*;
-use sea_orm::IntoActiveModel;
+use sea_orm::sea_query::Expr;
 use sea_orm::{ConnectionTrait, Set};
+use sea_orm::{QuerySelect, QueryTrait};
 
-pub async fn process_vehicle(tx: &impl ConnectionTrait, entity: FeedEntity) -> RtResult<()> {
+pub async fn process_vehicle(
+    ctx: &ContextData,
+    tx: &impl ConnectionTrait,
+    batch: &mut WriteBatch,
+    entity: FeedEntity,
+) -> RtResult<()> {
     let vehicle = entity.vehicle.expect("Expected vehicle to be set");
 
     let timestamp = vehicle.timestamp.unwrap_or_else(chrono::Utc::now);
@@ -25,6 +35,7 @@ pub async fn process_vehicle(tx: &impl ConnectionTrait, entity: FeedEntity) -> R
         ),
         None => (None, None, None, None),
     };
+    let congestion_level = vehicle.congestion_level.map(|c| c as i32);
 
     let trip = vehicle.trip;
 
@@ -35,32 +46,104 @@ pub async fn process_vehicle(tx: &impl ConnectionTrait, entity: FeedEntity) -> R
         .id
         .ok_or_else(|| crate::gtfs::realtime::Error::InvalidData("No vehicle id".to_string()))?;
 
-    let mut db_vehicle = Vehicle::find()
-        .filter(vehicle::Column::VehicleId.eq(vehicle_id.clone()))
-        .one(tx)
-        .await?
-        .map(|v| v.into_active_model())
-        .unwrap_or_else(|| vehicle::ActiveModel {
+    // Anything not in this update keeps its previous value
+    batch.upsert_vehicle(
+        vehicle_id.clone(),
+        vehicle::ActiveModel {
             vehicle_id: Set(vehicle_id.clone()),
+            label: Set(vehicle.label),
+            license_plate: Set(vehicle.license_plate),
+            latitude: Set(lat),
+            longitude: Set(lng),
+            bearing: Set(bearing),
+            speed: Set(speed),
+            congestion_level: Set(congestion_level),
+            timestamp: Set(timestamp.timestamp_millis()),
+            ..Default::default()
+        },
+    );
+
+    // And update the trip if the vehicle is on one, unless it's since been reported elsewhere
+    let trip_run = match trip {
+        Some(trip) => {
+            let trip_run = batch.pending_trip_run(find_trip_run(tx, trip).await?);
+            let timestamp = timestamp.timestamp_millis();
+            if reconcile_vehicle(tx, batch, &trip_run, &vehicle_id, timestamp).await? {
+                batch.assign_vehicle(trip_run.clone(), vehicle_id.clone(), timestamp);
+            }
+            Some(trip_run)
+        }
+        None => None,
+    };
+    let trip_run_id = trip_run.as_ref().map(|tr| tr.id);
+
+    if let (Some(latitude), Some(longitude)) = (lat, lng) {
+        batch.record_position(vehicle_position_history::ActiveModel {
+            vehicle_id: Set(vehicle_id),
+            timestamp: Set(timestamp.timestamp_millis()),
+            latitude: Set(latitude),
+            longitude: Set(longitude),
+            bearing: Set(bearing),
+            speed: Set(speed),
+            congestion_level: Set(congestion_level),
+            trip_run_id: Set(trip_run_id),
             ..Default::default()
         });
 
-    db_vehicle.label = vehicle.label.map_set();
-    db_vehicle.license_plate = vehicle.license_plate.map_set();
-    db_vehicle.latitude = lat.map_set();
-    db_vehicle.longitude = lng.map_set();
-    db_vehicle.bearing = bearing.map_set();
-    db_vehicle.speed = speed.map_set();
-    db_vehicle.timestamp = Set(timestamp.timestamp_millis());
-
-    db_vehicle.save(tx).await?;
-
-    // And update the trip if the vehicle is on one
-    if let Some(trip) = trip {
-        let mut trip_run = find_trip_run(tx, trip).await?.into_active_model();
-        trip_run.vehicle_id = Set(Some(vehicle_id));
-        trip_run.save(tx).await?;
+        if let Some(trip_run) = &trip_run {
+            eta::estimate_from_position(
+                ctx,
+                tx,
+                batch,
+                trip_run,
+                Point::new(longitude, latitude),
+                timestamp.timestamp_millis(),
+            )
+            .await?;
+        }
     }
 
     Ok(())
 }
+
+/// Removes vehicles which haven't been seen since `older_than`,
+/// and takes them off any trips they were assigned to
+pub async fn cleanup_vehicles(tx: &impl ConnectionTrait, older_than: i64) -> RtResult<()> {
+    let stale_vehicles = Vehicle::find()
+        .select_only()
+        .column(vehicle::Column::VehicleId)
+        .filter(vehicle::Column::Timestamp.lt(older_than))
+        .into_query();
+
+    TripRun::update_many()
+        .col_expr(
+            trip_run::Column::VehicleId,
+            Expr::value(Option::<String>::None),
+        )
+        .col_expr(
+            trip_run::Column::VehicleTimestamp,
+            Expr::value(Option::<i64>::None),
+        )
+        .filter(trip_run::Column::VehicleId.in_subquery(stale_vehicles))
+        .exec(tx)
+        .await?;
+
+    let deleted = Vehicle::delete_many()
+        .filter(vehicle::Column::Timestamp.lt(older_than))
+        .exec(tx)
+        .await?;
+    tracing::info!("Removed {} stale vehicles", deleted.rows_affected);
+
+    Ok(())
+}
+
+/// Removes positions recorded before `older_than`
+pub async fn cleanup_position_history(tx: &impl ConnectionTrait, older_than: i64) -> RtResult<()> {
+    let deleted = vehicle_position_history::Entity::delete_many()
+        .filter(vehicle_position_history::Column::Timestamp.lt(older_than))
+        .exec(tx)
+        .await?;
+    tracing::info!("Removed {} old vehicle positions", deleted.rows_affected);
+
+    Ok(())
+}
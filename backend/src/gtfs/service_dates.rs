@@ -0,0 +1,208 @@
+//! calendar.txt and calendar_dates.txt expanded into every date each service runs on,
+//! so that finding the services for a date is a simple lookup.
+
+use std::collections::BTreeSet;
+
+use chrono::{Datelike, NaiveDate};
+use rusqlite::Connection;
+
+use super::sync::GtfsSyncResult;
+
+/// A service from calendar.txt
+#[derive(Debug, Clone)]
+pub struct Calendar {
+    pub service_id: String,
+    /// Whether the service runs on each day of the week, from Monday
+    pub days: [bool; 7],
+    pub start_date: NaiveDate,
+    pub end_date: NaiveDate,
+}
+
+/// An exception from calendar_dates.txt
+#[derive(Debug, Clone)]
+pub struct CalendarDate {
+    pub service_id: String,
+    pub date: NaiveDate,
+    /// 1 if the service is added for the date, 2 if it's removed
+    pub exception_type: i32,
+}
+
+/// Every date each service runs on.
+/// That's the days of the week in its calendar, except dates it's removed for,
+/// plus any dates it's added for.
+pub fn expand_service_dates(
+    calendars: &[Calendar],
+    calendar_dates: &[CalendarDate],
+) -> BTreeSet<(String, NaiveDate)> {
+    let mut dates = BTreeSet::new();
+
+    for calendar in calendars {
+        let days = calendar
+            .start_date
+            .iter_days()
+            .take_while(|d| *d <= calendar.end_date)
+            .filter(|d| calendar.days[d.weekday().num_days_from_monday() as usize]);
+        for date in days {
+            dates.insert((calendar.service_id.clone(), date));
+        }
+    }
+
+    for exception in calendar_dates {
+        let key = (exception.service_id.clone(), exception.date);
+        match exception.exception_type {
+            1 => {
+                dates.insert(key);
+            }
+            2 => {
+                dates.remove(&key);
+            }
+            other => tracing::warn!(
+                "Unknown exception_type {} for service {}",
+                other,
+                exception.service_id
+            ),
+        }
+    }
+
+    dates
+}
+
+/// From the YYYYMMDD integers they're stored as
+fn parse_date(date: i32) -> Option<NaiveDate> {
+    NaiveDate::from_ymd_opt(date / 10000, (date / 100 % 100) as u32, (date % 100) as u32)
+}
+
+fn to_gtfs_date(date: NaiveDate) -> i32 {
+    date.year() * 10000 + date.month() as i32 * 100 + date.day() as i32
+}
+
+fn load_calendars(db: &Connection) -> rusqlite::Result<Vec<Calendar>> {
+    let mut statement = db.prepare(
+        "SELECT service_id, monday, tuesday, wednesday, thursday, friday, saturday, sunday,
+            start_date, end_date
+        FROM gtfs_calendar",
+    )?;
+    let rows = statement.query_map([], |row| {
+        let mut days = [false; 7];
+        for (i, day) in days.iter_mut().enumerate() {
+            *day = row.get::<_, i32>(i + 1)? == 1;
+        }
+        Ok((
+            row.get::<_, String>(0)?,
+            days,
+            row.get::<_, i32>(8)?,
+            row.get::<_, i32>(9)?,
+        ))
+    })?;
+
+    let mut calendars = vec![];
+    for row in rows {
+        let (service_id, days, start_date, end_date) = row?;
+        match (parse_date(start_date), parse_date(end_date)) {
+            (Some(start_date), Some(end_date)) => calendars.push(Calendar {
+                service_id,
+                days,
+                start_date,
+                end_date,
+            }),
+            _ => tracing::warn!("Invalid dates in the calendar of service {}", service_id),
+        }
+    }
+
+    Ok(calendars)
+}
+
+fn load_calendar_dates(db: &Connection) -> rusqlite::Result<Vec<CalendarDate>> {
+    let mut statement = db.prepare(
+        "SELECT service_id, date, exception_type FROM gtfs_calendar_dates
+        WHERE service_id IS NOT NULL",
+    )?;
+    let rows = statement.query_map([], |row| {
+        Ok((
+            row.get::<_, String>(0)?,
+            row.get::<_, i32>(1)?,
+            row.get::<_, i32>(2)?,
+        ))
+    })?;
+
+    let mut calendar_dates = vec![];
+    for row in rows {
+        let (service_id, date, exception_type) = row?;
+        match parse_date(date) {
+            Some(date) => calendar_dates.push(CalendarDate {
+                service_id,
+                date,
+                exception_type,
+            }),
+            None => tracing::warn!("Invalid date {} for service {}", date, service_id),
+        }
+    }
+
+    Ok(calendar_dates)
+}
+
+/// Replaces the service_dates table from the calendars of every feed
+pub fn build_service_dates_table(db: &Connection) -> GtfsSyncResult<()> {
+    let calendars = load_calendars(db)?;
+    let calendar_dates = load_calendar_dates(db)?;
+    let dates = expand_service_dates(&calendars, &calendar_dates);
+
+    db.execute("DELETE FROM service_dates", [])?;
+    let mut insert = db.prepare("INSERT INTO service_dates (service_id, date) VALUES (?1, ?2)")?;
+    for (service_id, date) in &dates {
+        insert.execute((service_id, to_gtfs_date(*date)))?;
+    }
+
+    tracing::info!("{} service dates", dates.len());
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn test_expand_service_dates() {
+        let date = |d: u32| NaiveDate::from_ymd_opt(2024, 1, d).unwrap();
+
+        // Weekdays for two weeks from Monday 1 January
+        let calendars = [Calendar {
+            service_id: "weekdays".to_string(),
+            days: [true, true, true, true, true, false, false],
+            start_date: date(1),
+            end_date: date(14),
+        }];
+        let calendar_dates = [
+            CalendarDate {
+                service_id: "weekdays".to_string(),
+                date: date(2),
+                exception_type: 2,
+            },
+            CalendarDate {
+                service_id: "weekdays".to_string(),
+                date: date(6),
+                exception_type: 1,
+            },
+            // Services can be entirely from calendar_dates.txt
+            CalendarDate {
+                service_id: "special".to_string(),
+                date: date(20),
+                exception_type: 1,
+            },
+        ];
+
+        let dates = expand_service_dates(&calendars, &calendar_dates);
+
+        let weekdays = dates
+            .iter()
+            .filter(|(s, _)| s == "weekdays")
+            .map(|(_, d)| d.day())
+            .collect::<Vec<_>>();
+        assert_eq!(weekdays, vec![1, 3, 4, 5, 6, 8, 9, 10, 11, 12]);
+        assert!(dates.contains(&("special".to_string(), date(20))));
+        assert_eq!(to_gtfs_date(date(20)), 20240120);
+        assert_eq!(parse_date(20240120), Some(date(20)));
+    }
+}
@@ -0,0 +1,232 @@
+//! Imports are written to shadow tables, which then replace the tables being served in one go.
+//! The replaced tables are kept for a few imports, so an earlier import can be made active again.
+
+use std::collections::HashSet;
+
+use itertools::Itertools;
+use rusqlite::{Connection, OptionalExtension};
+
+use super::sync::GtfsSyncResult;
+
+/// Name of the table a file is imported into, before it replaces the table
+pub fn shadow_table(table: &str) -> String {
+    format!("shadow_{}", table)
+}
+
+/// Name of a replaced table, still holding the records of the import
+fn archive_table(import_id: i64, table: &str) -> String {
+    format!("import_{}_{}", import_id, table)
+}
+
+fn table_exists(db: &Connection, table: &str) -> rusqlite::Result<bool> {
+    db.query_row(
+        "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = ?1",
+        [table],
+        |row| row.get::<_, i64>(0),
+    )
+    .map(|count| count > 0)
+}
+
+/// Creates an empty table with the same definition (columns and constraints) under the other name
+fn create_like(db: &Connection, table: &str, new_table: &str) -> GtfsSyncResult<()> {
+    let sql: String = db.query_row(
+        "SELECT sql FROM sqlite_master WHERE type = 'table' AND name = ?1",
+        [table],
+        |row| row.get(0),
+    )?;
+    let definition = &sql[sql.find('(').unwrap()..];
+
+    db.execute_batch(&format!(
+        r#"
+        DROP TABLE IF EXISTS "{new_table}";
+        CREATE TABLE "{new_table}" {definition};
+    "#
+    ))?;
+
+    Ok(())
+}
+
+/// Creates a copy of the table with only the records of other feeds,
+/// which the feed is then imported into without changing what's being served
+pub fn create_shadow_table(db: &Connection, table: &str, feed_id: &str) -> GtfsSyncResult<()> {
+    let shadow = shadow_table(table);
+    create_like(db, table, &shadow)?;
+    db.execute(
+        &format!(r#"INSERT INTO "{shadow}" SELECT * FROM "{table}" WHERE feed_id != ?1"#),
+        [feed_id],
+    )?;
+
+    Ok(())
+}
+
+/// Creates a shadow table with the feed's records from a kept import
+fn restore_shadow_table(
+    db: &Connection,
+    table: &str,
+    feed_id: &str,
+    import_id: i64,
+) -> GtfsSyncResult<()> {
+    create_shadow_table(db, table, feed_id)?;
+
+    // Columns may have been added since the import.
+    // Ids are given out again, they could clash with other feeds' records imported since.
+    let archive = archive_table(import_id, table);
+    let columns = db
+        .prepare(&format!("SELECT name FROM pragma_table_info('{archive}')"))?
+        .query_map([], |row| row.get::<_, String>(0))?
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .filter(|c| c != "id")
+        .map(|c| format!(r#""{c}""#))
+        .join(", ");
+
+    db.execute(
+        &format!(
+            r#"INSERT INTO "{}" ({columns}) SELECT {columns} FROM "{archive}" WHERE feed_id = ?1"#,
+            shadow_table(table)
+        ),
+        [feed_id],
+    )?;
+
+    Ok(())
+}
+
+/// Replaces the tables with their shadow tables in one transaction,
+/// so readers see either all of the previous import or all of the new one.
+/// The replaced tables are kept under the name of the feed's previously active import.
+pub fn swap_shadow_tables(
+    db: &mut Connection,
+    tables: &[String],
+    feed_id: &str,
+    import_id: i64,
+) -> GtfsSyncResult<()> {
+    // Otherwise renaming a table also renames references to it in other tables
+    db.pragma_update(None, "legacy_alter_table", "ON")?;
+
+    let tx = db.transaction()?;
+
+    let previous_id: Option<i64> = tx
+        .query_row(
+            "SELECT id FROM import WHERE feed_id = ?1 AND active = 1 AND id != ?2",
+            (feed_id, import_id),
+            |row| row.get(0),
+        )
+        .optional()?;
+
+    for table in tables {
+        // Indexes stay with a renamed table, so are moved across to the new one
+        let indexes = tx
+            .prepare(
+                "SELECT name, sql FROM sqlite_master
+                WHERE type = 'index' AND tbl_name = ?1 AND sql IS NOT NULL",
+            )?
+            .query_map([table], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        for (name, _) in &indexes {
+            tx.execute_batch(&format!(r#"DROP INDEX "{name}";"#))?;
+        }
+
+        match previous_id {
+            Some(previous_id) => {
+                let archive = archive_table(previous_id, table);
+                tx.execute_batch(&format!(
+                    r#"
+                    DROP TABLE IF EXISTS "{archive}";
+                    ALTER TABLE "{table}" RENAME TO "{archive}";
+                "#
+                ))?;
+            }
+            None => tx.execute_batch(&format!(r#"DROP TABLE "{table}";"#))?,
+        }
+
+        // A restored import is served from the table again
+        let shadow = shadow_table(table);
+        let restored = archive_table(import_id, table);
+        tx.execute_batch(&format!(
+            r#"
+            DROP TABLE IF EXISTS "{restored}";
+            ALTER TABLE "{shadow}" RENAME TO "{table}";
+        "#
+        ))?;
+
+        for (_, sql) in &indexes {
+            tx.execute_batch(sql)?;
+        }
+    }
+
+    tx.execute(
+        "UPDATE import SET active = (id = ?2) WHERE feed_id = ?1",
+        (feed_id, import_id),
+    )?;
+
+    tx.commit()?;
+
+    db.pragma_update(None, "legacy_alter_table", "OFF")?;
+
+    Ok(())
+}
+
+/// Whether an import's records have been kept
+pub fn is_kept(db: &Connection, import_id: i64, tables: &[String]) -> GtfsSyncResult<bool> {
+    for table in tables {
+        if !table_exists(db, &archive_table(import_id, table))? {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Makes a kept import of the feed the one being served again
+pub fn restore_import(
+    db: &mut Connection,
+    tables: &[String],
+    feed_id: &str,
+    import_id: i64,
+    retention: usize,
+) -> GtfsSyncResult<()> {
+    for table in tables {
+        restore_shadow_table(db, table, feed_id, import_id)?;
+    }
+    swap_shadow_tables(db, tables, feed_id, import_id)?;
+    remove_old_imports(db, tables, feed_id, retention)
+}
+
+/// Drops kept imports of the feed beyond the `retention` most recent
+pub fn remove_old_imports(
+    db: &Connection,
+    tables: &[String],
+    feed_id: &str,
+    retention: usize,
+) -> GtfsSyncResult<()> {
+    // Ids of the imports with any tables kept
+    let kept_ids = db
+        .prepare("SELECT name FROM sqlite_master WHERE type = 'table' AND name GLOB 'import_*'")?
+        .query_map([], |row| row.get::<_, String>(0))?
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .filter_map(|name| name.split('_').nth(1)?.parse::<i64>().ok())
+        .collect::<HashSet<_>>();
+
+    let imports = db
+        .prepare("SELECT id FROM import WHERE feed_id = ?1 AND active = 0 ORDER BY id DESC")?
+        .query_map([feed_id], |row| row.get::<_, i64>(0))?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut kept = 0;
+    for import_id in imports.into_iter().filter(|id| kept_ids.contains(id)) {
+        if kept < retention && is_kept(db, import_id, tables)? {
+            kept += 1;
+            continue;
+        }
+        tracing::info!("Removing the records of import {}", import_id);
+        for table in tables {
+            let archive = archive_table(import_id, table);
+            db.execute_batch(&format!(r#"DROP TABLE IF EXISTS "{archive}";"#))?;
+        }
+    }
+
+    Ok(())
+}
@@ -3,6 +3,8 @@
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 
+use crate::translations::Languages;
+
 use self::feed_header::Incrementality;
 use super::serde_helpers::{deserialize_option_unix_date, Many, MaybeStringWrapped};
 
@@ -791,22 +793,28 @@ pub struct TranslatedString {
 }
 
 impl TranslatedString {
-    pub fn get(&self, lang: &str) -> Option<String> {
+    pub fn translations(&self) -> &[translated_string::Translation] {
         match &self.translation {
-            Some(Many::One(t)) => Some(t.text.clone()),
-            Some(Many::Many(v)) => {
-                for t in v {
-                    if let Some(l) = &t.language {
-                        if l == lang {
-                            return Some(t.text.clone());
-                        }
-                    }
-                }
-                None
-            }
-            None => None,
+            Some(Many::One(t)) => std::slice::from_ref(t),
+            Some(Many::Many(v)) => v,
+            None => &[],
         }
     }
+
+    /// The translation in the most preferred language,
+    /// otherwise the one without a language, otherwise the first
+    pub fn get_preferred(&self, languages: &Languages) -> Option<String> {
+        let translations = self.translations();
+        languages
+            .pick(
+                translations
+                    .iter()
+                    .filter_map(|t| Some((t.language.as_deref()?, t))),
+            )
+            .or_else(|| translations.iter().find(|t| t.language.is_none()))
+            .or_else(|| translations.first())
+            .map(|t| t.text.clone())
+    }
 }
 
 /// Nested message and enum types in `TranslatedString`.
@@ -839,6 +847,16 @@ pub struct TranslatedImage {
     /// At least one localized image must be provided.
     pub localized_image: Option<Many<translated_image::LocalizedImage>>,
 }
+
+impl TranslatedImage {
+    pub fn localized_images(&self) -> &[translated_image::LocalizedImage] {
+        match &self.localized_image {
+            Some(Many::One(i)) => std::slice::from_ref(i),
+            Some(Many::Many(v)) => v,
+            None => &[],
+        }
+    }
+}
 /// Nested message and enum types in `TranslatedImage`.
 pub mod translated_image {
     use serde::Deserialize;
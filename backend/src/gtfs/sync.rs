@@ -1,27 +1,54 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
 use crate::entity::prelude::*;
 use crate::{db::util::SeaRusqliteAdapter, entity::service};
 use async_zip::{base::read::mem::ZipFileReader, error::ZipError};
+use chrono::Utc;
 use itertools::Itertools;
-use rusqlite::vtab::csvtab;
+use rusqlite::{vtab::csvtab, OptionalExtension};
 use sea_orm::sea_query::UnionType;
 use sea_orm::{
     sea_query::{self, Alias, Expr, OnConflict, Query, SqliteQueryBuilder},
-    ActiveModelTrait, DatabaseConnection, EntityName, EntityTrait, Iden, IntoActiveModel, Iterable,
-    QueryOrder, Set,
+    ActiveModelTrait, ColumnTrait, DatabaseConnection, EntityName, EntityTrait, Iden,
+    IntoActiveModel, Iterable, QueryFilter, QueryOrder, Set,
 };
 use tempfile::TempDir;
 use tokio::{
-    fs::File,
+    fs::{self, File},
     io::{self, AsyncWriteExt},
     task,
 };
 use tokio_util::compat::FuturesAsyncReadCompatExt;
+use tracing::Span;
+use url::Url;
 
 use crate::{
-    db::util::open_rusqlite,
+    config::{parse_var, var},
+    db::{
+        retry::{retry_busy, Busy},
+        util::open_rusqlite,
+    },
     entity::{
-        gtfs_agency, gtfs_calendar, gtfs_calendar_dates, gtfs_feed_info, gtfs_routes, gtfs_shapes,
-        gtfs_stop_times, gtfs_stops, gtfs_trips, import, prelude::Import,
+        gtfs_agency, gtfs_calendar, gtfs_calendar_dates, gtfs_fare_attributes, gtfs_fare_rules,
+        gtfs_feed_info, gtfs_frequencies, gtfs_levels, gtfs_pathways, gtfs_routes, gtfs_shapes,
+        gtfs_stop_times, gtfs_stops, gtfs_transfers, gtfs_translations, gtfs_trips, import,
+        prelude::Import,
+    },
+    gtfs::{
+        changes::{record_changed_trips, record_full_rebuild},
+        download::download_gtfs_zip,
+        feed::Feed,
+        feed_version::{notify_version_change, FeedVersion},
+        progress::{SyncPhase, SyncProgress},
+        service_dates::build_service_dates_table,
+        shadow::{
+            create_shadow_table, is_kept, remove_old_imports, restore_import, shadow_table,
+            swap_shadow_tables,
+        },
+        validate::{validate_gtfs_files, Severities, Severity},
     },
 };
 
@@ -47,14 +74,115 @@ pub enum GtfsSyncError {
 
     #[error("Index build error: {0}")]
     IndexBuildError(#[from] crate::gtfs::index::Error),
+
+    #[error("Invalid GTFS feed: {0}")]
+    ValidationError(String),
+
+    #[error("Feeds not synced: {0}")]
+    FeedsFailed(String),
+}
+
+impl Busy for GtfsSyncError {
+    fn is_busy(&self) -> bool {
+        match self {
+            GtfsSyncError::DbError(e) => e.is_busy(),
+            GtfsSyncError::CsvImportError(e) => e.is_busy(),
+            _ => false,
+        }
+    }
 }
 
 pub type GtfsSyncResult<T> = Result<T, GtfsSyncError>;
 
-const AT_GTFS_ZIP_URL: &str = "https://gtfs.at.govt.nz/gtfs.zip";
+/// How many times an interrupted download is resumed, if `GTFS_DOWNLOAD_RETRIES` isn't set
+const DEFAULT_DOWNLOAD_RETRIES: u32 = 5;
+
+/// How many replaced imports of each feed are kept, if `IMPORT_RETENTION` isn't set
+const DEFAULT_IMPORT_RETENTION: usize = 1;
+
+/// How feeds are downloaded, checked and imported
+#[derive(Debug, Clone)]
+pub struct SyncConfig {
+    /// `GTFS_DOWNLOAD_DIR`, or next to the database if it isn't set
+    pub download_dir: Option<PathBuf>,
+    /// How many times an interrupted download is resumed
+    pub download_retries: u32,
+    /// How many replaced imports of each feed are kept to go back to
+    pub import_retention: usize,
+    /// Posted changes to a feed's version, if set
+    pub feed_version_webhook_url: Option<String>,
+    pub severities: Severities,
+}
+
+impl SyncConfig {
+    /// Every problem with the settings is added to `errors`
+    pub fn from_env(errors: &mut Vec<String>) -> Self {
+        let feed_version_webhook_url = var("FEED_VERSION_WEBHOOK_URL").ok();
+        if let Some(url) = &feed_version_webhook_url {
+            if let Err(e) = Url::parse(url) {
+                errors.push(format!("Invalid FEED_VERSION_WEBHOOK_URL: {}", e));
+            }
+        }
+
+        Self {
+            download_dir: var("GTFS_DOWNLOAD_DIR").ok().map(PathBuf::from),
+            download_retries: parse_var("GTFS_DOWNLOAD_RETRIES", DEFAULT_DOWNLOAD_RETRIES, errors),
+            import_retention: parse_var("IMPORT_RETENTION", DEFAULT_IMPORT_RETENTION, errors),
+            feed_version_webhook_url,
+            severities: Severities::from_env(errors),
+        }
+    }
+}
+
+impl Default for SyncConfig {
+    fn default() -> Self {
+        Self {
+            download_dir: None,
+            download_retries: DEFAULT_DOWNLOAD_RETRIES,
+            import_retention: DEFAULT_IMPORT_RETENTION,
+            feed_version_webhook_url: None,
+            severities: Severities::default(),
+        }
+    }
+}
+
+/// Feeds are synced independently, so one failing doesn't hold up the others
+#[derive(Debug, Default)]
+pub struct SyncOutcome {
+    /// Records changed by the feeds that synced
+    pub new_records: u64,
+    /// Id and error of each feed that didn't
+    pub feed_errors: Vec<(String, GtfsSyncError)>,
+}
+
+impl SyncOutcome {
+    pub fn failed_feed_ids(&self) -> Vec<&String> {
+        self.feed_errors.iter().map(|(id, _)| id).collect()
+    }
+
+    fn describe_errors(&self) -> Option<String> {
+        if self.feed_errors.is_empty() {
+            return None;
+        }
+        Some(
+            self.feed_errors
+                .iter()
+                .map(|(id, e)| format!("{}: {}", id, e))
+                .join("; "),
+        )
+    }
+
+    /// An error if any of the feeds failed
+    pub fn check(&self) -> GtfsSyncResult<()> {
+        match self.describe_errors() {
+            Some(errors) => Err(GtfsSyncError::FeedsFailed(errors)),
+            None => Ok(()),
+        }
+    }
+}
 
 // Order is important!
-const FILE_NAMES: [&str; 9] = [
+const FILE_NAMES: [&str; 16] = [
     "feed_info.txt",
     "agency.txt",
     "calendar.txt",
@@ -64,46 +192,79 @@ const FILE_NAMES: [&str; 9] = [
     "shapes.txt",
     "stops.txt",
     "stop_times.txt",
+    "frequencies.txt",
+    "transfers.txt",
+    "levels.txt",
+    "pathways.txt",
+    "fare_attributes.txt",
+    "fare_rules.txt",
+    "translations.txt",
+];
+
+/// Files which feeds may leave out, these are imported as if empty.
+/// Either of the calendar files is required though.
+const OPTIONAL_FILE_NAMES: [&str; 11] = [
+    "feed_info.txt",
+    "calendar.txt",
+    "calendar_dates.txt",
+    "shapes.txt",
+    "frequencies.txt",
+    "transfers.txt",
+    "levels.txt",
+    "pathways.txt",
+    "fare_attributes.txt",
+    "fare_rules.txt",
+    "translations.txt",
 ];
 
+/// Files that GTFS requires but are missing from the extracted feed
+pub fn missing_required_files(dir: &Path) -> Vec<String> {
+    let exists = |f: &str| dir.join(f).exists();
+
+    let mut missing = FILE_NAMES
+        .iter()
+        .filter(|f| !OPTIONAL_FILE_NAMES.contains(f) && !exists(f))
+        .map(|f| f.to_string())
+        .collect_vec();
+    if !exists("calendar.txt") && !exists("calendar_dates.txt") {
+        missing.push("calendar.txt or calendar_dates.txt".to_string());
+    }
+
+    missing
+}
+
 trait ImportEx {
-    async fn get_last_import(db: &DatabaseConnection) -> GtfsSyncResult<Option<import::Model>>;
+    async fn get_last_import(
+        db: &DatabaseConnection,
+        feed_id: &str,
+    ) -> GtfsSyncResult<Option<import::Model>>;
 }
 
 impl ImportEx for Import {
-    async fn get_last_import(db: &DatabaseConnection) -> GtfsSyncResult<Option<import::Model>> {
+    async fn get_last_import(
+        db: &DatabaseConnection,
+        feed_id: &str,
+    ) -> GtfsSyncResult<Option<import::Model>> {
         use import::Column::*;
 
-        let found = Import::find().order_by_desc(Timestamp).one(db).await?;
+        let found = Import::find()
+            .filter(FeedId.eq(feed_id))
+            .order_by_desc(Timestamp)
+            .one(db)
+            .await?;
         Ok(found)
     }
 }
 
-pub async fn get_gtfs_files_from_zip(
-    url: &str,
-    if_modified_since: Option<String>,
-) -> GtfsSyncResult<Option<(Option<String>, TempDir)>> {
-    let resp = reqwest::get(url).await?.error_for_status()?;
-
-    let last_modified = resp
-        .headers()
-        .get("last-modified")
-        .and_then(|v| v.to_str().ok())
-        .map(|v| v.to_string());
-
-    // Check the last modified header here - Azure block storage seems to ignore If-Modified-Since/If-None-Match!
-    if if_modified_since == last_modified {
-        return Ok(None);
-    }
-
-    // I'd prefer to stream the response into the zip reader
+/// Extracts the GTFS files from a downloaded zip
+pub async fn get_gtfs_files_from_zip(zip_path: &Path) -> GtfsSyncResult<TempDir> {
+    // I'd prefer to stream the file into the zip reader
     // however it appears, at least for the tested zips, that they are are compressed
     // in a way that requires the dictionary (end of the file) to be read
-    // If memory usage becomes an issue, consider reading ranges from the server
 
-    let bytes = resp.bytes().await?;
+    let bytes = fs::read(zip_path).await?;
 
-    let zip_reader = ZipFileReader::new(bytes.into()).await?;
+    let zip_reader = ZipFileReader::new(bytes).await?;
 
     let tmp_dir = TempDir::new()?;
 
@@ -128,25 +289,25 @@ pub async fn get_gtfs_files_from_zip(
         file.flush().await?;
     }
 
-    Ok(Some((last_modified, tmp_dir)))
+    Ok(tmp_dir)
 }
 
 macro_rules! insert_from_csv {
-    ($import_id:expr, $mod:ident, $id_cols:expr) => {
+    ($import_id:expr, $feed_id:expr, $mod:ident, $id_cols:expr) => {
         {
             use $mod::*;
 
             let table_name = Entity::default().table_name().to_string();
             let csv_table_name = format!("{}_{}", table_name, $import_id);
 
-            // Same as the table without the id/import_id
+            // Same as the table without the id/import_id/feed_id
             let csv_columns = Column::iter()
-                .filter(|c| !["id", "import_id"].contains(&c.to_string().as_str()))
+                .filter(|c| !["id", "import_id", "feed_id"].contains(&c.to_string().as_str()))
                 .collect_vec();
 
-            // import_id at the end so we can select in the same order
+            // import_id and feed_id at the end so we can select in the same order
             let all_columns = csv_columns.clone().into_iter()
-                .chain([Column::ImportId]);
+                .chain([Column::ImportId, Column::FeedId]);
 
             let unique_cols = $id_cols
                 .iter()
@@ -155,6 +316,7 @@ macro_rules! insert_from_csv {
             let csv_data = Query::select()
                 .columns(csv_columns.clone())
                 .expr_as(Expr::value($import_id), Alias::new("import_id")) // must come after cols
+                .expr_as(Expr::value($feed_id), Alias::new("feed_id"))
                 .from(Alias::new(csv_table_name.clone()))
                 // sqlite docs on select/insert upserts:
                 // to avoid a parsing ambiguity, the SELECT statement should always contain a WHERE clause,
@@ -163,7 +325,7 @@ macro_rules! insert_from_csv {
                 .to_owned();
 
             let mut insert = Query::insert()
-                .into_table(Entity)
+                .into_table(Alias::new(shadow_table(&table_name)))
                 .columns(all_columns.clone())
                 .select_from(csv_data)?
                 .to_owned();
@@ -177,38 +339,57 @@ macro_rules! insert_from_csv {
                     );
             }
 
-            let insert_sql = insert.to_string(SqliteQueryBuilder);
-
-            // and cleanup old records
-            let cleanup_sql = Query::delete()
-                .from_table(Entity)
-                .and_where(Expr::col(Column::ImportId).lt($import_id))
-                .to_string(SqliteQueryBuilder);
-
-            // The last statement is the insert so the count is correct
-            let sql = [
-                cleanup_sql,
-                insert_sql
-            ].join(";\n");
-
-            (csv_table_name, sql)
+            let sql = insert.to_string(SqliteQueryBuilder);
+
+            // Header of an empty file, for optional files that are missing
+            let csv_header = csv_columns.iter().map(|c| c.to_string()).join(",");
+
+            // The shadow table only has other feeds' records at this point,
+            // any with the same ids would be taken over by the upsert
+            let clash_sql = $id_cols.first().map(|first_col| {
+                let on = $id_cols
+                    .iter()
+                    .map(|c| format!(r#"s."{c}" = c."{c}""#))
+                    .join(" AND ");
+                format!(
+                    r#"SELECT c."{first_col}", s.feed_id FROM temp.{csv_table_name} c
+                    JOIN "{}" s ON {on} LIMIT 1"#,
+                    shadow_table(&table_name)
+                )
+            });
+
+            (csv_table_name, csv_header, sql, clash_sql)
         }
     };
 }
 
+/// The tables are named after the files
+fn import_tables() -> Vec<String> {
+    FILE_NAMES
+        .iter()
+        .map(|f| format!("gtfs_{}", f.trim_end_matches(".txt")))
+        .collect()
+}
+
 struct SyncState {
     import_id: i64,
+    feed_id: String,
     file_dir: TempDir,
+    progress: Arc<SyncProgress>,
+    import_retention: usize,
 }
 
 fn import_csvs(state: &SyncState) -> GtfsSyncResult<u64> {
     let SyncState {
         import_id,
+        feed_id,
         file_dir,
+        progress,
+        import_retention,
     } = state;
 
     // Rusqlite is used directly for its csv import functionality
-    let db = open_rusqlite()?;
+    let mut db = open_rusqlite()?;
     csvtab::load_module(&db)?;
 
     let dir_path = file_dir.path();
@@ -219,133 +400,393 @@ fn import_csvs(state: &SyncState) -> GtfsSyncResult<u64> {
         let path = dir_path.join(filename).to_str().unwrap().to_string(); // only if somehow invalid utf-8
 
         let i_id = *import_id;
-        let (csv_table_name, update_from_csv) = match *filename {
-            "feed_info.txt" => insert_from_csv!(i_id, gtfs_feed_info, [] as [String; 0]),
-            "agency.txt" => insert_from_csv!(i_id, gtfs_agency, ["agency_id"]),
-            "calendar.txt" => insert_from_csv!(i_id, gtfs_calendar, ["service_id"]),
+        let f_id = feed_id.as_str();
+        let (csv_table_name, csv_header, update_from_csv, clash_sql) = match *filename {
+            "feed_info.txt" => insert_from_csv!(i_id, f_id, gtfs_feed_info, [] as [String; 0]),
+            "agency.txt" => insert_from_csv!(i_id, f_id, gtfs_agency, ["agency_id"]),
+            "calendar.txt" => insert_from_csv!(i_id, f_id, gtfs_calendar, ["service_id"]),
             "calendar_dates.txt" => {
-                insert_from_csv!(i_id, gtfs_calendar_dates, ["service_id", "date"])
+                insert_from_csv!(i_id, f_id, gtfs_calendar_dates, ["service_id", "date"])
             }
-            "routes.txt" => insert_from_csv!(i_id, gtfs_routes, ["route_id"]),
-            "trips.txt" => insert_from_csv!(i_id, gtfs_trips, ["trip_id"]),
-            "shapes.txt" => insert_from_csv!(i_id, gtfs_shapes, ["shape_id", "shape_pt_sequence"]),
-            "stops.txt" => insert_from_csv!(i_id, gtfs_stops, ["stop_id"]),
+            "routes.txt" => insert_from_csv!(i_id, f_id, gtfs_routes, ["route_id"]),
+            "trips.txt" => insert_from_csv!(i_id, f_id, gtfs_trips, ["trip_id"]),
+            "shapes.txt" => {
+                insert_from_csv!(i_id, f_id, gtfs_shapes, ["shape_id", "shape_pt_sequence"])
+            }
+            "stops.txt" => insert_from_csv!(i_id, f_id, gtfs_stops, ["stop_id"]),
             "stop_times.txt" => {
-                insert_from_csv!(i_id, gtfs_stop_times, ["trip_id", "stop_sequence"])
+                insert_from_csv!(i_id, f_id, gtfs_stop_times, ["trip_id", "stop_sequence"])
+            }
+            "frequencies.txt" => {
+                insert_from_csv!(i_id, f_id, gtfs_frequencies, ["trip_id", "start_time"])
+            }
+            "transfers.txt" => {
+                insert_from_csv!(i_id, f_id, gtfs_transfers, ["from_stop_id", "to_stop_id"])
+            }
+            "levels.txt" => insert_from_csv!(i_id, f_id, gtfs_levels, ["level_id"]),
+            "pathways.txt" => insert_from_csv!(i_id, f_id, gtfs_pathways, ["pathway_id"]),
+            "fare_attributes.txt" => {
+                insert_from_csv!(i_id, f_id, gtfs_fare_attributes, ["fare_id"])
+            }
+            "fare_rules.txt" => insert_from_csv!(i_id, f_id, gtfs_fare_rules, [] as [String; 0]),
+            "translations.txt" => {
+                insert_from_csv!(i_id, f_id, gtfs_translations, [] as [String; 0])
             }
             other => panic!("FILE_NAMES out of sync with insert code: {}", other),
         };
 
+        let table = format!("gtfs_{}", filename.trim_end_matches(".txt"));
+        create_shadow_table(&db, &table, feed_id)?;
+
+        // Still imported so that records from the previous import are cleaned up
+        if OPTIONAL_FILE_NAMES.contains(filename) && !Path::new(&path).exists() {
+            tracing::warn!(
+                "{} feed has no {}, importing it as empty",
+                feed_id,
+                filename
+            );
+            std::fs::write(&path, format!("{csv_header}\n"))?;
+        }
+
         let statement = format!(
             "
             BEGIN;
             CREATE VIRTUAL TABLE temp.{csv_table_name} USING csv(filename='{path}', header=yes);
-            {update_from_csv};
-            COMMIT;
         "
         );
 
-        log::trace!("{}", statement);
+        tracing::trace!("{}", statement);
 
+        progress.file_started(filename);
         db.execute_batch(&statement)?;
+
+        if let Some(clash_sql) = clash_sql {
+            let clash = db
+                .query_row(&clash_sql, [], |row| {
+                    Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+                })
+                .optional()?;
+            if let Some((id, other_feed_id)) = clash {
+                db.execute_batch("ROLLBACK;")?;
+                return Err(GtfsSyncError::ValidationError(format!(
+                    "{} feed not imported: {} has id {} which the {} feed already uses",
+                    feed_id, filename, id, other_feed_id
+                )));
+            }
+        }
+
+        tracing::trace!("{}", update_from_csv);
+
+        db.execute_batch(&format!("{update_from_csv}; COMMIT;"))?;
+        progress.file_done(db.changes());
         insert_count += db.changes();
     }
 
+    progress.phase(SyncPhase::FindingChanges);
+    let changed_trips = record_changed_trips(&db, feed_id)?;
+    tracing::info!("{} feed import changes {} trips", feed_id, changed_trips);
+
+    progress.phase(SyncPhase::SwappingTables);
+    let tables = import_tables();
+    swap_shadow_tables(&mut db, &tables, feed_id, *import_id)?;
+    remove_old_imports(&db, &tables, feed_id, *import_retention)?;
+
     Ok(insert_count)
 }
 
-pub struct Sync<'a> {
-    db: &'a DatabaseConnection,
-    // state: SyncState,
+/// Fills the service table from the calendars of every feed.
+/// This is not in the spec, but it provides a way to have FKs between all the tables.
+/// The dates each service runs on are expanded into service_dates at the same time.
+fn build_service_table() -> GtfsSyncResult<()> {
+    let mut db = open_rusqlite()?;
+    let tx = db.transaction()?;
+
+    let mut date_services = Query::select()
+        .distinct()
+        .column(gtfs_calendar_dates::Column::ServiceId)
+        .from(gtfs_calendar_dates::Entity)
+        .to_owned();
+    let regular_services = Query::select()
+        .distinct()
+        .column(gtfs_calendar::Column::ServiceId)
+        .from(gtfs_calendar::Entity)
+        .to_owned();
+    let all_services = date_services
+        .union(UnionType::Distinct, regular_services)
+        .to_owned();
+
+    Query::delete()
+        .from_table(service::Entity)
+        .prepare(&tx)?
+        .execute()?;
+    Query::insert()
+        .into_table(Service)
+        .columns([service::Column::ServiceId])
+        .select_from(all_services)?
+        .prepare(&tx)?
+        .execute()?;
+
+    build_service_dates_table(&tx)?;
+
+    tx.commit()?;
+
+    Ok(())
 }
 
-impl<'a> Sync<'a> {
-    async fn do_sync(&self) -> GtfsSyncResult<u64> {
-        log::debug!("Syncing GTFS data...");
+/// Makes an earlier import of its feed the one being served again.
+/// Returns false if the import's records are no longer kept.
+pub async fn activate_import(
+    config: &SyncConfig,
+    import_id: i64,
+    feed_id: String,
+) -> GtfsSyncResult<bool> {
+    let import_retention = config.import_retention;
+    task::spawn_blocking(move || {
+        let mut db = open_rusqlite()?;
+        let tables = import_tables();
+        if !is_kept(&db, import_id, &tables)? {
+            return Ok(false);
+        }
+        restore_import(&mut db, &tables, &feed_id, import_id, import_retention)?;
+        build_service_table()?;
+        record_full_rebuild(&db)?;
+        Ok(true)
+    })
+    .await
+    .unwrap() // unwrap spawn error
+}
 
-        let last_import = Import::get_last_import(self.db).await?;
+/// Deletes everything imported from feeds that aren't in `feed_ids`
+fn remove_other_feeds(feed_ids: &[String]) -> GtfsSyncResult<u64> {
+    // `NOT IN ()` would match every record
+    if feed_ids.is_empty() {
+        tracing::warn!("No feeds are configured, keeping the records of earlier ones");
+        return Ok(0);
+    }
+
+    let db = open_rusqlite()?;
 
-        let prev_last_modified = last_import.and_then(|i| i.file_last_modified);
+    let mut delete_count = 0;
 
-        let (last_modified, tmp_dir) =
-            match get_gtfs_files_from_zip(AT_GTFS_ZIP_URL, prev_last_modified).await? {
-                Some((last_modified, tmp_dir)) => (last_modified, tmp_dir),
-                None => {
-                    log::debug!("No new GTFS data available");
-                    return Ok(0);
-                }
-            };
+    // Dependents are deleted first
+    for table in import_tables().into_iter().rev() {
+        Query::delete()
+            .from_table(Alias::new(table))
+            .and_where(Expr::col(Alias::new("feed_id")).is_not_in(feed_ids.iter().cloned()))
+            .prepare(&db)?
+            .execute()?;
+        delete_count += db.changes();
+    }
+
+    if delete_count > 0 {
+        tracing::info!(
+            "Removed {} records of feeds no longer configured",
+            delete_count
+        );
+        record_full_rebuild(&db)?;
+    }
+
+    Ok(delete_count)
+}
 
-        log::debug!("GTFS files extracted to {:?}", tmp_dir.path());
+pub struct Sync<'a> {
+    db: &'a DatabaseConnection,
+    config: &'a SyncConfig,
+    progress: Arc<SyncProgress>,
+    // state: SyncState,
+}
 
-        let new_import = import::ActiveModel {
-            ..Default::default()
+impl<'a> Sync<'a> {
+    #[tracing::instrument(name = "sync_feed", skip_all, fields(feed_id = %feed.id))]
+    async fn do_sync(&self, feed: &Feed) -> GtfsSyncResult<u64> {
+        tracing::debug!("Syncing GTFS data for the {} feed...", feed.id);
+        self.progress.feed_started(&feed.id);
+        self.progress.phase(SyncPhase::Downloading);
+
+        let last_import = Import::get_last_import(self.db, &feed.id).await?;
+
+        let (prev_last_modified, prev_sha256) = last_import
+            .map(|i| (i.file_last_modified, i.file_sha256))
+            .unwrap_or_default();
+
+        let downloaded = download_gtfs_zip(
+            self.config,
+            &feed.id,
+            &feed.gtfs_url,
+            prev_last_modified.as_deref(),
+            &self.progress,
+        )
+        .await?;
+        let downloaded = match downloaded {
+            // Servers that don't send Last-Modified may still send the same file
+            Some(downloaded) if prev_sha256.as_ref() != Some(&downloaded.sha256) => downloaded,
+            Some(downloaded) => {
+                downloaded.remove().await;
+                tracing::debug!("No new GTFS data available for the {} feed", feed.id);
+                return Ok(0);
+            }
+            None => {
+                tracing::debug!("No new GTFS data available for the {} feed", feed.id);
+                return Ok(0);
+            }
+        };
+
+        self.progress.phase(SyncPhase::Extracting);
+        let tmp_dir = get_gtfs_files_from_zip(&downloaded.path).await;
+        let last_modified = downloaded.last_modified.clone();
+        let sha256 = downloaded.sha256.clone();
+        downloaded.remove().await;
+        let tmp_dir = tmp_dir?;
+
+        tracing::debug!("GTFS files extracted to {:?}", tmp_dir.path());
+
+        // Check before anything is replaced, a broken feed leaves the previous import in place
+        self.progress.phase(SyncPhase::Validating);
+        let dir = tmp_dir.path().to_path_buf();
+        let severities = self.config.severities.clone();
+        let problems = task::spawn_blocking(move || validate_gtfs_files(&dir, &severities))
+            .await
+            .unwrap()?; // unwrap spawn error
+        for problem in &problems {
+            match problem.severity {
+                Severity::Error => tracing::error!("{} feed: {}", feed.id, problem),
+                _ => tracing::warn!("{} feed: {}", feed.id, problem),
+            }
+        }
+        let errors = problems
+            .iter()
+            .filter(|p| p.severity == Severity::Error)
+            .map(|p| p.to_string())
+            .collect_vec();
+        if !errors.is_empty() {
+            return Err(GtfsSyncError::ValidationError(format!(
+                "{} feed not imported: {}",
+                feed.id,
+                errors.join("; ")
+            )));
         }
-        .insert(self.db)
+
+        let new_import = retry_busy("Recording the import", || {
+            import::ActiveModel {
+                feed_id: Set(feed.id.clone()),
+                ..Default::default()
+            }
+            .insert(self.db)
+        })
         .await?;
 
+        let import_id = new_import.id;
+        let previous_version = FeedVersion::current(self.db, &feed.id).await?;
+
+        self.progress.phase(SyncPhase::Importing);
+        let feed_id = feed.id.clone();
+        let progress = self.progress.clone();
+        let import_retention = self.config.import_retention;
+        let span = Span::current();
         let record_count = task::spawn_blocking(move || {
-            import_csvs(&SyncState {
-                import_id: new_import.id,
-                file_dir: tmp_dir,
+            span.in_scope(|| {
+                import_csvs(&SyncState {
+                    import_id: new_import.id,
+                    feed_id,
+                    file_dir: tmp_dir,
+                    progress,
+                    import_retention,
+                })
             })
         })
         .await
         .unwrap()?; // unwrap spawn error
 
-        log::debug!("Finished GTFS static data import");
+        tracing::debug!("Finished GTFS static data import");
 
         // And build service table
-        // This is not in the spec, but it provides a way to have FKs between all the tables
-        let mut db = open_rusqlite()?;
-        let tx = db.transaction()?;
-
-        let mut date_services = Query::select()
-            .distinct()
-            .column(gtfs_calendar_dates::Column::ServiceId)
-            .from(gtfs_calendar_dates::Entity)
-            .to_owned();
-        let regular_services = Query::select()
-            .distinct()
-            .column(gtfs_calendar::Column::ServiceId)
-            .from(gtfs_calendar::Entity)
-            .to_owned();
-        let all_services = date_services
-            .union(UnionType::Distinct, regular_services)
-            .to_owned();
-
-        Query::delete()
-            .from_table(service::Entity)
-            .prepare(&tx)?
-            .execute()?;
-        Query::insert()
-            .into_table(Service)
-            .columns([service::Column::ServiceId])
-            .select_from(all_services)?
-            .prepare(&tx)?
-            .execute()?;
-
-        tx.commit()?;
+        self.progress.phase(SyncPhase::BuildingServiceTable);
+        retry_busy("Building the service table", || async {
+            task::spawn_blocking(build_service_table).await.unwrap() // unwrap spawn error
+        })
+        .await?;
 
         // success
         let mut this_import = new_import.into_active_model();
         this_import.file_last_modified = Set(last_modified);
-        this_import.save(self.db).await?;
+        this_import.file_sha256 = Set(Some(sha256));
+        this_import.record_count = Set(Some(record_count as i64));
+        this_import.completed_timestamp = Set(Some(
+            // same format as sqlite's CURRENT_TIMESTAMP
+            Utc::now().format("%Y-%m-%d %H:%M:%S").to_string(),
+        ));
+        retry_busy("Completing the import", || {
+            this_import.clone().save(self.db)
+        })
+        .await?;
+
+        let current_version = FeedVersion::current(self.db, &feed.id).await?;
+        notify_version_change(
+            self.config.feed_version_webhook_url.as_deref(),
+            &feed.id,
+            import_id,
+            previous_version,
+            current_version,
+        )
+        .await;
 
         // build_stop_index(self.db).await?;
 
         Ok(record_count)
     }
 
-    pub async fn sync(db: &'a DatabaseConnection) -> GtfsSyncResult<u64> {
-        Self {
+    /// Imports each feed that has changed, and removes feeds that are no longer configured.
+    /// Feeds that fail are left as they were and reported in the outcome.
+    #[tracing::instrument(skip_all)]
+    pub async fn sync(
+        db: &'a DatabaseConnection,
+        config: &'a SyncConfig,
+        feeds: &[Feed],
+        progress: Arc<SyncProgress>,
+    ) -> GtfsSyncResult<SyncOutcome> {
+        progress.started();
+        let result = Self {
             db,
+            config,
+            progress: progress.clone(),
             // state: SyncState {
             //     import_id: 0,
             //     file_dir: TempDir::new()?,
             // },
         }
-        .do_sync()
-        .await
+        .sync_all(feeds)
+        .await;
+        progress.finished(match &result {
+            Ok(outcome) => outcome.describe_errors(),
+            Err(e) => Some(e.to_string()),
+        });
+        result
+    }
+
+    async fn sync_all(&self, feeds: &[Feed]) -> GtfsSyncResult<SyncOutcome> {
+        let mut outcome = SyncOutcome::default();
+        for feed in feeds {
+            match self.do_sync(feed).await {
+                Ok(record_count) => outcome.new_records += record_count,
+                Err(e) => {
+                    tracing::error!("Failed to sync the {} feed: {}", feed.id, e);
+                    outcome.feed_errors.push((feed.id.clone(), e));
+                }
+            }
+        }
+
+        self.progress.phase(SyncPhase::RemovingOldFeeds);
+        let feed_ids = feeds.iter().map(|f| f.id.clone()).collect_vec();
+        outcome.new_records += retry_busy("Removing old feeds", || {
+            let feed_ids = feed_ids.clone();
+            async move {
+                task::spawn_blocking(move || remove_other_feeds(&feed_ids))
+                    .await
+                    .unwrap() // unwrap spawn error
+            }
+        })
+        .await?;
+
+        Ok(outcome)
     }
 }
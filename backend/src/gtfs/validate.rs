@@ -0,0 +1,353 @@
+use std::{collections::HashMap, fmt::Display, path::Path};
+
+use rusqlite::{vtab::csvtab, Connection};
+use serde::Serialize;
+
+use crate::config;
+
+use super::sync::missing_required_files;
+
+/// Files the checks read, all of which are required
+const CHECKED_FILE_NAMES: [&str; 5] = [
+    "agency.txt",
+    "routes.txt",
+    "trips.txt",
+    "stops.txt",
+    "stop_times.txt",
+];
+
+/// Columns the checks read that files may leave out, these are read as empty
+const OPTIONAL_COLUMNS: [(&str, &str); 2] =
+    [("agency.txt", "agency_id"), ("stops.txt", "location_type")];
+
+/// How many offending ids are included in a problem
+const MAX_EXAMPLES: usize = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Ignore,
+    /// Logged, but the feed is still imported
+    Warn,
+    /// The feed isn't imported
+    Error,
+}
+
+impl Severity {
+    fn parse(s: &str) -> Option<Self> {
+        match s.to_lowercase().as_str() {
+            "ignore" => Some(Self::Ignore),
+            "warn" => Some(Self::Warn),
+            "error" => Some(Self::Error),
+            _ => None,
+        }
+    }
+}
+
+/// A check run against a feed before it's imported.
+/// Its severity can be overridden with `GTFS_VALIDATE_<NAME>` set to `error`, `warn` or `ignore`.
+struct Check {
+    name: &'static str,
+    description: &'static str,
+    default_severity: Severity,
+    /// Selects an identifier of each offending row
+    sql: &'static str,
+}
+
+const CHECKS: [Check; 10] = [
+    Check {
+        name: "trip_routes",
+        description: "trips with a route_id not in routes.txt",
+        default_severity: Severity::Error,
+        sql: "SELECT trip_id FROM trips WHERE route_id NOT IN (SELECT route_id FROM routes)",
+    },
+    Check {
+        name: "stop_time_trips",
+        description: "stop times with a trip_id not in trips.txt",
+        default_severity: Severity::Error,
+        sql: "SELECT DISTINCT trip_id FROM stop_times WHERE trip_id NOT IN (SELECT trip_id FROM trips)",
+    },
+    Check {
+        name: "stop_time_stops",
+        description: "stop times with a stop_id not in stops.txt",
+        default_severity: Severity::Error,
+        sql: "SELECT DISTINCT stop_id FROM stop_times WHERE stop_id NOT IN (SELECT stop_id FROM stops)",
+    },
+    Check {
+        name: "stop_coordinates",
+        description: "stops or stations without a sensible location",
+        default_severity: Severity::Warn,
+        // nodes and entrances may leave out their location
+        sql: "SELECT stop_id FROM stops
+            WHERE location_type IN ('', '0', '1')
+            AND (
+                stop_lat = '' OR stop_lon = ''
+                OR CAST(stop_lat AS REAL) NOT BETWEEN -90 AND 90
+                OR CAST(stop_lon AS REAL) NOT BETWEEN -180 AND 180
+                OR (CAST(stop_lat AS REAL) = 0 AND CAST(stop_lon AS REAL) = 0)
+            )",
+    },
+    Check {
+        name: "calendar_dates",
+        description: "services with invalid or backwards date ranges",
+        default_severity: Severity::Warn,
+        sql: "SELECT service_id FROM calendar
+            WHERE start_date NOT GLOB '[0-9][0-9][0-9][0-9][0-1][0-9][0-3][0-9]'
+            OR end_date NOT GLOB '[0-9][0-9][0-9][0-9][0-1][0-9][0-3][0-9]'
+            OR start_date > end_date",
+    },
+    Check {
+        name: "duplicate_agencies",
+        description: "agency_ids used more than once",
+        default_severity: Severity::Error,
+        sql: "SELECT agency_id FROM agency GROUP BY agency_id HAVING COUNT(*) > 1",
+    },
+    Check {
+        name: "duplicate_routes",
+        description: "route_ids used more than once",
+        default_severity: Severity::Error,
+        sql: "SELECT route_id FROM routes GROUP BY route_id HAVING COUNT(*) > 1",
+    },
+    Check {
+        name: "duplicate_trips",
+        description: "trip_ids used more than once",
+        default_severity: Severity::Error,
+        sql: "SELECT trip_id FROM trips GROUP BY trip_id HAVING COUNT(*) > 1",
+    },
+    Check {
+        name: "duplicate_stops",
+        description: "stop_ids used more than once",
+        default_severity: Severity::Error,
+        sql: "SELECT stop_id FROM stops GROUP BY stop_id HAVING COUNT(*) > 1",
+    },
+    Check {
+        name: "duplicate_stop_times",
+        description: "trips with a stop_sequence used more than once",
+        default_severity: Severity::Error,
+        sql: "SELECT DISTINCT trip_id FROM stop_times
+            GROUP BY trip_id, stop_sequence HAVING COUNT(*) > 1",
+    },
+];
+
+/// The severities of the checks that have been overridden, by check name
+#[derive(Debug, Clone, Default)]
+pub struct Severities(HashMap<&'static str, Severity>);
+
+impl Severities {
+    /// Every problem with the settings is added to `errors`
+    pub fn from_env(errors: &mut Vec<String>) -> Self {
+        let mut severities = HashMap::new();
+        for check in &CHECKS {
+            let var = format!("GTFS_VALIDATE_{}", check.name.to_uppercase());
+            if let Ok(value) = config::var(&var) {
+                match Severity::parse(&value) {
+                    Some(severity) => {
+                        severities.insert(check.name, severity);
+                    }
+                    None => errors.push(format!(
+                        "Invalid {}: {}, expected error, warn or ignore",
+                        var, value
+                    )),
+                }
+            }
+        }
+        Self(severities)
+    }
+
+    fn of(&self, check: &Check) -> Severity {
+        self.0
+            .get(check.name)
+            .copied()
+            .unwrap_or(check.default_severity)
+    }
+}
+
+/// Something wrong with a feed
+#[derive(Debug, Clone, Serialize)]
+pub struct Problem {
+    pub check: &'static str,
+    pub description: &'static str,
+    pub severity: Severity,
+    pub count: usize,
+    /// Up to a few of the offending ids
+    pub examples: Vec<String>,
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {} ({}), e.g. {}",
+            self.count,
+            self.description,
+            self.check,
+            self.examples.join(", ")
+        )
+    }
+}
+
+fn run_check(
+    db: &Connection,
+    check: &Check,
+    severity: Severity,
+) -> rusqlite::Result<Option<Problem>> {
+    let mut statement = db.prepare(check.sql)?;
+    let ids = statement
+        .query_map([], |row| row.get::<_, String>(0))?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if ids.is_empty() {
+        return Ok(None);
+    }
+
+    Ok(Some(Problem {
+        check: check.name,
+        description: check.description,
+        severity,
+        count: ids.len(),
+        examples: ids.into_iter().take(MAX_EXAMPLES).collect(),
+    }))
+}
+
+/// Checks the extracted files of a feed, returning the problems found.
+/// The feed shouldn't be imported if any are errors.
+pub fn validate_gtfs_files(dir: &Path, severities: &Severities) -> rusqlite::Result<Vec<Problem>> {
+    let missing = missing_required_files(dir);
+    if !missing.is_empty() {
+        return Ok(vec![Problem {
+            check: "missing_files",
+            description: "required files missing",
+            severity: Severity::Error,
+            count: missing.len(),
+            examples: missing,
+        }]);
+    }
+
+    // The files are read where they are rather than imported
+    let db = Connection::open_in_memory()?;
+    csvtab::load_module(&db)?;
+
+    for filename in &CHECKED_FILE_NAMES {
+        let path = dir.join(filename).to_str().unwrap().to_string(); // only if somehow invalid utf-8
+        let table = filename.trim_end_matches(".txt");
+        db.execute_batch(&format!(
+            "CREATE VIRTUAL TABLE temp.{table}_file USING csv(filename='{path}', header=yes);"
+        ))?;
+
+        let header = db
+            .prepare(&format!(
+                "SELECT name FROM pragma_table_info('{table}_file', 'temp')"
+            ))?
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        let missing = OPTIONAL_COLUMNS
+            .iter()
+            .filter(|(file, column)| file == filename && !header.iter().any(|h| h == column))
+            .map(|(_, column)| format!(", '' AS {column}"))
+            .collect::<String>();
+        db.execute_batch(&format!(
+            "CREATE TEMP VIEW {table} AS SELECT *{missing} FROM temp.{table}_file;"
+        ))?;
+    }
+
+    // Feeds with only calendar_dates.txt have nothing to check in the calendar
+    let calendar_path = dir.join("calendar.txt");
+    if calendar_path.exists() {
+        let path = calendar_path.to_str().unwrap();
+        db.execute_batch(&format!(
+            "CREATE VIRTUAL TABLE temp.calendar USING csv(filename='{path}', header=yes);"
+        ))?;
+    } else {
+        db.execute_batch("CREATE TEMP TABLE calendar (service_id, start_date, end_date);")?;
+    }
+
+    let mut problems = vec![];
+    for check in &CHECKS {
+        let severity = severities.of(check);
+        if severity == Severity::Ignore {
+            continue;
+        }
+        problems.extend(run_check(&db, check, severity)?);
+    }
+
+    Ok(problems)
+}
+
+#[cfg(test)]
+mod test {
+
+    use itertools::Itertools;
+
+    use super::*;
+
+    #[test]
+    fn test_validate_gtfs_files() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let files = [
+            ("agency.txt", "agency_id,agency_name\nAT,Auckland Transport\n"),
+            (
+                "calendar.txt",
+                "service_id,start_date,end_date\nweekday,20240101,20241231\nbad,20241231,20240101\n",
+            ),
+            ("routes.txt", "route_id,agency_id\nNX1,AT\n"),
+            ("trips.txt", "trip_id,route_id\n1,NX1\n2,NX2\n2,NX1\n"),
+            (
+                "stops.txt",
+                "stop_id,stop_lat,stop_lon,location_type\nA,-36.8,174.7,0\nB,0,0,\n",
+            ),
+            ("stop_times.txt", "trip_id,stop_id,stop_sequence\n1,A,1\n1,B,2\n1,C,3\n"),
+        ];
+        for (name, content) in files {
+            std::fs::write(dir.path().join(name), content).unwrap();
+        }
+
+        let problems = validate_gtfs_files(dir.path(), &Severities::default()).unwrap();
+        let found = problems.iter().map(|p| p.check).sorted().collect_vec();
+
+        assert_eq!(
+            found,
+            [
+                "calendar_dates",
+                "duplicate_trips",
+                "stop_coordinates",
+                "stop_time_stops",
+                "trip_routes"
+            ]
+        );
+        let unknown_stops = problems
+            .iter()
+            .find(|p| p.check == "stop_time_stops")
+            .unwrap();
+        assert_eq!(unknown_stops.examples, ["C"]);
+    }
+
+    #[test]
+    fn test_optional_columns() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let files = [
+            ("agency.txt", "agency_name\nAuckland Transport\n"),
+            (
+                "calendar_dates.txt",
+                "service_id,date,exception_type\n1,20240101,1\n",
+            ),
+            ("routes.txt", "route_id\nNX1\n"),
+            ("trips.txt", "trip_id,route_id\n1,NX1\n"),
+            (
+                "stops.txt",
+                "stop_id,stop_lat,stop_lon\nA,-36.8,174.7\nB,0,0\n",
+            ),
+            (
+                "stop_times.txt",
+                "trip_id,stop_id,stop_sequence\n1,A,1\n1,B,2\n",
+            ),
+        ];
+        for (name, content) in files {
+            std::fs::write(dir.path().join(name), content).unwrap();
+        }
+
+        let problems = validate_gtfs_files(dir.path(), &Severities::default()).unwrap();
+        let found = problems.iter().map(|p| p.check).collect_vec();
+        assert_eq!(found, ["stop_coordinates"]);
+        assert_eq!(problems[0].examples, ["B"]);
+    }
+}
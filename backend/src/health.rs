@@ -0,0 +1,235 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU32, Ordering};
+use std::sync::Mutex;
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use sea_orm::{EntityTrait, QueryOrder};
+use serde::Serialize;
+
+use crate::at::policy::{BreakerReport, BreakerState};
+use crate::entity::{import, prelude::Import};
+use crate::ContextData;
+
+/// Realtime is considered stale if it hasn't been polled successfully in this long
+pub const REALTIME_STALE_SECONDS: i64 = 5 * 60;
+
+/// The time something last happened, safe to share between tasks
+#[derive(Debug, Default)]
+pub struct LastRun(AtomicI64);
+
+impl LastRun {
+    pub fn record(&self) {
+        self.0.store(Utc::now().timestamp_millis(), Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> Option<DateTime<Utc>> {
+        match self.0.load(Ordering::Relaxed) {
+            0 => None,
+            millis => DateTime::from_timestamp_millis(millis),
+        }
+    }
+}
+
+/// Whether a supervised background task is running, and how it last failed
+#[derive(Debug, Default)]
+pub struct TaskStatus {
+    running: AtomicBool,
+    restarts: AtomicU32,
+    last_error: Mutex<Option<String>>,
+}
+
+impl TaskStatus {
+    pub fn started(&self) {
+        self.running.store(true, Ordering::Relaxed);
+    }
+
+    pub fn failed(&self, error: String) {
+        self.running.store(false, Ordering::Relaxed);
+        self.restarts.fetch_add(1, Ordering::Relaxed);
+        *self.last_error.lock().unwrap() = Some(error);
+    }
+}
+
+/// Status of the background tasks, updated as they run
+#[derive(Debug, Default)]
+pub struct Health {
+    pub realtime_poll: LastRun,
+    pub gtfs_sync: LastRun,
+    pub index_build: LastRun,
+    pub firehose: TaskStatus,
+    pub maintenance: TaskStatus,
+    pub notifier: TaskStatus,
+    pub realtime_watcher: TaskStatus,
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Ok,
+    Degraded,
+    Down,
+}
+
+#[derive(Serialize)]
+pub struct SubsystemReport {
+    pub status: Status,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_success: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub age_seconds: Option<i64>,
+}
+
+impl SubsystemReport {
+    fn from_last_run(last_run: &LastRun, stale_after_seconds: Option<i64>) -> Self {
+        let last_success = last_run.get();
+        let age_seconds = last_success.map(|t| (Utc::now() - t).num_seconds());
+
+        let status = match (age_seconds, stale_after_seconds) {
+            (None, _) => Status::Degraded,
+            (Some(age), Some(stale)) if age > stale => Status::Degraded,
+            _ => Status::Ok,
+        };
+
+        Self {
+            status,
+            last_success,
+            age_seconds,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct TaskReport {
+    pub status: Status,
+    pub restarts: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+}
+
+impl TaskReport {
+    fn from_status(task: &TaskStatus) -> Self {
+        Self {
+            // a task waiting to be restarted isn't doing its job
+            status: if task.running.load(Ordering::Relaxed) {
+                Status::Ok
+            } else {
+                Status::Degraded
+            },
+            restarts: task.restarts.load(Ordering::Relaxed),
+            last_error: task.last_error.lock().unwrap().clone(),
+        }
+    }
+}
+
+/// The circuit breakers of the publishers, degraded while any aren't closed,
+/// and when each endpoint last responded successfully
+#[derive(Serialize)]
+pub struct UpstreamReport {
+    pub status: Status,
+    pub breakers: BTreeMap<String, BreakerReport>,
+    pub last_success: BTreeMap<String, Option<DateTime<Utc>>>,
+}
+
+#[derive(Serialize)]
+pub struct HealthReport {
+    pub status: Status,
+    pub database: SubsystemReport,
+    pub realtime: SubsystemReport,
+    pub gtfs_sync: SubsystemReport,
+    pub index_build: SubsystemReport,
+    pub import: SubsystemReport,
+    pub upstream: UpstreamReport,
+    pub firehose: TaskReport,
+    pub maintenance: TaskReport,
+    pub notifier: TaskReport,
+    pub realtime_watcher: TaskReport,
+}
+
+/// Checks each subsystem, the overall status is the worst of them
+pub async fn report(ctx: &ContextData) -> HealthReport {
+    let db_reachable = ctx.read_db.ping().await.is_ok();
+
+    let database = SubsystemReport {
+        status: if db_reachable { Status::Ok } else { Status::Down },
+        last_success: None,
+        age_seconds: None,
+    };
+
+    let last_import = if db_reachable {
+        Import::find()
+            .order_by_desc(import::Column::Id)
+            .one(&ctx.read_db)
+            .await
+            .ok()
+            .flatten()
+    } else {
+        None
+    };
+
+    // sqlite CURRENT_TIMESTAMP format, in UTC
+    let import_time = last_import
+        .and_then(|i| NaiveDateTime::parse_from_str(&i.timestamp, "%Y-%m-%d %H:%M:%S").ok())
+        .map(|t| t.and_utc());
+
+    let import = SubsystemReport {
+        status: if import_time.is_some() {
+            Status::Ok
+        } else {
+            Status::Degraded
+        },
+        last_success: import_time,
+        age_seconds: import_time.map(|t| (Utc::now() - t).num_seconds()),
+    };
+
+    let health = &ctx.health;
+    let realtime = SubsystemReport::from_last_run(&health.realtime_poll, Some(REALTIME_STALE_SECONDS));
+    let gtfs_sync = SubsystemReport::from_last_run(&health.gtfs_sync, None);
+    let index_build = SubsystemReport::from_last_run(&health.index_build, None);
+    let firehose = TaskReport::from_status(&health.firehose);
+    let breakers = ctx.at_client.breaker_report();
+    let upstream = UpstreamReport {
+        status: if breakers.values().all(|b| b.state == BreakerState::Closed) {
+            Status::Ok
+        } else {
+            Status::Degraded
+        },
+        breakers,
+        last_success: ctx.at_client.last_success(),
+    };
+    let maintenance = TaskReport::from_status(&health.maintenance);
+    let notifier = TaskReport::from_status(&health.notifier);
+    let realtime_watcher = TaskReport::from_status(&health.realtime_watcher);
+
+    let status = if database.status == Status::Down {
+        Status::Down
+    } else if [&realtime, &gtfs_sync, &index_build, &import]
+        .iter()
+        .map(|s| s.status)
+        .chain([
+            upstream.status,
+            firehose.status,
+            maintenance.status,
+            notifier.status,
+            realtime_watcher.status,
+        ])
+        .any(|s| s != Status::Ok)
+    {
+        Status::Degraded
+    } else {
+        Status::Ok
+    };
+
+    HealthReport {
+        status,
+        database,
+        realtime,
+        gtfs_sync,
+        index_build,
+        import,
+        upstream,
+        firehose,
+        maintenance,
+        notifier,
+        realtime_watcher,
+    }
+}
@@ -0,0 +1,294 @@
+//! Logs and spans through `tracing`, as text or with `LOG_FORMAT=json` as JSON lines
+//! for log aggregators to query by field. Spans are logged when they close, with how long they took.
+
+use std::{
+    fmt::Debug,
+    io::{stderr, Write},
+    time::Instant,
+};
+
+use chrono::{SecondsFormat, Utc};
+use serde_json::{json, Map, Value};
+use tracing::{
+    field::{Field, Visit},
+    level_filters::LevelFilter,
+    span::{Attributes, Id, Record},
+    Event, Level, Subscriber,
+};
+use tracing_subscriber::{
+    fmt::{self, format::FmtSpan},
+    layer::Context,
+    prelude::*,
+    registry::LookupSpan,
+    util::TryInitError,
+    EnvFilter, Layer,
+};
+
+use crate::{config, request_id, telemetry::OtlpLayer};
+
+/// If `RUST_LOG` isn't set
+const DEFAULT_FILTER: &str = "info";
+
+/// What's logged and how
+#[derive(Debug, Clone)]
+pub struct LogConfig {
+    /// `RUST_LOG`'s directives, e.g. `info,next_at_rs=debug`
+    filter: String,
+    json: bool,
+}
+
+impl LogConfig {
+    /// Every problem with the settings is added to `errors`
+    pub fn from_env(errors: &mut Vec<String>) -> Self {
+        let mut filter = config::var("RUST_LOG").unwrap_or_else(|_| DEFAULT_FILTER.to_string());
+        if let Err(e) = EnvFilter::try_new(&filter) {
+            errors.push(format!("Invalid RUST_LOG: {}", e));
+            filter = DEFAULT_FILTER.to_string();
+        }
+
+        let json = match config::var("LOG_FORMAT").as_deref() {
+            Ok("json") => true,
+            Ok("text") | Err(_) => false,
+            Ok(format) => {
+                errors.push(format!(
+                    "Invalid LOG_FORMAT: {}, expected text or json",
+                    format
+                ));
+                false
+            }
+        };
+
+        Self { filter, json }
+    }
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            filter: DEFAULT_FILTER.to_string(),
+            json: false,
+        }
+    }
+}
+
+/// Passes on what libraries log through the `log` crate
+struct LogBridge;
+
+impl log::Log for LogBridge {
+    fn enabled(&self, _: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        let target = record.target();
+        let message = record.args();
+        match record.level() {
+            log::Level::Error => tracing::error!(log.target = target, "{}", message),
+            log::Level::Warn => tracing::warn!(log.target = target, "{}", message),
+            log::Level::Info => tracing::info!(log.target = target, "{}", message),
+            log::Level::Debug => tracing::debug!(log.target = target, "{}", message),
+            log::Level::Trace => tracing::trace!(log.target = target, "{}", message),
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+fn log_level(level: Option<LevelFilter>) -> log::LevelFilter {
+    match level {
+        Some(LevelFilter::OFF) => log::LevelFilter::Off,
+        Some(LevelFilter::ERROR) => log::LevelFilter::Error,
+        Some(LevelFilter::WARN) => log::LevelFilter::Warn,
+        Some(LevelFilter::INFO) => log::LevelFilter::Info,
+        Some(LevelFilter::DEBUG) => log::LevelFilter::Debug,
+        _ => log::LevelFilter::Trace,
+    }
+}
+
+/// Collects fields as JSON
+pub struct JsonFields<'a>(pub &'a mut Map<String, Value>);
+
+impl Visit for JsonFields<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        self.0
+            .insert(field.name().to_string(), json!(format!("{:?}", value)));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), json!(value));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_string(), json!(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_string(), json!(value));
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.insert(field.name().to_string(), json!(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_string(), json!(value));
+    }
+}
+
+/// Kept with each span for the JSON layer
+struct SpanData {
+    fields: Map<String, Value>,
+    opened: Instant,
+}
+
+/// Writes events as JSON lines, with the fields of the spans they happened in,
+/// and spans as they close with how long they were open
+struct JsonLayer;
+
+impl JsonLayer {
+    fn write(level: &Level, target: &str, message: Value, fields: Map<String, Value>) {
+        let line = json!({
+            "timestamp": Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            "level": level.as_str(),
+            "target": target,
+            "message": message,
+            "request_id": request_id::current(),
+            "fields": fields,
+        });
+        writeln!(stderr().lock(), "{}", line).ok();
+    }
+}
+
+impl<S> Layer<S> for JsonLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let mut fields = Map::new();
+        attrs.record(&mut JsonFields(&mut fields));
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(SpanData {
+                fields,
+                opened: Instant::now(),
+            });
+        }
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(data) = span.extensions_mut().get_mut::<SpanData>() {
+                values.record(&mut JsonFields(&mut data.fields));
+            }
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        // The fields of the spans it's in, innermost last so that they win
+        let mut fields = Map::new();
+        if let Some(scope) = ctx.event_scope(event) {
+            for span in scope.from_root() {
+                if let Some(data) = span.extensions().get::<SpanData>() {
+                    fields.extend(data.fields.clone());
+                }
+            }
+        }
+        event.record(&mut JsonFields(&mut fields));
+
+        let message = fields.remove("message").unwrap_or_default();
+        let target = match fields.remove("log.target") {
+            Some(Value::String(target)) => target,
+            _ => event.metadata().target().to_string(),
+        };
+        Self::write(event.metadata().level(), &target, message, fields);
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(&id) else {
+            return;
+        };
+        let extensions = span.extensions();
+        let Some(data) = extensions.get::<SpanData>() else {
+            return;
+        };
+        let mut fields = data.fields.clone();
+        fields.insert(
+            "elapsed_ms".to_string(),
+            json!(data.opened.elapsed().as_secs_f64() * 1000.0),
+        );
+        let metadata = span.metadata();
+        Self::write(
+            metadata.level(),
+            metadata.target(),
+            json!(format!("{} closed", metadata.name())),
+            fields,
+        );
+    }
+}
+
+/// Sets up logging, with what's logged filtered by `RUST_LOG`.
+/// Fails if it's already been set up.
+pub fn init(config: &LogConfig) -> Result<(), TryInitError> {
+    let filter = EnvFilter::new(&config.filter);
+    let max_level = filter.max_level_hint();
+    let json = config.json;
+
+    let text_layer = fmt::layer()
+        .with_ansi(false)
+        .with_writer(stderr)
+        .with_span_events(FmtSpan::CLOSE);
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(json.then_some(JsonLayer))
+        .with((!json).then_some(text_layer))
+        .with(OtlpLayer)
+        .try_init()?;
+
+    if log::set_boxed_logger(Box::new(LogBridge)).is_ok() {
+        log::set_max_level(log_level(max_level));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+
+    use std::sync::{Arc, Mutex};
+
+    use tracing_subscriber::registry;
+
+    use super::*;
+
+    /// Records the fields of events
+    struct Capture(Arc<Mutex<Vec<Map<String, Value>>>>);
+
+    impl<S: Subscriber> Layer<S> for Capture {
+        fn on_event(&self, event: &Event<'_>, _: Context<'_, S>) {
+            let mut fields = Map::new();
+            event.record(&mut JsonFields(&mut fields));
+            self.0.lock().unwrap().push(fields);
+        }
+    }
+
+    #[test]
+    fn test_json_fields() {
+        let events = Arc::new(Mutex::new(vec![]));
+        let subscriber = registry().with(Capture(events.clone()));
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!(feed_id = "at", entities = 42, busy = false, "Polled feed");
+        });
+
+        assert_eq!(
+            events.lock().unwrap()[0],
+            json!({
+                "message": "Polled feed",
+                "feed_id": "at",
+                "entities": 42,
+                "busy": false,
+            })
+            .as_object()
+            .cloned()
+            .unwrap()
+        );
+        assert_eq!(log_level(Some(LevelFilter::INFO)), log::LevelFilter::Info);
+    }
+}
@@ -1,216 +1,253 @@
 extern crate derive_builder;
 
+mod api;
 mod at;
+mod auth;
+mod cache;
+mod config;
+mod cors;
 mod db;
 mod entity;
 mod error;
+mod etag;
+mod fares;
 mod geo;
 mod gtfs;
+mod health;
+mod logging;
 mod maintenance;
+mod map;
+mod notifications;
+mod patterns;
+mod protobuf;
+mod request_id;
+mod service_status;
+mod shapes;
+mod stations;
 mod stops;
+mod supervisor;
+mod telemetry;
+mod tiles;
+mod translations;
+mod vehicles;
+mod versions;
+mod webhooks;
 
 #[cfg(test)]
 mod test_utils;
 
-use std::env;
+use std::{pin::pin, sync::Arc};
 
-use actix_web::{get, middleware::Logger, post, web, App, HttpResponse, HttpServer, Responder};
-use at::client::AtClient;
+use actix_web::{middleware::Logger, web, App, HttpServer};
+use at::api::AtApi;
 
-use error::{NextAtError, NextAtResult};
 use migration::{Migrator, MigratorTrait};
-use reqwest::StatusCode;
 use sea_orm::DatabaseConnection;
-use serde::Deserialize;
-use serde_json::json;
-use tokio::select;
-
-use crate::{db::util::open_seaorm, gtfs::realtime::monitor_firehose, maintenance::sync_and_index};
+use tokio::{join, select, sync::Mutex, time::timeout};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    auth::ApiKeys,
+    cache::QueryCaches,
+    db::util::{migrate_realtime, open_seaorm, open_seaorm_read_only},
+    gtfs::feed::Feed, gtfs::realtime::{monitor_firehose, RealtimeConfig},
+    gtfs::progress::{IndexProgress, SyncProgress},
+    gtfs::{index::IndexConfig, sync::SyncConfig},
+    maintenance::sync_and_index,
+    health::Health, notifications::web_push::WebPush, supervisor::supervise, tiles::TileCache,
+    versions::DataVersions, webhooks::Webhooks,
+};
 
 #[derive(Clone)]
 pub struct ContextData {
-    at_client: AtClient,
+    /// A trait object so tests can serve feeds from fixtures
+    at_client: Arc<dyn AtApi>,
+    /// For writes, a single connection
     db: DatabaseConnection,
+    /// For the API's reads
+    read_db: DatabaseConnection,
+    versions: Arc<DataVersions>,
+    health: Arc<Health>,
+    api_keys: Arc<ApiKeys>,
+    feeds: Arc<Vec<Feed>>,
+    sync: Arc<SyncConfig>,
+    index: Arc<IndexConfig>,
+    realtime: Arc<RealtimeConfig>,
+    sync_progress: Arc<SyncProgress>,
+    index_progress: Arc<IndexProgress>,
+    /// Held while syncing or building an index, which would break each other if they overlapped
+    sync_lock: Arc<Mutex<()>>,
+    /// Held while backing up, so that backups don't pile up
+    backup_lock: Arc<Mutex<()>>,
+    tile_cache: Arc<TileCache>,
+    query_cache: Arc<QueryCaches>,
+    web_push: Arc<WebPush>,
+    webhooks: Arc<Webhooks>,
+    /// Cancelled on shutdown, for the background tasks to stop once they're at a good point
+    shutdown: CancellationToken,
 }
 
-#[derive(Deserialize)]
-struct StopsQuery {
-    lat: Option<f64>,
-    lon: Option<f64>,
-    code: Option<String>
-}
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    dotenvy::from_filename(".env").ok();
 
-#[get("/ok")]
-async fn ok() -> NextAtResult<impl Responder> {
-    Ok(HttpResponse::Ok().finish())
-}
+    let config = config::Config::load()
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
 
-#[get("/stops")]
-async fn get_stops(
-    query: web::Query<StopsQuery>,
-    ctx: web::Data<ContextData>,
-) -> NextAtResult<impl Responder> {
-    let mut stops = vec![];
-    let mut lat = query.lat;
-    let mut lon = query.lon;
-    
-    if let Some(code) = &query.code {
-        if let Some(stop) = stops::get_stop_by_code(&ctx, code).await? {
-            stops.push(stop.clone());
-            // And nearby stops if no other location set
-            if let (None, None, Some(stop_lat), Some(stop_lon)) = (lat, lon, stop.lat, stop.lon) {
-                lat = Some(stop_lat);
-                lon = Some(stop_lon);
-            }
-        }
-    }
+    // After loading, so the settings from the config file and .env apply
+    logging::init(&config.log).ok();
 
-    if let (Some(lat), Some(lon)) = (lat, lon) {
-        let mut nearby_stops = stops::get_closest_stops(&ctx, lat, lon, 5).await?;
-        // without the existing stop if set
-        if let Some(code) = &query.code {
-            nearby_stops.retain(|s| s.code != *code);
-        }
+    tracing::debug!("Debug logging enabled");
 
-        stops.extend(nearby_stops);
+    if let Some(file) = &config.file {
+        tracing::info!("Read config from {}", file.display());
     }
-
-    let response = web::Json(json!({
-        "stops": stops,
-    }));
-    Ok(response)
-}
-
-#[get("/stops/{stop_id}/routes")]
-async fn get_stop_routes(
-    params: web::Path<(String,)>,
-    ctx: web::Data<ContextData>,
-) -> NextAtResult<impl Responder> {
-    let (stop_id,) = params.into_inner();
-
-    let routes = stops::get_stop_routes(&ctx, &stop_id).await?;
-    let response = web::Json(json!({
-        "routes": routes,
-    }));
-    Ok(response)
-}
-
-#[get("/stops/{stop_id}/arrivals")]
-async fn get_stop_arrivals(
-    params: web::Path<(String,)>,
-    ctx: web::Data<ContextData>,
-) -> NextAtResult<impl Responder> {
-    let (stop_id,) = params.into_inner();
-
-    let arrivals = stops::get_stop_arrivals(&ctx, &stop_id).await?;
-    let response = web::Json(json!({
-        "stop_arrivals": arrivals,
-    }));
-    Ok(response)
-}
-
-#[post("/management/gtfs/sync")]
-async fn sync_gtfs(ctx: web::Data<ContextData>) -> NextAtResult<impl Responder> {
-    let new_records = gtfs::sync::Sync::sync(&ctx.db).await?;
-    let response = web::Json(json!({
-        "newRecords": new_records,
-    }));
-    Ok(response)
-}
-
-#[post("/management/gtfs/index-stoptimes")]
-async fn index_stop_times() -> NextAtResult<impl Responder> {
-    gtfs::index::build_stop_time_index().await?;
-    let response = HttpResponse::Ok().status(StatusCode::NO_CONTENT).finish();
-    Ok(response)
-}
-
-#[post("/management/gtfs/index-stops")]
-async fn index_stops() -> NextAtResult<impl Responder> {
-    gtfs::index::build_stop_index().await?;
-    let response = HttpResponse::Ok().status(StatusCode::NO_CONTENT).finish();
-    Ok(response)
-}
-
-#[actix_web::main]
-async fn main() -> std::io::Result<()> {
-    if env::var("RUST_LOG").is_err() {
-        env::set_var("RUST_LOG", "info");
+    if config.api_keys.is_empty() {
+        tracing::warn!("MANAGEMENT_API_KEYS is not set, management endpoints are disabled");
+    }
+    tracing::info!("Database at {}", config.db.path);
+    tracing::info!("Database config: {:?}", config.db);
+    db::config::init(config.db);
+    if config.telemetry.is_some() {
+        telemetry::enable();
     }
-    env_logger::try_init().ok();
-
-    log::debug!("Debug logging enabled");
-
-    dotenvy::from_filename(".env").ok();
 
-    let at_client = AtClient::new().map_err(NextAtError::At).unwrap();
     let db = open_seaorm().await;
 
-    log::info!("Migrating database");
+    tracing::info!("Migrating database");
+    migrate_realtime()
+        .await
+        .expect("Failed to migrate realtime database");
     Migrator::up(&db, None)
         .await
         .expect("Failed to migrate database");
-
-    sync_and_index(&db).await?;
-
-    let ctx = ContextData { at_client, db };
-
+    let read_db = open_seaorm_read_only().await;
+
+    let ctx = ContextData {
+        at_client: Arc::new(config.at_client),
+        db,
+        read_db,
+        versions: Arc::new(DataVersions::new()),
+        health: Arc::new(Health::default()),
+        api_keys: Arc::new(config.api_keys),
+        feeds: Arc::new(config.feeds),
+        sync: Arc::new(config.sync),
+        index: Arc::new(config.index),
+        realtime: Arc::new(config.realtime),
+        sync_progress: Arc::new(SyncProgress::default()),
+        index_progress: Arc::new(IndexProgress::default()),
+        sync_lock: Arc::new(Mutex::new(())),
+        backup_lock: Arc::new(Mutex::new(())),
+        tile_cache: Arc::new(TileCache::default()),
+        query_cache: Arc::new(QueryCaches::new(&config.cache)),
+        web_push: Arc::new(WebPush::new(config.notifications.vapid)),
+        webhooks: Arc::new(config.webhooks),
+        shutdown: CancellationToken::new(),
+    };
+
+    sync_and_index(&ctx).await?;
+
+    // The background tasks are restarted if they fail, rather than taking the server down
     let firehose_ctx = ctx.clone();
-    let firehose = monitor_firehose(&firehose_ctx);
-
-    let maintenance = maintenance::keep_maintained();
+    let firehose = supervise(
+        "firehose monitor",
+        &firehose_ctx.health.firehose,
+        &firehose_ctx.shutdown,
+        || monitor_firehose(&firehose_ctx),
+    );
+
+    let maintenance_ctx = ctx.clone();
+    let maintenance = supervise(
+        "maintenance loop",
+        &maintenance_ctx.health.maintenance,
+        &maintenance_ctx.shutdown,
+        || maintenance::keep_maintained(&maintenance_ctx),
+    );
+
+    let notifier_ctx = ctx.clone();
+    let notifier = supervise(
+        "push notifier",
+        &notifier_ctx.health.notifier,
+        &notifier_ctx.shutdown,
+        || notifications::notify_subscribers(&notifier_ctx, config.notifications.interval),
+    );
+
+    let watcher_ctx = ctx.clone();
+    let realtime_watcher = supervise(
+        "realtime watcher",
+        &watcher_ctx.health.realtime_watcher,
+        &watcher_ctx.shutdown,
+        || webhooks::watch_realtime(&watcher_ctx),
+    );
+
+    let telemetry_ctx = ctx.clone();
+    let telemetry = async {
+        if let Some(telemetry) = &config.telemetry {
+            telemetry::export(&telemetry_ctx, telemetry).await;
+        }
+    };
 
-    let listen_address = env::var("LISTEN_ADDRESS").unwrap_or("127.0.0.1:8080".to_string());
+    tracing::info!("Starting server at {}", config.listen_address);
 
-    log::info!("Starting server at {}", listen_address);
+    let cors = config.cors.clone();
+    let server_ctx = ctx.clone();
 
     let server = HttpServer::new(move || {
-        let logger = Logger::default();
-
-        let mut cors = actix_cors::Cors::default()
-            .allowed_methods(vec!["GET"])
-            .allowed_headers(vec!["accept"]);
-
-        if let Ok(allowed_origin) = env::var("ALLOW_ORIGIN") {
-            if allowed_origin == "*" {
-                cors = cors.allow_any_origin();
-            } else {
-                cors = cors.allowed_origin(&allowed_origin);
-            }
-        }
+        // The default format, plus the request id
+        let logger = Logger::new(
+            r#"%a "%r" %s %b "%{Referer}i" "%{User-Agent}i" %T %{x-request-id}o"#,
+        );
 
         App::new()
+            .wrap(etag::ETag)
             .wrap(logger)
-            .wrap(cors)
-            .app_data(web::Data::new(ctx.clone()))
-            .service(ok)
-            .service(get_stops)
-            .service(get_stop_routes)
-            .service(get_stop_arrivals)
-            .service(sync_gtfs)
-            .service(index_stop_times)
-            .service(index_stops)
+            .wrap(request_id::RequestId)
+            .app_data(web::Data::new(server_ctx.clone()))
+            .configure(|cfg| api::configure(cfg, &cors))
     })
-    .bind(listen_address)?
+    .bind(config.listen_address.as_str())?
+    // Stopped below, once the background tasks have
+    .disable_signals()
     .run();
+    let server_handle = server.handle();
+    let mut server = actix_web::rt::spawn(server);
 
+    let mut background =
+        pin!(async { join!(firehose, maintenance, notifier, realtime_watcher, telemetry) });
+
+    // Polled here so that they run, they should only stop once shut down
+    let mut background_stopped = false;
     select! {
-        res = server => {
-            log::info!("Server stopped");
-            res?;
-            Ok::<_, std::io::Error>(())
-        },
-        res = firehose => {
-            log::info!("Firehose monitor stopped");
-            res?;
-            Ok(())
+        res = &mut server => {
+            tracing::info!("Server stopped");
+            return res.map_err(std::io::Error::other)?;
         }
-        res = maintenance => {
-            log::info!("Maintenance loop stopped");
-            res?;
-            Ok::<_, std::io::Error>(())
+        _ = &mut background => {
+            tracing::error!("Background tasks stopped before shutdown, shutting down");
+            background_stopped = true;
         }
-    }?;
+        _ = supervisor::shutdown_signal() => {}
+    }
+
+    tracing::info!("Shutting down, waiting for background tasks");
+    ctx.shutdown.cancel();
+    let stopped = async {
+        // Can't be polled again once it's finished
+        if !background_stopped {
+            background.await;
+        }
+        // Held by syncs, index builds and backups started from the management API
+        let _sync_lock = ctx.sync_lock.lock().await;
+        let _backup_lock = ctx.backup_lock.lock().await;
+    };
+    if timeout(config.shutdown_timeout, stopped).await.is_err() {
+        tracing::warn!(
+            "Background tasks still running after {} seconds, stopping anyway",
+            config.shutdown_timeout.as_secs()
+        );
+    }
 
-    Ok(())
+    tracing::info!("Stopping server");
+    server_handle.stop(true).await;
+    server.await.map_err(std::io::Error::other)?
 }
@@ -1,11 +1,17 @@
-use chrono::Utc;
-use sea_orm::{DatabaseConnection, EntityTrait};
-use tokio::time::sleep;
+use chrono::{DateTime, Duration, NaiveTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use sea_orm::EntityTrait;
+use serde_json::json;
 
-use crate::db::util::open_seaorm;
+use crate::db::backup;
+use crate::db::optimise::optimise_database;
 use crate::entity::prelude::*;
+use crate::gtfs::index::IndexOptions;
 use crate::gtfs::sync::Sync;
 use crate::gtfs::{index, realtime};
+use crate::supervisor::sleep_until_shutdown;
+use crate::webhooks::WebhookEvent;
+use crate::ContextData;
 use sea_orm::DbErr;
 use sea_orm::TransactionTrait;
 
@@ -14,6 +20,9 @@ pub enum Error {
     #[error("Database error: {0}")]
     Database(#[from] DbErr),
 
+    #[error("Database error: {0}")]
+    DatabaseRusqlite(#[from] rusqlite::Error),
+
     #[error("Realtime error: {0}")]
     Realtime(#[from] crate::gtfs::realtime::Error),
 
@@ -22,6 +31,9 @@ pub enum Error {
 
     #[error("Sync error: {0}")]
     Sync(#[from] crate::gtfs::sync::GtfsSyncError),
+
+    #[error("Backup error: {0}")]
+    Backup(#[from] backup::Error),
 }
 
 impl From<Error> for std::io::Error {
@@ -32,49 +44,162 @@ impl From<Error> for std::io::Error {
 
 pub type Result<T> = std::result::Result<T, Error>;
 
-pub async fn sync_and_index(db: &DatabaseConnection) -> Result<()> {
-    log::info!("Checking for new data");
+#[tracing::instrument(skip_all)]
+pub async fn sync_and_index(ctx: &ContextData) -> Result<()> {
+    // Waits for any sync or build started from the management API
+    let _lock = ctx.sync_lock.lock().await;
+
+    tracing::info!("Checking for new data");
 
-    if Sync::sync(db).await? > 0 {
+    let synced = Sync::sync(&ctx.db, &ctx.sync, &ctx.feeds, ctx.sync_progress.clone()).await?;
+    let new_records = synced.new_records;
+    if synced.feed_errors.is_empty() {
+        ctx.health.gtfs_sync.record();
+    }
+    let feed_ids = ctx.feeds.iter().map(|f| &f.id).collect::<Vec<_>>();
+    ctx.webhooks.send(
+        WebhookEvent::SyncCompleted,
+        json!({
+            "feeds": feed_ids,
+            "new_records": new_records,
+            "failed_feeds": synced.failed_feed_ids(),
+        }),
+    );
+
+    if new_records > 0 {
         // Indexes only need to be rebuilt if there is new data
         index::build_stop_index().await?;
-        index::build_stop_time_index().await?;
+        // Only the trips that changed, unless too much did
+        index::build_stop_time_index(
+            &ctx.index,
+            IndexOptions {
+                incremental: true,
+                ..Default::default()
+            },
+            ctx.index_progress.clone(),
+        )
+        .await?;
+        ctx.versions.bump_static();
     }
+    // Without new data the index built before is still up to date,
+    // e.g. after a restart, so health needn't wait for the data to change
+    ctx.health.index_build.record();
+
+    // Imports and index builds leave a large WAL and the statistics out of date
+    optimise_database().await?;
+
+    // Reported once the other feeds' data is indexed
+    synced.check()?;
 
     Ok(())
 }
 
+/// The next time after now that it's the minute of the day in the timezone.
+/// When a DST change skips the minute, it's an hour later that day.
+fn next_window(now: DateTime<Utc>, minute_of_day: u32, timezone: Tz) -> DateTime<Utc> {
+    let time = NaiveTime::from_hms_opt(minute_of_day / 60 % 24, minute_of_day % 60, 0).unwrap();
+
+    let mut date = now.with_timezone(&timezone).date_naive();
+    loop {
+        let local = date.and_time(time);
+        let window = timezone.from_local_datetime(&local).earliest().or_else(|| {
+            timezone
+                .from_local_datetime(&(local + Duration::try_hours(1).unwrap()))
+                .earliest()
+        });
+        if let Some(window) = window.filter(|w| *w > now) {
+            return window.with_timezone(&Utc);
+        }
+        date = date.succ_opt().unwrap();
+    }
+}
+
 /// Runs forever, doing maintenance at the maintenance window time
-pub async fn keep_maintained() -> Result<()> {
-    let db = open_seaorm().await;
+pub async fn keep_maintained(ctx: &ContextData) -> Result<()> {
+    let db = &ctx.db;
 
     loop {
         let maintenance_time = MaintenanceTime::find_by_id(1)
-            .one(&db)
+            .one(db)
             .await?
-            .expect("No maintenance time. Not synced and indexed?")
-            .minute_of_day as i64;
-
-        let current_minute = Utc::now().timestamp() % 86400 / 60;
-        let wait_time = if current_minute < maintenance_time {
-            maintenance_time - current_minute
-        } else {
-            1440 /* minutes in a day */ - current_minute + maintenance_time
+            .expect("No maintenance time. Not synced and indexed?");
+
+        // Windows from before they were local to the agency are in UTC
+        let timezone = match maintenance_time.timezone.as_deref() {
+            Some(timezone) => timezone.parse().unwrap_or_else(|_| {
+                tracing::warn!("Invalid maintenance timezone: {}", timezone);
+                Tz::UTC
+            }),
+            None => Tz::UTC,
         };
+        let now = Utc::now();
+        let window = next_window(now, maintenance_time.minute_of_day as u32, timezone);
+        let wait_time = (window - now).to_std().unwrap_or_default();
 
-        log::info!("Waiting {} minutes for maintenance window", wait_time);
-        sleep(tokio::time::Duration::from_secs(wait_time as u64 * 60)).await;
+        tracing::info!(
+            "Waiting {} minutes for maintenance window at {}",
+            wait_time.as_secs() / 60,
+            window.with_timezone(&timezone)
+        );
+        if !sleep_until_shutdown(wait_time, &ctx.shutdown).await {
+            return Ok(());
+        }
 
-        log::info!("Starting maintenance");
+        tracing::info!("Starting maintenance");
 
         // update static data
         // this also deletes all the old data
-        sync_and_index(&db).await?;
+        sync_and_index(ctx).await?;
+        // Each step finishes before shutting down, but there's no need to start the next
+        if ctx.shutdown.is_cancelled() {
+            return Ok(());
+        }
+
+        // keep the index a full horizon ahead, without rebuilding it all
+        {
+            let _lock = ctx.sync_lock.lock().await;
+            index::extend_stop_time_index(&ctx.index, ctx.index_progress.clone()).await?;
+            ctx.health.index_build.record();
+            ctx.versions.bump_static();
+        }
 
         let tx = db.begin().await?;
-        realtime::cleanup(&tx).await?;
+        realtime::cleanup(&ctx.realtime, &tx).await?;
         tx.commit().await?;
 
-        log::info!("Maintenance done");
+        if let Some(dir) = backup::backup_dir() {
+            let _lock = ctx.backup_lock.lock().await;
+            backup::backup_database(dir).await?;
+        }
+
+        tracing::info!("Maintenance done");
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn test_next_window() {
+        let auckland = chrono_tz::Pacific::Auckland;
+        let utc = |s: &str| s.parse::<DateTime<Utc>>().unwrap();
+
+        // 3am NZST is 3pm UTC the day before
+        assert_eq!(
+            next_window(utc("2024-06-01T12:00:00Z"), 180, auckland),
+            utc("2024-06-01T15:00:00Z")
+        );
+        // but once it's NZDT it's 2pm
+        assert_eq!(
+            next_window(utc("2024-12-01T15:00:00Z"), 180, auckland),
+            utc("2024-12-02T14:00:00Z")
+        );
+        // 2:30am doesn't exist when the clocks go forward on 29 September 2024
+        assert_eq!(
+            next_window(utc("2024-09-28T12:00:00Z"), 150, auckland),
+            utc("2024-09-28T14:30:00Z")
+        );
     }
 }
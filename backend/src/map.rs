@@ -0,0 +1,229 @@
+//! Stops for showing on a map, a viewport at a time
+
+use std::collections::HashMap;
+
+use migration::{Alias, Expr, Query};
+use sea_orm::{ColumnTrait, EntityTrait, QueryFilter, QueryOrder};
+use serde::Serialize;
+
+use crate::{
+    db::error::DbResult,
+    entity::gtfs_stops,
+    error::{NextAtError, NextAtResult},
+    stops::Stop,
+    ContextData,
+};
+
+/// Closest zoom level of web map tiles
+pub const MAX_ZOOM: u8 = 22;
+
+/// From this zoom in every stop is shown, further out they're clustered
+const CLUSTER_MAX_ZOOM: u8 = 16;
+
+/// Cells across a 256 px tile that stops are clustered into, so each is about 32 px
+const CLUSTER_CELLS_PER_TILE: f64 = 8.0;
+
+/// Most stops returned without clustering, any more and the box needs to be smaller
+const MAX_BOX_STOPS: usize = 2000;
+
+/// Of a map's viewport, in degrees
+#[derive(Debug, Clone, Copy)]
+pub struct BoundingBox {
+    pub min_lat: f64,
+    pub max_lat: f64,
+    pub min_lon: f64,
+    pub max_lon: f64,
+}
+
+impl BoundingBox {
+    fn validate(&self) -> NextAtResult<()> {
+        let lat_valid = |lat: f64| (-90.0..=90.0).contains(&lat);
+        let lon_valid = |lon: f64| (-180.0..=180.0).contains(&lon);
+        if !(lat_valid(self.min_lat)
+            && lat_valid(self.max_lat)
+            && lon_valid(self.min_lon)
+            && lon_valid(self.max_lon))
+        {
+            return Err(NextAtError::InvalidData(
+                "Bounding box is outside of valid coordinates".to_string(),
+            ));
+        }
+        if self.min_lat > self.max_lat || self.min_lon > self.max_lon {
+            return Err(NextAtError::InvalidData(
+                "Bounding box minimums must not be more than its maximums".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn contains(&self, lat: f64, lon: f64) -> bool {
+        (self.min_lat..=self.max_lat).contains(&lat) && (self.min_lon..=self.max_lon).contains(&lon)
+    }
+}
+
+/// Stops too close together to show separately at the zoom level
+#[derive(Debug, Serialize, Clone)]
+pub struct StopCluster {
+    /// The middle of the stops
+    pub lat: f64,
+    pub lon: f64,
+    pub count: usize,
+}
+
+#[derive(Debug, Serialize, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum MapStop {
+    Stop(Stop),
+    Cluster(StopCluster),
+}
+
+/// Width and height of a cluster cell in degrees at the zoom level.
+/// Degrees of latitude are shortened as they are on the (web mercator) map.
+fn cell_size(zoom: u8, lat: f64) -> (f64, f64) {
+    let lon_size = 360.0 / (2f64.powi(zoom.into()) * CLUSTER_CELLS_PER_TILE);
+    (lon_size * lat.to_radians().cos(), lon_size)
+}
+
+/// Groups stops in the same cell, those alone in theirs are left as they are
+fn cluster(stops: Vec<Stop>, zoom: u8, bbox: &BoundingBox) -> Vec<MapStop> {
+    let (lat_size, lon_size) = cell_size(zoom, (bbox.min_lat + bbox.max_lat) / 2.0);
+
+    let mut cells = HashMap::<(i64, i64), Vec<Stop>>::new();
+    for stop in stops {
+        let (Some(lat), Some(lon)) = (stop.lat, stop.lon) else {
+            continue;
+        };
+        let cell = (
+            (lat / lat_size).floor() as i64,
+            (lon / lon_size).floor() as i64,
+        );
+        cells.entry(cell).or_default().push(stop);
+    }
+
+    let mut cells = cells.into_iter().collect::<Vec<_>>();
+    // So the same box gives the same response
+    cells.sort_by_key(|(cell, _)| *cell);
+
+    cells
+        .into_iter()
+        .map(|(_, mut stops)| {
+            if stops.len() == 1 {
+                return MapStop::Stop(stops.remove(0));
+            }
+            let count = stops.len();
+            let (lat, lon) = stops.iter().fold((0.0, 0.0), |(lat, lon), s| {
+                (
+                    lat + s.lat.unwrap_or_default(),
+                    lon + s.lon.unwrap_or_default(),
+                )
+            });
+            MapStop::Cluster(StopCluster {
+                lat: lat / count as f64,
+                lon: lon / count as f64,
+                count,
+            })
+        })
+        .collect()
+}
+
+/// Stops inside the box, in order of their IDs
+pub async fn find_stops_in_box(ctx: &ContextData, bbox: &BoundingBox) -> DbResult<Vec<Stop>> {
+    use gtfs_stops as s;
+
+    // Stops' boxes in stop_index overlapping the box, only some of which are inside it
+    let si = |column: &str| Expr::col(Alias::new(column));
+    let overlapping = Query::select()
+        .column(Alias::new("stop_id"))
+        .from(Alias::new("stop_index"))
+        .and_where(si("min_lat").lte(bbox.max_lat))
+        .and_where(si("max_lat").gte(bbox.min_lat))
+        .and_where(si("min_lon").lte(bbox.max_lon))
+        .and_where(si("max_lon").gte(bbox.min_lon))
+        .to_owned();
+
+    let stops = s::Entity::find()
+        .filter(s::Column::StopId.in_subquery(overlapping))
+        .order_by_asc(s::Column::StopId)
+        .all(&ctx.read_db)
+        .await?
+        .into_iter()
+        .map(Stop::from)
+        .filter(|s| matches!((s.lat, s.lon), (Some(lat), Some(lon)) if bbox.contains(lat, lon)))
+        .collect();
+    Ok(stops)
+}
+
+/// Stops inside the box, clustered if `zoom` is too far out to show them all
+pub async fn get_stops_in_box(
+    ctx: &ContextData,
+    bbox: BoundingBox,
+    zoom: Option<u8>,
+) -> NextAtResult<Vec<MapStop>> {
+    bbox.validate()?;
+    if zoom.is_some_and(|z| z > MAX_ZOOM) {
+        return Err(NextAtError::InvalidData(format!(
+            "zoom can be at most {}",
+            MAX_ZOOM
+        )));
+    }
+
+    let stops = find_stops_in_box(ctx, &bbox).await?;
+
+    match zoom {
+        Some(zoom) if zoom < CLUSTER_MAX_ZOOM => Ok(cluster(stops, zoom, &bbox)),
+        _ if stops.len() > MAX_BOX_STOPS => Err(NextAtError::InvalidData(format!(
+            "More than {} stops in the box, set a zoom level to cluster them",
+            MAX_BOX_STOPS
+        ))),
+        _ => Ok(stops.into_iter().map(MapStop::Stop).collect()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use crate::test_utils::ctx;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_get_stops_in_box() {
+        let ctx = ctx().await;
+        let bbox = BoundingBox {
+            min_lat: -36.846,
+            max_lat: -36.843,
+            min_lon: 174.765,
+            max_lon: 174.769,
+        };
+
+        let stops = get_stops_in_box(&ctx, bbox, None).await.unwrap();
+        let ids = stops
+            .iter()
+            .filter_map(|s| match s {
+                MapStop::Stop(stop) => Some(stop.id.as_str()),
+                MapStop::Cluster(_) => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            ids,
+            [
+                "1010-0c2d2a6b",
+                "133-1b4e7d5f",
+                "4018-7ef4a7b7",
+                "9218-20fd5c4e",
+                "9218-3d2f2a11"
+            ]
+        );
+
+        // Zoomed out, Britomart's stops are one cluster, and Quay and Lower Albert Streets another
+        let stops = get_stops_in_box(&ctx, bbox, Some(14)).await.unwrap();
+        let counts = stops
+            .iter()
+            .filter_map(|s| match s {
+                MapStop::Cluster(c) => Some(c.count),
+                MapStop::Stop(_) => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(counts, [2, 3]);
+    }
+}
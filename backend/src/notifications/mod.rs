@@ -0,0 +1,637 @@
+//! Web Push notifications of delays and alerts, at the stops and on the routes riders subscribe to
+
+pub mod web_push;
+
+use std::{collections::HashSet, time::Duration};
+
+use chrono::Utc;
+use futures_util::{stream, StreamExt};
+use itertools::Itertools;
+use migration::{Expr, Query};
+use sea_orm::{
+    sea_query::Condition, ActiveModelTrait, ColumnTrait, DbErr, EntityTrait, FromQueryResult,
+    JoinType, PaginatorTrait, QueryFilter, QueryOrder, QuerySelect, RelationTrait, Set,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use url::Url;
+use uuid::Uuid;
+
+use crate::{
+    config::{parse_var, var},
+    entity::{
+        alert, alert_active_period, alert_image, alert_informed_entity, gtfs_routes,
+        push_notification, push_subscription, stop_time_index, trip_run,
+    },
+    error::{NextAtError, NextAtResult},
+    stops,
+    supervisor::sleep_until_shutdown,
+    ContextData,
+};
+
+use self::web_push::{Delivery, VapidKey};
+
+/// How often subscriptions are checked for something to send, if `NOTIFY_INTERVAL_SECONDS` isn't set
+const DEFAULT_NOTIFY_INTERVAL_SECONDS: u64 = 60;
+
+/// Delay threshold of subscriptions that don't set one
+const DEFAULT_DELAY_THRESHOLD_SECONDS: i32 = 5 * 60;
+
+/// Any less and riders would be told about every trip
+const MIN_DELAY_THRESHOLD_SECONDS: i32 = 60;
+
+/// Only trips due within this long are notified
+const DELAY_LOOKAHEAD_MINUTES: i64 = 60;
+
+/// What's been sent is remembered for this long, so it isn't sent again
+const SENT_RETENTION_HOURS: i64 = 24;
+
+/// Most subscriptions there can be, as each is checked for what to send it every interval
+const MAX_SUBSCRIPTIONS: u64 = 10_000;
+
+/// Most subscriptions one browser can have
+const MAX_ENDPOINT_SUBSCRIPTIONS: u64 = 50;
+
+/// How many browsers are sent messages at once
+const SEND_CONCURRENCY: usize = 10;
+
+/// A subscription is removed once its push service has refused this many messages in a row
+const MAX_FAILURES: i32 = 5;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Database error: {0}")]
+    Database(#[from] DbErr),
+}
+
+pub struct NotificationsConfig {
+    /// How often subscriptions are checked for something to send
+    pub interval: Duration,
+    /// Notifications are only sent with one
+    pub vapid: Option<VapidKey>,
+}
+
+impl NotificationsConfig {
+    /// Enabled by a key in `VAPID_PRIVATE_KEY` (see `VapidKey::new`),
+    /// with the contact in `VAPID_SUBJECT`. Every problem with the settings is added to `errors`.
+    pub fn from_env(errors: &mut Vec<String>) -> Self {
+        let vapid = var("VAPID_PRIVATE_KEY").ok().and_then(|key| {
+            VapidKey::new(&key, var("VAPID_SUBJECT").ok())
+                .map_err(|e| errors.push(format!("Invalid VAPID_PRIVATE_KEY: {}", e)))
+                .ok()
+        });
+        Self {
+            interval: Duration::from_secs(parse_var(
+                "NOTIFY_INTERVAL_SECONDS",
+                DEFAULT_NOTIFY_INTERVAL_SECONDS,
+                errors,
+            )),
+            vapid,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SubscriptionKeys {
+    pub p256dh: String,
+    pub auth: String,
+}
+
+/// A browser's PushSubscription (as from its `toJSON()`), with what to notify it of
+#[derive(Debug, Deserialize)]
+pub struct NewSubscription {
+    pub endpoint: String,
+    pub keys: SubscriptionKeys,
+    pub stop_id: Option<String>,
+    pub route_id: Option<String>,
+    /// Trips at least this late are notified
+    pub delay_threshold_seconds: Option<i32>,
+    /// Whether alerts are notified too, they are unless it's false
+    pub alerts: Option<bool>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Subscription {
+    /// Needed to unsubscribe
+    pub id: String,
+    pub stop_id: Option<String>,
+    pub route_id: Option<String>,
+    pub delay_threshold_seconds: i32,
+    pub alerts: bool,
+}
+
+impl From<push_subscription::Model> for Subscription {
+    fn from(s: push_subscription::Model) -> Self {
+        Subscription {
+            id: s.id,
+            stop_id: s.stop_id,
+            route_id: s.route_id,
+            delay_threshold_seconds: s.delay_threshold_seconds,
+            alerts: s.alerts != 0,
+        }
+    }
+}
+
+/// The key browsers subscribe with (base64url), which our messages are signed by
+pub fn public_key(ctx: &ContextData) -> NextAtResult<String> {
+    ctx.web_push
+        .vapid()
+        .map(|vapid| vapid.public_key())
+        .ok_or_else(|| NextAtError::Response(503, "Push notifications aren't enabled".to_string()))
+}
+
+pub async fn create_subscription(
+    ctx: &ContextData,
+    new: NewSubscription,
+) -> NextAtResult<Subscription> {
+    public_key(ctx)?;
+
+    let endpoint = Url::parse(&new.endpoint)
+        .map_err(|e| NextAtError::InvalidData(format!("Invalid endpoint: {}", e)))?;
+    web_push::check_endpoint(&endpoint)
+        .await
+        .map_err(|e| NextAtError::InvalidData(e.to_string()))?;
+    let key_length = |key: &str| web_push::decode_base64(key).map_or(0, |k| k.len());
+    if key_length(&new.keys.p256dh) != 65 || key_length(&new.keys.auth) != 16 {
+        return Err(NextAtError::InvalidData(
+            "keys.p256dh must be a P-256 public key and keys.auth 16 bytes, in base64url"
+                .to_string(),
+        ));
+    }
+
+    if new.stop_id.is_none() && new.route_id.is_none() {
+        return Err(NextAtError::InvalidData(
+            "A stop_id or route_id to be notified about is needed".to_string(),
+        ));
+    }
+    if let Some(stop_id) = &new.stop_id {
+        stops::get_stop(ctx, stop_id).await?;
+    }
+    if let Some(route_id) = &new.route_id {
+        stops::get_route(ctx, route_id).await?;
+    }
+
+    let delay_threshold_seconds = new
+        .delay_threshold_seconds
+        .unwrap_or(DEFAULT_DELAY_THRESHOLD_SECONDS);
+    if delay_threshold_seconds < MIN_DELAY_THRESHOLD_SECONDS {
+        return Err(NextAtError::InvalidData(format!(
+            "delay_threshold_seconds must be at least {}",
+            MIN_DELAY_THRESHOLD_SECONDS
+        )));
+    }
+
+    if push_subscription::Entity::find().count(&ctx.db).await? >= MAX_SUBSCRIPTIONS {
+        return Err(NextAtError::Response(
+            503,
+            "No more push subscriptions can be made".to_string(),
+        ));
+    }
+    let endpoint_subscriptions = push_subscription::Entity::find()
+        .filter(push_subscription::Column::Endpoint.eq(&new.endpoint))
+        .count(&ctx.db)
+        .await?;
+    if endpoint_subscriptions >= MAX_ENDPOINT_SUBSCRIPTIONS {
+        return Err(NextAtError::Response(
+            429,
+            format!(
+                "A browser can have at most {} subscriptions",
+                MAX_ENDPOINT_SUBSCRIPTIONS
+            ),
+        ));
+    }
+
+    let subscription = push_subscription::ActiveModel {
+        id: Set(Uuid::new_v4().to_string()),
+        endpoint: Set(new.endpoint),
+        p256dh: Set(new.keys.p256dh),
+        auth: Set(new.keys.auth),
+        stop_id: Set(new.stop_id),
+        route_id: Set(new.route_id),
+        delay_threshold_seconds: Set(delay_threshold_seconds),
+        alerts: Set(new.alerts.unwrap_or(true) as i32),
+        created_timestamp: Set(Utc::now().timestamp_millis()),
+        failures: Set(0),
+    }
+    .insert(&ctx.db)
+    .await?;
+    Ok(subscription.into())
+}
+
+pub async fn delete_subscription(ctx: &ContextData, id: &str) -> NextAtResult<()> {
+    let result = push_subscription::Entity::delete_by_id(id)
+        .exec(&ctx.db)
+        .await?;
+    if result.rows_affected == 0 {
+        return Err(NextAtError::NotFound(format!(
+            "Subscription not found: {}",
+            id
+        )));
+    }
+    Ok(())
+}
+
+/// A message for a subscription.
+/// `key` is what it's about, so that it's only sent once.
+#[derive(Debug)]
+struct Notification {
+    key: String,
+    payload: Value,
+}
+
+#[derive(Debug, FromQueryResult)]
+struct DelayedStop {
+    trip_run_id: i64,
+    stop_id: String,
+    route_short_name: String,
+    arrival_timestamp: i64,
+    updated_arrival_timestamp: i64,
+}
+
+/// Trips running later than the subscription's threshold, at its stop or on its route
+async fn delays(
+    ctx: &ContextData,
+    subscription: &push_subscription::Model,
+) -> Result<Vec<Notification>, DbErr> {
+    use gtfs_routes as r;
+    use stop_time_index as sti;
+    use trip_run as tr;
+
+    let now = Utc::now().timestamp_millis();
+    let lookahead = now + DELAY_LOOKAHEAD_MINUTES * 60 * 1000;
+    let threshold = i64::from(subscription.delay_threshold_seconds) * 1000;
+
+    let delay = Expr::col((sti::Entity, sti::Column::UpdatedArrivalTimestamp))
+        .sub(Expr::col((sti::Entity, sti::Column::ArrivalTimestamp)));
+    let mut query = sti::Entity::find()
+        .select_only()
+        .columns([
+            sti::Column::TripRunId,
+            sti::Column::StopId,
+            sti::Column::ArrivalTimestamp,
+            sti::Column::UpdatedArrivalTimestamp,
+        ])
+        .column(r::Column::RouteShortName)
+        .join(JoinType::InnerJoin, sti::Relation::TripRun.def())
+        .join(JoinType::InnerJoin, tr::Relation::GtfsRoutes.def())
+        .filter(sti::Column::Skipped.eq(0))
+        .filter(sti::Column::UpdatedArrivalTimestamp.between(now, lookahead))
+        .filter(Expr::expr(delay).gte(threshold));
+    if let Some(stop_id) = &subscription.stop_id {
+        query = query.filter(sti::Column::StopId.eq(stop_id));
+    }
+    if let Some(route_id) = &subscription.route_id {
+        query = query.filter(tr::Column::RouteId.eq(route_id));
+    }
+
+    let delayed = query
+        .order_by_asc(sti::Column::UpdatedArrivalTimestamp)
+        .into_model::<DelayedStop>()
+        .all(&ctx.read_db)
+        .await?;
+
+    // Once per trip, at the next of its stops
+    let notifications = delayed
+        .into_iter()
+        .unique_by(|d| d.trip_run_id)
+        .map(|d| {
+            let delay_seconds = (d.updated_arrival_timestamp - d.arrival_timestamp) / 1000;
+            Notification {
+                key: format!("delay:{}", d.trip_run_id),
+                payload: json!({
+                    "type": "delay",
+                    "title": format!("{} running late", d.route_short_name),
+                    "body": format!("{} minutes behind schedule", delay_seconds / 60),
+                    "trip_run_id": d.trip_run_id,
+                    "stop_id": d.stop_id,
+                    "delay_seconds": delay_seconds,
+                    "expected_timestamp": d.updated_arrival_timestamp,
+                }),
+            }
+        })
+        .collect();
+    Ok(notifications)
+}
+
+/// The feed's `SeverityLevel`, None if it's unknown
+fn severity_name(severity_level: i32) -> Option<&'static str> {
+    match severity_level {
+        2 => Some("info"),
+        3 => Some("warning"),
+        4 => Some("severe"),
+        _ => None,
+    }
+}
+
+/// Alerts in effect now, for the subscription's stop or route
+async fn alerts(
+    ctx: &ContextData,
+    subscription: &push_subscription::Model,
+) -> Result<Vec<Notification>, DbErr> {
+    use alert_active_period as ap;
+    use alert_informed_entity as ie;
+
+    let now = Utc::now().timestamp_millis();
+
+    let mut informed = Condition::any();
+    if let Some(stop_id) = &subscription.stop_id {
+        informed = informed.add(ie::Column::StopId.eq(stop_id));
+    }
+    if let Some(route_id) = &subscription.route_id {
+        informed = informed.add(ie::Column::RouteId.eq(route_id));
+    }
+    let informed = Query::select()
+        .column(ie::Column::AlertId)
+        .from(ie::Entity)
+        .cond_where(informed)
+        .to_owned();
+    let active = Query::select()
+        .column(ap::Column::AlertId)
+        .from(ap::Entity)
+        .and_where(ap::Column::StartTimestamp.lte(now))
+        .and_where(ap::Column::EndTimestamp.gte(now))
+        .to_owned();
+
+    let alerts = alert::Entity::find()
+        .filter(alert::Column::AlertId.in_subquery(informed))
+        .filter(alert::Column::AlertId.in_subquery(active))
+        .order_by_asc(alert::Column::Id)
+        .all(&ctx.read_db)
+        .await?;
+    let mut images = alert_image::Entity::find()
+        .filter(
+            alert_image::Column::AlertId.is_in(alerts.iter().filter_map(|a| a.alert_id.clone())),
+        )
+        .order_by_asc(alert_image::Column::Id)
+        .all(&ctx.read_db)
+        .await?
+        .into_iter()
+        .into_group_map_by(|i| i.alert_id.clone());
+
+    let notifications = alerts
+        .into_iter()
+        .filter_map(|a| {
+            let alert_id = a.alert_id?;
+            Some(Notification {
+                key: format!("alert:{}", alert_id),
+                payload: json!({
+                    "type": "alert",
+                    "title": a.header_text.unwrap_or_else(|| "Service alert".to_string()),
+                    "body": a.description_text,
+                    "alert_id": alert_id,
+                    "severity": a.severity_level.and_then(severity_name),
+                    "url": a.url,
+                    "images": images
+                        .remove(&alert_id)
+                        .unwrap_or_default()
+                        .into_iter()
+                        .map(|i| json!({
+                            "url": i.url,
+                            "media_type": i.media_type,
+                            "language": i.language,
+                        }))
+                        .collect_vec(),
+                    "image_alternative_text": a.image_alternative_text,
+                }),
+            })
+        })
+        .collect();
+    Ok(notifications)
+}
+
+/// What the subscription should be told, that it hasn't been already
+async fn pending_notifications(
+    ctx: &ContextData,
+    subscription: &push_subscription::Model,
+) -> Result<Vec<Notification>, DbErr> {
+    use push_notification as pn;
+
+    let mut notifications = delays(ctx, subscription).await?;
+    if subscription.alerts != 0 {
+        notifications.extend(alerts(ctx, subscription).await?);
+    }
+
+    let sent = pn::Entity::find()
+        .filter(pn::Column::SubscriptionId.eq(&subscription.id))
+        .select_only()
+        .column(pn::Column::Key)
+        .into_tuple::<String>()
+        .all(&ctx.read_db)
+        .await?
+        .into_iter()
+        .collect::<HashSet<_>>();
+    notifications.retain(|n| !sent.contains(&n.key));
+    Ok(notifications)
+}
+
+async fn record_sent(ctx: &ContextData, subscription_id: &str, key: &str) -> Result<(), DbErr> {
+    push_notification::Entity::insert(push_notification::ActiveModel {
+        subscription_id: Set(subscription_id.to_string()),
+        key: Set(key.to_string()),
+        timestamp: Set(Utc::now().timestamp_millis()),
+    })
+    .exec(&ctx.db)
+    .await?;
+    Ok(())
+}
+
+async fn set_failures(
+    ctx: &ContextData,
+    subscription_id: &str,
+    failures: i32,
+) -> Result<(), DbErr> {
+    push_subscription::Entity::update_many()
+        .col_expr(push_subscription::Column::Failures, Expr::value(failures))
+        .filter(push_subscription::Column::Id.eq(subscription_id))
+        .exec(&ctx.db)
+        .await?;
+    Ok(())
+}
+
+/// Sends a browser's subscriptions what they haven't been told yet, one after another
+/// as they all go when it unsubscribes
+async fn notify_endpoint(
+    ctx: &ContextData,
+    subscriptions: Vec<push_subscription::Model>,
+) -> Result<(), Error> {
+    for mut subscription in subscriptions {
+        for notification in pending_notifications(ctx, &subscription).await? {
+            let payload = notification.payload.to_string();
+            match ctx.web_push.send(&subscription, payload.as_bytes()).await {
+                Ok(Delivery::Sent) => {
+                    record_sent(ctx, &subscription.id, &notification.key).await?;
+                    if subscription.failures > 0 {
+                        set_failures(ctx, &subscription.id, 0).await?;
+                        subscription.failures = 0;
+                    }
+                }
+                Ok(Delivery::Gone) => {
+                    tracing::info!(
+                        "Push subscription {} has gone, removing it",
+                        subscription.id
+                    );
+                    push_subscription::Entity::delete_many()
+                        .filter(push_subscription::Column::Endpoint.eq(&subscription.endpoint))
+                        .exec(&ctx.db)
+                        .await?;
+                    return Ok(());
+                }
+                Ok(Delivery::Rejected(status)) => {
+                    subscription.failures += 1;
+                    if subscription.failures >= MAX_FAILURES {
+                        tracing::info!(
+                            "Push service has refused {} messages in a row to subscription {} ({}), removing it",
+                            subscription.failures,
+                            subscription.id,
+                            status
+                        );
+                        push_subscription::Entity::delete_by_id(subscription.id.clone())
+                            .exec(&ctx.db)
+                            .await?;
+                    } else {
+                        tracing::warn!(
+                            "Push service refused a message to subscription {}: {}",
+                            subscription.id,
+                            status
+                        );
+                        set_failures(ctx, &subscription.id, subscription.failures).await?;
+                    }
+                    // The rest would be refused too
+                    break;
+                }
+                // Tried again next time
+                Err(e) => tracing::warn!(
+                    "Error sending push notification to {}: {}",
+                    subscription.id,
+                    e
+                ),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Sends every subscription what it hasn't been told yet
+async fn notify(ctx: &ContextData) -> Result<(), Error> {
+    let subscriptions = push_subscription::Entity::find()
+        .order_by_asc(push_subscription::Column::CreatedTimestamp)
+        .all(&ctx.read_db)
+        .await?;
+
+    let browsers = subscriptions
+        .into_iter()
+        .into_group_map_by(|s| s.endpoint.clone());
+    stream::iter(browsers.into_values())
+        .map(|subscriptions| notify_endpoint(ctx, subscriptions))
+        .buffer_unordered(SEND_CONCURRENCY)
+        .collect::<Vec<_>>()
+        .await
+        .into_iter()
+        .collect::<Result<(), _>>()?;
+
+    let retained_since = Utc::now().timestamp_millis() - SENT_RETENTION_HOURS * 60 * 60 * 1000;
+    push_notification::Entity::delete_many()
+        .filter(push_notification::Column::Timestamp.lt(retained_since))
+        .exec(&ctx.db)
+        .await?;
+
+    Ok(())
+}
+
+/// Checks for delays and alerts to notify subscribers of every `interval`, forever
+pub async fn notify_subscribers(ctx: &ContextData, interval: Duration) -> Result<(), Error> {
+    if ctx.web_push.vapid().is_none() {
+        tracing::info!("Push notifications are disabled, set VAPID_PRIVATE_KEY to enable them");
+        // Nothing to do, but stopping would restart it
+        ctx.shutdown.cancelled().await;
+        return Ok(());
+    }
+
+    tracing::info!(
+        "Notifying push subscribers every {} seconds",
+        interval.as_secs()
+    );
+
+    loop {
+        notify(ctx).await?;
+        if !sleep_until_shutdown(interval, &ctx.shutdown).await {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use sea_orm::ConnectionTrait;
+
+    use crate::test_utils::ctx;
+
+    use super::*;
+
+    fn subscription(
+        id: &str,
+        delay_threshold_seconds: i32,
+        alerts: bool,
+    ) -> push_subscription::ActiveModel {
+        push_subscription::ActiveModel {
+            id: Set(id.to_string()),
+            endpoint: Set(format!("https://push.example.com/{}", id)),
+            p256dh: Set(String::new()),
+            auth: Set(String::new()),
+            stop_id: Set(Some("4018-7ef4a7b7".to_string())),
+            route_id: Set(None),
+            delay_threshold_seconds: Set(delay_threshold_seconds),
+            alerts: Set(alerts as i32),
+            created_timestamp: Set(0),
+            failures: Set(0),
+        }
+    }
+
+    async fn pending_keys(
+        ctx: &ContextData,
+        subscription: &push_subscription::Model,
+    ) -> Vec<String> {
+        pending_notifications(ctx, subscription)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|n| n.key)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_pending_notifications() {
+        let ctx = ctx().await;
+        ctx.db
+            .execute_unprepared(
+                "UPDATE stop_time_index SET updated_arrival_timestamp = arrival_timestamp + 300000
+                WHERE trip_run_id = 1;
+                INSERT INTO alert (alert_id, header_text) VALUES ('lifts', 'Lifts out of service');
+                INSERT INTO alert_active_period (alert_id, start_timestamp, end_timestamp)
+                VALUES ('lifts', 0, 9999999999999);
+                INSERT INTO alert_informed_entity (alert_id, stop_id) VALUES ('lifts', '4018-7ef4a7b7');",
+            )
+            .await
+            .unwrap();
+
+        let subscribed = subscription("subscribed", 120, true)
+            .insert(&ctx.db)
+            .await
+            .unwrap();
+        assert_eq!(
+            pending_keys(&ctx, &subscribed).await,
+            ["delay:1", "alert:lifts"]
+        );
+
+        record_sent(&ctx, &subscribed.id, "delay:1").await.unwrap();
+        assert_eq!(pending_keys(&ctx, &subscribed).await, ["alert:lifts"]);
+
+        // The trip isn't late enough for it, and it doesn't want alerts
+        let strict = subscription("strict", 600, false)
+            .insert(&ctx.db)
+            .await
+            .unwrap();
+        assert!(pending_keys(&ctx, &strict).await.is_empty());
+    }
+}
@@ -0,0 +1,454 @@
+//! Sending messages to browsers with Web Push, encrypted for the browser (RFC 8291)
+//! and signed with our VAPID key so push services know who they're from (RFC 8292)
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::Duration as StdDuration,
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{Duration, Utc};
+use reqwest::StatusCode;
+use ring::{
+    aead::{self, Aad, LessSafeKey, Nonce, UnboundKey},
+    agreement::{self, EphemeralPrivateKey, UnparsedPublicKey},
+    hkdf,
+    rand::{SecureRandom, SystemRandom},
+    signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING},
+};
+use serde_json::json;
+use tokio::net::lookup_host;
+use url::{Host, Url};
+
+use crate::entity::push_subscription;
+
+/// How long push services hold a message for a browser that's offline, in seconds.
+/// Delays and alerts are soon out of date.
+const TTL_SECONDS: u32 = 15 * 60;
+
+/// How long a VAPID token is valid for, push services accept at most 24 hours
+const TOKEN_VALID_HOURS: i64 = 12;
+
+/// Connecting to a push service is given up on after this long
+const CONNECT_TIMEOUT: StdDuration = StdDuration::from_secs(10);
+
+/// And getting its response, so one slow push service can't hold up the rest
+const REQUEST_TIMEOUT: StdDuration = StdDuration::from_secs(30);
+
+/// Messages are sent as one record of at most this many bytes
+const RECORD_SIZE: u32 = 4096;
+
+/// Of the AES-GCM tag, and the delimiter after the last record
+const RECORD_OVERHEAD: usize = 17;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Invalid key: {0}")]
+    InvalidKey(String),
+
+    #[error("Invalid endpoint: {0}")]
+    InvalidEndpoint(String),
+
+    #[error("Message is longer than {0} bytes")]
+    TooLong(usize),
+
+    #[error("Encryption failed")]
+    Encryption,
+
+    #[error("Push notifications aren't enabled")]
+    Disabled,
+
+    #[error(transparent)]
+    Request(#[from] reqwest::Error),
+
+    #[error("Push service responded {0}")]
+    Response(StatusCode),
+}
+
+impl From<ring::error::Unspecified> for Error {
+    fn from(_: ring::error::Unspecified) -> Self {
+        Error::Encryption
+    }
+}
+
+/// Keys as browsers give them, base64url with or without padding
+pub fn decode_base64(value: &str) -> Result<Vec<u8>, Error> {
+    URL_SAFE_NO_PAD
+        .decode(value.trim_end_matches('='))
+        .map_err(|e| Error::InvalidKey(e.to_string()))
+}
+
+/// Our key pair, which browsers are given to subscribe with
+pub struct VapidKey {
+    key_pair: EcdsaKeyPair,
+    /// How push services can contact us, a mailto: or https: URL
+    subject: Option<String>,
+}
+
+impl VapidKey {
+    /// `private_key` is a P-256 key in base64url PKCS#8, e.g. from
+    /// `openssl ecparam -name prime256v1 -genkey -noout | openssl pkcs8 -topk8 -nocrypt -outform DER | basenc --base64url -w0`
+    pub fn new(private_key: &str, subject: Option<String>) -> Result<Self, Error> {
+        let key_pair = EcdsaKeyPair::from_pkcs8(
+            &ECDSA_P256_SHA256_FIXED_SIGNING,
+            &decode_base64(private_key)?,
+            &SystemRandom::new(),
+        )
+        .map_err(|e| Error::InvalidKey(e.to_string()))?;
+        Ok(Self { key_pair, subject })
+    }
+
+    /// The `applicationServerKey` for subscribing, base64url
+    pub fn public_key(&self) -> String {
+        URL_SAFE_NO_PAD.encode(self.key_pair.public_key())
+    }
+
+    /// The Authorization header for messages to the endpoint's push service
+    fn authorization(&self, endpoint: &Url) -> Result<String, Error> {
+        let header = json!({ "typ": "JWT", "alg": "ES256" });
+        let mut claims = json!({
+            "aud": endpoint.origin().ascii_serialization(),
+            "exp": (Utc::now() + Duration::hours(TOKEN_VALID_HOURS)).timestamp(),
+        });
+        if let Some(subject) = &self.subject {
+            claims["sub"] = json!(subject);
+        }
+
+        let unsigned = format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(header.to_string()),
+            URL_SAFE_NO_PAD.encode(claims.to_string())
+        );
+        let signature = self
+            .key_pair
+            .sign(&SystemRandom::new(), unsigned.as_bytes())?;
+        Ok(format!(
+            "vapid t={}.{}, k={}",
+            unsigned,
+            URL_SAFE_NO_PAD.encode(signature.as_ref()),
+            self.public_key()
+        ))
+    }
+}
+
+/// Length of HKDF output
+struct Len(usize);
+
+impl hkdf::KeyType for Len {
+    fn len(&self) -> usize {
+        self.0
+    }
+}
+
+fn expand(prk: &hkdf::Prk, info: &[u8], out: &mut [u8]) -> Result<(), Error> {
+    prk.expand(&[info], Len(out.len()))?.fill(out)?;
+    Ok(())
+}
+
+/// The content encryption key and nonce, from the shared secret and the browser's auth secret
+fn content_keys(
+    ecdh_secret: &[u8],
+    auth_secret: &[u8],
+    ua_public: &[u8],
+    as_public: &[u8],
+    salt: &[u8],
+) -> Result<([u8; 16], [u8; 12]), Error> {
+    let key_info = [&b"WebPush: info\0"[..], ua_public, as_public].concat();
+    let prk_key = hkdf::Salt::new(hkdf::HKDF_SHA256, auth_secret).extract(ecdh_secret);
+    let mut ikm = [0; 32];
+    expand(&prk_key, &key_info, &mut ikm)?;
+
+    let prk = hkdf::Salt::new(hkdf::HKDF_SHA256, salt).extract(&ikm);
+    let mut cek = [0; 16];
+    expand(&prk, b"Content-Encoding: aes128gcm\0", &mut cek)?;
+    let mut nonce = [0; 12];
+    expand(&prk, b"Content-Encoding: nonce\0", &mut nonce)?;
+    Ok((cek, nonce))
+}
+
+/// The payload encrypted for the browser's keys, as an aes128gcm body (RFC 8188)
+fn encrypt(
+    payload: &[u8],
+    ua_public: &[u8],
+    auth_secret: &[u8],
+    rng: &dyn SecureRandom,
+) -> Result<Vec<u8>, Error> {
+    let max_length = RECORD_SIZE as usize - RECORD_OVERHEAD;
+    if payload.len() > max_length {
+        return Err(Error::TooLong(max_length));
+    }
+
+    // A key pair for just this message
+    let as_private = EphemeralPrivateKey::generate(&agreement::ECDH_P256, rng)?;
+    let as_public = as_private.compute_public_key()?;
+    let ecdh_secret = agreement::agree_ephemeral(
+        as_private,
+        &UnparsedPublicKey::new(&agreement::ECDH_P256, ua_public),
+        |secret| secret.to_vec(),
+    )
+    .map_err(|_| Error::InvalidKey("p256dh isn't a P-256 public key".to_string()))?;
+
+    let mut salt = [0; 16];
+    rng.fill(&mut salt)?;
+    encrypt_with(
+        payload,
+        ua_public,
+        auth_secret,
+        as_public.as_ref(),
+        &ecdh_secret,
+        &salt,
+    )
+}
+
+/// `encrypt` once the key pair and salt for the message are chosen
+fn encrypt_with(
+    payload: &[u8],
+    ua_public: &[u8],
+    auth_secret: &[u8],
+    as_public: &[u8],
+    ecdh_secret: &[u8],
+    salt: &[u8],
+) -> Result<Vec<u8>, Error> {
+    let (cek, nonce) = content_keys(ecdh_secret, auth_secret, ua_public, as_public, salt)?;
+
+    // The only record, so it's ended by the last record delimiter
+    let mut record = [payload, &[2]].concat();
+    LessSafeKey::new(UnboundKey::new(&aead::AES_128_GCM, &cek)?).seal_in_place_append_tag(
+        Nonce::assume_unique_for_key(nonce),
+        Aad::empty(),
+        &mut record,
+    )?;
+
+    let key_id = as_public;
+    Ok([
+        salt,
+        &RECORD_SIZE.to_be_bytes(),
+        &[key_id.len() as u8],
+        key_id,
+        &record,
+    ]
+    .concat())
+}
+
+/// Whether the address is on the internet, rather than our own network or machine
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                || a == 0
+                // Shared by carrier-grade NAT
+                || (a == 100 && (64..128).contains(&b)))
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(IpAddr::V4(ip)),
+            None => {
+                let first = ip.segments()[0];
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_multicast()
+                    // Unique local and link-local
+                    || first & 0xfe00 == 0xfc00
+                    || first & 0xffc0 == 0xfe80)
+            }
+        },
+    }
+}
+
+/// Push services are on the internet. An endpoint anywhere else would have us
+/// make requests to our own network for whoever subscribed.
+/// Returns the addresses it was checked at, which messages must be sent to
+/// so it can't resolve somewhere else in between.
+pub async fn check_endpoint(endpoint: &Url) -> Result<Vec<SocketAddr>, Error> {
+    if endpoint.scheme() != "https" {
+        return Err(Error::InvalidEndpoint("it must be https".to_string()));
+    }
+    let port = endpoint.port_or_known_default().unwrap_or(443);
+    let addresses = match endpoint.host() {
+        Some(Host::Domain(domain)) => lookup_host((domain, port))
+            .await
+            .map_err(|e| Error::InvalidEndpoint(format!("{}: {}", domain, e)))?
+            .collect(),
+        Some(Host::Ipv4(ip)) => vec![SocketAddr::new(IpAddr::V4(ip), port)],
+        Some(Host::Ipv6(ip)) => vec![SocketAddr::new(IpAddr::V6(ip), port)],
+        None => return Err(Error::InvalidEndpoint("it has no host".to_string())),
+    };
+    if addresses.iter().any(|address| !is_public(address.ip())) {
+        return Err(Error::InvalidEndpoint(format!(
+            "{} isn't a public address",
+            endpoint.host_str().unwrap_or_default()
+        )));
+    }
+    Ok(addresses)
+}
+
+/// A client that only connects to the endpoint at the addresses it was checked at,
+/// and doesn't follow redirects, which could be to anywhere
+fn client_for(endpoint: &Url, addresses: &[SocketAddr]) -> Result<reqwest::Client, Error> {
+    let mut builder = reqwest::Client::builder()
+        .connect_timeout(CONNECT_TIMEOUT)
+        .timeout(REQUEST_TIMEOUT)
+        .redirect(reqwest::redirect::Policy::none());
+    if let Some(Host::Domain(domain)) = endpoint.host() {
+        builder = builder.resolve_to_addrs(domain, addresses);
+    }
+    Ok(builder.build()?)
+}
+
+/// What the push service did with a message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delivery {
+    Sent,
+    /// The browser unsubscribed or the subscription expired, so it can be deleted
+    Gone,
+    /// The push service won't take it, e.g. because the subscription's keys are wrong,
+    /// which sending again won't change
+    Rejected(StatusCode),
+}
+
+/// Sends messages to subscriptions, when there's a VAPID key to sign them with
+#[derive(Default)]
+pub struct WebPush {
+    vapid: Option<VapidKey>,
+}
+
+impl WebPush {
+    /// Disabled without a key
+    pub fn new(vapid: Option<VapidKey>) -> Self {
+        Self { vapid }
+    }
+
+    pub fn vapid(&self) -> Option<&VapidKey> {
+        self.vapid.as_ref()
+    }
+
+    pub async fn send(
+        &self,
+        subscription: &push_subscription::Model,
+        payload: &[u8],
+    ) -> Result<Delivery, Error> {
+        let vapid = self.vapid.as_ref().ok_or(Error::Disabled)?;
+        let endpoint = Url::parse(&subscription.endpoint)
+            .map_err(|e| Error::InvalidEndpoint(e.to_string()))?;
+        // Again, as where it points may have changed
+        let addresses = check_endpoint(&endpoint).await?;
+        let body = encrypt(
+            payload,
+            &decode_base64(&subscription.p256dh)?,
+            &decode_base64(&subscription.auth)?,
+            &SystemRandom::new(),
+        )?;
+
+        let response = client_for(&endpoint, &addresses)?
+            .post(endpoint.clone())
+            .header("TTL", TTL_SECONDS)
+            .header("Content-Encoding", "aes128gcm")
+            .header("Content-Type", "application/octet-stream")
+            .header("Authorization", vapid.authorization(&endpoint)?)
+            .body(body)
+            .send()
+            .await?;
+
+        match response.status() {
+            status if status.is_success() => Ok(Delivery::Sent),
+            StatusCode::NOT_FOUND | StatusCode::GONE => Ok(Delivery::Gone),
+            // Worth trying again
+            StatusCode::REQUEST_TIMEOUT | StatusCode::TOO_MANY_REQUESTS => {
+                Err(Error::Response(response.status()))
+            }
+            status if status.is_client_error() => Ok(Delivery::Rejected(status)),
+            status => Err(Error::Response(status)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn test_encrypt() {
+        let rng = SystemRandom::new();
+
+        // The browser's side
+        let ua_private = EphemeralPrivateKey::generate(&agreement::ECDH_P256, &rng).unwrap();
+        let ua_public = ua_private.compute_public_key().unwrap();
+        let mut auth_secret = [0; 16];
+        rng.fill(&mut auth_secret).unwrap();
+
+        let payload = br#"{"title":"NX1 running late"}"#;
+        let body = encrypt(payload, ua_public.as_ref(), &auth_secret, &rng).unwrap();
+
+        let (salt, rest) = body.split_at(16);
+        let (record_size, rest) = rest.split_at(4);
+        assert_eq!(record_size, RECORD_SIZE.to_be_bytes());
+        let (key_id, record) = rest[1..].split_at(rest[0].into());
+
+        let ecdh_secret = agreement::agree_ephemeral(
+            ua_private,
+            &UnparsedPublicKey::new(&agreement::ECDH_P256, key_id),
+            |secret| secret.to_vec(),
+        )
+        .unwrap();
+        let (cek, nonce) =
+            content_keys(&ecdh_secret, &auth_secret, ua_public.as_ref(), key_id, salt).unwrap();
+
+        let mut record = record.to_vec();
+        let plaintext = LessSafeKey::new(UnboundKey::new(&aead::AES_128_GCM, &cek).unwrap())
+            .open_in_place(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::empty(),
+                &mut record,
+            )
+            .unwrap();
+        assert_eq!(plaintext, [&payload[..], &[2]].concat());
+
+        let too_long = vec![0; RECORD_SIZE as usize];
+        assert!(encrypt(&too_long, ua_public.as_ref(), &auth_secret, &rng).is_err());
+
+        // The example in RFC 8291 Appendix A, given its key pair and salt
+        let decode = |value| decode_base64(value).unwrap();
+        let body = encrypt_with(
+            b"When I grow up, I want to be a watermelon",
+            &decode("BCVxsr7N_eNgVRqvHtD0zTZsEc6-VV-JvLexhqUzORcxaOzi6-AYWXvTBHm4bjyPjs7Vd8pZGH6SRpkNtoIAiw4"),
+            &decode("BTBZMqHH6r4Tts7J_aSIgg"),
+            &decode("BP4z9KsN6nGRTbVYI_c7VJSPQTBtkgcy27mlmlMoZIIgDll6e3vCYLocInmYWAmS6TlzAC8wEqKK6PBru3jl7A8"),
+            &decode("kyrL1jIIOHEzg3sM2ZWRHDRB62YACZhhSlknJ672kSs"),
+            &decode("DGv6ra1nlYgDCS1FRnbzlw"),
+        )
+        .unwrap();
+        assert_eq!(
+            URL_SAFE_NO_PAD.encode(body),
+            "DGv6ra1nlYgDCS1FRnbzlwAAEABBBP4z9KsN6nGRTbVYI_c7VJSPQTBtkgcy27mlmlMoZIIgDll6e3vCYLoc\
+            InmYWAmS6TlzAC8wEqKK6PBru3jl7A_yl95bQpu6cVPTpK4Mqgkf1CXztLVBSt2Ks3oZwbuwXPXLWyouBWLV\
+            WGNWQexSgSxsj_Qulcy4a-fN"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_check_endpoint() {
+        let check = |url| async move { check_endpoint(&Url::parse(url).unwrap()).await };
+
+        assert_eq!(
+            check("https://1.1.1.1:8443/push").await.unwrap(),
+            ["1.1.1.1:8443".parse::<SocketAddr>().unwrap()]
+        );
+        assert!(check("https://[2606:4700::1111]/push").await.is_ok());
+
+        assert!(check("http://203.0.114.1/push").await.is_err());
+        assert!(check("https://127.0.0.1/push").await.is_err());
+        assert!(check("https://10.1.2.3/push").await.is_err());
+        assert!(check("https://169.254.169.254/").await.is_err());
+        assert!(check("https://100.64.0.1/push").await.is_err());
+        assert!(check("https://[::1]/push").await.is_err());
+        assert!(check("https://[fd00::1]/push").await.is_err());
+        assert!(check("https://[::ffff:192.168.1.1]/push").await.is_err());
+    }
+}
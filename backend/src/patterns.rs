@@ -0,0 +1,202 @@
+//! The orders of stops a route's trips make, for drawing the route and picking a stop to board at
+
+use std::{cmp::Reverse, collections::HashMap};
+
+use itertools::Itertools;
+use sea_orm::{
+    ColumnTrait, EntityTrait, FromQueryResult, JoinType, QueryFilter, QueryOrder, QuerySelect,
+    RelationTrait,
+};
+use serde::Serialize;
+
+use crate::{
+    entity::{gtfs_stop_times, gtfs_stops, gtfs_trips},
+    error::{NextAtError, NextAtResult},
+    stops::{self, Stop},
+    ContextData,
+};
+
+/// Stops that some of the route's trips make in the same order,
+/// e.g. the whole route or a short working of it
+#[derive(Debug, Serialize, Clone)]
+pub struct RoutePattern {
+    pub direction_id: Option<i32>,
+    /// The first of the trips that make these stops
+    pub trip_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trip_headsign: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shape_id: Option<String>,
+    /// How many of the route's trips make these stops
+    pub trip_count: usize,
+    pub stops: Vec<PatternStop>,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct PatternStop {
+    /// Of the representative trip
+    pub stop_sequence: i32,
+    #[serde(flatten)]
+    pub stop: Stop,
+}
+
+#[derive(Debug, FromQueryResult)]
+struct TripStop {
+    trip_id: String,
+    direction_id: Option<i32>,
+    trip_headsign: Option<String>,
+    shape_id: Option<String>,
+    stop_id: String,
+    stop_sequence: i32,
+}
+
+/// The route's patterns, in order of direction then with the most trips first,
+/// only those in `direction_id` if it's given
+pub async fn get_route_patterns(
+    ctx: &ContextData,
+    route_id: &str,
+    direction_id: Option<i32>,
+) -> NextAtResult<Vec<RoutePattern>> {
+    use gtfs_stop_times as st;
+    use gtfs_trips as t;
+
+    if direction_id.is_some_and(|d| d != 0 && d != 1) {
+        return Err(NextAtError::InvalidData(
+            "direction_id must be 0 or 1".to_string(),
+        ));
+    }
+
+    stops::get_route(ctx, route_id).await?;
+
+    let mut query = st::Entity::find()
+        .join(JoinType::InnerJoin, st::Relation::GtfsTrips.def())
+        .filter(t::Column::RouteId.eq(route_id));
+    if let Some(direction_id) = direction_id {
+        query = query.filter(t::Column::DirectionId.eq(direction_id));
+    }
+    let trip_stops = query
+        .select_only()
+        .columns([
+            st::Column::TripId,
+            st::Column::StopId,
+            st::Column::StopSequence,
+        ])
+        .columns([
+            t::Column::DirectionId,
+            t::Column::TripHeadsign,
+            t::Column::ShapeId,
+        ])
+        .order_by_asc(st::Column::TripId)
+        .order_by_asc(st::Column::StopSequence)
+        .into_model::<TripStop>()
+        .all(&ctx.read_db)
+        .await?;
+
+    // Trips making the same stops in the same direction share a pattern
+    let mut patterns: Vec<(Vec<TripStop>, usize)> = vec![];
+    let mut pattern_index: HashMap<(Option<i32>, Vec<String>), usize> = HashMap::new();
+    for (_, trip) in &trip_stops.into_iter().group_by(|s| s.trip_id.clone()) {
+        let trip = trip.collect_vec();
+        let key = (
+            trip[0].direction_id,
+            trip.iter().map(|s| s.stop_id.clone()).collect_vec(),
+        );
+        match pattern_index.get(&key) {
+            Some(&i) => patterns[i].1 += 1,
+            None => {
+                pattern_index.insert(key, patterns.len());
+                patterns.push((trip, 1));
+            }
+        }
+    }
+
+    let stop_ids = pattern_index.into_keys().flat_map(|(_, s)| s).unique();
+    let stops: HashMap<String, Stop> = gtfs_stops::Entity::find()
+        .filter(gtfs_stops::Column::StopId.is_in(stop_ids))
+        .all(&ctx.read_db)
+        .await?
+        .into_iter()
+        .map(|s| (s.stop_id.clone(), Stop::from(s)))
+        .collect();
+
+    let patterns = patterns
+        .into_iter()
+        .sorted_by_key(|(trip, count)| (trip[0].direction_id, Reverse(*count)))
+        .map(|(trip, trip_count)| RoutePattern {
+            direction_id: trip[0].direction_id,
+            trip_id: trip[0].trip_id.clone(),
+            trip_headsign: trip[0].trip_headsign.clone(),
+            shape_id: trip[0].shape_id.clone(),
+            trip_count,
+            stops: trip
+                .iter()
+                .filter_map(|s| {
+                    Some(PatternStop {
+                        stop_sequence: s.stop_sequence,
+                        stop: stops.get(&s.stop_id)?.clone(),
+                    })
+                })
+                .collect(),
+        })
+        .collect();
+    Ok(patterns)
+}
+
+#[cfg(test)]
+mod test {
+
+    use sea_orm::ConnectionTrait;
+
+    use crate::test_utils::ctx;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_route_patterns() {
+        let ctx = ctx().await;
+        // A short working, and a trip back the other way
+        ctx.db
+            .execute_unprepared(
+                "INSERT INTO gtfs_trips (trip_id, service_id, route_id, trip_headsign, direction_id, import_id, feed_id)
+                VALUES ('1-NX1-2', 'daily', 'NX1-203', 'Wellesley Street', 0, 1, 'at'),
+                    ('1-NX1-3', 'daily', 'NX1-203', 'Britomart', 1, 1, 'at'),
+                    ('1-NX1-4', 'daily', 'NX1-203', 'Hibiscus Coast', 0, 1, 'at');
+                INSERT INTO gtfs_stop_times (trip_id, stop_sequence, arrival_time, departure_time, stop_id, import_id, feed_id)
+                VALUES ('1-NX1-2', 1, '09:00:00', '09:00:00', '4018-7ef4a7b7', 1, 'at'),
+                    ('1-NX1-2', 2, '09:05:00', '09:05:00', '7000-0b6a8a4a', 1, 'at'),
+                    ('1-NX1-3', 1, '09:00:00', '09:00:00', '7000-0b6a8a4a', 1, 'at'),
+                    ('1-NX1-3', 2, '09:05:00', '09:05:00', '1010-0c2d2a6b', 1, 'at'),
+                    ('1-NX1-4', 1, '10:00:00', '10:00:00', '1010-0c2d2a6b', 1, 'at'),
+                    ('1-NX1-4', 2, '10:05:00', '10:05:00', '4018-7ef4a7b7', 1, 'at'),
+                    ('1-NX1-4', 3, '10:10:00', '10:10:00', '7000-0b6a8a4a', 1, 'at');",
+            )
+            .await
+            .unwrap();
+
+        let patterns = get_route_patterns(&ctx, "NX1-203", None).await.unwrap();
+        assert_eq!(
+            patterns
+                .iter()
+                .map(|p| (p.direction_id, p.trip_id.as_str(), p.trip_count))
+                .collect_vec(),
+            [
+                (Some(0), "1-NX1-1", 2),
+                (Some(0), "1-NX1-2", 1),
+                (Some(1), "1-NX1-3", 1)
+            ]
+        );
+        assert_eq!(
+            patterns[0]
+                .stops
+                .iter()
+                .map(|s| s.stop.name.as_str())
+                .collect_vec(),
+            ["Quay Street", "Lower Albert Street", "Wellesley Street"]
+        );
+
+        let patterns = get_route_patterns(&ctx, "NX1-203", Some(1)).await.unwrap();
+        assert_eq!(patterns.len(), 1);
+        assert!(get_route_patterns(&ctx, "NX1-203", Some(2)).await.is_err());
+        assert!(get_route_patterns(&ctx, "NX2", None).await.is_err());
+    }
+}
@@ -0,0 +1,53 @@
+//! Writing the protobuf wire format, for the few messages we produce by hand
+
+const WIRE_VARINT: u32 = 0;
+const WIRE_FIXED64: u32 = 1;
+const WIRE_LENGTH_DELIMITED: u32 = 2;
+const WIRE_FIXED32: u32 = 5;
+
+pub fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn write_key(buf: &mut Vec<u8>, field: u32, wire_type: u32) {
+    write_varint(buf, ((field << 3) | wire_type).into());
+}
+
+pub fn write_varint_field(buf: &mut Vec<u8>, field: u32, value: u64) {
+    write_key(buf, field, WIRE_VARINT);
+    write_varint(buf, value);
+}
+
+/// An int32, int64 or enum, negatives are sign extended to ten bytes
+pub fn write_int_field(buf: &mut Vec<u8>, field: u32, value: i64) {
+    write_varint_field(buf, field, value as u64);
+}
+
+pub fn write_double_field(buf: &mut Vec<u8>, field: u32, value: f64) {
+    write_key(buf, field, WIRE_FIXED64);
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+pub fn write_float_field(buf: &mut Vec<u8>, field: u32, value: f32) {
+    write_key(buf, field, WIRE_FIXED32);
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+/// Bytes, a string or an embedded message
+pub fn write_bytes_field(buf: &mut Vec<u8>, field: u32, bytes: &[u8]) {
+    write_key(buf, field, WIRE_LENGTH_DELIMITED);
+    write_varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+pub fn write_packed_field(buf: &mut Vec<u8>, field: u32, values: &[u32]) {
+    let mut packed = vec![];
+    for value in values {
+        write_varint(&mut packed, (*value).into());
+    }
+    write_bytes_field(buf, field, &packed);
+}
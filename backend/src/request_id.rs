@@ -0,0 +1,127 @@
+use std::future::{ready, Future, Ready};
+
+use actix_web::{
+    body::MessageBody,
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    http::header::{HeaderName, HeaderValue},
+    Error,
+};
+use futures_util::future::LocalBoxFuture;
+use tracing::{field, Instrument, Span};
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Longest client supplied id we'll accept, anything else is replaced
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+pub fn new_id() -> String {
+    uuid::Uuid::new_v4().to_string()
+}
+
+/// The id of the request (or other unit of work) currently being handled, if any
+pub fn current() -> Option<String> {
+    REQUEST_ID.try_with(|id| id.clone()).ok()
+}
+
+/// Runs the future with the id attached, so that it is included in log lines
+pub async fn scope<F: Future>(id: String, f: F) -> F::Output {
+    REQUEST_ID.scope(id, f).await
+}
+
+fn is_valid(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_REQUEST_ID_LEN
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_.:".contains(c))
+}
+
+/// Middleware that accepts or generates an `X-Request-Id`,
+/// makes it available to everything handling the request and echoes it in the response.
+/// Each request gets a span, so everything logged while handling it has the id.
+pub struct RequestId;
+
+impl<S, B> Transform<S, ServiceRequest> for RequestId
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = RequestIdMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequestIdMiddleware { service }))
+    }
+}
+
+pub struct RequestIdMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for RequestIdMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let id = req
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .filter(|id| is_valid(id))
+            .map(|id| id.to_string())
+            .unwrap_or_else(new_id);
+
+        let span = tracing::info_span!(
+            "http_request",
+            method = %req.method(),
+            path = %req.path(),
+            request_id = %id,
+            status = field::Empty,
+        );
+
+        // The handler is called within the scope too, as it may do work before returning a future
+        let fut = span.in_scope(|| REQUEST_ID.sync_scope(id.clone(), || self.service.call(req)));
+
+        let fut = scope(id.clone(), async move {
+            let mut res = fut.await?;
+            Span::current().record("status", res.status().as_u16());
+            if let Ok(value) = HeaderValue::from_str(&id) {
+                res.headers_mut()
+                    .insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+            }
+            Ok(res)
+        });
+        Box::pin(fut.instrument(span))
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn test_is_valid() {
+        assert!(is_valid(&new_id()));
+        assert!(is_valid("client-123_abc.def:1"));
+        assert!(!is_valid(""));
+        assert!(!is_valid("has spaces"));
+        assert!(!is_valid(&"a".repeat(MAX_REQUEST_ID_LEN + 1)));
+    }
+}
@@ -0,0 +1,143 @@
+//! Each route's service status, rolled up from the alerts in effect on it as the AT app shows it
+
+use std::collections::HashMap;
+
+use chrono::Utc;
+use sea_orm::{
+    ColumnTrait, EntityTrait, FromQueryResult, JoinType, QueryFilter, QueryOrder, QuerySelect,
+    QueryTrait, RelationTrait,
+};
+use serde::Serialize;
+
+use crate::{
+    db::error::DbResult,
+    entity::{alert, alert_active_period, alert_informed_entity},
+    stops::StopRoute,
+    ContextData,
+};
+
+/// In order of how bad it is, so a route's status is the worst of its alerts'
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ServiceStatus {
+    #[default]
+    GoodService,
+    Delays,
+    Suspended,
+}
+
+impl ServiceStatus {
+    /// Of an alert with the feed's `Effect`.
+    /// Extra service, accessibility issues and the like don't affect it.
+    fn of_effect(effect: Option<i32>) -> Self {
+        match effect {
+            // NO_SERVICE
+            Some(1) => ServiceStatus::Suspended,
+            // REDUCED_SERVICE, SIGNIFICANT_DELAYS, DETOUR, MODIFIED_SERVICE and STOP_MOVED
+            Some(2 | 3 | 4 | 6 | 9) => ServiceStatus::Delays,
+            _ => ServiceStatus::GoodService,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Clone, Default)]
+pub struct RouteStatus {
+    pub service_status: ServiceStatus,
+    /// Of the alerts in effect on the route
+    pub alert_ids: Vec<String>,
+}
+
+/// A route with its status, for listing them all
+#[derive(Debug, Serialize, Clone)]
+pub struct RouteServiceStatus {
+    #[serde(flatten)]
+    pub route: StopRoute,
+    #[serde(flatten)]
+    pub status: RouteStatus,
+}
+
+#[derive(Debug, FromQueryResult)]
+struct RouteAlert {
+    route_id: String,
+    alert_id: String,
+    effect: Option<i32>,
+}
+
+/// Statuses of the routes with alerts in effect now, those without have good service.
+/// Alerts about only some of a route's stops or trips aren't counted.
+pub async fn get_route_statuses(ctx: &ContextData) -> DbResult<HashMap<String, RouteStatus>> {
+    use alert_active_period as ap;
+    use alert_informed_entity as ie;
+
+    let now = Utc::now().timestamp_millis();
+
+    let active = ap::Entity::find()
+        .select_only()
+        .column(ap::Column::AlertId)
+        .filter(ap::Column::StartTimestamp.lte(now))
+        .filter(ap::Column::EndTimestamp.gte(now))
+        .into_query();
+    let route_alerts = ie::Entity::find()
+        .join(JoinType::InnerJoin, ie::Relation::Alert.def())
+        .filter(ie::Column::RouteId.is_not_null())
+        .filter(ie::Column::StopId.is_null())
+        .filter(ie::Column::TripRunId.is_null())
+        .filter(ie::Column::AlertId.in_subquery(active))
+        .select_only()
+        .columns([ie::Column::RouteId, ie::Column::AlertId])
+        .column(alert::Column::Effect)
+        .order_by_asc(alert::Column::Id)
+        .into_model::<RouteAlert>()
+        .all(&ctx.read_db)
+        .await?;
+
+    let mut statuses = HashMap::<String, RouteStatus>::new();
+    for route_alert in route_alerts {
+        let status = statuses.entry(route_alert.route_id).or_default();
+        status.service_status = status
+            .service_status
+            .max(ServiceStatus::of_effect(route_alert.effect));
+        // It may name the route more than once
+        if !status.alert_ids.contains(&route_alert.alert_id) {
+            status.alert_ids.push(route_alert.alert_id);
+        }
+    }
+    Ok(statuses)
+}
+
+#[cfg(test)]
+mod test {
+
+    use sea_orm::ConnectionTrait;
+
+    use super::*;
+    use crate::test_utils::ctx;
+
+    #[tokio::test]
+    async fn test_route_statuses() {
+        let ctx = ctx().await;
+        // Cancelled, delayed, a lift out at one of its stops, and a detour that's over
+        ctx.db
+            .execute_unprepared(
+                "INSERT INTO alert (alert_id, effect) VALUES ('cancelled', 1), ('delayed', 3),
+                    ('lifts', 11), ('lift', 1), ('detour', 4);
+                INSERT INTO alert_active_period (alert_id, start_timestamp, end_timestamp)
+                VALUES ('cancelled', 0, 9999999999999), ('delayed', 0, 9999999999999),
+                    ('lifts', 0, 9999999999999), ('lift', 0, 9999999999999), ('detour', 0, 1);
+                INSERT INTO alert_informed_entity (alert_id, route_id, stop_id)
+                VALUES ('cancelled', 'NX1-203', NULL), ('delayed', 'NX1-203', NULL),
+                    ('lifts', 'WEST-201', NULL), ('lift', 'WEST-201', '9218-20fd5c4e'),
+                    ('detour', 'WEST-201', NULL);",
+            )
+            .await
+            .unwrap();
+
+        let statuses = get_route_statuses(&ctx).await.unwrap();
+        let nx1 = &statuses["NX1-203"];
+        assert_eq!(nx1.service_status, ServiceStatus::Suspended);
+        assert_eq!(nx1.alert_ids, ["cancelled", "delayed"]);
+        let west = &statuses["WEST-201"];
+        assert_eq!(west.service_status, ServiceStatus::GoodService);
+        assert_eq!(west.alert_ids, ["lifts"]);
+    }
+}
@@ -0,0 +1,153 @@
+use std::collections::HashMap;
+
+use geo::Point;
+use itertools::Itertools;
+use sea_orm::{
+    ColumnTrait, ConnectionTrait, DbErr, EntityTrait, QueryFilter, QueryOrder, QuerySelect,
+    QueryTrait,
+};
+use serde::Serialize;
+
+use crate::{
+    entity::{gtfs_shapes, gtfs_stop_times, gtfs_stops, gtfs_trips},
+    error::{NextAtError, NextAtResult},
+    geo::{distance_along, encode_polyline, simplify},
+    map::MAX_ZOOM,
+    stops, ContextData,
+};
+
+/// Metres across a pixel of a zoom 0 web map at the equator, halving with each zoom level
+const METRES_PER_PIXEL_ZOOM_0: f64 = 156_543.03;
+
+/// Decimal places of coordinates in polylines, the usual for Google's format
+const POLYLINE_PRECISION: u32 = 5;
+
+/// A path the route's trips take
+#[derive(Debug, Serialize, Clone)]
+pub struct RouteShape {
+    pub shape_id: String,
+    /// Google encoded polyline
+    pub polyline: String,
+    #[serde(skip)]
+    pub line: Vec<Point>,
+}
+
+/// A trip's path, with how far along it its stops are
+#[derive(Debug, Clone)]
+pub struct TripShapeStops {
+    pub shape: Vec<Point>,
+    /// Metres along the shape by stop_id, stops without a location are left out
+    pub stops_along: HashMap<String, f64>,
+}
+
+/// The path of the trip, None if it doesn't have one
+pub async fn trip_shape(
+    db: &impl ConnectionTrait,
+    trip_id: &str,
+) -> Result<Option<Vec<Point>>, DbErr> {
+    let shape_id = gtfs_trips::Entity::find()
+        .filter(gtfs_trips::Column::TripId.eq(trip_id))
+        .one(db)
+        .await?
+        .and_then(|t| t.shape_id);
+    let Some(shape_id) = shape_id else {
+        return Ok(None);
+    };
+
+    let shape = gtfs_shapes::Entity::find()
+        .filter(gtfs_shapes::Column::ShapeId.eq(shape_id))
+        .order_by_asc(gtfs_shapes::Column::ShapePtSequence)
+        .all(db)
+        .await?
+        .into_iter()
+        .map(|p| Point::new(p.shape_pt_lon, p.shape_pt_lat))
+        .collect();
+    Ok(Some(shape))
+}
+
+/// The path of the trip and where its stops are on it, None if it doesn't have a path
+pub async fn trip_shape_stops(
+    db: &impl ConnectionTrait,
+    trip_id: &str,
+) -> Result<Option<TripShapeStops>, DbErr> {
+    let Some(shape) = trip_shape(db, trip_id).await? else {
+        return Ok(None);
+    };
+
+    let trip_stops = gtfs_stop_times::Entity::find()
+        .select_only()
+        .column(gtfs_stop_times::Column::StopId)
+        .filter(gtfs_stop_times::Column::TripId.eq(trip_id))
+        .into_query();
+    let stops_along = gtfs_stops::Entity::find()
+        .filter(gtfs_stops::Column::StopId.in_subquery(trip_stops))
+        .all(db)
+        .await?
+        .into_iter()
+        .filter_map(|s| {
+            let point = Point::new(s.stop_lon?, s.stop_lat?);
+            Some((s.stop_id, distance_along(&shape, point)?))
+        })
+        .collect();
+
+    Ok(Some(TripShapeStops { shape, stops_along }))
+}
+
+/// Shapes of the route's trips. With `zoom`, points that wouldn't be a pixel off the line
+/// at that zoom level are left out.
+pub async fn get_route_shapes(
+    ctx: &ContextData,
+    route_id: &str,
+    zoom: Option<u8>,
+) -> NextAtResult<Vec<RouteShape>> {
+    use gtfs_shapes as sh;
+    use gtfs_trips as t;
+
+    if zoom.is_some_and(|z| z > MAX_ZOOM) {
+        return Err(NextAtError::InvalidData(format!(
+            "zoom can be at most {}",
+            MAX_ZOOM
+        )));
+    }
+
+    stops::get_route(ctx, route_id).await?;
+
+    let shape_ids = t::Entity::find()
+        .filter(t::Column::RouteId.eq(route_id))
+        .filter(t::Column::ShapeId.is_not_null())
+        .select_only()
+        .column(t::Column::ShapeId)
+        .distinct()
+        .into_tuple::<String>()
+        .all(&ctx.read_db)
+        .await?;
+
+    let points = sh::Entity::find()
+        .filter(sh::Column::ShapeId.is_in(shape_ids))
+        .order_by_asc(sh::Column::ShapeId)
+        .order_by_asc(sh::Column::ShapePtSequence)
+        .all(&ctx.read_db)
+        .await?;
+
+    let tolerance = zoom.map(|z| METRES_PER_PIXEL_ZOOM_0 / 2f64.powi(z.into()));
+    let shapes = points
+        .into_iter()
+        .group_by(|p| p.shape_id.clone())
+        .into_iter()
+        .map(|(shape_id, points)| {
+            let line = points
+                .map(|p| Point::new(p.shape_pt_lon, p.shape_pt_lat))
+                .collect_vec();
+            let line = match tolerance {
+                Some(tolerance) => simplify(&line, tolerance),
+                None => line,
+            };
+            RouteShape {
+                shape_id,
+                polyline: encode_polyline(&line, POLYLINE_PRECISION),
+                line,
+            }
+        })
+        .collect();
+    Ok(shapes)
+}
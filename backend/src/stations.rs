@@ -0,0 +1,197 @@
+use std::collections::HashSet;
+
+use sea_orm::{ColumnTrait, Condition, EntityTrait, QueryFilter, QueryOrder};
+use serde::Serialize;
+
+use crate::{
+    entity::{gtfs_levels, gtfs_pathways, gtfs_stops},
+    error::{NextAtError, NextAtResult},
+    ContextData,
+};
+
+/// GTFS location type of a station
+const LOCATION_TYPE_STATION: i32 = 1;
+
+/// Somewhere within a station, e.g. a platform, an entrance, or a node joining pathways
+#[derive(Debug, Serialize, Clone)]
+pub struct StationLocation {
+    pub id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
+    pub name: String,
+    /// GTFS location type: 0 platform, 2 entrance/exit, 3 generic node, 4 boarding area
+    pub location_type: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub level_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub platform_code: Option<String>,
+    pub lat: Option<f64>,
+    pub lon: Option<f64>,
+}
+
+impl From<gtfs_stops::Model> for StationLocation {
+    fn from(s: gtfs_stops::Model) -> Self {
+        Self {
+            id: s.stop_id,
+            code: s.stop_code,
+            name: s.stop_name,
+            location_type: s.location_type,
+            parent_id: s.parent_station,
+            level_id: s.level_id,
+            platform_code: s.platform_code,
+            lat: s.stop_lat,
+            lon: s.stop_lon,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct Level {
+    pub id: String,
+    /// 0 is ground level, levels above are positive and below are negative
+    pub index: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+}
+
+/// A way between two locations in a station
+#[derive(Debug, Serialize, Clone)]
+pub struct StationPathway {
+    pub id: String,
+    pub from_id: String,
+    pub to_id: String,
+    /// e.g. `stairs` or `elevator`
+    pub mode: &'static str,
+    /// If not, it can only be used from `from_id` to `to_id`
+    pub bidirectional: bool,
+    /// Metres
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub length: Option<f64>,
+    /// Seconds to walk through
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub traversal_time: Option<i32>,
+    /// Negative when going down from `from_id`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stair_count: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_slope: Option<f64>,
+    /// Metres
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_width: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signposted_as: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reversed_signposted_as: Option<String>,
+}
+
+impl From<gtfs_pathways::Model> for StationPathway {
+    fn from(p: gtfs_pathways::Model) -> Self {
+        Self {
+            id: p.pathway_id,
+            from_id: p.from_stop_id,
+            to_id: p.to_stop_id,
+            mode: pathway_mode_name(p.pathway_mode),
+            bidirectional: p.is_bidirectional == 1,
+            length: p.length,
+            traversal_time: p.traversal_time,
+            stair_count: p.stair_count,
+            max_slope: p.max_slope,
+            min_width: p.min_width,
+            signposted_as: p.signposted_as,
+            reversed_signposted_as: p.reversed_signposted_as,
+        }
+    }
+}
+
+/// Names of the GTFS `pathway_mode` values
+fn pathway_mode_name(mode: i32) -> &'static str {
+    match mode {
+        1 => "walkway",
+        2 => "stairs",
+        3 => "moving_sidewalk",
+        4 => "escalator",
+        5 => "elevator",
+        6 => "fare_gate",
+        7 => "exit_gate",
+        _ => "unknown",
+    }
+}
+
+/// Everything needed to find a way through a station
+#[derive(Debug, Serialize)]
+pub struct StationPathways {
+    pub locations: Vec<StationLocation>,
+    pub levels: Vec<Level>,
+    pub pathways: Vec<StationPathway>,
+}
+
+pub async fn get_station_pathways(
+    ctx: &ContextData,
+    station_id: &str,
+) -> NextAtResult<StationPathways> {
+    use gtfs_levels as l;
+    use gtfs_pathways as p;
+    use gtfs_stops as s;
+
+    let station = s::Entity::find()
+        .filter(s::Column::StopId.eq(station_id))
+        .filter(s::Column::LocationType.eq(LOCATION_TYPE_STATION))
+        .one(&ctx.read_db)
+        .await?
+        .ok_or_else(|| NextAtError::NotFound(format!("Station not found: {}", station_id)))?;
+
+    let mut locations = s::Entity::find()
+        .filter(s::Column::ParentStation.eq(&station.stop_id))
+        .order_by_asc(s::Column::StopId)
+        .all(&ctx.read_db)
+        .await?;
+
+    // boarding areas belong to a platform rather than the station
+    let boarding_areas = s::Entity::find()
+        .filter(s::Column::ParentStation.is_in(locations.iter().map(|l| l.stop_id.clone())))
+        .order_by_asc(s::Column::StopId)
+        .all(&ctx.read_db)
+        .await?;
+    locations.extend(boarding_areas);
+
+    let location_ids = locations
+        .iter()
+        .map(|l| l.stop_id.clone())
+        .collect::<Vec<_>>();
+
+    let pathways = p::Entity::find()
+        .filter(
+            Condition::any()
+                .add(p::Column::FromStopId.is_in(location_ids.clone()))
+                .add(p::Column::ToStopId.is_in(location_ids)),
+        )
+        .order_by_asc(p::Column::PathwayId)
+        .all(&ctx.read_db)
+        .await?;
+
+    let level_ids = locations
+        .iter()
+        .filter_map(|l| l.level_id.clone())
+        .collect::<HashSet<_>>();
+
+    let levels = l::Entity::find()
+        .filter(l::Column::LevelId.is_in(level_ids))
+        .order_by_asc(l::Column::LevelIndex)
+        .all(&ctx.read_db)
+        .await?;
+
+    Ok(StationPathways {
+        locations: locations.into_iter().map(StationLocation::from).collect(),
+        levels: levels
+            .into_iter()
+            .map(|l| Level {
+                id: l.level_id,
+                index: l.level_index,
+                name: l.level_name,
+            })
+            .collect(),
+        pathways: pathways.into_iter().map(StationPathway::from).collect(),
+    })
+}
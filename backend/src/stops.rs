@@ -2,17 +2,32 @@ use crate::entity::{prelude::*, trip_run};
 use crate::{
     db::{
         error::DbResult,
-        util::{col, pow},
+        links,
+        util::{blank_to_null, col, pow},
     },
-    entity::{gtfs_routes, gtfs_stop_times, gtfs_stops, gtfs_trips, stop_index, stop_time_index},
-    error::NextAtResult,
+    entity::{
+        gtfs_agency, gtfs_routes, gtfs_stop_times, gtfs_stops, gtfs_transfers, service_area,
+        stop_time_index,
+    },
+    error::{NextAtError, NextAtResult},
+    geo::{decode_polyline, distance_outside},
+    gtfs::index::SERVICE_AREA_PRECISION,
+    service_status::{self, ServiceStatus},
     ContextData,
 };
-use chrono::{Duration, Utc};
+use chrono::{DateTime, Duration, NaiveDate, SecondsFormat, TimeZone, Utc};
+use chrono_tz::Tz;
+use geo::{LineString, Point, Polygon};
 use itertools::Itertools;
-use migration::{Expr, Func};
-use sea_orm::sea_query::all;
-use sea_orm::{ColumnTrait, EntityTrait, JoinType, QueryFilter, QueryOrder, QuerySelect};
+use migration::{
+    Alias, Asterisk, Expr, Func, LikeExpr, Order, OrderedStatement, Query, SimpleExpr,
+    WindowStatement,
+};
+use sea_orm::sea_query::{all, any, Condition};
+use sea_orm::{
+    ColumnTrait, ConnectionTrait, EntityTrait, JoinType, QueryFilter, QueryOrder, QuerySelect,
+    QueryTrait,
+};
 use sea_orm::{FromQueryResult, RelationTrait};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
@@ -27,6 +42,53 @@ pub struct Stop {
     pub name: String,
     pub lat: Option<f64>,
     pub lon: Option<f64>,
+    /// Which of the configured feeds the stop is from
+    pub feed_id: String,
+    /// How the stop matched a code that was looked up
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub matched_by: Option<MatchedBy>,
+}
+
+impl From<gtfs_stops::Model> for Stop {
+    fn from(s: gtfs_stops::Model) -> Self {
+        Stop {
+            id: s.stop_id.clone(),
+            code: s.stop_code.unwrap_or(s.stop_id),
+            name: s.stop_name,
+            lat: s.stop_lat,
+            lon: s.stop_lon,
+            feed_id: s.feed_id,
+            matched_by: None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum MatchedBy {
+    Exact,
+    /// The code starts with what was looked up
+    Prefix,
+    /// The code is a typo away from what was looked up
+    Fuzzy,
+}
+
+/// Most stops returned for a partial or mistyped code
+const MAX_CODE_MATCHES: u64 = 10;
+
+/// Locations this close to the service area can still have stops nearby,
+/// as it's the stop index's search distance
+const SERVICE_AREA_MARGIN_METRES: f64 = 1000.0;
+
+/// A stop that can be transferred to, from transfers.txt
+#[derive(Debug, Serialize, Clone)]
+pub struct StopTransfer {
+    pub stop: Stop,
+    /// GTFS transfer type, 1 is a timed transfer where the connection waits
+    pub transfer_type: i32,
+    /// Seconds needed to make the transfer
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_transfer_time: Option<i32>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, FromQueryResult)]
@@ -39,21 +101,161 @@ pub struct StopRoute {
     pub route_text_color: String,
 }
 
+/// How often a route stops at a stop through a service day
+#[derive(Debug, Serialize, Clone)]
+pub struct StopRouteStats {
+    pub route_id: String,
+    pub route_short_name: String,
+    /// Trips stopping here on the day
+    pub trips: usize,
+    /// Scheduled times of the first and last departures, in the agency's timezone as ISO 8601
+    pub first_departure: String,
+    pub last_departure: String,
+    /// Median minutes between departures, None with only the one
+    pub headway_minutes: Option<i64>,
+}
+
+#[derive(Debug, FromQueryResult)]
+struct StopRun {
+    trip_run_id: i64,
+    start_date: String,
+    route_id: String,
+    route_short_name: String,
+    departure_timestamp: i64,
+    agency_timezone: Option<String>,
+}
+
 #[derive(Debug, Serialize, Clone, FromQueryResult)]
 pub struct StopArrival {
     pub trip_id: String,
     #[serde(skip_serializing)]
     pub route_id: String,
     pub stop_sequence: u32,
+    /// What the trip is grouped under: the stop's headsign, otherwise the trip's,
+    /// otherwise the last stop
     #[serde(skip_serializing)]
     pub stop_headsign: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trip_headsign: Option<String>,
     pub start_timestamp: i64,
+    /// Set when the trip only runs roughly this often, so the times aren't exact
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub headway_secs: Option<i32>,
     pub arrival_timestamp: i64,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub updated_arrival_timestamp: Option<i64>,
+    pub departure_timestamp: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub updated_departure_timestamp: Option<i64>,
+    /// Estimated from where the vehicle is, when the feed has no predictions for the trip
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub estimated_arrival_timestamp: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub estimated_departure_timestamp: Option<i64>,
+    /// Where the time comes from: `realtime`, `estimated` or `scheduled`
+    pub prediction: String,
+    /// `departed` once the time has passed, otherwise `upcoming`
+    pub status: String,
+    /// The vehicle won't stop here
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub skipped: bool,
+    /// The whole trip has been cancelled
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub cancelled: bool,
+    /// The stop (platform) the trip has been moved to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub updated_stop_id: Option<String>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub platform_changed: bool,
+    /// Of the route's agency, which local times are in
+    #[serde(skip_serializing)]
+    pub agency_timezone: Option<String>,
+    /// Until the expected time of the event, negative once it's passed
+    #[sea_orm(skip)]
+    pub due_in_seconds: i64,
+    /// The expected time of the event in the agency's timezone, as ISO 8601
+    #[sea_orm(skip)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub local_time: Option<String>,
 }
 
-#[derive(Serialize)]
+impl StopArrival {
+    /// Scheduled time of the event
+    pub fn timestamp(&self, event: StopEvent) -> i64 {
+        match event {
+            StopEvent::Arrival => self.arrival_timestamp,
+            StopEvent::Departure => self.departure_timestamp,
+        }
+    }
+
+    /// Time of the event from the feed, otherwise estimated, otherwise scheduled
+    pub fn expected_timestamp(&self, event: StopEvent) -> i64 {
+        let (updated, estimated) = match event {
+            StopEvent::Arrival => (
+                self.updated_arrival_timestamp,
+                self.estimated_arrival_timestamp,
+            ),
+            StopEvent::Departure => (
+                self.updated_departure_timestamp,
+                self.estimated_departure_timestamp,
+            ),
+        };
+        updated
+            .or(estimated)
+            .unwrap_or_else(|| self.timestamp(event))
+    }
+
+    /// Sets the times worked out from the expected time, so clients needn't
+    fn set_due(&mut self, event: StopEvent, now: DateTime<Utc>) {
+        let expected = self.expected_timestamp(event);
+        self.due_in_seconds = (expected - now.timestamp_millis()) / 1000;
+
+        let timezone = self
+            .agency_timezone
+            .as_deref()
+            .and_then(|tz| tz.parse::<Tz>().ok());
+        self.local_time = timezone
+            .zip(Utc.timestamp_millis_opt(expected).single())
+            .map(|(tz, time)| {
+                time.with_timezone(&tz)
+                    .to_rfc3339_opts(SecondsFormat::Secs, false)
+            });
+    }
+}
+
+/// Which stop time event arrivals are listed by
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum StopEvent {
+    Arrival,
+    Departure,
+}
+
+impl StopEvent {
+    /// The updated, estimated and scheduled time columns of the event
+    fn columns(
+        self,
+    ) -> (
+        stop_time_index::Column,
+        stop_time_index::Column,
+        stop_time_index::Column,
+    ) {
+        use stop_time_index::Column::*;
+        match self {
+            StopEvent::Arrival => (
+                UpdatedArrivalTimestamp,
+                EstimatedArrivalTimestamp,
+                ArrivalTimestamp,
+            ),
+            StopEvent::Departure => (
+                UpdatedDepartureTimestamp,
+                EstimatedDepartureTimestamp,
+                DepartureTimestamp,
+            ),
+        }
+    }
+}
+
+#[derive(Serialize, Clone)]
 pub struct RouteTrip {
     pub route_id: String,
     pub route_short_name: String,
@@ -62,6 +264,8 @@ pub struct RouteTrip {
     pub route_color: String,
     pub route_text_color: String,
     pub stop_headsign: String,
+    /// From the alerts in effect on the route
+    pub service_status: ServiceStatus,
 }
 
 #[derive(Serialize)]
@@ -70,83 +274,411 @@ pub struct StopRouteTripArrival {
     pub arrivals: Vec<StopArrival>,
 }
 
+/// An arrival with its route, for listing them all in time order
+#[derive(Serialize)]
+pub struct RouteTripArrival {
+    pub route_trip: RouteTrip,
+    #[serde(flatten)]
+    pub arrival: StopArrival,
+}
+
+/// Just the next arrival of a route, for signs that show nothing more
+#[derive(Debug, Serialize, Clone, FromQueryResult)]
+pub struct NextArrival {
+    pub route_id: String,
+    pub route_short_name: String,
+    pub trip_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trip_headsign: Option<String>,
+    pub arrival_timestamp: i64,
+    /// From the feed, otherwise estimated, otherwise as scheduled
+    pub expected_timestamp: i64,
+    /// Where the time comes from: `realtime`, `estimated` or `scheduled`
+    pub prediction: String,
+    #[sea_orm(skip)]
+    pub due_in_seconds: i64,
+}
+
+/// Ungroups arrivals, sorting them by when they're expected
+pub fn flatten_stop_events(
+    groups: Vec<StopRouteTripArrival>,
+    event: StopEvent,
+) -> Vec<RouteTripArrival> {
+    groups
+        .into_iter()
+        .flat_map(|group| {
+            let route_trip = group.route_trip;
+            group
+                .arrivals
+                .into_iter()
+                .map(move |arrival| RouteTripArrival {
+                    route_trip: route_trip.clone(),
+                    arrival,
+                })
+        })
+        .sorted_by_key(|a| a.arrival.expected_timestamp(event))
+        .collect()
+}
+
+/// The kinds of vehicle stops can be filtered by
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TransportMode {
+    Bus,
+    Train,
+    Ferry,
+}
+
+impl TransportMode {
+    /// Matches the route types of the mode, including the extended ones
+    fn route_type_condition(self) -> Condition {
+        use gtfs_routes::Column::RouteType;
+
+        let (basic, extended): (&[i32], &[(i32, i32)]) = match self {
+            TransportMode::Bus => (&[3], &[(200, 299), (700, 899)]),
+            TransportMode::Train => (&[1, 2], &[(100, 199), (400, 499)]),
+            TransportMode::Ferry => (&[4], &[(1000, 1099), (1200, 1299)]),
+        };
+        extended.iter().fold(
+            any![RouteType.is_in(basic.iter().copied())],
+            |condition, (from, to)| condition.add(RouteType.between(*from, *to)),
+        )
+    }
+}
+
+/// Closest first, only those served by `mode` if set
 pub async fn get_closest_stops(
     ctx: &ContextData,
     lat: f64,
     lon: f64,
     limit: u64,
+    mode: Option<TransportMode>,
 ) -> DbResult<Vec<Stop>> {
+    use gtfs_routes as r;
     use gtfs_stops as s;
     use gtfs_stops::Entity as GtfsStop;
-    use stop_index as si;
-
-    let gtfs_stops = GtfsStop::find()
-        .join(JoinType::InnerJoin, gtfs_stops::Relation::StopIndex.def())
-        .filter(si::Column::MinLat.lte(lat))
-        .filter(si::Column::MaxLat.gte(lat))
-        .filter(si::Column::MinLon.lte(lon))
-        .filter(si::Column::MaxLon.gte(lon))
+    use stop_time_index as sti;
+    use trip_run as tr;
+
+    // stop_index is an R*Tree, which answers this from the boxes containing the point
+    let si = |column: &str| Expr::col(Alias::new(column));
+    let near_stops = Query::select()
+        .column(Alias::new("stop_id"))
+        .from(Alias::new("stop_index"))
+        .and_where(si("min_lat").lte(lat))
+        .and_where(si("max_lat").gte(lat))
+        .and_where(si("min_lon").lte(lon))
+        .and_where(si("max_lon").gte(lon))
+        .to_owned();
+
+    let mut query = GtfsStop::find().filter(s::Column::StopId.in_subquery(near_stops));
+    if let Some(mode) = mode {
+        // Checked for each nearby stop, rather than finding every stop the mode serves
+        let served = Query::select()
+            .expr(Expr::val(1))
+            .from(sti::Entity)
+            .inner_join(
+                tr::Entity,
+                Expr::col((tr::Entity, tr::Column::Id))
+                    .equals((sti::Entity, sti::Column::TripRunId)),
+            )
+            .inner_join(
+                r::Entity,
+                Expr::col((r::Entity, r::Column::RouteId))
+                    .equals((tr::Entity, tr::Column::RouteId)),
+            )
+            .and_where(
+                Expr::col((sti::Entity, sti::Column::StopId))
+                    .equals((s::Entity, s::Column::StopId)),
+            )
+            .cond_where(mode.route_type_condition())
+            .to_owned();
+        query = query.filter(Expr::exists(served));
+    }
+
+    let gtfs_stops = query
         .order_by_asc(
             pow(col(s::Column::StopLat).sub(lat), 2).add(pow(col(s::Column::StopLon).sub(lon), 2)),
         )
         .limit(limit)
-        .all(&ctx.db)
+        .all(&ctx.read_db)
         .await?;
 
-    let stops = gtfs_stops
+    let stops = gtfs_stops.into_iter().map(Stop::from).collect();
+    Ok(stops)
+}
+
+/// How far (in metres) the location is from the area stops are in, when it's far enough out
+/// that none will be nearby. None if it's in the area, or the area isn't known yet.
+pub async fn distance_outside_service_area(
+    ctx: &ContextData,
+    lat: f64,
+    lon: f64,
+) -> NextAtResult<Option<f64>> {
+    let Some(area) = service_area::Entity::find().one(&ctx.read_db).await? else {
+        return Ok(None);
+    };
+    let hull = decode_polyline(&area.hull, SERVICE_AREA_PRECISION)
+        .ok_or_else(|| NextAtError::DataFormat("Invalid service area polyline".to_string()))?;
+    let area = Polygon::new(LineString::from(hull), vec![]);
+
+    let distance =
+        distance_outside(&area, Point::new(lon, lat)).filter(|d| *d > SERVICE_AREA_MARGIN_METRES);
+    Ok(distance)
+}
+
+/// Edits between two codes, where swapping neighbouring characters is one edit
+/// (optimal string alignment distance)
+fn code_distance(a: &str, b: &str) -> usize {
+    let a = a.chars().collect_vec();
+    let b = b.chars().collect_vec();
+
+    // Three rows of the matrix are enough for transpositions
+    let mut before: Vec<usize> = vec![0; b.len() + 1];
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    let mut current = vec![0; b.len() + 1];
+    for i in 1..=a.len() {
+        current[0] = i;
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            current[j] = (previous[j] + 1)
+                .min(current[j - 1] + 1)
+                .min(previous[j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                current[j] = current[j].min(before[j - 2] + 1);
+            }
+        }
+        std::mem::swap(&mut before, &mut previous);
+        std::mem::swap(&mut previous, &mut current);
+    }
+    previous[b.len()]
+}
+
+/// The stop with the code, otherwise those whose codes start with it,
+/// otherwise those a typo away from it, as signs can be hard to read
+pub async fn find_stops_by_code(ctx: &ContextData, code: &str) -> DbResult<Vec<Stop>> {
+    use gtfs_stops as s;
+    use gtfs_stops::Entity as GtfsStop;
+
+    let code = code.trim();
+    if code.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let with_match = |matched_by| {
+        move |stop: gtfs_stops::Model| Stop {
+            matched_by: Some(matched_by),
+            ..Stop::from(stop)
+        }
+    };
+
+    if let Some(stop) = GtfsStop::find()
+        .filter(s::Column::StopCode.eq(code))
+        .one(&ctx.read_db)
+        .await?
+    {
+        return Ok(vec![with_match(MatchedBy::Exact)(stop)]);
+    }
+
+    let code_length = || Expr::expr(Func::char_length(Expr::col(s::Column::StopCode)));
+
+    let pattern = code
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+    let prefixed = GtfsStop::find()
+        .filter(
+            Expr::col(s::Column::StopCode)
+                .like(LikeExpr::new(format!("{}%", pattern)).escape('\\')),
+        )
+        .order_by_asc(code_length())
+        .order_by_asc(s::Column::StopCode)
+        .limit(MAX_CODE_MATCHES)
+        .all(&ctx.read_db)
+        .await?;
+    if !prefixed.is_empty() {
+        return Ok(prefixed
+            .into_iter()
+            .map(with_match(MatchedBy::Prefix))
+            .collect());
+    }
+
+    // Only codes of a similar length can be a single edit away
+    let length = code.chars().count() as i64;
+    let similar = GtfsStop::find()
+        .filter(code_length().between(length - 1, length + 1))
+        .all(&ctx.read_db)
+        .await?;
+    let stops = similar
         .into_iter()
-        .map(|s| Stop {
-            id: s.stop_id.clone(),
-            code: s.stop_code.unwrap_or(s.stop_id),
-            name: s.stop_name,
-            lat: s.stop_lat,
-            lon: s.stop_lon,
+        .filter_map(|stop| {
+            let distance = code_distance(code, stop.stop_code.as_deref()?);
+            (distance <= 1).then_some((distance, stop))
         })
+        .sorted_by(|(a, a_stop), (b, b_stop)| {
+            a.cmp(b).then(a_stop.stop_code.cmp(&b_stop.stop_code))
+        })
+        .take(MAX_CODE_MATCHES as usize)
+        .map(|(_, stop)| with_match(MatchedBy::Fuzzy)(stop))
         .collect();
+
     Ok(stops)
 }
 
-pub async fn get_stop_by_code(ctx: &ContextData, code: &str) -> DbResult<Option<Stop>> {
+pub async fn get_stop(ctx: &ContextData, stop_id: &str) -> NextAtResult<Stop> {
     use gtfs_stops as s;
     use gtfs_stops::Entity as GtfsStop;
 
-    let stop = GtfsStop::find()
-        .filter(s::Column::StopCode.eq(code))
-        .one(&ctx.db)
+    let static_version = ctx.versions.static_version();
+    ctx.query_cache
+        .stops
+        .get_or_try_insert(stop_id.to_string(), static_version, || async {
+            GtfsStop::find()
+                .filter(s::Column::StopId.eq(stop_id))
+                .one(&ctx.read_db)
+                .await?
+                .map(Stop::from)
+                .ok_or_else(|| NextAtError::NotFound(format!("Stop not found: {}", stop_id)))
+        })
+        .await
+}
+
+pub async fn get_route(ctx: &ContextData, route_id: &str) -> NextAtResult<gtfs_routes::Model> {
+    let static_version = ctx.versions.static_version();
+    ctx.query_cache
+        .routes
+        .get_or_try_insert(route_id.to_string(), static_version, || async {
+            gtfs_routes::Entity::find()
+                .filter(gtfs_routes::Column::RouteId.eq(route_id))
+                .one(&ctx.read_db)
+                .await?
+                .ok_or_else(|| NextAtError::NotFound(format!("Route not found: {}", route_id)))
+        })
+        .await
+}
+
+/// Stops connected to this one, in the order of the quickest transfer
+pub async fn get_stop_transfers(ctx: &ContextData, stop_id: &str) -> DbResult<Vec<StopTransfer>> {
+    use gtfs_stops as s;
+    use gtfs_stops::Entity as GtfsStop;
+    use gtfs_transfers as t;
+
+    let transfers = GtfsTransfers::find()
+        .filter(t::Column::FromStopId.eq(stop_id))
+        // type 3 means the transfer isn't possible
+        .filter(t::Column::TransferType.ne(3))
+        .all(&ctx.read_db)
+        .await?;
+
+    let stops: HashMap<_, _> = GtfsStop::find()
+        .filter(s::Column::StopId.is_in(transfers.iter().map(|t| t.to_stop_id.clone())))
+        .all(&ctx.read_db)
         .await?
-        .map(|s| Stop {
-            id: s.stop_id.clone(),
-            code: s.stop_code.unwrap_or(s.stop_id),
-            name: s.stop_name,
-            lat: s.stop_lat,
-            lon: s.stop_lon,
-        });
+        .into_iter()
+        .map(|s| (s.stop_id.clone(), Stop::from(s)))
+        .collect();
+
+    let transfers = transfers
+        .into_iter()
+        .filter_map(|t| {
+            Some(StopTransfer {
+                stop: stops.get(&t.to_stop_id)?.clone(),
+                transfer_type: t.transfer_type,
+                min_transfer_time: t.min_transfer_time,
+            })
+        })
+        .sorted_by_key(|t| t.min_transfer_time.unwrap_or(0))
+        .collect();
+
+    Ok(transfers)
+}
+
+/// The most `departed_minutes` can be, well within how long past stop times are kept
+pub const MAX_DEPARTED_MINUTES: u32 = 60;
+
+/// Includes those that departed in the last `departed_minutes`
+pub async fn get_stop_arrivals(
+    ctx: &ContextData,
+    stop_id: &str,
+    departed_minutes: u32,
+) -> NextAtResult<Vec<StopRouteTripArrival>> {
+    get_stop_events(ctx, stop_id, StopEvent::Arrival, departed_minutes).await
+}
 
-    Ok(stop)
+/// Includes those that departed in the last `departed_minutes`
+pub async fn get_stop_departures(
+    ctx: &ContextData,
+    stop_id: &str,
+    departed_minutes: u32,
+) -> NextAtResult<Vec<StopRouteTripArrival>> {
+    get_stop_events(ctx, stop_id, StopEvent::Departure, departed_minutes).await
 }
 
-pub async fn get_stop_arrivals(ctx: &ContextData, stop_id: &str) -> NextAtResult<Vec<StopRouteTripArrival>> {
+async fn get_stop_events(
+    ctx: &ContextData,
+    stop_id: &str,
+    event: StopEvent,
+    departed_minutes: u32,
+) -> NextAtResult<Vec<StopRouteTripArrival>> {
     use gtfs_routes as r;
     use gtfs_stop_times as st;
+    use gtfs_stops as s;
     use gtfs_trips as t;
     use stop_time_index as sti;
     use trip_run as tr;
 
-    let now = Utc::now().timestamp_millis();
+    let now_time = Utc::now();
+    let now = now_time.timestamp_millis();
+    let departed_since =
+        (Utc::now() - Duration::try_minutes(departed_minutes.into()).unwrap()).timestamp_millis();
     let tomorrow = Utc::now().add(Duration::try_days(1).unwrap()).timestamp_millis();
 
-    let ts_col = || Expr::expr(Func::coalesce([col(sti::Column::UpdatedArrivalTimestamp).into(), col(sti::Column::ArrivalTimestamp).into()]));
+    let (updated_col, estimated_col, scheduled_col) = event.columns();
+    // Feed predictions are preferred over estimates
+    let ts_col = || {
+        Expr::expr(Func::coalesce([
+            col(updated_col).into(),
+            col(estimated_col).into(),
+            col(scheduled_col).into(),
+        ]))
+    };
+
+    // Where the trip ends, as it's run, for when it has no headsign
+    let last_stop = Alias::new("last_stop");
+    let final_destination = Query::select()
+        .column((s::Entity, s::Column::StopName))
+        .from_as(sti::Entity, last_stop.clone())
+        .inner_join(
+            s::Entity,
+            Expr::col((s::Entity, s::Column::StopId)).eq(Func::coalesce([
+                Expr::col((last_stop.clone(), sti::Column::UpdatedStopId)).into(),
+                Expr::col((last_stop.clone(), sti::Column::StopId)).into(),
+            ])),
+        )
+        .and_where(
+            Expr::col((last_stop.clone(), sti::Column::TripRunId))
+                .equals((sti::Entity, sti::Column::TripRunId)),
+        )
+        .order_by((last_stop, sti::Column::StopSequence), Order::Desc)
+        .limit(1)
+        .to_owned();
 
     let arrivals = StopTimeIndex::find()
         .filter(all![
-            sti::Column::StopId.eq(stop_id),
-            ts_col().gte(now),
+            // either scheduled here or moved here
+            any![
+                sti::Column::StopId.eq(stop_id),
+                sti::Column::UpdatedStopId.eq(stop_id)
+            ],
+            ts_col().gte(departed_since),
             ts_col().lt(tomorrow),
         ])
         .join(JoinType::InnerJoin, sti::Relation::TripRun.def())
-        .join(JoinType::InnerJoin, sti::Relation::GtfsStopTimes.def())
-        .join(JoinType::InnerJoin, st::Relation::GtfsTrips.def())
-        .join(JoinType::InnerJoin, t::Relation::GtfsRoutes.def())
+        .join(JoinType::InnerJoin, tr::Relation::GtfsRoutes.def())
+        .join(JoinType::LeftJoin, r::Relation::GtfsAgency.def())
+        // added trips have no scheduled stop time
+        .join(JoinType::LeftJoin, links::stop_time_index_stop_time())
+        .join(JoinType::LeftJoin, links::trip_run_trip())
         .order_by_asc(ts_col())
         .select_only()
         .columns([
@@ -154,20 +686,56 @@ pub async fn get_stop_arrivals(ctx: &ContextData, stop_id: &str) -> NextAtResult
             sti::Column::StopSequence,
             sti::Column::ArrivalTimestamp,
             sti::Column::UpdatedArrivalTimestamp,
+            sti::Column::DepartureTimestamp,
+            sti::Column::UpdatedDepartureTimestamp,
+            sti::Column::EstimatedArrivalTimestamp,
+            sti::Column::EstimatedDepartureTimestamp,
+            sti::Column::Skipped,
+            sti::Column::Cancelled,
+            sti::Column::UpdatedStopId,
         ])
-        .column(tr::Column::StartTimestamp)
-        .column(st::Column::StopHeadsign)
+        .expr_as(
+            Expr::case(Expr::col(updated_col).is_not_null(), "realtime")
+                .case(Expr::col(estimated_col).is_not_null(), "estimated")
+                .finally("scheduled"),
+            "prediction",
+        )
+        .expr_as(
+            Expr::case(ts_col().lt(now), "departed").finally("upcoming"),
+            "status",
+        )
+        .expr_as(
+            Expr::col(sti::Column::UpdatedStopId).is_not_null(),
+            "platform_changed",
+        )
+        .columns([tr::Column::StartTimestamp, tr::Column::HeadwaySecs])
+        .expr_as(
+            Func::coalesce([
+                blank_to_null(col(st::Column::StopHeadsign)),
+                blank_to_null(col(t::Column::TripHeadsign)),
+                blank_to_null(SimpleExpr::SubQuery(
+                    None,
+                    Box::new(final_destination.into_sub_query_statement()),
+                )),
+                col(r::Column::RouteLongName).into(),
+            ]),
+            "stop_headsign",
+        )
+        .expr_as(blank_to_null(col(t::Column::TripHeadsign)), "trip_headsign")
         .column(r::Column::RouteId)
+        .column(gtfs_agency::Column::AgencyTimezone)
         .limit(50)
         .into_model::<StopArrival>()
-        .all(&ctx.db)
+        .all(&ctx.read_db)
         .await?;
 
     let routes = get_stop_routes(ctx, stop_id).await?;
+    let statuses = service_status::get_route_statuses(ctx).await?;
     
     let mut stop_arrivals = HashMap::<(String, String), StopRouteTripArrival>::new();
 
-    for arrival in arrivals {
+    for mut arrival in arrivals {
+        arrival.set_due(event, now_time);
 
         if let Some(route) = routes.iter().find(|r| r.route_id == arrival.route_id) {
             let item = stop_arrivals
@@ -181,6 +749,10 @@ pub async fn get_stop_arrivals(ctx: &ContextData, stop_id: &str) -> NextAtResult
                         route_color: route.route_color.clone(),
                         route_text_color: route.route_text_color.clone(),
                         stop_headsign: arrival.stop_headsign.clone(),
+                        service_status: statuses
+                            .get(&route.route_id)
+                            .map(|s| s.service_status)
+                            .unwrap_or_default(),
                     },
                     arrivals: vec![],
                 });
@@ -192,12 +764,116 @@ pub async fn get_stop_arrivals(ctx: &ContextData, stop_id: &str) -> NextAtResult
 
     let stop_arrivals = stop_arrivals.into_values()
         .filter(|v| !v.arrivals.is_empty())
-        .sorted_by_key(|a| a.arrivals[0].arrival_timestamp)
+        .sorted_by_key(|a| a.arrivals[0].timestamp(event))
         .collect::<Vec<_>>();
     Ok(stop_arrivals)
 }
 
+/// The next arrival at the stop of each route, soonest first, of `routes` (ids or short names)
+/// if there are any. Skipped stops and cancelled trips are left out.
+pub async fn get_next_arrivals(
+    ctx: &ContextData,
+    stop_id: &str,
+    routes: &[String],
+) -> DbResult<Vec<NextArrival>> {
+    use gtfs_routes as r;
+    use gtfs_trips as t;
+    use stop_time_index as sti;
+    use trip_run as tr;
+
+    let now_time = Utc::now();
+    let now = now_time.timestamp_millis();
+    let tomorrow = now_time
+        .add(Duration::try_days(1).unwrap())
+        .timestamp_millis();
+
+    let (updated_col, estimated_col, scheduled_col) = StopEvent::Arrival.columns();
+    let ts_col = || {
+        Expr::expr(Func::coalesce([
+            col(updated_col).into(),
+            col(estimated_col).into(),
+            col(scheduled_col).into(),
+        ]))
+    };
+
+    let route_filter = (!routes.is_empty()).then(|| {
+        let routes = || routes.iter().map(String::as_str);
+        any![
+            r::Column::RouteId.is_in(routes()),
+            r::Column::RouteShortName.is_in(routes()),
+        ]
+    });
+
+    // Numbered within each route, soonest first, so that only the first of each is kept
+    let position = Alias::new("position");
+    let mut window = WindowStatement::partition_by((r::Entity, r::Column::RouteId));
+    window.order_by_expr(ts_col().into(), Order::Asc);
+    let arrivals = StopTimeIndex::find()
+        .filter(
+            all![
+                any![
+                    sti::Column::StopId.eq(stop_id),
+                    sti::Column::UpdatedStopId.eq(stop_id)
+                ],
+                ts_col().gte(now),
+                ts_col().lt(tomorrow),
+                sti::Column::Skipped.eq(0),
+                sti::Column::Cancelled.eq(0),
+            ]
+            .add_option(route_filter),
+        )
+        .join(JoinType::InnerJoin, sti::Relation::TripRun.def())
+        .join(JoinType::InnerJoin, tr::Relation::GtfsRoutes.def())
+        .join(JoinType::LeftJoin, links::trip_run_trip())
+        .select_only()
+        .columns([r::Column::RouteId, r::Column::RouteShortName])
+        .columns([sti::Column::TripId, sti::Column::ArrivalTimestamp])
+        .expr_as(blank_to_null(col(t::Column::TripHeadsign)), "trip_headsign")
+        .expr_as(ts_col(), "expected_timestamp")
+        .expr_as(
+            Expr::case(Expr::col(updated_col).is_not_null(), "realtime")
+                .case(Expr::col(estimated_col).is_not_null(), "estimated")
+                .finally("scheduled"),
+            "prediction",
+        )
+        .into_query()
+        .expr_window_as(
+            Func::cust(Alias::new("ROW_NUMBER")),
+            window,
+            position.clone(),
+        )
+        .to_owned();
+
+    let next = Alias::new("next");
+    let query = Query::select()
+        .column(Asterisk)
+        .from_subquery(arrivals, next)
+        .and_where(Expr::col(position).eq(1))
+        .order_by(Alias::new("expected_timestamp"), Order::Asc)
+        .to_owned();
+    let backend = ctx.read_db.get_database_backend();
+    let mut arrivals = NextArrival::find_by_statement(backend.build(&query))
+        .all(&ctx.read_db)
+        .await?;
+
+    for arrival in &mut arrivals {
+        arrival.due_in_seconds = (arrival.expected_timestamp - now) / 1000;
+    }
+    Ok(arrivals)
+}
+
+/// Routes stopping at the stop in the next week, busiest first
 pub async fn get_stop_routes(ctx: &ContextData, stop_id: &str) -> DbResult<Vec<StopRoute>> {
+    let static_version = ctx.versions.static_version();
+    ctx.query_cache
+        .stop_routes
+        .get_or_try_insert(stop_id.to_string(), static_version, || {
+            query_stop_routes(ctx, stop_id)
+        })
+        .await
+}
+
+async fn query_stop_routes(ctx: &ContextData, stop_id: &str) -> DbResult<Vec<StopRoute>> {
     
     use stop_time_index::Column as sti;
     use gtfs_routes::Column as r;
@@ -223,15 +899,147 @@ pub async fn get_stop_routes(ctx: &ContextData, stop_id: &str) -> DbResult<Vec<S
             r::RouteTextColor,
         ])
         .into_model::<StopRoute>()
-        .all(&ctx.db)
+        .all(&ctx.read_db)
         .await?;
 
     Ok(stop_routes)
 }
 
+/// Every route, of the `mode` if there is one, by short name
+pub async fn get_routes(
+    ctx: &ContextData,
+    mode: Option<TransportMode>,
+) -> DbResult<Vec<StopRoute>> {
+    use gtfs_routes::Column as r;
+
+    let mut query = GtfsRoutes::find();
+    if let Some(mode) = mode {
+        query = query.filter(mode.route_type_condition());
+    }
+    let routes = query
+        .order_by_asc(r::RouteShortName)
+        .select_only()
+        .columns([
+            r::RouteId,
+            r::RouteShortName,
+            r::RouteLongName,
+            r::RouteType,
+            r::RouteColor,
+            r::RouteTextColor,
+        ])
+        .into_model::<StopRoute>()
+        .all(&ctx.read_db)
+        .await?;
+
+    Ok(routes)
+}
+
+/// Each route's trips at the stop on the service `date`, busiest first.
+/// Without a date, it's today in the agency's timezone. Only days in the index have any.
+pub async fn get_stop_stats(
+    ctx: &ContextData,
+    stop_id: &str,
+    date: Option<NaiveDate>,
+) -> NextAtResult<Vec<StopRouteStats>> {
+    use gtfs_routes as r;
+    use stop_time_index as sti;
+    use trip_run as tr;
+
+    get_stop(ctx, stop_id).await?;
+
+    let now = Utc::now();
+    let gtfs_date = |date: NaiveDate| date.format("%Y%m%d").to_string();
+    // Today in any timezone is a day either side of today in UTC
+    let today = now.date_naive();
+    let dates = match date {
+        Some(date) => vec![date],
+        None => [today.pred_opt(), Some(today), today.succ_opt()]
+            .into_iter()
+            .flatten()
+            .collect(),
+    };
+
+    let runs = StopTimeIndex::find()
+        .filter(all![
+            sti::Column::StopId.eq(stop_id),
+            tr::Column::StartDate.is_in(dates.into_iter().map(gtfs_date)),
+        ])
+        .join(JoinType::InnerJoin, sti::Relation::TripRun.def())
+        .join(JoinType::InnerJoin, tr::Relation::GtfsRoutes.def())
+        .join(JoinType::LeftJoin, r::Relation::GtfsAgency.def())
+        .order_by_asc(sti::Column::DepartureTimestamp)
+        .select_only()
+        .columns([sti::Column::TripRunId, sti::Column::DepartureTimestamp])
+        .column(tr::Column::StartDate)
+        .columns([r::Column::RouteId, r::Column::RouteShortName])
+        .column(gtfs_agency::Column::AgencyTimezone)
+        .into_model::<StopRun>()
+        .all(&ctx.read_db)
+        .await?;
+
+    let local_time = |timestamp: i64, tz: &Tz| {
+        Utc.timestamp_millis_opt(timestamp)
+            .single()
+            .map(|time| {
+                time.with_timezone(tz)
+                    .to_rfc3339_opts(SecondsFormat::Secs, false)
+            })
+            .unwrap_or_default()
+    };
+
+    let stats = runs
+        .into_iter()
+        .map(|run| {
+            let tz = run
+                .agency_timezone
+                .as_deref()
+                .and_then(|tz| tz.parse::<Tz>().ok())
+                .unwrap_or(Tz::UTC);
+            (run, tz)
+        })
+        .filter(|(run, tz)| {
+            let date = date.unwrap_or_else(|| now.with_timezone(tz).date_naive());
+            run.start_date == gtfs_date(date)
+        })
+        .into_group_map_by(|(run, _)| run.route_id.clone())
+        .into_values()
+        .map(|runs| {
+            // A loop can stop here more than once a trip
+            let runs = runs
+                .into_iter()
+                .unique_by(|(run, _)| run.trip_run_id)
+                .collect_vec();
+            let (first, tz) = &runs[0];
+            let (last, _) = &runs[runs.len() - 1];
+            let gaps = runs
+                .iter()
+                .tuple_windows()
+                .map(|((a, _), (b, _))| (b.departure_timestamp - a.departure_timestamp) / 60_000)
+                .sorted()
+                .collect_vec();
+            StopRouteStats {
+                route_id: first.route_id.clone(),
+                route_short_name: first.route_short_name.clone(),
+                trips: runs.len(),
+                first_departure: local_time(first.departure_timestamp, tz),
+                last_departure: local_time(last.departure_timestamp, tz),
+                headway_minutes: gaps.get(gaps.len() / 2).copied(),
+            }
+        })
+        .sorted_by(|a, b| {
+            b.trips
+                .cmp(&a.trips)
+                .then_with(|| a.route_short_name.cmp(&b.route_short_name))
+        })
+        .collect();
+    Ok(stats)
+}
+
 #[cfg(test)]
 mod test {
 
+    use chrono::Offset;
+
     use crate::test_utils::ctx;
 
     use super::*;
@@ -240,16 +1048,216 @@ mod test {
     async fn test_closest_stops() {
         let ctx = ctx().await;
 
-        let stops = get_closest_stops(&ctx, -36.8485, 174.7633, 5)
+        let stops = get_closest_stops(&ctx, -36.8485, 174.7633, 5, None)
             .await
             .unwrap();
         println!("Closest stops: {:?}", stops);
+        assert_eq!(stops.len(), 5);
+        assert_eq!(stops[0].id, "7000-0b6a8a4a");
+
+        let stops = get_closest_stops(&ctx, -36.8485, 174.7633, 5, Some(TransportMode::Train))
+            .await
+            .unwrap();
+        assert_eq!(
+            stops.iter().map(|s| s.id.as_str()).collect_vec(),
+            ["9218-20fd5c4e"]
+        );
+
+        let stops = get_closest_stops(&ctx, -36.8485, 174.7633, 5, Some(TransportMode::Ferry))
+            .await
+            .unwrap();
+        assert!(stops.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_distance_outside_service_area() {
+        let ctx = ctx().await;
+
+        let inside = distance_outside_service_area(&ctx, -36.8485, 174.7633)
+            .await
+            .unwrap();
+        assert!(inside.is_none());
+
+        // Wellington
+        let outside = distance_outside_service_area(&ctx, -41.2865, 174.7762)
+            .await
+            .unwrap();
+        assert!(outside.is_some_and(|d| d > 400_000.0));
+    }
+
+    #[test]
+    fn test_code_distance() {
+        assert_eq!(code_distance("7000", "7000"), 0);
+        assert_eq!(code_distance("7001", "7000"), 1);
+        assert_eq!(code_distance("4081", "4018"), 1);
+        assert_eq!(code_distance("700", "7000"), 1);
+        assert_eq!(code_distance("1010", "4018"), 2);
+    }
+
+    #[tokio::test]
+    async fn test_find_stops_by_code() {
+        let ctx = ctx().await;
+
+        let stops = find_stops_by_code(&ctx, "7000").await.unwrap();
+        assert_eq!(stops.len(), 1);
+        assert_eq!(stops[0].matched_by, Some(MatchedBy::Exact));
+
+        let stops = find_stops_by_code(&ctx, "921").await.unwrap();
+        assert_eq!(
+            stops.iter().map(|s| s.code.as_str()).collect_vec(),
+            ["9218", "9219"]
+        );
+        assert_eq!(stops[0].matched_by, Some(MatchedBy::Prefix));
+
+        let stops = find_stops_by_code(&ctx, "4081").await.unwrap();
+        assert_eq!(stops.len(), 1);
+        assert_eq!(stops[0].id, "4018-7ef4a7b7");
+        assert_eq!(stops[0].matched_by, Some(MatchedBy::Fuzzy));
     }
 
     #[tokio::test]
     async fn test_stop_arrivals() {
         // println!("Now: {}", now);
         let ctx = ctx().await;
-        get_stop_arrivals(&ctx, "4018-7ef4a7b7").await.unwrap();
+        let arrivals = get_stop_arrivals(&ctx, "7000-0b6a8a4a", 0).await.unwrap();
+        assert_eq!(arrivals.len(), 1);
+        assert_eq!(arrivals[0].arrivals[0].trip_id, "1-NX1-1");
+    }
+
+    #[tokio::test]
+    async fn test_flatten_stop_events() {
+        let ctx = ctx().await;
+        // Both routes stop here, in separate groups
+        ctx.db
+            .execute_unprepared(
+                "UPDATE stop_time_index SET stop_id = '7000-0b6a8a4a' WHERE trip_id = '1-WEST-1'",
+            )
+            .await
+            .unwrap();
+        let groups = get_stop_arrivals(&ctx, "7000-0b6a8a4a", 0).await.unwrap();
+        assert_eq!(groups.len(), 2);
+
+        let arrivals = flatten_stop_events(groups, StopEvent::Arrival);
+        assert_eq!(
+            arrivals
+                .iter()
+                .map(|a| a.route_trip.route_id.as_str())
+                .collect_vec(),
+            ["NX1-203", "WEST-201"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_next_arrivals() {
+        let ctx = ctx().await;
+        ctx.db
+            .execute_unprepared(
+                "UPDATE stop_time_index SET stop_id = '7000-0b6a8a4a' WHERE trip_id = '1-WEST-1'",
+            )
+            .await
+            .unwrap();
+
+        let next = get_next_arrivals(&ctx, "7000-0b6a8a4a", &[]).await.unwrap();
+        assert_eq!(
+            next.iter().map(|n| n.route_id.as_str()).collect_vec(),
+            ["NX1-203", "WEST-201"]
+        );
+        assert!((295..=300).contains(&next[0].due_in_seconds));
+
+        // By short name or id
+        let routes = ["NX1".to_string(), "WEST-201".to_string()];
+        let next = get_next_arrivals(&ctx, "7000-0b6a8a4a", &routes[..1])
+            .await
+            .unwrap();
+        assert_eq!(next.len(), 1);
+        assert_eq!(next[0].trip_headsign.as_deref(), Some("Hibiscus Coast"));
+        let next = get_next_arrivals(&ctx, "7000-0b6a8a4a", &routes[1..])
+            .await
+            .unwrap();
+        assert_eq!(next[0].route_short_name, "WEST");
+    }
+
+    #[tokio::test]
+    async fn test_stop_stats() {
+        let ctx = ctx().await;
+        // Another run of the trip 25 minutes later
+        ctx.db
+            .execute_unprepared(
+                "INSERT INTO trip_run (id, trip_id, route_id, direction_id, start_date, start_timestamp, feed_id)
+                SELECT 3, trip_id, route_id, direction_id, start_date, start_timestamp + 1500000, feed_id
+                FROM trip_run WHERE id = 1;
+                INSERT INTO stop_time_index (stop_id, stop_sequence, trip_id, trip_run_id, arrival_timestamp, departure_timestamp)
+                SELECT stop_id, stop_sequence, trip_id, 3, arrival_timestamp + 1500000, departure_timestamp + 1500000
+                FROM stop_time_index WHERE trip_run_id = 1;",
+            )
+            .await
+            .unwrap();
+
+        // The fixture's runs are on today's date in UTC
+        let today = Utc::now().date_naive();
+        let stats = get_stop_stats(&ctx, "7000-0b6a8a4a", Some(today))
+            .await
+            .unwrap();
+        assert_eq!(stats.len(), 1);
+        assert_eq!(stats[0].route_short_name, "NX1");
+        assert_eq!(stats[0].trips, 2);
+        assert_eq!(stats[0].headway_minutes, Some(25));
+        assert!(stats[0].first_departure < stats[0].last_departure);
+
+        let tomorrow = today.succ_opt();
+        assert!(get_stop_stats(&ctx, "7000-0b6a8a4a", tomorrow)
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(get_stop_stats(&ctx, "0000", None).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_stop_arrivals_due() {
+        let ctx = ctx().await;
+        let arrivals = get_stop_arrivals(&ctx, "7000-0b6a8a4a", 0).await.unwrap();
+        let arrival = &arrivals[0].arrivals[0];
+        // Five minutes away in the fixture
+        assert!((295..=300).contains(&arrival.due_in_seconds));
+
+        let local_time = arrival.local_time.as_deref().unwrap();
+        let expected = DateTime::parse_from_rfc3339(local_time).unwrap();
+        assert_eq!(
+            expected.timestamp_millis() / 1000,
+            arrival.arrival_timestamp / 1000
+        );
+        let offset = Tz::Pacific__Auckland
+            .timestamp_millis_opt(arrival.arrival_timestamp)
+            .unwrap()
+            .offset()
+            .fix();
+        assert_eq!(*expected.offset(), offset);
+    }
+
+    #[tokio::test]
+    async fn test_stop_arrivals_headsign_fallback() {
+        let ctx = ctx().await;
+        let arrivals = get_stop_arrivals(&ctx, "7000-0b6a8a4a", 0).await.unwrap();
+        assert_eq!(arrivals[0].route_trip.stop_headsign, "Hibiscus Coast");
+
+        ctx.db
+            .execute_unprepared("UPDATE gtfs_stop_times SET stop_headsign = ' '")
+            .await
+            .unwrap();
+        let arrivals = get_stop_arrivals(&ctx, "7000-0b6a8a4a", 0).await.unwrap();
+        assert_eq!(arrivals[0].route_trip.stop_headsign, "Hibiscus Coast");
+        assert_eq!(
+            arrivals[0].arrivals[0].trip_headsign.as_deref(),
+            Some("Hibiscus Coast")
+        );
+
+        // Neither, so the last stop
+        ctx.db
+            .execute_unprepared("UPDATE gtfs_trips SET trip_headsign = ''")
+            .await
+            .unwrap();
+        let arrivals = get_stop_arrivals(&ctx, "7000-0b6a8a4a", 0).await.unwrap();
+        assert_eq!(arrivals[0].route_trip.stop_headsign, "Wellesley Street");
+        assert_eq!(arrivals[0].arrivals[0].trip_headsign, None);
     }
 }
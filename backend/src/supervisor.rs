@@ -0,0 +1,96 @@
+use std::fmt::Display;
+use std::future::{self, Future};
+use std::time::{Duration, Instant};
+
+use tokio::{select, time::sleep};
+use tokio_util::sync::CancellationToken;
+
+use crate::health::TaskStatus;
+
+const MIN_BACKOFF: Duration = Duration::from_secs(5);
+const MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+
+/// A task that ran this long before failing is considered to have been healthy,
+/// so it is restarted without waiting long
+const HEALTHY_RUN: Duration = Duration::from_secs(10 * 60);
+
+/// Runs a long lived task until shutdown.
+/// Whenever it fails (or stops) it's logged and restarted, waiting longer after each failure in a row.
+/// The task should return once `shutdown` is cancelled, after finishing what it's doing.
+pub async fn supervise<F, Fut, E>(
+    name: &str,
+    status: &TaskStatus,
+    shutdown: &CancellationToken,
+    mut task: F,
+) where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<(), E>>,
+    E: Display,
+{
+    let mut backoff = MIN_BACKOFF;
+
+    loop {
+        tracing::info!("Starting {}", name);
+        status.started();
+        let started_at = Instant::now();
+
+        let result = task().await;
+        if shutdown.is_cancelled() {
+            match result {
+                Ok(()) => tracing::info!("{} stopped", name),
+                Err(e) => tracing::error!("{} failed while stopping: {}", name, e),
+            }
+            return;
+        }
+
+        let error = match result {
+            Ok(()) => "stopped unexpectedly".to_string(),
+            Err(e) => e.to_string(),
+        };
+        tracing::error!("{} failed: {}", name, error);
+        status.failed(error);
+
+        if started_at.elapsed() > HEALTHY_RUN {
+            backoff = MIN_BACKOFF;
+        }
+
+        tracing::info!("Restarting {} in {} seconds", name, backoff.as_secs());
+        if !sleep_until_shutdown(backoff, shutdown).await {
+            return;
+        }
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+/// Sleeps for the duration, unless shutdown comes first.
+/// Whether it slept the whole time, i.e. the caller should carry on.
+pub async fn sleep_until_shutdown(duration: Duration, shutdown: &CancellationToken) -> bool {
+    select! {
+        _ = sleep(duration) => true,
+        _ = shutdown.cancelled() => false,
+    }
+}
+
+/// Waits for Ctrl-C, or SIGTERM as sent by `docker stop`
+pub async fn shutdown_signal() {
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                tracing::error!("Can't listen for SIGTERM: {}", e);
+                future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = future::pending::<()>();
+
+    select! {
+        _ = tokio::signal::ctrl_c() => tracing::info!("Interrupted"),
+        _ = terminate => tracing::info!("Terminated"),
+    }
+}
@@ -0,0 +1,453 @@
+//! Sends spans and metrics to an OpenTelemetry collector (e.g. Jaeger, Tempo or Grafana Alloy)
+//! as OTLP over HTTP, in its JSON encoding. Only when `OTEL_EXPORTER_OTLP_ENDPOINT` is set.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use percent_encoding::percent_decode_str;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use serde_json::{json, Map, Value};
+use tracing::{
+    span::{Attributes, Id, Record},
+    Event, Level, Subscriber,
+};
+use tracing_subscriber::{layer::Context, registry::LookupSpan, Layer};
+use url::Url;
+
+use crate::{config, logging::JsonFields, supervisor::sleep_until_shutdown, ContextData};
+
+/// If `OTEL_SERVICE_NAME` isn't set
+const DEFAULT_SERVICE_NAME: &str = "next-at";
+
+/// If `OTEL_EXPORT_INTERVAL_SECONDS` isn't set
+const DEFAULT_EXPORT_INTERVAL_SECONDS: u64 = 10;
+
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Spans closed after this many are waiting to be sent are dropped
+const MAX_QUEUED_SPANS: usize = 10_000;
+
+/// OTLP span kinds
+const SPAN_KIND_INTERNAL: u8 = 1;
+const SPAN_KIND_SERVER: u8 = 2;
+
+/// OTLP status code
+const STATUS_CODE_ERROR: u8 = 2;
+
+/// OTLP aggregation temporality, counts since starting
+const CUMULATIVE: u8 = 2;
+
+/// Set once the exporter is configured, spans are only kept after that
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Closed spans waiting to be sent
+static QUEUE: Mutex<Vec<Value>> = Mutex::new(Vec::new());
+
+static DROPPED_SPANS: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone)]
+pub struct TelemetryConfig {
+    endpoint: Url,
+    service_name: String,
+    interval: Duration,
+    /// e.g. for the collector's authentication
+    headers: HeaderMap,
+}
+
+impl TelemetryConfig {
+    /// None unless `OTEL_EXPORTER_OTLP_ENDPOINT` is set.
+    /// `OTEL_EXPORTER_OTLP_HEADERS` is a comma separated list of `name=value`, with values percent encoded.
+    /// Every problem with them is added to `errors`.
+    pub fn from_env(errors: &mut Vec<String>) -> Option<Self> {
+        let endpoint = config::var("OTEL_EXPORTER_OTLP_ENDPOINT").ok()?;
+        let endpoint = Url::parse(&endpoint)
+            .map_err(|e| {
+                errors.push(format!(
+                    "Invalid OTEL_EXPORTER_OTLP_ENDPOINT: {} ({})",
+                    endpoint, e
+                ))
+            })
+            .ok()?;
+
+        let service_name =
+            config::var("OTEL_SERVICE_NAME").unwrap_or_else(|_| DEFAULT_SERVICE_NAME.to_string());
+
+        let interval = match config::var("OTEL_EXPORT_INTERVAL_SECONDS") {
+            Ok(interval) => interval.trim().parse().unwrap_or_else(|_| {
+                errors.push(format!(
+                    "Invalid OTEL_EXPORT_INTERVAL_SECONDS: {}",
+                    interval
+                ));
+                DEFAULT_EXPORT_INTERVAL_SECONDS
+            }),
+            Err(_) => DEFAULT_EXPORT_INTERVAL_SECONDS,
+        };
+
+        let mut headers = HeaderMap::new();
+        let header_list = config::var("OTEL_EXPORTER_OTLP_HEADERS").unwrap_or_default();
+        for header in header_list
+            .split(',')
+            .map(str::trim)
+            .filter(|h| !h.is_empty())
+        {
+            match parse_header(header) {
+                Some((name, value)) => {
+                    headers.insert(name, value);
+                }
+                None => errors.push(format!("Invalid OTEL_EXPORTER_OTLP_HEADERS: {}", header)),
+            }
+        }
+
+        Some(Self {
+            endpoint,
+            service_name,
+            interval: Duration::from_secs(interval),
+            headers,
+        })
+    }
+
+    /// e.g. `v1/traces`, under the endpoint's path
+    fn url(&self, signal: &str) -> String {
+        format!(
+            "{}/{}",
+            self.endpoint.as_str().trim_end_matches('/'),
+            signal
+        )
+    }
+}
+
+fn parse_header(header: &str) -> Option<(HeaderName, HeaderValue)> {
+    let (name, value) = header.split_once('=')?;
+    let value = percent_decode_str(value.trim()).decode_utf8().ok()?;
+    Some((
+        name.trim().parse().ok()?,
+        HeaderValue::from_str(&value).ok()?,
+    ))
+}
+
+/// Starts keeping spans for the exporter
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+fn unix_nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .to_string()
+}
+
+/// As hex, for trace and span ids from random bytes.
+/// Not from a UUID, which has fixed version bits.
+fn hex_id(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// A JSON value as an OTLP `AnyValue`, 64 bit integers are strings
+fn any_value(value: Value) -> Value {
+    match value {
+        Value::Bool(b) => json!({ "boolValue": b }),
+        Value::Number(n) if n.is_f64() => json!({ "doubleValue": n }),
+        Value::Number(n) => json!({ "intValue": n.to_string() }),
+        Value::String(s) => json!({ "stringValue": s }),
+        value => json!({ "stringValue": value.to_string() }),
+    }
+}
+
+fn attributes(fields: Map<String, Value>) -> Vec<Value> {
+    fields
+        .into_iter()
+        .map(|(key, value)| json!({ "key": key, "value": any_value(value) }))
+        .collect()
+}
+
+/// Kept with each span, until it's closed and queued
+struct SpanData {
+    trace_id: String,
+    span_id: String,
+    parent_span_id: Option<String>,
+    started: SystemTime,
+    fields: Map<String, Value>,
+    events: Vec<Value>,
+    error: bool,
+}
+
+/// Queues spans as they close, with the events that happened in them
+pub struct OtlpLayer;
+
+impl<S> Layer<S> for OtlpLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        if !ENABLED.load(Ordering::Relaxed) {
+            return;
+        }
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        // Part of the parent's trace, or the start of a new one
+        let parent = span.parent().and_then(|parent| {
+            let extensions = parent.extensions();
+            let data = extensions.get::<SpanData>()?;
+            Some((data.trace_id.clone(), data.span_id.clone()))
+        });
+        let (trace_id, parent_span_id) = match parent {
+            Some((trace_id, span_id)) => (trace_id, Some(span_id)),
+            None => (hex_id(&rand::random::<[u8; 16]>()), None),
+        };
+
+        let mut fields = Map::new();
+        attrs.record(&mut JsonFields(&mut fields));
+        span.extensions_mut().insert(SpanData {
+            trace_id,
+            span_id: hex_id(&rand::random::<[u8; 8]>()),
+            parent_span_id,
+            started: SystemTime::now(),
+            fields,
+            events: vec![],
+            error: false,
+        });
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(data) = span.extensions_mut().get_mut::<SpanData>() {
+                values.record(&mut JsonFields(&mut data.fields));
+            }
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let Some(span) = ctx.event_span(event) else {
+            return;
+        };
+        let mut extensions = span.extensions_mut();
+        let Some(data) = extensions.get_mut::<SpanData>() else {
+            return;
+        };
+        let mut fields = Map::new();
+        event.record(&mut JsonFields(&mut fields));
+        let name = match fields.remove("message") {
+            Some(Value::String(message)) => message,
+            _ => event.metadata().name().to_string(),
+        };
+        fields.insert(
+            "level".to_string(),
+            json!(event.metadata().level().as_str()),
+        );
+        data.error |= *event.metadata().level() == Level::ERROR;
+        data.events.push(json!({
+            "timeUnixNano": unix_nanos(SystemTime::now()),
+            "name": name,
+            "attributes": attributes(fields),
+        }));
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(&id) else {
+            return;
+        };
+        let Some(data) = span.extensions_mut().remove::<SpanData>() else {
+            return;
+        };
+        let name = span.metadata().name();
+        let mut otlp_span = json!({
+            "traceId": data.trace_id,
+            "spanId": data.span_id,
+            "name": name,
+            "kind": if name == "http_request" { SPAN_KIND_SERVER } else { SPAN_KIND_INTERNAL },
+            "startTimeUnixNano": unix_nanos(data.started),
+            "endTimeUnixNano": unix_nanos(SystemTime::now()),
+            "attributes": attributes(data.fields),
+            "events": data.events,
+        });
+        if let Some(parent_span_id) = data.parent_span_id {
+            otlp_span["parentSpanId"] = json!(parent_span_id);
+        }
+        if data.error {
+            otlp_span["status"] = json!({ "code": STATUS_CODE_ERROR });
+        }
+
+        let mut queue = QUEUE.lock().unwrap();
+        if queue.len() < MAX_QUEUED_SPANS {
+            queue.push(otlp_span);
+        } else {
+            DROPPED_SPANS.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+struct Exporter<'a> {
+    config: &'a TelemetryConfig,
+    client: reqwest::Client,
+    resource: Value,
+    started: SystemTime,
+}
+
+impl Exporter<'_> {
+    async fn post(&self, signal: &str, body: Value) -> reqwest::Result<()> {
+        self.client
+            .post(self.config.url(signal))
+            .headers(self.config.headers.clone())
+            .timeout(EXPORT_TIMEOUT)
+            .json(&body)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    /// The queued spans, which are dropped if they can't be sent
+    async fn export_traces(&self) {
+        let spans = std::mem::take(&mut *QUEUE.lock().unwrap());
+        let dropped = DROPPED_SPANS.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            tracing::warn!("Dropped {} spans waiting to be exported", dropped);
+        }
+        if spans.is_empty() {
+            return;
+        }
+
+        let count = spans.len();
+        let body = json!({
+            "resourceSpans": [{
+                "resource": self.resource,
+                "scopeSpans": [{ "scope": { "name": DEFAULT_SERVICE_NAME }, "spans": spans }],
+            }],
+        });
+        if let Err(e) = self.post("v1/traces", body).await {
+            tracing::warn!("Couldn't export {} spans: {}", count, e);
+        }
+    }
+
+    /// Requests to the publishers, counted since starting
+    async fn export_metrics(&self, ctx: &ContextData) {
+        let start = unix_nanos(self.started);
+        let now = unix_nanos(SystemTime::now());
+        let mut requests = vec![];
+        let mut errors = vec![];
+        let mut latencies = vec![];
+        for (endpoint, report) in ctx.at_client.request_report() {
+            let endpoint = attributes(Map::from_iter([("endpoint".to_string(), json!(endpoint))]));
+            let point = |value: u64| {
+                json!({
+                    "attributes": endpoint,
+                    "startTimeUnixNano": start,
+                    "timeUnixNano": now,
+                    "asInt": value.to_string(),
+                })
+            };
+            requests.push(point(report.requests));
+            errors.push(point(report.errors));
+            latencies.push(json!({
+                "attributes": endpoint,
+                "startTimeUnixNano": start,
+                "timeUnixNano": now,
+                "count": report.requests.to_string(),
+                "sum": report.mean_latency_ms.unwrap_or_default() * report.requests,
+                "bucketCounts": report.latency_ms.iter().map(|b| b.count.to_string()).collect::<Vec<_>>(),
+                "explicitBounds": report.latency_ms.iter().filter_map(|b| b.up_to_ms).collect::<Vec<_>>(),
+            }));
+        }
+        if requests.is_empty() {
+            return;
+        }
+
+        let sum = |points: Vec<Value>| {
+            json!({
+                "aggregationTemporality": CUMULATIVE,
+                "isMonotonic": true,
+                "dataPoints": points,
+            })
+        };
+        let metrics = json!([
+            {
+                "name": "next_at.publisher.requests",
+                "unit": "{request}",
+                "sum": sum(requests),
+            },
+            {
+                "name": "next_at.publisher.errors",
+                "unit": "{request}",
+                "sum": sum(errors),
+            },
+            {
+                "name": "next_at.publisher.latency",
+                "unit": "ms",
+                "histogram": { "aggregationTemporality": CUMULATIVE, "dataPoints": latencies },
+            },
+        ]);
+        let body = json!({
+            "resourceMetrics": [{
+                "resource": self.resource,
+                "scopeMetrics": [{ "scope": { "name": DEFAULT_SERVICE_NAME }, "metrics": metrics }],
+            }],
+        });
+        if let Err(e) = self.post("v1/metrics", body).await {
+            tracing::warn!("Couldn't export metrics: {}", e);
+        }
+    }
+}
+
+/// Sends what's been collected every `OTEL_EXPORT_INTERVAL_SECONDS`, until shutdown.
+/// Failures are logged rather than stopping it, telemetry isn't worth restarting for.
+pub async fn export(ctx: &ContextData, config: &TelemetryConfig) {
+    tracing::info!("Exporting telemetry to {}", config.endpoint);
+    let exporter = Exporter {
+        config,
+        client: reqwest::Client::new(),
+        resource: json!({
+            "attributes": attributes(Map::from_iter([
+                ("service.name".to_string(), json!(config.service_name)),
+                ("service.version".to_string(), json!(env!("CARGO_PKG_VERSION"))),
+            ])),
+        }),
+        started: SystemTime::now(),
+    };
+
+    loop {
+        let carry_on = sleep_until_shutdown(config.interval, &ctx.shutdown).await;
+        // Once more on shutdown, for what's been collected since
+        exporter.export_traces().await;
+        exporter.export_metrics(ctx).await;
+        if !carry_on {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn test_attributes() {
+        let fields = json!({
+            "elapsed_ms": 1.5,
+            "entities": 42,
+            "feed_id": "at",
+            "incremental": true,
+        });
+        assert_eq!(
+            attributes(fields.as_object().cloned().unwrap()),
+            [
+                json!({ "key": "elapsed_ms", "value": { "doubleValue": 1.5 } }),
+                json!({ "key": "entities", "value": { "intValue": "42" } }),
+                json!({ "key": "feed_id", "value": { "stringValue": "at" } }),
+                json!({ "key": "incremental", "value": { "boolValue": true } }),
+            ]
+        );
+
+        let (name, value) = parse_header("Authorization=Basic%20dXNlcjprZXk=").unwrap();
+        assert_eq!(name, "authorization");
+        assert_eq!(value, "Basic dXNlcjprZXk=");
+        assert!(parse_header("no-value").is_none());
+    }
+}
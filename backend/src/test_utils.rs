@@ -1,23 +1,98 @@
-use crate::ContextData;
+use std::{str::FromStr, sync::Arc};
+
+use migration::{Migrator, MigratorTrait, RealtimeMigrator};
+use sea_orm::{ConnectionTrait, DatabaseConnection, SqlxSqliteConnector};
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
+use tokio::sync::Mutex;
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    at::{api::AtApi, mock::MockAt},
+    auth::ApiKeys,
+    cache::{CacheConfig, QueryCaches},
+    gtfs::{
+        index::IndexConfig,
+        progress::{IndexProgress, SyncProgress},
+        realtime::RealtimeConfig,
+        sync::SyncConfig,
+    },
+    health::Health,
+    logging::LogConfig,
+    notifications::web_push::WebPush,
+    tiles::TileCache,
+    versions::DataVersions,
+    webhooks::Webhooks,
+    ContextData,
+};
+
+/// A small feed with an index built around now, see the file for what's in it
+const GTFS_FIXTURE: &str = include_str!("../fixtures/gtfs.sql");
 
 pub fn init() {
     dotenvy::from_filename(".dev.vars").ok();
-    env_logger::try_init().ok();
+    crate::logging::init(&LogConfig::from_env(&mut vec![])).ok();
 }
 
-pub fn at() -> crate::at::client::AtClient {
-    init();
-    crate::at::client::AtClient::new().unwrap()
+/// A migrated in-memory database with the fixture feed loaded, a new one each time
+pub async fn db() -> DatabaseConnection {
+    // Every connection to :memory: is its own database, so there's only one,
+    // and it's kept for as long as the pool is
+    let pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .idle_timeout(None)
+        .max_lifetime(None)
+        .connect_with(SqliteConnectOptions::from_str("sqlite::memory:").unwrap())
+        .await
+        .unwrap();
+    let db = SqlxSqliteConnector::from_sqlx_sqlite_pool(pool);
+
+    Migrator::up(&db, None)
+        .await
+        .expect("Failed to migrate test database");
+    // Nothing's attached, so the realtime tables are alongside the rest,
+    // and there's no old realtime data for migrate_realtime to copy
+    RealtimeMigrator::up(&db, None)
+        .await
+        .expect("Failed to migrate test realtime database");
+    db.execute_unprepared(GTFS_FIXTURE)
+        .await
+        .expect("Failed to load GTFS fixture");
+
+    db
 }
 
+/// Context over a fixture database, which is used for both reads and writes
 #[cfg(test)]
 pub async fn ctx() -> ContextData {
+    ctx_with_at(MockAt::default()).await
+}
+
+/// Context with feeds served by `at_client`, e.g. a mock with fixtures
+#[cfg(test)]
+pub async fn ctx_with_at(at_client: impl AtApi + 'static) -> ContextData {
     init();
 
-    todo!();
+    let db = db().await;
 
-    // ContextData {
-    //     at_client: at(),
-    //     // db: db().await,
-    // }
+    ContextData {
+        at_client: Arc::new(at_client),
+        read_db: db.clone(),
+        db,
+        versions: Arc::new(DataVersions::new()),
+        health: Arc::new(Health::default()),
+        api_keys: Arc::new(ApiKeys::default()),
+        feeds: Arc::new(vec![]),
+        sync: Arc::new(SyncConfig::default()),
+        index: Arc::new(IndexConfig::default()),
+        realtime: Arc::new(RealtimeConfig::default()),
+        sync_progress: Arc::new(SyncProgress::default()),
+        index_progress: Arc::new(IndexProgress::default()),
+        sync_lock: Arc::new(Mutex::new(())),
+        backup_lock: Arc::new(Mutex::new(())),
+        tile_cache: Arc::new(TileCache::default()),
+        query_cache: Arc::new(QueryCaches::new(&CacheConfig::default())),
+        web_push: Arc::new(WebPush::default()),
+        webhooks: Arc::new(Webhooks::default()),
+        shutdown: CancellationToken::new(),
+    }
 }
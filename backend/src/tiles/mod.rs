@@ -0,0 +1,261 @@
+//! Vector tiles of stops and vehicles for web maps
+
+pub mod mvt;
+
+use std::{collections::HashMap, f64::consts::PI, sync::Mutex, time::Instant};
+
+use chrono::{Duration, Utc};
+use sea_orm::{ColumnTrait, EntityTrait, QueryFilter};
+
+use crate::{
+    entity::vehicle,
+    error::{NextAtError, NextAtResult},
+    map::{self, BoundingBox, MAX_ZOOM},
+    ContextData,
+};
+
+use self::mvt::{Layer, Value, EXTENT};
+
+/// Further out than this tiles have no stops, there'd be too many to see
+const MIN_STOPS_ZOOM: u8 = 12;
+
+/// Further out than this tiles have no vehicles
+const MIN_VEHICLES_ZOOM: u8 = 10;
+
+/// Vehicles that haven't reported their position for longer aren't shown
+const VEHICLE_MAX_AGE_MINUTES: i64 = 5;
+
+/// Points this far outside a tile (in tile units) are included,
+/// so icons on the edge aren't cut off
+const TILE_BUFFER: u32 = 256;
+
+/// Most tiles kept in the cache, the oldest are dropped first
+const MAX_CACHED_TILES: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TileId {
+    pub z: u8,
+    pub x: u32,
+    pub y: u32,
+}
+
+impl TileId {
+    pub fn new(z: u8, x: u32, y: u32) -> NextAtResult<Self> {
+        if z > MAX_ZOOM {
+            return Err(NextAtError::InvalidData(format!(
+                "Zoom can be at most {}",
+                MAX_ZOOM
+            )));
+        }
+        let tiles = 1u32 << z;
+        if x >= tiles || y >= tiles {
+            return Err(NextAtError::InvalidData(format!(
+                "There's no tile {}/{}/{}",
+                z, x, y
+            )));
+        }
+        Ok(Self { z, x, y })
+    }
+
+    /// Tiles across the world at this zoom
+    fn tiles(&self) -> f64 {
+        2f64.powi(self.z.into())
+    }
+
+    /// Where the point is on the tile, in tile units from its top left (web mercator)
+    fn project(&self, lat: f64, lon: f64) -> (i32, i32) {
+        let world_x = (lon + 180.0) / 360.0;
+        let lat = lat.to_radians();
+        let world_y = (1.0 - (lat.tan() + 1.0 / lat.cos()).ln() / PI) / 2.0;
+        let extent = f64::from(EXTENT);
+        (
+            ((world_x * self.tiles() - f64::from(self.x)) * extent).round() as i32,
+            ((world_y * self.tiles() - f64::from(self.y)) * extent).round() as i32,
+        )
+    }
+
+    /// What the tile covers, and its buffer
+    fn bounding_box(&self) -> BoundingBox {
+        let buffer = f64::from(TILE_BUFFER) / f64::from(EXTENT);
+        let lon = |x: f64| (x / self.tiles() * 360.0 - 180.0).clamp(-180.0, 180.0);
+        let lat = |y: f64| {
+            (PI * (1.0 - 2.0 * y / self.tiles()))
+                .sinh()
+                .atan()
+                .to_degrees()
+        };
+        let (x, y) = (f64::from(self.x), f64::from(self.y));
+        BoundingBox {
+            min_lat: lat(y + 1.0 + buffer),
+            max_lat: lat(y - buffer),
+            min_lon: lon(x - buffer),
+            max_lon: lon(x + 1.0 + buffer),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct TileKey {
+    tile: TileId,
+    vehicles: bool,
+}
+
+#[derive(Debug)]
+struct CachedTile {
+    /// Versions of the static and realtime data the tile was made from
+    versions: (i64, i64),
+    cached_at: Instant,
+    data: Vec<u8>,
+}
+
+/// Tiles already made, until the data they're from changes
+#[derive(Debug, Default)]
+pub struct TileCache {
+    tiles: Mutex<HashMap<TileKey, CachedTile>>,
+}
+
+impl TileCache {
+    fn get(&self, key: &TileKey, versions: (i64, i64)) -> Option<Vec<u8>> {
+        let tiles = self.tiles.lock().unwrap();
+        tiles
+            .get(key)
+            .filter(|t| t.versions == versions)
+            .map(|t| t.data.clone())
+    }
+
+    fn insert(&self, key: TileKey, versions: (i64, i64), data: Vec<u8>) {
+        let mut tiles = self.tiles.lock().unwrap();
+        if tiles.len() >= MAX_CACHED_TILES && !tiles.contains_key(&key) {
+            let oldest = tiles
+                .iter()
+                .min_by_key(|(_, t)| t.cached_at)
+                .map(|(k, _)| *k);
+            if let Some(oldest) = oldest {
+                tiles.remove(&oldest);
+            }
+        }
+        tiles.insert(
+            key,
+            CachedTile {
+                versions,
+                cached_at: Instant::now(),
+                data,
+            },
+        );
+    }
+}
+
+async fn stops_layer(ctx: &ContextData, tile: &TileId) -> NextAtResult<Layer> {
+    let mut layer = Layer::new("stops");
+    if tile.z < MIN_STOPS_ZOOM {
+        return Ok(layer);
+    }
+
+    for stop in map::find_stops_in_box(ctx, &tile.bounding_box()).await? {
+        let (Some(lat), Some(lon)) = (stop.lat, stop.lon) else {
+            continue;
+        };
+        let (x, y) = tile.project(lat, lon);
+        layer.add_point(
+            x,
+            y,
+            vec![
+                ("id", Value::String(stop.id)),
+                ("code", Value::String(stop.code)),
+                ("name", Value::String(stop.name)),
+            ],
+        );
+    }
+    Ok(layer)
+}
+
+async fn vehicles_layer(ctx: &ContextData, tile: &TileId) -> NextAtResult<Layer> {
+    use vehicle::Column as v;
+
+    let mut layer = Layer::new("vehicles");
+    if tile.z < MIN_VEHICLES_ZOOM {
+        return Ok(layer);
+    }
+
+    let bbox = tile.bounding_box();
+    let since = (Utc::now() - Duration::minutes(VEHICLE_MAX_AGE_MINUTES)).timestamp_millis();
+    let vehicles = vehicle::Entity::find()
+        .filter(v::Timestamp.gte(since))
+        .filter(v::Latitude.between(bbox.min_lat, bbox.max_lat))
+        .filter(v::Longitude.between(bbox.min_lon, bbox.max_lon))
+        .all(&ctx.read_db)
+        .await?;
+
+    for vehicle in vehicles {
+        let (Some(lat), Some(lon)) = (vehicle.latitude, vehicle.longitude) else {
+            continue;
+        };
+        let (x, y) = tile.project(lat, lon);
+        let mut properties = vec![
+            ("id", Value::String(vehicle.vehicle_id)),
+            ("timestamp", Value::Int(vehicle.timestamp)),
+        ];
+        if let Some(label) = vehicle.label {
+            properties.push(("label", Value::String(label)));
+        }
+        if let Some(bearing) = vehicle.bearing {
+            properties.push(("bearing", Value::Double(bearing)));
+        }
+        layer.add_point(x, y, properties);
+    }
+    Ok(layer)
+}
+
+/// The tile with a layer of stops, and one of vehicles if `vehicles` is set
+pub async fn get_tile(ctx: &ContextData, tile: TileId, vehicles: bool) -> NextAtResult<Vec<u8>> {
+    let key = TileKey { tile, vehicles };
+    // Vehicles move, but stops only change when the static data does
+    let versions = (
+        ctx.versions.static_version(),
+        if vehicles {
+            ctx.versions.realtime_version()
+        } else {
+            0
+        },
+    );
+    if let Some(data) = ctx.tile_cache.get(&key, versions) {
+        return Ok(data);
+    }
+
+    let mut layers = vec![stops_layer(ctx, &tile).await?];
+    if vehicles {
+        layers.push(vehicles_layer(ctx, &tile).await?);
+    }
+    let data = mvt::encode_tile(&layers);
+
+    ctx.tile_cache.insert(key, versions, data.clone());
+    Ok(data)
+}
+
+#[cfg(test)]
+mod test {
+
+    use crate::test_utils::ctx;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_get_tile() {
+        let ctx = ctx().await;
+
+        // The tile with Britomart
+        let tile = TileId::new(16, 64583, 39991).unwrap();
+        let (x, y) = tile.project(-36.84429, 174.76811);
+        assert!((0..EXTENT as i32).contains(&x) && (0..EXTENT as i32).contains(&y));
+
+        let data = get_tile(&ctx, tile, true).await.unwrap();
+        let britomart = "Britomart Train Station".as_bytes();
+        assert!(data.windows(britomart.len()).any(|w| w == britomart));
+        assert_eq!(get_tile(&ctx, tile, true).await.unwrap(), data);
+
+        let empty = TileId::new(16, 0, 0).unwrap();
+        assert!(get_tile(&ctx, empty, false).await.unwrap().is_empty());
+
+        assert!(TileId::new(2, 4, 0).is_err());
+    }
+}
@@ -0,0 +1,154 @@
+//! Just enough of the Mapbox vector tile format (protobuf) to write layers of points,
+//! see <https://github.com/mapbox/vector-tile-spec/tree/master/2.1>
+
+use std::collections::HashMap;
+
+use crate::protobuf::{
+    write_bytes_field, write_double_field, write_packed_field, write_varint_field,
+};
+
+/// Units across a tile that coordinates are in
+pub const EXTENT: u32 = 4096;
+
+/// Geometry type of a feature
+const GEOM_POINT: u64 = 1;
+
+/// A MoveTo command for a single point
+const COMMAND_MOVE_TO_ONE: u32 = 1 | (1 << 3);
+
+fn zigzag(n: i32) -> u32 {
+    ((n << 1) ^ (n >> 31)) as u32
+}
+
+/// A property of a feature
+#[derive(Debug, Clone)]
+pub enum Value {
+    String(String),
+    Double(f64),
+    Int(i64),
+}
+
+impl Value {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![];
+        match self {
+            Value::String(s) => write_bytes_field(&mut buf, 1, s.as_bytes()),
+            Value::Double(d) => write_double_field(&mut buf, 3, *d),
+            Value::Int(i) => write_varint_field(&mut buf, 4, *i as u64),
+        }
+        buf
+    }
+}
+
+/// Points with properties, whose keys and values are shared between its features
+#[derive(Debug)]
+pub struct Layer {
+    name: String,
+    keys: HashMap<String, u32>,
+    /// Encoded, so equal values are shared
+    values: HashMap<Vec<u8>, u32>,
+    features: Vec<Vec<u8>>,
+}
+
+impl Layer {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            keys: HashMap::new(),
+            values: HashMap::new(),
+            features: vec![],
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.features.is_empty()
+    }
+
+    /// `x` and `y` are in tile units from the top left, and can be outside the tile
+    pub fn add_point(&mut self, x: i32, y: i32, properties: Vec<(&str, Value)>) {
+        let mut tags = vec![];
+        for (key, value) in properties {
+            let next_key = self.keys.len() as u32;
+            tags.push(*self.keys.entry(key.to_string()).or_insert(next_key));
+            let next_value = self.values.len() as u32;
+            tags.push(*self.values.entry(value.encode()).or_insert(next_value));
+        }
+
+        let mut feature = vec![];
+        write_packed_field(&mut feature, 2, &tags);
+        write_varint_field(&mut feature, 3, GEOM_POINT);
+        write_packed_field(
+            &mut feature,
+            4,
+            &[COMMAND_MOVE_TO_ONE, zigzag(x), zigzag(y)],
+        );
+        self.features.push(feature);
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![];
+        write_varint_field(&mut buf, 15, 2);
+        write_bytes_field(&mut buf, 1, self.name.as_bytes());
+        for feature in &self.features {
+            write_bytes_field(&mut buf, 2, feature);
+        }
+        // In the order of their indexes
+        let mut keys = self.keys.iter().collect::<Vec<_>>();
+        keys.sort_by_key(|(_, i)| **i);
+        for (key, _) in keys {
+            write_bytes_field(&mut buf, 3, key.as_bytes());
+        }
+        let mut values = self.values.iter().collect::<Vec<_>>();
+        values.sort_by_key(|(_, i)| **i);
+        for (value, _) in values {
+            write_bytes_field(&mut buf, 4, value);
+        }
+        write_varint_field(&mut buf, 5, EXTENT.into());
+        buf
+    }
+}
+
+/// The tile of the layers, empty ones are left out
+pub fn encode_tile(layers: &[Layer]) -> Vec<u8> {
+    let mut buf = vec![];
+    for layer in layers.iter().filter(|l| !l.is_empty()) {
+        write_bytes_field(&mut buf, 3, &layer.encode());
+    }
+    buf
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn test_encode_tile() {
+        assert_eq!(zigzag(0), 0);
+        assert_eq!(zigzag(-1), 1);
+        assert_eq!(zigzag(1), 2);
+
+        let mut layer = Layer::new("stops");
+        layer.add_point(25, 17, vec![("code", Value::String("7000".to_string()))]);
+        layer.add_point(1, -1, vec![("code", Value::String("7000".to_string()))]);
+
+        #[rustfmt::skip]
+        let expected = [
+            // layer
+            0x1a, 52,
+            // version 2
+            0x78, 0x02,
+            // name
+            0x0a, 5, b's', b't', b'o', b'p', b's',
+            // features, each with tags 0 0, a point, and MoveTo (25, 17) then (1, -1)
+            0x12, 11, 0x12, 2, 0, 0, 0x18, 1, 0x22, 3, 9, 50, 34,
+            0x12, 11, 0x12, 2, 0, 0, 0x18, 1, 0x22, 3, 9, 2, 1,
+            // the key, and the value both features share
+            0x1a, 4, b'c', b'o', b'd', b'e',
+            0x22, 6, 0x0a, 4, b'7', b'0', b'0', b'0',
+            // extent 4096
+            0x28, 0x80, 0x20,
+        ];
+        assert_eq!(encode_tile(&[layer, Layer::new("empty")]), expected);
+    }
+}
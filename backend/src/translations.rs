@@ -0,0 +1,259 @@
+use std::collections::HashMap;
+
+use actix_web::{http::header, web, HttpRequest};
+use itertools::Itertools;
+use sea_orm::sea_query::{all, any};
+use sea_orm::{ColumnTrait, EntityTrait, QueryFilter};
+use serde::Deserialize;
+
+use crate::{
+    db::error::DbResult,
+    entity::{gtfs_translations, prelude::*},
+    stops::{RouteTrip, Stop, StopRoute},
+    ContextData,
+};
+
+#[derive(Deserialize)]
+struct LangQuery {
+    lang: Option<String>,
+}
+
+/// Languages a client wants names in, the most preferred first.
+/// When empty, names are returned as they are in the feed.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Languages(Vec<String>);
+
+impl Languages {
+    pub fn new(languages: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        Self(languages.into_iter().map(Into::into).collect())
+    }
+
+    /// From the `lang` query parameter (which may be a comma separated list),
+    /// otherwise the `Accept-Language` header
+    pub fn from_request(req: &HttpRequest) -> Self {
+        let query = web::Query::<LangQuery>::from_query(req.query_string()).ok();
+        if let Some(lang) = query.and_then(|q| q.into_inner().lang) {
+            return Self::new(lang.split(',').map(str::trim).filter(|l| !l.is_empty()));
+        }
+
+        req.headers()
+            .get(header::ACCEPT_LANGUAGE)
+            .and_then(|v| v.to_str().ok())
+            .map(Self::from_accept_language)
+            .unwrap_or_default()
+    }
+
+    /// e.g. `mi-NZ, mi;q=0.9, en;q=0.8, *;q=0.5`
+    fn from_accept_language(value: &str) -> Self {
+        let languages = value
+            .split(',')
+            .filter_map(|part| {
+                let mut params = part.split(';');
+                let tag = params.next()?.trim();
+                let q = match params.find_map(|p| p.trim().strip_prefix("q=")) {
+                    Some(q) => q.parse::<f32>().ok()?,
+                    None => 1.0,
+                };
+                // Anything else is what the feed has anyway
+                (!tag.is_empty() && tag != "*" && q > 0.0).then_some((tag, q))
+            })
+            // Stable, so languages of the same weight keep their order
+            .sorted_by(|(_, a), (_, b)| b.total_cmp(a))
+            .map(|(tag, _)| tag);
+
+        Self::new(languages)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// How well the language matches, lower is better, or None if it isn't wanted.
+    /// A language with a different region (`en` for `en-NZ`) matches, but not as well.
+    fn rank(&self, language: &str) -> Option<usize> {
+        let primary = |l: &str| l.split('-').next().unwrap_or(l).to_lowercase();
+
+        self.0.iter().enumerate().find_map(|(i, preferred)| {
+            if preferred.eq_ignore_ascii_case(language) {
+                Some(i * 2)
+            } else if primary(preferred) == primary(language) {
+                Some(i * 2 + 1)
+            } else {
+                None
+            }
+        })
+    }
+
+    /// The option in the most preferred language, the first if there's more than one
+    pub fn pick<'a, T>(&self, options: impl IntoIterator<Item = (&'a str, T)>) -> Option<T> {
+        options
+            .into_iter()
+            .filter_map(|(language, option)| Some((self.rank(language)?, option)))
+            .min_by_key(|(rank, _)| *rank)
+            .map(|(_, option)| option)
+    }
+}
+
+/// Translations of a field of some records from translations.txt, by record id.
+/// Translations of the record itself are picked over those of the field's value.
+async fn translate_field(
+    ctx: &ContextData,
+    languages: &Languages,
+    table: &str,
+    field: &str,
+    records: &[(String, String)],
+) -> DbResult<HashMap<String, String>> {
+    use gtfs_translations::Column as t;
+
+    if languages.is_empty() || records.is_empty() {
+        return Ok(HashMap::new());
+    }
+
+    let translations = GtfsTranslations::find()
+        .filter(all![
+            t::TableName.eq(table),
+            t::FieldName.eq(field),
+            any![
+                t::RecordId.is_in(records.iter().map(|(id, _)| id.as_str())),
+                t::FieldValue.is_in(records.iter().map(|(_, value)| value.as_str())),
+            ],
+        ])
+        .all(&ctx.read_db)
+        .await?;
+
+    // Empty in the file means not set
+    let record_id = |t: &gtfs_translations::Model| t.record_id.clone().filter(|id| !id.is_empty());
+
+    let translated = records
+        .iter()
+        .filter_map(|(id, value)| {
+            let of_record = translations
+                .iter()
+                .filter(|t| record_id(t).as_ref() == Some(id));
+            let of_value = translations
+                .iter()
+                .filter(|t| record_id(t).is_none() && t.field_value.as_ref() == Some(value));
+            let translation = languages.pick(
+                of_record
+                    .chain(of_value)
+                    .map(|t| (t.language.as_str(), &t.translation)),
+            )?;
+            Some((id.clone(), translation.clone()))
+        })
+        .collect();
+
+    Ok(translated)
+}
+
+/// Replaces the stops' names with those in the client's language, where the feed has them
+pub async fn translate_stops<'a>(
+    ctx: &ContextData,
+    languages: &Languages,
+    stops: impl IntoIterator<Item = &'a mut Stop>,
+) -> DbResult<()> {
+    let stops = stops.into_iter().collect_vec();
+    let records = stops
+        .iter()
+        .map(|s| (s.id.clone(), s.name.clone()))
+        .collect_vec();
+
+    let names = translate_field(ctx, languages, "stops", "stop_name", &records).await?;
+    for stop in stops {
+        if let Some(name) = names.get(&stop.id) {
+            stop.name = name.clone();
+        }
+    }
+
+    Ok(())
+}
+
+/// A route's names as returned in the API
+pub trait RouteNames {
+    fn route_id(&self) -> &str;
+    fn short_name(&mut self) -> &mut String;
+    fn long_name(&mut self) -> &mut String;
+}
+
+impl RouteNames for StopRoute {
+    fn route_id(&self) -> &str {
+        &self.route_id
+    }
+
+    fn short_name(&mut self) -> &mut String {
+        &mut self.route_short_name
+    }
+
+    fn long_name(&mut self) -> &mut String {
+        &mut self.route_long_name
+    }
+}
+
+impl RouteNames for RouteTrip {
+    fn route_id(&self) -> &str {
+        &self.route_id
+    }
+
+    fn short_name(&mut self) -> &mut String {
+        &mut self.route_short_name
+    }
+
+    fn long_name(&mut self) -> &mut String {
+        &mut self.route_long_name
+    }
+}
+
+/// Replaces the routes' names with those in the client's language, where the feed has them
+pub async fn translate_routes<'a, R: RouteNames + 'a>(
+    ctx: &ContextData,
+    languages: &Languages,
+    routes: impl IntoIterator<Item = &'a mut R>,
+) -> DbResult<()> {
+    let mut routes = routes.into_iter().collect_vec();
+
+    let fields: [(&str, fn(&mut R) -> &mut String); 2] = [
+        ("route_short_name", R::short_name),
+        ("route_long_name", R::long_name),
+    ];
+    for (field, name) in fields {
+        let records = routes
+            .iter_mut()
+            .map(|r| (r.route_id().to_string(), name(r).clone()))
+            .collect_vec();
+
+        let names = translate_field(ctx, languages, "routes", field, &records).await?;
+        for route in routes.iter_mut() {
+            if let Some(translation) = names.get(route.route_id()).cloned() {
+                *name(route) = translation;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+
+    use actix_web::test::TestRequest;
+
+    use super::*;
+
+    #[test]
+    fn test_languages() {
+        let req = TestRequest::default()
+            .insert_header((header::ACCEPT_LANGUAGE, "en;q=0.8, mi-NZ, *;q=0.5, fr;q=0"))
+            .to_http_request();
+        let languages = Languages::from_request(&req);
+        assert_eq!(languages, Languages::new(["mi-NZ", "en"]));
+
+        assert_eq!(languages.pick([("en", 1), ("mi", 2)]), Some(2));
+        assert_eq!(languages.pick([("en-GB", 1), ("en", 2)]), Some(2));
+        assert_eq!(languages.pick([("fr", 1)]), None);
+
+        // The query parameter takes precedence
+        let req = TestRequest::with_uri("/stops?lang=fr")
+            .insert_header((header::ACCEPT_LANGUAGE, "en"))
+            .to_http_request();
+        assert_eq!(Languages::from_request(&req), Languages::new(["fr"]));
+    }
+}
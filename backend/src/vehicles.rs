@@ -0,0 +1,413 @@
+use std::collections::HashMap;
+
+use chrono::{Duration, Utc};
+use geo::Point;
+use itertools::Itertools;
+use sea_orm::{ColumnTrait, EntityTrait, QueryFilter, QueryOrder, QuerySelect, QueryTrait};
+use serde::Serialize;
+
+use crate::{
+    entity::{gtfs_stops, stop_time_index, trip_run, vehicle, vehicle_position_history},
+    error::{NextAtError, NextAtResult},
+    geo::{distance_along, line_length},
+    shapes::trip_shape,
+    stops, ContextData,
+};
+
+/// Most points returned for a trajectory, the most recent are kept
+const MAX_TRAJECTORY_POINTS: u64 = 1000;
+
+/// How far back a trajectory goes if not asked for
+const DEFAULT_TRAJECTORY_MINUTES: i64 = 60;
+
+/// A position a vehicle reported, as returned in the API
+#[derive(Debug, Serialize, Clone)]
+pub struct TrajectoryPoint {
+    pub timestamp: i64,
+    pub lat: f64,
+    pub lon: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bearing: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub speed: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub congestion_level: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trip_run_id: Option<i64>,
+}
+
+/// How far through its trip a vehicle is, worked out from its position along the trip's shape
+#[derive(Debug, Serialize, Clone)]
+pub struct TripProgress {
+    pub distance_travelled: f64,
+    /// Length of the trip's shape
+    pub distance_total: f64,
+    pub percent_complete: f64,
+    /// The next stop the vehicle gets to, none once it's past the last
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_stop_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metres_to_next_stop: Option<f64>,
+}
+
+/// A vehicle's last reported position, and its trip if it's on one
+#[derive(Debug, Serialize, Clone)]
+pub struct Vehicle {
+    pub vehicle_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    pub timestamp: i64,
+    pub lat: Option<f64>,
+    pub lon: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bearing: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub speed: Option<f64>,
+    /// How traffic is affecting it, from the feed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub congestion_level: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trip_run_id: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trip_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub progress: Option<TripProgress>,
+}
+
+/// How far back segment speeds are averaged over if not asked for
+const DEFAULT_SPEED_MINUTES: i64 = 60;
+
+/// How fast a route's vehicles have been going between two of its stops
+#[derive(Debug, Serialize, Clone)]
+pub struct SegmentSpeed {
+    pub from_stop_id: String,
+    pub to_stop_id: String,
+    /// Mean of the speeds vehicles reported, in metres per second
+    pub average_speed: f64,
+    /// How many positions it's the mean of
+    pub samples: usize,
+}
+
+/// The feed's `CongestionLevel`, None if it's unknown
+fn congestion_name(congestion_level: i32) -> Option<&'static str> {
+    match congestion_level {
+        1 => Some("running_smoothly"),
+        2 => Some("stop_and_go"),
+        3 => Some("congestion"),
+        4 => Some("severe_congestion"),
+        _ => None,
+    }
+}
+
+/// Progress of a vehicle at `position`, with the trip's stops in order.
+/// None if the shape's too short to be along.
+fn trip_progress(
+    shape: &[Point],
+    stops: &[(String, Point)],
+    position: Point,
+) -> Option<TripProgress> {
+    let travelled = distance_along(shape, position)?;
+    let total = line_length(shape);
+
+    let next_stop = stops.iter().find_map(|(stop_id, point)| {
+        let along = distance_along(shape, *point)?;
+        (along > travelled).then(|| (stop_id.clone(), along - travelled))
+    });
+
+    Some(TripProgress {
+        distance_travelled: travelled,
+        distance_total: total,
+        percent_complete: if total > 0.0 {
+            (travelled / total * 100.0).clamp(0.0, 100.0)
+        } else {
+            0.0
+        },
+        metres_to_next_stop: next_stop.as_ref().map(|(_, metres)| *metres),
+        next_stop_id: next_stop.map(|(stop_id, _)| stop_id),
+    })
+}
+
+async fn get_progress(
+    ctx: &ContextData,
+    trip_run: &trip_run::Model,
+    position: Point,
+) -> NextAtResult<Option<TripProgress>> {
+    use stop_time_index as sti;
+
+    let Some(shape) = trip_shape(&ctx.read_db, &trip_run.trip_id).await? else {
+        return Ok(None);
+    };
+
+    // Where it actually stops, as stops can be moved
+    let stop_ids = sti::Entity::find()
+        .filter(sti::Column::TripRunId.eq(trip_run.id))
+        .order_by_asc(sti::Column::StopSequence)
+        .all(&ctx.read_db)
+        .await?
+        .into_iter()
+        .filter(|st| st.skipped == 0)
+        .map(|st| st.updated_stop_id.unwrap_or(st.stop_id))
+        .collect::<Vec<_>>();
+    let points = gtfs_stops::Entity::find()
+        .filter(gtfs_stops::Column::StopId.is_in(stop_ids.clone()))
+        .all(&ctx.read_db)
+        .await?
+        .into_iter()
+        .filter_map(|s| Some((s.stop_id, Point::new(s.stop_lon?, s.stop_lat?))))
+        .collect::<HashMap<_, _>>();
+    let stops = stop_ids
+        .into_iter()
+        .filter_map(|stop_id| {
+            let point = *points.get(&stop_id)?;
+            Some((stop_id, point))
+        })
+        .collect::<Vec<_>>();
+
+    Ok(trip_progress(&shape, &stops, position))
+}
+
+pub async fn get_vehicle(ctx: &ContextData, vehicle_id: &str) -> NextAtResult<Vehicle> {
+    let vehicle = vehicle::Entity::find()
+        .filter(vehicle::Column::VehicleId.eq(vehicle_id))
+        .one(&ctx.read_db)
+        .await?
+        .ok_or_else(|| NextAtError::NotFound(format!("Vehicle not found: {}", vehicle_id)))?;
+
+    // The latest trip it's been assigned
+    let trip_run = trip_run::Entity::find()
+        .filter(trip_run::Column::VehicleId.eq(vehicle_id))
+        .order_by_desc(trip_run::Column::StartTimestamp)
+        .one(&ctx.read_db)
+        .await?;
+
+    let progress = match (&trip_run, vehicle.latitude, vehicle.longitude) {
+        (Some(trip_run), Some(lat), Some(lon)) => {
+            get_progress(ctx, trip_run, Point::new(lon, lat)).await?
+        }
+        _ => None,
+    };
+
+    Ok(Vehicle {
+        vehicle_id: vehicle.vehicle_id,
+        label: vehicle.label,
+        timestamp: vehicle.timestamp,
+        lat: vehicle.latitude,
+        lon: vehicle.longitude,
+        bearing: vehicle.bearing,
+        speed: vehicle.speed,
+        congestion_level: vehicle.congestion_level.and_then(congestion_name),
+        trip_run_id: trip_run.as_ref().map(|tr| tr.id),
+        trip_id: trip_run.map(|tr| tr.trip_id),
+        progress,
+    })
+}
+
+/// Where the vehicle has been since `since` (in millis), oldest first
+pub async fn get_trajectory(
+    ctx: &ContextData,
+    vehicle_id: &str,
+    since: Option<i64>,
+) -> NextAtResult<Vec<TrajectoryPoint>> {
+    use vehicle_position_history as vph;
+
+    let since = since.unwrap_or_else(|| {
+        (Utc::now() - Duration::minutes(DEFAULT_TRAJECTORY_MINUTES)).timestamp_millis()
+    });
+
+    let mut positions = vph::Entity::find()
+        .filter(vph::Column::VehicleId.eq(vehicle_id))
+        .filter(vph::Column::Timestamp.gte(since))
+        .order_by_desc(vph::Column::Timestamp)
+        .limit(MAX_TRAJECTORY_POINTS)
+        .all(&ctx.read_db)
+        .await?;
+    positions.reverse();
+
+    if positions.is_empty() {
+        let known_vehicle = vehicle::Entity::find()
+            .filter(vehicle::Column::VehicleId.eq(vehicle_id))
+            .one(&ctx.read_db)
+            .await?
+            .is_some();
+        if !known_vehicle {
+            return Err(NextAtError::NotFound(format!(
+                "Vehicle not found: {}",
+                vehicle_id
+            )));
+        }
+    }
+
+    let points = positions
+        .into_iter()
+        .map(|p| TrajectoryPoint {
+            timestamp: p.timestamp,
+            lat: p.latitude,
+            lon: p.longitude,
+            bearing: p.bearing,
+            speed: p.speed,
+            congestion_level: p.congestion_level.and_then(congestion_name),
+            trip_run_id: p.trip_run_id,
+        })
+        .collect();
+    Ok(points)
+}
+
+/// Which of its stops the trip run was between at `timestamp`, going by when it was expected
+/// to leave each. None before it leaves the first or after it leaves the last.
+fn segment_at(stop_times: &[stop_time_index::Model], timestamp: i64) -> Option<(String, String)> {
+    let departure = |st: &stop_time_index::Model| {
+        st.updated_departure_timestamp
+            .or(st.estimated_departure_timestamp)
+            .unwrap_or(st.departure_timestamp)
+    };
+    let stop_id = |st: &stop_time_index::Model| {
+        st.updated_stop_id
+            .clone()
+            .unwrap_or_else(|| st.stop_id.clone())
+    };
+    let from = stop_times
+        .iter()
+        .rposition(|st| departure(st) <= timestamp)?;
+    let to = stop_times.get(from + 1)?;
+    Some((stop_id(&stop_times[from]), stop_id(to)))
+}
+
+/// Average speeds between each pair of the route's stops since `since` (in millis),
+/// from the positions of vehicles on its trips, in the order of the route
+pub async fn get_segment_speeds(
+    ctx: &ContextData,
+    route_id: &str,
+    since: Option<i64>,
+) -> NextAtResult<Vec<SegmentSpeed>> {
+    use stop_time_index as sti;
+    use vehicle_position_history as vph;
+
+    stops::get_route(ctx, route_id).await?;
+
+    let since = since.unwrap_or_else(|| {
+        (Utc::now() - Duration::minutes(DEFAULT_SPEED_MINUTES)).timestamp_millis()
+    });
+    let route_trip_runs = trip_run::Entity::find()
+        .select_only()
+        .column(trip_run::Column::Id)
+        .filter(trip_run::Column::RouteId.eq(route_id))
+        .into_query();
+    let positions = vph::Entity::find()
+        .filter(vph::Column::TripRunId.in_subquery(route_trip_runs))
+        .filter(vph::Column::Timestamp.gte(since))
+        .filter(vph::Column::Speed.is_not_null())
+        .all(&ctx.read_db)
+        .await?;
+
+    let trip_run_ids = positions.iter().filter_map(|p| p.trip_run_id).unique();
+    let stop_times = sti::Entity::find()
+        .filter(sti::Column::TripRunId.is_in(trip_run_ids))
+        .filter(sti::Column::Skipped.eq(0))
+        .order_by_asc(sti::Column::TripRunId)
+        .order_by_asc(sti::Column::StopSequence)
+        .all(&ctx.read_db)
+        .await?
+        .into_iter()
+        .into_group_map_by(|st| st.trip_run_id);
+
+    // Speeds of each segment, and where it comes along the route
+    let mut segments: HashMap<(String, String), (Vec<f64>, i32)> = HashMap::new();
+    for position in positions {
+        let (Some(trip_run_id), Some(speed)) = (position.trip_run_id, position.speed) else {
+            continue;
+        };
+        let Some(stop_times) = stop_times.get(&trip_run_id) else {
+            continue;
+        };
+        let Some(segment) = segment_at(stop_times, position.timestamp) else {
+            continue;
+        };
+        let sequence = stop_times
+            .iter()
+            .find(|st| st.stop_id == segment.0 || st.updated_stop_id.as_ref() == Some(&segment.0))
+            .map_or(0, |st| st.stop_sequence);
+        let (speeds, first_sequence) = segments
+            .entry(segment)
+            .or_insert_with(|| (vec![], sequence));
+        speeds.push(speed);
+        *first_sequence = (*first_sequence).min(sequence);
+    }
+
+    let speeds = segments
+        .into_iter()
+        .sorted_by_key(|(_, (_, sequence))| *sequence)
+        .map(|((from_stop_id, to_stop_id), (speeds, _))| SegmentSpeed {
+            from_stop_id,
+            to_stop_id,
+            average_speed: speeds.iter().sum::<f64>() / speeds.len() as f64,
+            samples: speeds.len(),
+        })
+        .collect();
+    Ok(speeds)
+}
+
+#[cfg(test)]
+mod test {
+
+    use sea_orm::ConnectionTrait;
+
+    use super::*;
+    use crate::test_utils::ctx;
+
+    #[test]
+    fn test_trip_progress() {
+        let shape = [
+            Point::new(174.0, -36.0),
+            Point::new(174.01, -36.0),
+            Point::new(174.02, -36.0),
+        ];
+        let stops = [
+            ("first".to_string(), shape[0]),
+            ("middle".to_string(), shape[1]),
+            ("last".to_string(), shape[2]),
+        ];
+
+        // A quarter of the way, a little off the road
+        let progress = trip_progress(&shape, &stops, Point::new(174.005, -36.0001)).unwrap();
+        assert!((progress.percent_complete - 25.0).abs() < 0.1);
+        assert_eq!(progress.next_stop_id.as_deref(), Some("middle"));
+        let to_middle = progress.metres_to_next_stop.unwrap();
+        assert!((to_middle - progress.distance_total / 4.0).abs() < 5.0);
+
+        let progress = trip_progress(&shape, &stops, Point::new(174.03, -36.0)).unwrap();
+        assert_eq!(progress.percent_complete, 100.0);
+        assert!(progress.next_stop_id.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_segment_speeds() {
+        let ctx = ctx().await;
+        // The NX1 run left Quay Street 5 minutes ago and Lower Albert Street just now
+        ctx.db
+            .execute_unprepared(
+                "INSERT INTO vehicle_position_history (vehicle_id, timestamp, latitude, longitude, speed, trip_run_id)
+                VALUES ('bus', (strftime('%s', 'now') - 120) * 1000, -36.844, 174.766, 10, 1),
+                    ('bus', (strftime('%s', 'now') - 60) * 1000, -36.844, 174.766, 20, 1),
+                    ('bus', (strftime('%s', 'now') + 60) * 1000, -36.848, 174.765, 5, 1);",
+            )
+            .await
+            .unwrap();
+
+        let speeds = get_segment_speeds(&ctx, "NX1-203", None).await.unwrap();
+        assert_eq!(
+            speeds
+                .iter()
+                .map(|s| (s.from_stop_id.as_str(), s.to_stop_id.as_str(), s.samples))
+                .collect::<Vec<_>>(),
+            [
+                ("1010-0c2d2a6b", "4018-7ef4a7b7", 2),
+                ("4018-7ef4a7b7", "7000-0b6a8a4a", 1)
+            ]
+        );
+        assert_eq!(speeds[0].average_speed, 15.0);
+        assert!(get_segment_speeds(&ctx, "WEST-201", None)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -0,0 +1,50 @@
+use std::sync::atomic::{AtomicI64, Ordering};
+
+use chrono::{DateTime, Utc};
+
+/// Tracks when the data behind the API last changed
+/// so that responses can be cached by clients
+#[derive(Debug)]
+pub struct DataVersions {
+    /// Changes whenever static data or the indexes are rebuilt
+    static_version: AtomicI64,
+    /// Header timestamp of the last realtime feed that was applied
+    realtime_version: AtomicI64,
+}
+
+impl DataVersions {
+    pub fn new() -> Self {
+        // Start from now so that a restart doesn't match an old version
+        let now = Utc::now().timestamp_millis();
+        Self {
+            static_version: AtomicI64::new(now),
+            realtime_version: AtomicI64::new(now),
+        }
+    }
+
+    pub fn static_version(&self) -> i64 {
+        self.static_version.load(Ordering::Relaxed)
+    }
+
+    pub fn realtime_version(&self) -> i64 {
+        self.realtime_version.load(Ordering::Relaxed)
+    }
+
+    /// Call after static data has been synced or re-indexed
+    pub fn bump_static(&self) {
+        self.static_version
+            .store(Utc::now().timestamp_millis(), Ordering::Relaxed);
+    }
+
+    /// Call after a realtime feed has been applied
+    pub fn set_realtime(&self, feed_timestamp: DateTime<Utc>) {
+        self.realtime_version
+            .store(feed_timestamp.timestamp_millis(), Ordering::Relaxed);
+    }
+}
+
+impl Default for DataVersions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
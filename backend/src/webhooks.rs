@@ -0,0 +1,260 @@
+//! Outbound webhooks, POSTed when alerts come in, a sync completes or the realtime feed goes stale
+
+use std::{collections::HashSet, time::Duration};
+
+use chrono::Utc;
+use reqwest::{header::CONTENT_TYPE, RequestBuilder};
+use ring::hmac;
+use serde::Serialize;
+use serde_json::{json, Value};
+use tokio::time::sleep;
+use url::Url;
+
+use crate::{
+    config, health::REALTIME_STALE_SECONDS, supervisor::sleep_until_shutdown, ContextData,
+};
+
+/// Header with the event's name
+const EVENT_HEADER: &str = "x-next-at-event";
+
+/// Header with `sha256=` and the hex HMAC of the body, keyed by the webhook's secret
+const SIGNATURE_HEADER: &str = "x-next-at-signature";
+
+/// Tries at delivering an event before it's given up on
+const MAX_ATTEMPTS: u32 = 3;
+
+/// Waited before retrying, longer after each attempt
+const RETRY_DELAY: Duration = Duration::from_secs(10);
+
+/// How often the realtime feed is checked for going stale
+const STALE_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookEvent {
+    /// An alert that wasn't in the feed before
+    Alert,
+    SyncCompleted,
+    /// Realtime hasn't been polled successfully for a while
+    RealtimeStale,
+    /// Realtime is being polled again after being stale
+    RealtimeRecovered,
+}
+
+impl WebhookEvent {
+    const ALL: [WebhookEvent; 4] = [
+        WebhookEvent::Alert,
+        WebhookEvent::SyncCompleted,
+        WebhookEvent::RealtimeStale,
+        WebhookEvent::RealtimeRecovered,
+    ];
+
+    fn name(&self) -> &'static str {
+        match self {
+            WebhookEvent::Alert => "alert",
+            WebhookEvent::SyncCompleted => "sync_completed",
+            WebhookEvent::RealtimeStale => "realtime_stale",
+            WebhookEvent::RealtimeRecovered => "realtime_recovered",
+        }
+    }
+
+    fn parse(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|e| e.name() == name)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Webhook {
+    id: String,
+    url: Url,
+    secret: Option<String>,
+    events: HashSet<WebhookEvent>,
+}
+
+/// Events separated by commas, unknown ones are added to `errors` and left out
+fn parse_events(webhook_id: &str, events: &str, errors: &mut Vec<String>) -> HashSet<WebhookEvent> {
+    events
+        .split(',')
+        .map(str::trim)
+        .filter(|e| !e.is_empty())
+        .filter_map(|e| {
+            let event = WebhookEvent::parse(e);
+            if event.is_none() {
+                errors.push(format!(
+                    "Unknown event {} for the {} webhook",
+                    e, webhook_id
+                ));
+            }
+            event
+        })
+        .collect()
+}
+
+impl Webhook {
+    /// A webhook configured with `WEBHOOK_<ID>_URL`, and optionally `WEBHOOK_<ID>_SECRET`
+    /// and `WEBHOOK_<ID>_EVENTS` (the events it's sent, separated by commas, otherwise all of them)
+    fn from_env_id(id: &str, errors: &mut Vec<String>) -> Option<Self> {
+        let prefix = format!("WEBHOOK_{}", id.to_uppercase().replace('-', "_"));
+
+        let Ok(url) = config::var(format!("{}_URL", prefix)) else {
+            errors.push(format!("{}_URL must be set for the {} webhook", prefix, id));
+            return None;
+        };
+        let url = Url::parse(&url)
+            .map_err(|e| errors.push(format!("Invalid {}_URL: {}", prefix, e)))
+            .ok()?;
+        let events = match config::var(format!("{}_EVENTS", prefix)) {
+            Ok(events) => parse_events(id, &events, errors),
+            Err(_) => WebhookEvent::ALL.into_iter().collect(),
+        };
+
+        Some(Self {
+            id: id.to_string(),
+            url,
+            secret: config::var(format!("{}_SECRET", prefix)).ok(),
+            events,
+        })
+    }
+}
+
+/// Hex HMAC-SHA256 of the body, so receivers can check it's from us
+fn sign(secret: &str, body: &str) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+    hmac::sign(&key, body.as_bytes())
+        .as_ref()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+async fn deliver(webhook_id: String, request: RequestBuilder) {
+    for attempt in 1..=MAX_ATTEMPTS {
+        let Some(request) = request.try_clone() else {
+            return;
+        };
+        match request.send().await.and_then(|r| r.error_for_status()) {
+            Ok(_) => return,
+            Err(e) => tracing::warn!(
+                "Error sending the {} webhook (attempt {} of {}): {}",
+                webhook_id,
+                attempt,
+                MAX_ATTEMPTS,
+                e
+            ),
+        }
+        if attempt < MAX_ATTEMPTS {
+            sleep(RETRY_DELAY * attempt).await;
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Webhooks {
+    webhooks: Vec<Webhook>,
+    client: reqwest::Client,
+}
+
+impl Webhooks {
+    /// Webhooks are listed by id in `WEBHOOKS`, separated by commas.
+    /// Every problem with their settings is added to `errors`.
+    pub fn from_env(errors: &mut Vec<String>) -> Self {
+        let webhooks = config::var("WEBHOOKS")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|id| !id.is_empty())
+            .filter_map(|id| Webhook::from_env_id(id, errors))
+            .collect();
+        Self {
+            webhooks,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.webhooks.is_empty()
+    }
+
+    /// POSTs the event to each webhook that wants it.
+    /// They're sent in the background, so whatever it's about isn't held up.
+    pub fn send(&self, event: WebhookEvent, data: Value) {
+        let body = json!({
+            "event": event,
+            "timestamp": Utc::now(),
+            "data": data,
+        })
+        .to_string();
+
+        for webhook in self.webhooks.iter().filter(|w| w.events.contains(&event)) {
+            let mut request = self
+                .client
+                .post(webhook.url.clone())
+                .header(CONTENT_TYPE, "application/json")
+                .header(EVENT_HEADER, event.name());
+            if let Some(secret) = &webhook.secret {
+                request =
+                    request.header(SIGNATURE_HEADER, format!("sha256={}", sign(secret, &body)));
+            }
+            tokio::spawn(deliver(webhook.id.clone(), request.body(body.clone())));
+        }
+    }
+}
+
+/// Sends realtime_stale when realtime hasn't been polled successfully for a while,
+/// then realtime_recovered once it has been again
+pub async fn watch_realtime(ctx: &ContextData) -> Result<(), std::convert::Infallible> {
+    if ctx.webhooks.is_empty() {
+        // Nothing to do, but stopping would restart it
+        ctx.shutdown.cancelled().await;
+        return Ok(());
+    }
+
+    let mut stale = false;
+    loop {
+        // Not stale before the first poll, which waits for the startup sync
+        let last_poll = ctx.health.realtime_poll.get();
+        let now_stale =
+            last_poll.is_some_and(|t| (Utc::now() - t).num_seconds() > REALTIME_STALE_SECONDS);
+
+        if now_stale != stale {
+            let event = if now_stale {
+                tracing::warn!("Realtime has gone stale, last polled at {:?}", last_poll);
+                WebhookEvent::RealtimeStale
+            } else {
+                tracing::info!("Realtime has recovered");
+                WebhookEvent::RealtimeRecovered
+            };
+            ctx.webhooks.send(event, json!({ "last_poll": last_poll }));
+            stale = now_stale;
+        }
+
+        if !sleep_until_shutdown(STALE_CHECK_INTERVAL, &ctx.shutdown).await {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn test_sign() {
+        // RFC 4231 test case 2
+        assert_eq!(
+            sign("Jefe", "what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn test_parse_events() {
+        let mut errors = vec![];
+        assert_eq!(
+            parse_events("ops", "alert, realtime_stale,,unknown", &mut errors),
+            HashSet::from([WebhookEvent::Alert, WebhookEvent::RealtimeStale])
+        );
+        assert_eq!(errors, ["Unknown event unknown for the ops webhook"]);
+    }
+}